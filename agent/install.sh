@@ -16,7 +16,9 @@ NC='\033[0m' # No Color
 INSTALL_DIR="/usr/local/bin"
 CONFIG_DIR="/etc/ubuntu-auto-update"
 SYSTEMD_DIR="/etc/systemd/system"
+APT_CONF_DIR="/etc/apt/apt.conf.d"
 LOG_DIR="/var/log/ubuntu-auto-update"
+STATE_DIR="/var/lib/ubuntu-auto-update"
 METRICS_DIR="/var/lib/node_exporter/textfile_collector"
 
 # Default values
@@ -217,12 +219,15 @@ create_directories() {
     print_status "Creating directories..."
     
     mkdir -p "$CONFIG_DIR"
+    mkdir -p "$CONFIG_DIR/agent.toml.d"
     mkdir -p "$LOG_DIR"
+    mkdir -p "$STATE_DIR"
     mkdir -p "$METRICS_DIR"
     
     # Set permissions
     chmod 755 "$CONFIG_DIR"
     chmod 755 "$LOG_DIR"
+    chmod 700 "$STATE_DIR"
     chmod 755 "$METRICS_DIR"
     
     print_success "Directories created"
@@ -304,12 +309,24 @@ install_systemd_units() {
     # Install systemd files
     cp "./agent/systemd/ubuntu-auto-update-agent.service" "$SYSTEMD_DIR/"
     cp "./agent/systemd/ubuntu-auto-update-agent.timer" "$SYSTEMD_DIR/"
+    cp "./agent/systemd/ubuntu-auto-update-agent-refresh.service" "$SYSTEMD_DIR/"
+    cp "./agent/systemd/ubuntu-auto-update-agentd.service" "$SYSTEMD_DIR/"
+    cp "./agent/systemd/ubuntu-auto-update-agent-beacon.service" "$SYSTEMD_DIR/"
+    cp "./agent/systemd/ubuntu-auto-update-agent-beacon.timer" "$SYSTEMD_DIR/"
     
     # Set permissions
     chmod 644 "$SYSTEMD_DIR/ubuntu-auto-update-agent.service"
     chmod 644 "$SYSTEMD_DIR/ubuntu-auto-update-agent.timer"
+    chmod 644 "$SYSTEMD_DIR/ubuntu-auto-update-agent-refresh.service"
+    chmod 644 "$SYSTEMD_DIR/ubuntu-auto-update-agentd.service"
+    chmod 644 "$SYSTEMD_DIR/ubuntu-auto-update-agent-beacon.service"
+    chmod 644 "$SYSTEMD_DIR/ubuntu-auto-update-agent-beacon.timer"
     chown root:root "$SYSTEMD_DIR/ubuntu-auto-update-agent.service"
     chown root:root "$SYSTEMD_DIR/ubuntu-auto-update-agent.timer"
+    chown root:root "$SYSTEMD_DIR/ubuntu-auto-update-agent-refresh.service"
+    chown root:root "$SYSTEMD_DIR/ubuntu-auto-update-agentd.service"
+    chown root:root "$SYSTEMD_DIR/ubuntu-auto-update-agent-beacon.service"
+    chown root:root "$SYSTEMD_DIR/ubuntu-auto-update-agent-beacon.timer"
     
     # Reload systemd
     systemctl daemon-reload
@@ -317,6 +334,36 @@ install_systemd_units() {
     print_success "Systemd units installed"
 }
 
+install_apt_hook() {
+    if [[ "$ENABLE_TIMER" == "false" || "${CONFIG_ONLY:-false}" == "true" ]]; then
+        return 0
+    fi
+
+    print_status "Installing apt hook for externally initiated updates..."
+
+    # Starts ubuntu-auto-update-agent-refresh.service after manual apt/dpkg runs
+    cp "./agent/apt/99ubuntu-auto-update-agent" "$APT_CONF_DIR/"
+    chmod 644 "$APT_CONF_DIR/99ubuntu-auto-update-agent"
+    chown root:root "$APT_CONF_DIR/99ubuntu-auto-update-agent"
+
+    print_success "apt hook installed at $APT_CONF_DIR/99ubuntu-auto-update-agent"
+}
+
+install_dbus_policy() {
+    if [[ "${CONFIG_ONLY:-false}" == "true" || ! -d /usr/share/dbus-1/system.d ]]; then
+        return 0
+    fi
+
+    print_status "Installing D-Bus policy..."
+
+    # Lets the daemon own org.ubuntuautoupdate.Agent1 once [dbus] is enabled
+    cp "./agent/dbus/org.ubuntuautoupdate.Agent1.conf" /usr/share/dbus-1/system.d/
+    chmod 644 /usr/share/dbus-1/system.d/org.ubuntuautoupdate.Agent1.conf
+    chown root:root /usr/share/dbus-1/system.d/org.ubuntuautoupdate.Agent1.conf
+
+    print_success "D-Bus policy installed at /usr/share/dbus-1/system.d/org.ubuntuautoupdate.Agent1.conf"
+}
+
 setup_apparmor() {
     print_status "Setting up AppArmor profile..."
     
@@ -363,6 +410,8 @@ enable_timer() {
     
     systemctl enable ubuntu-auto-update-agent.timer
     systemctl start ubuntu-auto-update-agent.timer
+    # Idle until a run opens a beacon window ([beacon] enabled)
+    systemctl enable --now ubuntu-auto-update-agent-beacon.timer
     
     print_success "Systemd timer enabled and started"
     
@@ -410,6 +459,16 @@ systemctl disable ubuntu-auto-update-agent.timer 2>/dev/null || true
 echo -e "${GREEN}[INFO]${NC} Removing systemd files..."
 rm -f /etc/systemd/system/ubuntu-auto-update-agent.service
 rm -f /etc/systemd/system/ubuntu-auto-update-agent.timer
+rm -f /etc/systemd/system/ubuntu-auto-update-agent-refresh.service
+systemctl stop ubuntu-auto-update-agent-beacon.timer 2>/dev/null || true
+systemctl disable ubuntu-auto-update-agent-beacon.timer 2>/dev/null || true
+rm -f /etc/systemd/system/ubuntu-auto-update-agent-beacon.service
+rm -f /etc/systemd/system/ubuntu-auto-update-agent-beacon.timer
+systemctl stop ubuntu-auto-update-agentd.service 2>/dev/null || true
+systemctl disable ubuntu-auto-update-agentd.service 2>/dev/null || true
+rm -f /etc/systemd/system/ubuntu-auto-update-agentd.service
+rm -f /etc/apt/apt.conf.d/99ubuntu-auto-update-agent
+rm -f /usr/share/dbus-1/system.d/org.ubuntuautoupdate.Agent1.conf
 systemctl daemon-reload 2>/dev/null || true
 
 echo -e "${GREEN}[INFO]${NC} Removing binary..."
@@ -490,6 +549,8 @@ main() {
     install_binary
     generate_config
     install_systemd_units
+    install_apt_hook
+    install_dbus_policy
     setup_apparmor
     enroll_agent
     enable_timer
@@ -0,0 +1,170 @@
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::process::Command;
+use tracing::{debug, warn};
+
+use crate::rollback::command_exists;
+
+/// Transient slice the run's package commands are placed in, each in its
+/// own `systemd-run --scope`. A dash would nest it under other slices.
+pub const SLICE: &str = "ua_agent_run.slice";
+
+/// `systemctl show` properties read at the end of the run
+const PROPERTIES: &[&str] = &[
+    "CPUUsageNSec",
+    "MemoryPeak",
+    "IOReadBytes",
+    "IOWriteBytes",
+    "IPIngressBytes",
+    "IPEgressBytes",
+];
+
+/// What the run's package commands and everything they started (dpkg,
+/// maintainer scripts, triggers) consumed, as systemd's cgroup accounting
+/// of `SLICE` has it. Fields are `None` where the kernel or systemd doesn't
+/// account for them, e.g. `memory_peak_bytes` before systemd 255.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ResourceUsage {
+    pub cpu_seconds: Option<f64>,
+    pub memory_peak_bytes: Option<u64>,
+    pub io_read_bytes: Option<u64>,
+    pub io_write_bytes: Option<u64>,
+    pub network_rx_bytes: Option<u64>,
+    pub network_tx_bytes: Option<u64>,
+}
+
+/// Accounting of one update run, with `updates.resource_limits.accounting`
+/// set. The slice outlives the scopes in it and keeps their totals, so
+/// they can be read once the last command has exited.
+pub struct RunAccounting;
+
+impl RunAccounting {
+    /// Starts `SLICE` afresh with CPU, memory, IO and IP accounting. `None`
+    /// without systemd, e.g. in containers; commands then run as usual.
+    pub fn start() -> Option<Self> {
+        if !Path::new("/run/systemd/system").exists()
+            || !command_exists("systemctl")
+            || !command_exists("systemd-run")
+        {
+            debug!("systemd not running, not accounting the run's resource usage");
+            return None;
+        }
+        // Left over from a run that died before stopping it
+        systemctl(&["stop", SLICE]);
+        let started = systemctl(&[
+            "set-property",
+            "--runtime",
+            SLICE,
+            "CPUAccounting=yes",
+            "MemoryAccounting=yes",
+            "IOAccounting=yes",
+            "IPAccounting=yes",
+        ]) && systemctl(&["start", SLICE]);
+        if !started {
+            warn!(
+                "Failed to set up {}, not accounting the run's resource usage",
+                SLICE
+            );
+            return None;
+        }
+        Some(Self)
+    }
+
+    /// Reads the run's totals and stops the slice.
+    pub fn finish(self) -> Option<ResourceUsage> {
+        let mut args = vec!["show", SLICE];
+        for property in PROPERTIES {
+            args.extend(["-p", property]);
+        }
+        let usage = match crate::audit::output(Command::new("systemctl").args(&args)) {
+            Ok(output) if output.status.success() => {
+                Some(parse_show(&String::from_utf8_lossy(&output.stdout)))
+            }
+            Ok(output) => {
+                warn!(
+                    "Failed to read resource usage of {}: {}",
+                    SLICE,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+                None
+            }
+            Err(e) => {
+                warn!("Failed to run systemctl: {}", e);
+                None
+            }
+        };
+        systemctl(&["stop", SLICE]);
+        debug!("Run resource usage: {:?}", usage);
+        usage
+    }
+}
+
+fn systemctl(args: &[&str]) -> bool {
+    match crate::audit::output(Command::new("systemctl").args(args)) {
+        Ok(output) if output.status.success() => true,
+        Ok(output) => {
+            debug!(
+                "systemctl {} failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            false
+        }
+        Err(e) => {
+            debug!("Failed to run systemctl {}: {}", args.join(" "), e);
+            false
+        }
+    }
+}
+
+/// `Key=value` lines; systemd shows `[not set]` or `u64::MAX` for values
+/// it doesn't have.
+fn parse_show(output: &str) -> ResourceUsage {
+    let mut usage = ResourceUsage::default();
+    for line in output.lines() {
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let Some(value) = value.parse::<u64>().ok().filter(|v| *v != u64::MAX) else {
+            continue;
+        };
+        match key {
+            "CPUUsageNSec" => usage.cpu_seconds = Some(value as f64 / 1e9),
+            "MemoryPeak" => usage.memory_peak_bytes = Some(value),
+            "IOReadBytes" => usage.io_read_bytes = Some(value),
+            "IOWriteBytes" => usage.io_write_bytes = Some(value),
+            "IPIngressBytes" => usage.network_rx_bytes = Some(value),
+            "IPEgressBytes" => usage.network_tx_bytes = Some(value),
+            _ => {}
+        }
+    }
+    usage
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_show() {
+        let usage = parse_show(
+            "CPUUsageNSec=84512000000\n\
+             MemoryPeak=[not set]\n\
+             IOReadBytes=18446744073709551615\n\
+             IOWriteBytes=912261120\n\
+             IPIngressBytes=241172480\n\
+             IPEgressBytes=3145728\n",
+        );
+        assert_eq!(
+            usage,
+            ResourceUsage {
+                cpu_seconds: Some(84.512),
+                memory_peak_bytes: None,
+                io_read_bytes: None,
+                io_write_bytes: Some(912_261_120),
+                network_rx_bytes: Some(241_172_480),
+                network_tx_bytes: Some(3_145_728),
+            }
+        );
+    }
+}
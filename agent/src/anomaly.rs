@@ -0,0 +1,192 @@
+use chrono::Duration;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::config::AgentConfig;
+use crate::history::{HistoryFilter, HistoryStore, RunRecord};
+
+/// Most recent runs that make up the baseline
+const BASELINE_RUNS: usize = 20;
+
+/// Fewer comparable runs than this flag nothing, so a new host isn't
+/// flagged on its second run
+const MIN_BASELINE_RUNS: usize = 5;
+
+const SLOW_RUN_FACTOR: f64 = 3.0;
+
+const HEAVY_DOWNLOAD_FACTOR: f64 = 5.0;
+
+/// Below this a download isn't flagged however small the usual ones are
+const MIN_HEAVY_DOWNLOAD_BYTES: u64 = 100 * 1024 * 1024;
+
+/// A package upgraded in this many runs within `REPEAT_WINDOW_DAYS`,
+/// counting the current one, is flagged
+const REPEAT_MIN_RUNS: usize = 3;
+
+const REPEAT_WINDOW_DAYS: i64 = 7;
+
+/// A run that stands out from this host's own history.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Anomaly {
+    /// Took more than three times the median of recent runs
+    SlowRun {
+        duration_seconds: f64,
+        median_seconds: f64,
+    },
+    /// Downloaded more than five times the median of recent runs
+    HeavyDownload { bytes: u64, median_bytes: u64 },
+    /// The same package upgraded again and again, e.g. a flapping hold or a
+    /// repository republishing versions
+    RepeatedUpdates { package: String, runs: usize },
+}
+
+/// Compares a run just recorded with the host's earlier runs in the local
+/// history. Reading the history failing flags nothing.
+pub fn detect(config: &AgentConfig, current: &RunRecord) -> Vec<Anomaly> {
+    let history = match HistoryStore::new(config).list(&HistoryFilter::default()) {
+        Ok(records) => records,
+        Err(e) => {
+            warn!("Failed to read run history for anomaly checks: {}", e);
+            return Vec::new();
+        }
+    };
+    let earlier: Vec<&RunRecord> = history
+        .iter()
+        .filter(|record| record.timestamp < current.timestamp)
+        .collect();
+    let anomalies = compare(&earlier, current);
+    for anomaly in &anomalies {
+        warn!("Run anomaly: {:?}", anomaly);
+    }
+    anomalies
+}
+
+/// `earlier` is oldest first.
+fn compare(earlier: &[&RunRecord], current: &RunRecord) -> Vec<Anomaly> {
+    let mut anomalies = Vec::new();
+    if !current.success || current.packages_updated == 0 {
+        return anomalies;
+    }
+
+    // Runs without updates take seconds and would make any upgrade look slow
+    let baseline: Vec<&RunRecord> = earlier
+        .iter()
+        .rev()
+        .filter(|record| record.success && record.packages_updated > 0)
+        .take(BASELINE_RUNS)
+        .copied()
+        .collect();
+    if baseline.len() >= MIN_BASELINE_RUNS {
+        let median_seconds = median(baseline.iter().map(|record| record.duration_seconds));
+        if current.duration_seconds > median_seconds * SLOW_RUN_FACTOR {
+            anomalies.push(Anomaly::SlowRun {
+                duration_seconds: current.duration_seconds,
+                median_seconds,
+            });
+        }
+        let median_bytes = median(baseline.iter().map(|record| record.bytes_downloaded as f64));
+        if current.bytes_downloaded >= MIN_HEAVY_DOWNLOAD_BYTES
+            && current.bytes_downloaded as f64 > median_bytes * HEAVY_DOWNLOAD_FACTOR
+        {
+            anomalies.push(Anomaly::HeavyDownload {
+                bytes: current.bytes_downloaded,
+                median_bytes: median_bytes as u64,
+            });
+        }
+    }
+
+    let since = current.timestamp - Duration::days(REPEAT_WINDOW_DAYS);
+    let recent: Vec<&RunRecord> = earlier
+        .iter()
+        .filter(|record| record.timestamp >= since)
+        .copied()
+        .collect();
+    for package in &current.packages {
+        let runs = 1 + recent
+            .iter()
+            .filter(|record| record.packages.contains(package))
+            .count();
+        if runs >= REPEAT_MIN_RUNS {
+            anomalies.push(Anomaly::RepeatedUpdates {
+                package: package.clone(),
+                runs,
+            });
+        }
+    }
+    anomalies
+}
+
+fn median(values: impl Iterator<Item = f64>) -> f64 {
+    let mut values: Vec<f64> = values.collect();
+    values.sort_by(f64::total_cmp);
+    match values.len() {
+        0 => 0.0,
+        len if len % 2 == 0 => (values[len / 2 - 1] + values[len / 2]) / 2.0,
+        len => values[len / 2],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn run(
+        days_ago: i64,
+        duration_seconds: f64,
+        bytes_downloaded: u64,
+        packages: &[&str],
+    ) -> RunRecord {
+        RunRecord {
+            timestamp: Utc::now() - Duration::days(days_ago),
+            success: true,
+            duration_seconds,
+            packages_updated: packages.len().max(1) as u64,
+            packages_available: 0,
+            bytes_downloaded,
+            reboot_required: false,
+            error_message: None,
+            skipped_reason: None,
+            packages: packages.iter().map(|package| package.to_string()).collect(),
+            prev_hash: None,
+            hash: None,
+        }
+    }
+
+    #[test]
+    fn test_flags_runs_far_from_the_baseline() {
+        let mut earlier: Vec<RunRecord> = (10..16)
+            .rev()
+            .map(|days_ago| run(days_ago, 100.0, 20_000_000, &["curl"]))
+            .collect();
+        earlier.push(run(3, 120.0, 30_000_000, &["firefox", "curl"]));
+        earlier.push(run(2, 90.0, 25_000_000, &["firefox"]));
+        let earlier: Vec<&RunRecord> = earlier.iter().collect();
+
+        let normal = run(0, 250.0, 90_000_000, &["curl"]);
+        assert!(compare(&earlier, &normal).is_empty());
+
+        let current = run(0, 400.0, 600_000_000, &["firefox", "libssl3t64"]);
+        assert_eq!(
+            compare(&earlier, &current),
+            vec![
+                Anomaly::SlowRun {
+                    duration_seconds: 400.0,
+                    median_seconds: 100.0
+                },
+                Anomaly::HeavyDownload {
+                    bytes: 600_000_000,
+                    median_bytes: 20_000_000
+                },
+                Anomaly::RepeatedUpdates {
+                    package: "firefox".to_string(),
+                    runs: 3
+                },
+            ]
+        );
+
+        // Too little history to judge speed or size
+        assert_eq!(compare(&earlier[5..], &current).len(), 1);
+    }
+}
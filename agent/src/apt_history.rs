@@ -0,0 +1,295 @@
+use anyhow::{Context, Result};
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use tracing::{debug, info};
+
+use crate::config::AgentConfig;
+
+/// apt's own transaction log, rotated monthly to history.log.1.gz
+const HISTORY_LOG: &str = "/var/log/apt/history.log";
+
+/// One apt or apt-get invocation recorded in history.log.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AptTransaction {
+    /// Local time, as apt logs it
+    pub start: NaiveDateTime,
+    pub commandline: Option<String>,
+    /// "user (uid)" when run through sudo or pkexec
+    pub requested_by: Option<String>,
+    pub changes: Vec<PackageChange>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PackageChange {
+    /// install, reinstall, upgrade, downgrade, remove or purge
+    pub action: String,
+    /// With the architecture, e.g. "curl:amd64"
+    pub package: String,
+    /// The version installed, or the one removed for remove and purge
+    pub version: Option<String>,
+    /// Set for upgrades and downgrades
+    pub previous_version: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct HistoryCursor {
+    /// Start of the newest transaction read from the log
+    seen_until: Option<NaiveDateTime>,
+    /// Read from the log but not yet delivered in a report
+    pending: Vec<AptTransaction>,
+}
+
+/// Picks out package changes made outside the agent (an admin's `apt
+/// install`, config management, unattended-upgrades) from apt's
+/// history.log, so the next report carries them and the backend's inventory
+/// doesn't drift. Transactions read from the log stay pending in the state
+/// directory until a report carrying them is delivered.
+pub struct AptHistory {
+    log_path: PathBuf,
+    path: PathBuf,
+}
+
+impl AptHistory {
+    pub fn new(config: &AgentConfig) -> Self {
+        Self {
+            log_path: PathBuf::from(HISTORY_LOG),
+            path: config.state.dir.join("apt-history.json"),
+        }
+    }
+
+    /// Moves the transactions logged since the last look to the pending
+    /// list. Called before the agent touches apt, so everything it finds was
+    /// done by someone else. The first look only marks where the log ends,
+    /// so older history isn't reported as new.
+    pub fn collect(&self) -> Result<()> {
+        let transactions = self.read_log()?;
+        let Some(newest) = transactions.last().map(|transaction| transaction.start) else {
+            return Ok(());
+        };
+        let mut cursor = self.load()?;
+        if let Some(seen_until) = cursor.seen_until {
+            let new: Vec<AptTransaction> = transactions
+                .into_iter()
+                .filter(|transaction| transaction.start > seen_until)
+                .collect();
+            if !new.is_empty() {
+                info!(
+                    "{} apt transaction(s) made outside the agent since its last run",
+                    new.len()
+                );
+            }
+            cursor.pending.extend(new);
+        }
+        cursor.seen_until = cursor.seen_until.max(Some(newest));
+        self.save(&cursor)
+    }
+
+    /// Moves past the transactions of the agent's own run.
+    pub fn skip_to_end(&self) -> Result<()> {
+        let Some(newest) = self.read_log()?.last().map(|transaction| transaction.start) else {
+            return Ok(());
+        };
+        let mut cursor = self.load()?;
+        cursor.seen_until = cursor.seen_until.max(Some(newest));
+        self.save(&cursor)
+    }
+
+    pub fn pending(&self) -> Result<Vec<AptTransaction>> {
+        Ok(self.load()?.pending)
+    }
+
+    /// Forgets the transactions a delivered report carried.
+    pub fn acknowledge(&self, delivered: &[AptTransaction]) -> Result<()> {
+        if delivered.is_empty() {
+            return Ok(());
+        }
+        let mut cursor = self.load()?;
+        cursor
+            .pending
+            .retain(|transaction| !delivered.contains(transaction));
+        self.save(&cursor)
+    }
+
+    /// The current log, preceded by last month's when logrotate left it
+    /// uncompressed. Transactions only in a compressed rotation are missed.
+    fn read_log(&self) -> Result<Vec<AptTransaction>> {
+        let mut transactions = Vec::new();
+        for path in [self.log_path.with_extension("log.1"), self.log_path.clone()] {
+            match fs::read_to_string(&path) {
+                Ok(content) => transactions.extend(parse_history(&content)),
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    debug!("No apt history at {:?}", path)
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Failed to read apt history {:?}", path))
+                }
+            }
+        }
+        Ok(transactions)
+    }
+
+    fn load(&self) -> Result<HistoryCursor> {
+        if !self.path.exists() {
+            return Ok(HistoryCursor::default());
+        }
+        let content = fs::read_to_string(&self.path)
+            .with_context(|| format!("Failed to read apt history state from {:?}", self.path))?;
+        serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse apt history state in {:?}", self.path))
+    }
+
+    fn save(&self, cursor: &HistoryCursor) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory: {:?}", parent))?;
+        }
+        fs::write(&self.path, serde_json::to_string_pretty(cursor)?)
+            .with_context(|| format!("Failed to write apt history state to {:?}", self.path))
+    }
+}
+
+/// Parses history.log: blank-line separated stanzas of `Key: value` lines,
+/// one per transaction. Stanzas without a readable Start-Date are skipped.
+fn parse_history(content: &str) -> Vec<AptTransaction> {
+    content
+        .split("\n\n")
+        .filter_map(|stanza| {
+            let mut start = None;
+            let mut commandline = None;
+            let mut requested_by = None;
+            let mut changes = Vec::new();
+            for line in stanza.lines() {
+                let Some((key, value)) = line.split_once(": ") else {
+                    continue;
+                };
+                match key {
+                    // "2026-10-14  10:21:33", with two spaces
+                    "Start-Date" => {
+                        let value = value.split_whitespace().collect::<Vec<_>>().join(" ");
+                        start = NaiveDateTime::parse_from_str(&value, "%Y-%m-%d %H:%M:%S").ok();
+                    }
+                    "Commandline" => commandline = Some(value.to_string()),
+                    "Requested-By" => requested_by = Some(value.to_string()),
+                    "Install" | "Reinstall" | "Upgrade" | "Downgrade" | "Remove" | "Purge" => {
+                        changes.extend(parse_changes(&key.to_lowercase(), value))
+                    }
+                    _ => {}
+                }
+            }
+            Some(AptTransaction {
+                start: start?,
+                commandline,
+                requested_by,
+                changes,
+            })
+        })
+        .collect()
+}
+
+/// "curl:amd64 (8.5.0-2ubuntu10.1, 8.5.0-2ubuntu10.4), htop:amd64 (3.3.0-4,
+/// automatic)": upgrades and downgrades list the old and new version, the
+/// rest one version and possibly "automatic".
+fn parse_changes(action: &str, value: &str) -> Vec<PackageChange> {
+    value
+        .split("), ")
+        .filter_map(|entry| {
+            let (package, versions) = entry.trim_end_matches(')').split_once(" (")?;
+            let mut versions = versions.split(", ").filter(|v| *v != "automatic");
+            let (previous_version, version) = match action {
+                "upgrade" | "downgrade" => (versions.next(), versions.next()),
+                _ => (None, versions.next()),
+            };
+            Some(PackageChange {
+                action: action.to_string(),
+                package: package.trim().to_string(),
+                version: version.map(str::to_string),
+                previous_version: previous_version.map(str::to_string),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOG: &str = "
+Start-Date: 2026-10-12  09:14:02
+Commandline: apt install htop
+Requested-By: alice (1000)
+Install: htop:amd64 (3.3.0-4), libnl-genl-3-200:amd64 (3.7.0-0.3build1, automatic)
+End-Date: 2026-10-12  09:14:05
+
+Start-Date: 2026-10-14  06:25:40
+Commandline: /usr/bin/unattended-upgrade
+Upgrade: curl:amd64 (8.5.0-2ubuntu10.1, 8.5.0-2ubuntu10.4)
+Remove: telnet:amd64 (0.17+2.5-3ubuntu4)
+End-Date: 2026-10-14  06:25:51
+";
+
+    #[test]
+    fn test_parse_history() {
+        let transactions = parse_history(LOG);
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(
+            transactions[0].requested_by.as_deref(),
+            Some("alice (1000)")
+        );
+        assert_eq!(
+            transactions[0].changes[1],
+            PackageChange {
+                action: "install".to_string(),
+                package: "libnl-genl-3-200:amd64".to_string(),
+                version: Some("3.7.0-0.3build1".to_string()),
+                previous_version: None,
+            }
+        );
+        assert_eq!(
+            transactions[1].changes,
+            vec![
+                PackageChange {
+                    action: "upgrade".to_string(),
+                    package: "curl:amd64".to_string(),
+                    version: Some("8.5.0-2ubuntu10.4".to_string()),
+                    previous_version: Some("8.5.0-2ubuntu10.1".to_string()),
+                },
+                PackageChange {
+                    action: "remove".to_string(),
+                    package: "telnet:amd64".to_string(),
+                    version: Some("0.17+2.5-3ubuntu4".to_string()),
+                    previous_version: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_collects_only_changes_since_last_look() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let history = AptHistory {
+            log_path: temp_dir.path().join("history.log"),
+            path: temp_dir.path().join("apt-history.json"),
+        };
+        let (first, second) = LOG.split_at(LOG.find("\n\nStart-Date: 2026-10-14").unwrap());
+
+        fs::write(&history.log_path, first).unwrap();
+        history.collect().unwrap();
+        assert!(history.pending().unwrap().is_empty());
+
+        fs::write(&history.log_path, LOG).unwrap();
+        history.collect().unwrap();
+        let pending = history.pending().unwrap();
+        assert_eq!(pending, parse_history(second));
+
+        // Nothing new, and the agent's own run is skipped
+        history.collect().unwrap();
+        history.skip_to_end().unwrap();
+        assert_eq!(history.pending().unwrap(), pending);
+
+        history.acknowledge(&pending).unwrap();
+        assert!(history.pending().unwrap().is_empty());
+    }
+}
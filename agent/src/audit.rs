@@ -0,0 +1,260 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::{ExitStatus, Output};
+use std::sync::Mutex;
+use tracing::warn;
+
+use crate::config::AgentConfig;
+
+/// Commands kept for the next report; later ones are only counted there,
+/// the audit log has them all
+const REPORT_LIMIT: usize = 200;
+
+/// `None` until `init`, or with auditing off
+static LOG: Mutex<Option<AuditLog>> = Mutex::new(None);
+
+/// One external command the agent ran, as a line of the audit log.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub argv: Vec<String>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    /// `None` when it was killed by a signal, stopped by the agent or never
+    /// started
+    pub exit_code: Option<i32>,
+    /// SHA-256 of stdout followed by stderr, as far as the agent read them
+    pub output_sha256: Option<String>,
+    pub output_bytes: u64,
+    /// Why it couldn't be started or didn't finish, e.g. a timeout
+    pub error: Option<String>,
+}
+
+impl AuditEntry {
+    fn failed(&self) -> bool {
+        self.exit_code != Some(0)
+    }
+}
+
+/// The commands run since the previous report, included in the next one.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AuditSummary {
+    pub commands: usize,
+    /// Exited non-zero, by a signal, or never started
+    pub failed: usize,
+    /// The first `REPORT_LIMIT` of them
+    pub entries: Vec<AuditEntry>,
+}
+
+/// Starts logging commands to `audit.file`, or `<state.dir>/audit.jsonl`,
+/// with `audit.enabled`.
+pub fn init(config: &AgentConfig) {
+    *LOG.lock().unwrap() = config.audit.enabled.then(|| {
+        AuditLog::new(
+            config
+                .audit
+                .file
+                .clone()
+                .unwrap_or_else(|| config.state.dir.join("audit.jsonl")),
+        )
+    });
+}
+
+/// Takes the commands run since the last call, for a report. `None` with
+/// auditing off.
+pub fn take_summary() -> Option<AuditSummary> {
+    LOG.lock()
+        .unwrap()
+        .as_mut()
+        .map(|log| std::mem::take(&mut log.pending))
+}
+
+struct AuditLog {
+    path: PathBuf,
+    /// Commands run since the last report
+    pending: AuditSummary,
+}
+
+impl AuditLog {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            pending: AuditSummary::default(),
+        }
+    }
+
+    fn record(&mut self, entry: AuditEntry) {
+        if let Err(e) = write_entry(&self.path, &entry) {
+            warn!("Failed to write audit log {:?}: {}", self.path, e);
+        }
+        self.pending.commands += 1;
+        if entry.failed() {
+            self.pending.failed += 1;
+        }
+        if self.pending.entries.len() < REPORT_LIMIT {
+            self.pending.entries.push(entry);
+        }
+    }
+}
+
+/// A command being audited, for callers that stream its output instead of
+/// going through `output`.
+pub struct CommandAudit {
+    argv: Vec<String>,
+    started_at: DateTime<Utc>,
+    hasher: Sha256,
+    output_bytes: u64,
+    /// Whether the output was read at all
+    captured: bool,
+}
+
+impl CommandAudit {
+    pub fn start(argv: &[String]) -> Self {
+        Self {
+            argv: argv.to_vec(),
+            started_at: Utc::now(),
+            hasher: Sha256::new(),
+            output_bytes: 0,
+            captured: false,
+        }
+    }
+
+    pub fn of(command: &std::process::Command) -> Self {
+        let argv: Vec<String> = std::iter::once(command.get_program())
+            .chain(command.get_args())
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        Self::start(&argv)
+    }
+
+    /// Adds output read from the command to its hash.
+    pub fn output(&mut self, bytes: &[u8]) {
+        self.hasher.update(bytes);
+        self.output_bytes += bytes.len() as u64;
+        self.captured = true;
+    }
+
+    pub fn finish(self, status: Option<ExitStatus>, error: Option<String>) {
+        record(self.entry(status, error));
+    }
+
+    fn entry(self, status: Option<ExitStatus>, error: Option<String>) -> AuditEntry {
+        AuditEntry {
+            argv: self.argv,
+            started_at: self.started_at,
+            finished_at: Utc::now(),
+            exit_code: status.and_then(|status| status.code()),
+            output_sha256: self
+                .captured
+                .then(|| format!("{:x}", self.hasher.finalize())),
+            output_bytes: self.output_bytes,
+            error,
+        }
+    }
+
+    fn output_entry(mut self, result: &io::Result<Output>) -> AuditEntry {
+        match result {
+            Ok(output) => {
+                self.output(&output.stdout);
+                self.output(&output.stderr);
+                self.entry(Some(output.status), None)
+            }
+            Err(e) => self.entry(None, Some(e.to_string())),
+        }
+    }
+}
+
+/// `Command::output`, audited.
+pub fn output(command: &mut std::process::Command) -> io::Result<Output> {
+    let audit = CommandAudit::of(command);
+    let result = command.output();
+    record(audit.output_entry(&result));
+    result
+}
+
+/// `Command::status`, audited. The output isn't captured, so it isn't
+/// hashed either.
+pub fn status(command: &mut std::process::Command) -> io::Result<ExitStatus> {
+    let audit = CommandAudit::of(command);
+    let result = command.status();
+    match &result {
+        Ok(status) => audit.finish(Some(*status), None),
+        Err(e) => audit.finish(None, Some(e.to_string())),
+    }
+    result
+}
+
+/// `tokio::process::Command::output`, audited.
+pub async fn output_async(command: &mut tokio::process::Command) -> io::Result<Output> {
+    let audit = CommandAudit::of(command.as_std());
+    let result = command.output().await;
+    record(audit.output_entry(&result));
+    result
+}
+
+fn record(entry: AuditEntry) {
+    if let Some(log) = LOG.lock().unwrap().as_mut() {
+        log.record(entry);
+    }
+}
+
+/// Only ever appended to, one JSON object per line.
+fn write_entry(path: &PathBuf, entry: &AuditEntry) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(&line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    fn run(command: &mut Command) -> AuditEntry {
+        let audit = CommandAudit::of(command);
+        audit.output_entry(&command.output())
+    }
+
+    #[test]
+    fn test_commands_are_logged_and_summarized() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("audit.jsonl");
+        let mut log = AuditLog::new(path.clone());
+
+        let echoed = run(Command::new("echo").arg("hello"));
+        assert_eq!(echoed.argv, ["echo", "hello"]);
+        assert_eq!(echoed.exit_code, Some(0));
+        assert_eq!(
+            echoed.output_sha256,
+            Some(format!("{:x}", Sha256::digest(b"hello\n")))
+        );
+        assert_eq!(echoed.output_bytes, 6);
+        let missing = run(&mut Command::new("/nonexistent/command"));
+        assert!(missing.error.is_some());
+        assert_eq!(missing.output_sha256, None);
+        log.record(echoed);
+        log.record(missing);
+        log.record(run(&mut Command::new("false")));
+
+        let summary = std::mem::take(&mut log.pending);
+        assert_eq!(summary.commands, 3);
+        assert_eq!(summary.failed, 2);
+
+        let logged: Vec<AuditEntry> = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(logged, summary.entries);
+    }
+}
@@ -0,0 +1,255 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+use tracing::{debug, info, warn};
+
+use crate::config::AgentConfig;
+use crate::http_client::SecureHttpClient;
+use crate::privacy::Redactor;
+
+/// Beacon window opened by an update run that changed packages.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BeaconState {
+    pub run_timestamp: DateTime<Utc>,
+    pub until: DateTime<Utc>,
+    pub last_sent: Option<DateTime<Utc>>,
+    pub sequence: u64,
+}
+
+impl BeaconState {
+    fn is_due(&self, now: DateTime<Utc>, interval: Duration) -> bool {
+        match self.last_sent {
+            Some(last_sent) => now - last_sent >= interval,
+            None => true,
+        }
+    }
+}
+
+/// "I'm alive" message POSTed to `/api/v1/beacon`; the backend alerts when
+/// the next one doesn't arrive before the window ends.
+#[derive(Debug, Serialize)]
+pub struct Beacon {
+    pub hostname: String,
+    pub agent_version: String,
+    pub timestamp: DateTime<Utc>,
+    /// Update run that opened the beacon window
+    pub run_timestamp: DateTime<Utc>,
+    pub sequence: u64,
+    pub expires_at: DateTime<Utc>,
+    pub interval_minutes: u64,
+    /// Lets the backend tell a rebooted host from one that stayed up
+    pub uptime_seconds: u64,
+    pub healthy: bool,
+    /// `systemctl is-system-running` output, e.g. "running" or "degraded"
+    pub system_state: String,
+    pub failed_units: Vec<String>,
+}
+
+/// Sends post-update health beacons for `beacon.duration_hours` after a run
+/// that changed packages, so hosts that die shortly after patching (e.g. on
+/// reboot) stand out. The window is kept in the state directory so it
+/// survives reboots and works with the oneshot timer.
+pub struct BeaconManager {
+    path: PathBuf,
+    enabled: bool,
+    interval: Duration,
+    duration: Duration,
+}
+
+impl BeaconManager {
+    pub fn new(config: &AgentConfig) -> Self {
+        Self {
+            path: config.state.dir.join("beacon.json"),
+            enabled: config.beacon.enabled,
+            interval: Duration::minutes(config.beacon.interval_minutes as i64),
+            duration: Duration::hours(config.beacon.duration_hours as i64),
+        }
+    }
+
+    /// Opens (or restarts) the beacon window for a run that updated packages.
+    pub fn arm(&self, run_timestamp: DateTime<Utc>) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        let state = BeaconState {
+            run_timestamp,
+            until: run_timestamp + self.duration,
+            last_sent: None,
+            sequence: 0,
+        };
+        self.save(&state)?;
+        info!(
+            "Sending health beacons until {}",
+            state.until.format("%Y-%m-%d %H:%M UTC")
+        );
+        Ok(())
+    }
+
+    /// Returns the open beacon window, clearing it once it has ended.
+    pub fn load(&self) -> Result<Option<BeaconState>> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+
+        let content = fs::read_to_string(&self.path)
+            .with_context(|| format!("Failed to read beacon state from {:?}", self.path))?;
+        let state: BeaconState = serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse beacon state in {:?}", self.path))?;
+
+        if Utc::now() < state.until {
+            Ok(Some(state))
+        } else {
+            debug!("Beacon window ended");
+            fs::remove_file(&self.path)
+                .with_context(|| format!("Failed to remove beacon state {:?}", self.path))?;
+            Ok(None)
+        }
+    }
+
+    /// Sends a beacon if a window is open and the interval has passed.
+    /// Returns whether one was sent.
+    pub async fn send_if_due(
+        &self,
+        config: &AgentConfig,
+        http_client: &SecureHttpClient,
+    ) -> Result<bool> {
+        let Some(mut state) = self.load()? else {
+            return Ok(false);
+        };
+        let now = Utc::now();
+        if !state.is_due(now, self.interval) {
+            return Ok(false);
+        }
+
+        let (system_state, failed_units) = check_system_health();
+        let hostname = crate::host::hostname();
+        let beacon = Beacon {
+            hostname: match Redactor::new(config) {
+                Some(redactor) => redactor.hash(&hostname),
+                None => hostname,
+            },
+            agent_version: env!("CARGO_PKG_VERSION").to_string(),
+            timestamp: now,
+            run_timestamp: state.run_timestamp,
+            sequence: state.sequence,
+            expires_at: state.until,
+            interval_minutes: self.interval.num_minutes() as u64,
+            uptime_seconds: crate::host::uptime_seconds(),
+            healthy: system_state == "running" && failed_units.is_empty(),
+            system_state,
+            failed_units,
+        };
+        if !beacon.healthy {
+            warn!(
+                "Sending unhealthy beacon: system {}, failed units: {}",
+                beacon.system_state,
+                beacon.failed_units.join(", ")
+            );
+        }
+
+        // post() signs the body when security.hmac_secret_file is set
+        let response = http_client
+            .for_subsystem("beacon")
+            .post("beacon", &beacon)
+            .await
+            .with_context(|| "Failed to send health beacon")?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(anyhow::anyhow!(
+                "Backend returned {} for beacon: {}",
+                status,
+                body
+            ));
+        }
+
+        debug!("Sent health beacon #{}", state.sequence);
+        state.last_sent = Some(now);
+        state.sequence += 1;
+        self.save(&state)?;
+        Ok(true)
+    }
+
+    fn save(&self, state: &BeaconState) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory: {:?}", parent))?;
+        }
+        fs::write(&self.path, serde_json::to_string_pretty(state)?)
+            .with_context(|| format!("Failed to write beacon state to {:?}", self.path))
+    }
+}
+
+/// Overall systemd state and the names of failed units.
+fn check_system_health() -> (String, Vec<String>) {
+    let system_state = crate::audit::output(Command::new("systemctl").arg("is-system-running"))
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .unwrap_or_else(|e| {
+            debug!("Failed to run systemctl is-system-running: {}", e);
+            "unknown".to_string()
+        });
+
+    let failed_units = crate::audit::output(Command::new("systemctl").args([
+        "list-units",
+        "--state=failed",
+        "--no-legend",
+        "--plain",
+    ]))
+    .map(|output| parse_failed_units(&String::from_utf8_lossy(&output.stdout)))
+    .unwrap_or_default();
+
+    (system_state, failed_units)
+}
+
+fn parse_failed_units(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .map(|unit| unit.to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_arm_and_expire_window() {
+        let temp_dir = tempdir().unwrap();
+        let mut config = AgentConfig::default();
+        config.state.dir = temp_dir.path().to_path_buf();
+        config.beacon.enabled = true;
+        config.beacon.interval_minutes = 15;
+        let manager = BeaconManager::new(&config);
+
+        manager.arm(Utc::now()).unwrap();
+        let state = manager.load().unwrap().unwrap();
+        assert!(state.is_due(Utc::now(), manager.interval));
+
+        let sent = BeaconState {
+            last_sent: Some(Utc::now()),
+            ..state.clone()
+        };
+        assert!(!sent.is_due(Utc::now(), manager.interval));
+        assert!(sent.is_due(Utc::now() + Duration::minutes(15), manager.interval));
+
+        manager.arm(Utc::now() - Duration::hours(48)).unwrap();
+        assert!(manager.load().unwrap().is_none());
+        assert!(!temp_dir.path().join("beacon.json").exists());
+    }
+
+    #[test]
+    fn test_parse_failed_units() {
+        let output = "nginx.service loaded failed failed A high performance web server\n\
+                      postgresql@16-main.service loaded failed failed PostgreSQL Cluster 16-main\n";
+        assert_eq!(
+            parse_failed_units(output),
+            vec!["nginx.service", "postgresql@16-main.service"]
+        );
+        assert!(parse_failed_units("").is_empty());
+    }
+}
@@ -0,0 +1,396 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use tracing::{debug, warn};
+
+use crate::config::AgentConfig;
+use crate::http_client::SecureHttpClient;
+use crate::pause::PauseState;
+use crate::reboot::ScheduledReboot;
+use crate::window::{maintenance_windows, Window};
+
+const PRODID: &str = "-//ubuntu-auto-update//ua-agent//EN";
+
+/// Length of the event for a scheduled reboot
+const REBOOT_EVENT_MINUTES: i64 = 15;
+
+/// iCalendar content lines should not be longer than this many octets
+const MAX_LINE_OCTETS: usize = 75;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WindowKind {
+    Maintenance,
+    Graphics,
+    Reboot,
+    /// From the calendar the backend assigned to this host
+    Backend,
+}
+
+impl WindowKind {
+    fn category(self) -> &'static str {
+        match self {
+            WindowKind::Maintenance => "MAINTENANCE",
+            WindowKind::Graphics => "GRAPHICS",
+            WindowKind::Reboot => "REBOOT",
+            WindowKind::Backend => "BACKEND",
+        }
+    }
+}
+
+/// One upcoming window, exported as a VEVENT.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CalendarEvent {
+    pub uid: String,
+    pub kind: WindowKind,
+    pub summary: String,
+    pub description: Option<String>,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+}
+
+/// Maintenance and graphics windows in the `days` from `from`, plus the
+/// reboot the agent scheduled, if any. Windows that start while updates are
+/// paused are left out.
+pub fn upcoming_windows(
+    config: &AgentConfig,
+    hostname: &str,
+    from: DateTime<Local>,
+    days: u32,
+    pause: Option<&PauseState>,
+    reboot: Option<&ScheduledReboot>,
+) -> Vec<CalendarEvent> {
+    let updates = &config.updates;
+    let mut description = "Updates are installed in this window".to_string();
+    if updates.auto_reboot {
+        description.push_str(&format!(
+            "; the host reboots {} minutes after a run that needs it",
+            updates.reboot_delay_minutes
+        ));
+    }
+    let mut events = window_events(
+        WindowKind::Maintenance,
+        &format!("Maintenance window: {}", hostname),
+        &description,
+        &maintenance_windows(updates),
+        hostname,
+        from,
+        days,
+    );
+    let graphics = &config.graphics;
+    if graphics.caution && !graphics.allow_updates {
+        let window = match (&graphics.window_start, &graphics.window_end) {
+            (Some(start), Some(end)) => Window::daily(start, end).ok(),
+            _ => None,
+        };
+        events.extend(window_events(
+            WindowKind::Graphics,
+            &format!("Graphics update window: {}", hostname),
+            "Graphics stack updates held back elsewhere are installed in this window",
+            window.as_slice(),
+            hostname,
+            from,
+            days,
+        ));
+    }
+    if let Some(pause) = pause {
+        events.retain(|event| !pause.is_active(event.start));
+    }
+
+    if let Some(reboot) = reboot.filter(|reboot| reboot.expected_at > from) {
+        events.push(CalendarEvent {
+            uid: uid(WindowKind::Reboot, reboot.expected_at, hostname),
+            kind: WindowKind::Reboot,
+            summary: format!("Reboot: {}", hostname),
+            description: Some("Reboot scheduled by ua-agent after updates".to_string()),
+            start: reboot.expected_at,
+            end: reboot.expected_at + Duration::minutes(REBOOT_EVENT_MINUTES),
+        });
+    }
+    events.sort_by_key(|event| event.start);
+    events
+}
+
+/// One event each time one of `windows` opens, running into the next day
+/// when it crosses midnight.
+fn window_events(
+    kind: WindowKind,
+    summary: &str,
+    description: &str,
+    windows: &[Window],
+    hostname: &str,
+    from: DateTime<Local>,
+    days: u32,
+) -> Vec<CalendarEvent> {
+    (0..=days)
+        .flat_map(|offset| {
+            let day = from.date_naive() + Duration::days(offset.into());
+            windows.iter().filter_map(move |window| window.on(day))
+        })
+        .filter_map(|(start, end)| {
+            let start = local_to_utc(start)?;
+            let end = local_to_utc(end)?;
+            Some(CalendarEvent {
+                uid: uid(kind, start, hostname),
+                kind,
+                summary: summary.to_string(),
+                description: Some(description.to_string()),
+                start,
+                end,
+            })
+        })
+        .filter(|event| event.end > from && event.start < from + Duration::days(days.into()))
+        .collect()
+}
+
+/// Stable across exports, so calendar clients update events in place.
+fn uid(kind: WindowKind, start: DateTime<Utc>, hostname: &str) -> String {
+    format!(
+        "{}-{}@{}",
+        kind.category().to_lowercase(),
+        start.format("%Y%m%dT%H%M%SZ"),
+        hostname
+    )
+}
+
+fn local_to_utc(time: NaiveDateTime) -> Option<DateTime<Utc>> {
+    Local
+        .from_local_datetime(&time)
+        .earliest()
+        .map(|time| time.to_utc())
+}
+
+/// Fetches the iCalendar feed the backend assigned to this host, e.g. change
+/// freezes or team patch windows. `None` when the host isn't enrolled or has
+/// no calendar.
+pub async fn fetch_backend_calendar(
+    config: &AgentConfig,
+    http_client: &SecureHttpClient,
+) -> Result<Option<Vec<CalendarEvent>>> {
+    let host_id_file = &config.enrollment.host_id_file;
+    if !host_id_file.exists() {
+        debug!("Host not enrolled, skipping backend calendar");
+        return Ok(None);
+    }
+    let host_id = fs::read_to_string(host_id_file)
+        .with_context(|| format!("Failed to read host ID from {:?}", host_id_file))?;
+
+    let response = http_client
+        .get(&format!("calendar/{}", host_id.trim()))
+        .await?;
+    match response.status() {
+        StatusCode::NOT_FOUND | StatusCode::NO_CONTENT => Ok(None),
+        status if status.is_success() => {
+            let body = response
+                .text()
+                .await
+                .with_context(|| "Failed to read backend calendar")?;
+            Ok(Some(parse_ical(&body)))
+        }
+        status => Err(anyhow::anyhow!(
+            "Backend returned {} for the host calendar",
+            status
+        )),
+    }
+}
+
+/// The VEVENTs of an iCalendar feed. Times with a TZID are read as local
+/// time, and all-day events span whole local days; events without a start
+/// are skipped.
+pub fn parse_ical(feed: &str) -> Vec<CalendarEvent> {
+    let mut events = Vec::new();
+    let mut current: Option<Vec<(String, String, String)>> = None;
+    for line in unfold(feed) {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let (name, params) = name.split_once(';').unwrap_or((name, ""));
+        match (name.to_ascii_uppercase().as_str(), value) {
+            ("BEGIN", "VEVENT") => current = Some(Vec::new()),
+            ("END", "VEVENT") => {
+                if let Some(event) = current.take().and_then(|props| backend_event(&props)) {
+                    events.push(event);
+                } else {
+                    warn!("Skipping backend calendar event without a valid DTSTART");
+                }
+            }
+            (name, value) => {
+                if let Some(props) = current.as_mut() {
+                    props.push((name.to_string(), params.to_string(), value.to_string()));
+                }
+            }
+        }
+    }
+    events
+}
+
+fn backend_event(props: &[(String, String, String)]) -> Option<CalendarEvent> {
+    let prop = |wanted: &str| props.iter().find(|(name, _, _)| name == wanted);
+    let (_, params, value) = prop("DTSTART")?;
+    let start = parse_ical_time(params, value)?;
+    let end = match prop("DTEND") {
+        Some((_, params, value)) => parse_ical_time(params, value)?,
+        // A date without an end lasts the day, a time is a moment
+        None if value.len() == 8 => start + Duration::days(1),
+        None => start,
+    };
+    let text = |wanted: &str| prop(wanted).map(|(_, _, value)| unescape(value));
+    Some(CalendarEvent {
+        uid: text("UID").unwrap_or_else(|| uid(WindowKind::Backend, start, "backend")),
+        kind: WindowKind::Backend,
+        summary: text("SUMMARY").unwrap_or_default(),
+        description: text("DESCRIPTION"),
+        start,
+        end,
+    })
+}
+
+fn parse_ical_time(params: &str, value: &str) -> Option<DateTime<Utc>> {
+    if let Some(utc) = value.strip_suffix('Z') {
+        return NaiveDateTime::parse_from_str(utc, "%Y%m%dT%H%M%S")
+            .ok()
+            .map(|time| time.and_utc());
+    }
+    if params.contains("VALUE=DATE") || value.len() == 8 {
+        let date = NaiveDate::parse_from_str(value, "%Y%m%d").ok()?;
+        return local_to_utc(date.and_time(NaiveTime::MIN));
+    }
+    local_to_utc(NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S").ok()?)
+}
+
+/// Joins folded content lines, which continue with a leading space or tab.
+fn unfold(feed: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for line in feed.lines() {
+        match (line.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(rest), Some(last)) => last.push_str(rest),
+            _ => lines.push(line.to_string()),
+        }
+    }
+    lines
+}
+
+/// Renders `events` as an iCalendar (RFC 5545) feed.
+pub fn to_ical(events: &[CalendarEvent], hostname: &str, now: DateTime<Utc>) -> String {
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        format!("PRODID:{}", PRODID),
+        "CALSCALE:GREGORIAN".to_string(),
+        format!("X-WR-CALNAME:{}", escape(&format!("Updates: {}", hostname))),
+    ];
+    let stamp = |time: DateTime<Utc>| time.format("%Y%m%dT%H%M%SZ").to_string();
+    for event in events {
+        lines.push("BEGIN:VEVENT".to_string());
+        lines.push(format!("UID:{}", escape(&event.uid)));
+        lines.push(format!("DTSTAMP:{}", stamp(now)));
+        lines.push(format!("DTSTART:{}", stamp(event.start)));
+        lines.push(format!("DTEND:{}", stamp(event.end)));
+        lines.push(format!("SUMMARY:{}", escape(&event.summary)));
+        if let Some(description) = &event.description {
+            lines.push(format!("DESCRIPTION:{}", escape(description)));
+        }
+        lines.push(format!("CATEGORIES:{}", event.kind.category()));
+        lines.push("TRANSP:TRANSPARENT".to_string());
+        lines.push("END:VEVENT".to_string());
+    }
+    lines.push("END:VCALENDAR".to_string());
+
+    lines
+        .iter()
+        .map(|line| fold(line))
+        .collect::<Vec<_>>()
+        .join("\r\n")
+        + "\r\n"
+}
+
+/// Splits a content line into 75-octet pieces without breaking a UTF-8
+/// character.
+fn fold(line: &str) -> String {
+    let mut folded = String::with_capacity(line.len());
+    let mut octets = 0;
+    for c in line.chars() {
+        if octets + c.len_utf8() > MAX_LINE_OCTETS {
+            folded.push_str("\r\n ");
+            octets = 1;
+        }
+        folded.push(c);
+        octets += c.len_utf8();
+    }
+    folded
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n' | 'N') => unescaped.push('\n'),
+            Some(next) => unescaped.push(next),
+            None => {}
+        }
+    }
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_windows_round_trip_through_ical() {
+        let mut config = AgentConfig::default();
+        config.updates.maintenance_window_start = Some("23:00".to_string());
+        config.updates.maintenance_window_end = Some("01:00".to_string());
+        let from = Local
+            .from_local_datetime(
+                &NaiveDate::from_ymd_opt(2026, 10, 16)
+                    .unwrap()
+                    .and_hms_opt(12, 0, 0)
+                    .unwrap(),
+            )
+            .unwrap();
+
+        let events = upcoming_windows(&config, "web-1", from, 3, None, None);
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0].end - events[0].start, Duration::hours(2));
+
+        // Paused until the second window has started
+        let pause = PauseState {
+            paused_at: from.to_utc(),
+            until: Some(events[1].start + Duration::minutes(1)),
+            reason: Some("change freeze, Q4".to_string()),
+        };
+        let paused = upcoming_windows(&config, "web-1", from, 3, Some(&pause), None);
+        assert_eq!(paused, events[2..]);
+
+        let feed = to_ical(&events, "web-1", from.to_utc());
+        assert!(feed.ends_with("END:VCALENDAR\r\n"));
+        assert!(feed.lines().all(|line| line.len() <= MAX_LINE_OCTETS + 1));
+        let parsed = parse_ical(&feed);
+        assert_eq!(parsed.len(), 3);
+        assert_eq!(parsed[0].uid, events[0].uid);
+        assert_eq!(parsed[0].start, events[0].start);
+        assert_eq!(parsed[0].description, events[0].description);
+
+        let backend = parse_ical(
+            "BEGIN:VCALENDAR\r\nBEGIN:VEVENT\r\nUID:freeze-1\r\nSUMMARY:Change freeze\\, \r\n payments\r\n\
+             DTSTART;VALUE=DATE:20261220\r\nDTEND;VALUE=DATE:20270104\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n",
+        );
+        assert_eq!(backend[0].summary, "Change freeze, payments");
+        assert_eq!((backend[0].end - backend[0].start).num_days(), 15);
+    }
+}
@@ -0,0 +1,455 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::path::Path;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+use crate::config::AgentConfig;
+#[cfg(feature = "nats")]
+use crate::config::Transport;
+use crate::debug_window::DebugWindowManager;
+use crate::http_client::SecureHttpClient;
+#[cfg(feature = "nats")]
+use crate::nats::NatsTransport;
+use crate::pause::PauseManager;
+use crate::release::ReleaseUpgrader;
+use crate::updater::UpdateManager;
+
+/// Back-off after a failed poll, so an unreachable backend isn't hammered
+const POLL_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Command IDs remembered to reject replays of a signed batch
+const SEEN_COMMANDS: usize = 256;
+
+/// Operator commands the backend can push to a daemon.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AgentCommand {
+    /// Start an update run now, regardless of window and pause
+    RunNow,
+    HoldPackage {
+        package: String,
+        #[serde(default)]
+        snap: bool,
+    },
+    UnholdPackage {
+        package: String,
+        #[serde(default)]
+        snap: bool,
+    },
+    /// Cancel a reboot scheduled with `shutdown -r`
+    CancelReboot,
+    /// Pause updates like `pause`, e.g. across the fleet during an incident
+    Pause {
+        #[serde(default)]
+        until: Option<String>,
+        #[serde(default)]
+        hours: Option<u32>,
+        #[serde(default)]
+        days: Option<u32>,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    Resume,
+    /// Raise the log level like `debug enable`; the daemon picks it up
+    /// within half a minute
+    DebugEnable {
+        minutes: u32,
+        #[serde(default)]
+        level: Option<String>,
+        #[serde(default)]
+        http_capture: bool,
+    },
+    DebugDisable,
+    /// Upgrade to the next Ubuntu release; needs
+    /// release_upgrade.allow_remote
+    ReleaseUpgrade,
+}
+
+/// Body of `GET /api/v1/commands` (or a message on the NATS commands
+/// subject), signed by the backend like the agent signs its requests
+/// (`X-Timestamp`, `X-Nonce`, `X-Signature`).
+#[derive(Debug, Deserialize)]
+struct CommandBatch {
+    commands: Vec<CommandEnvelope>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CommandEnvelope {
+    id: String,
+    expires_at: DateTime<Utc>,
+    /// Parsed separately so an unknown command type is rejected on its own
+    /// rather than failing the whole batch
+    command: serde_json::Value,
+}
+
+/// A command batch as received, before its signature is checked.
+struct SignedBatch {
+    timestamp: String,
+    nonce: String,
+    signature: String,
+    body: Vec<u8>,
+}
+
+/// A command from a correctly signed batch that hasn't expired or been seen.
+#[derive(Debug)]
+pub struct VerifiedCommand {
+    pub id: String,
+    pub command: AgentCommand,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AckStatus {
+    /// Received and about to be carried out
+    Accepted,
+    Succeeded,
+    Failed,
+    /// Expired, replayed or not understood; never carried out
+    Rejected,
+}
+
+#[derive(Debug, Serialize)]
+struct CommandAck<'a> {
+    id: &'a str,
+    status: AckStatus,
+    message: Option<&'a str>,
+    timestamp: DateTime<Utc>,
+}
+
+/// Long-polls the backend (or reads the NATS commands subject) for
+/// operator commands in daemon mode and acknowledges them. Batches must
+/// carry an HMAC signature made with `security.hmac_secret_file`; anything
+/// unsigned is dropped.
+#[derive(Clone)]
+pub struct CommandChannel {
+    http_client: SecureHttpClient,
+    #[cfg(feature = "nats")]
+    nats: Option<NatsTransport>,
+    wait: Duration,
+    request_timeout: Duration,
+}
+
+impl CommandChannel {
+    pub async fn new(config: &AgentConfig, http_client: &SecureHttpClient) -> Result<Self> {
+        let http_client = http_client.for_subsystem("commands");
+        #[cfg(feature = "nats")]
+        let nats = match config.backend.transport {
+            Transport::Nats => Some(NatsTransport::connect(config).await?),
+            Transport::Http | Transport::Mqtt | Transport::S3 => None,
+        };
+        let wait = Duration::from_secs(config.commands.poll_seconds);
+        Ok(Self {
+            http_client,
+            #[cfg(feature = "nats")]
+            nats,
+            wait,
+            request_timeout: wait + Duration::from_secs(config.backend.timeout_seconds),
+        })
+    }
+
+    /// Polls until `commands` is closed, passing on verified commands.
+    pub async fn run(self, commands: mpsc::UnboundedSender<VerifiedCommand>) {
+        let mut seen = VecDeque::with_capacity(SEEN_COMMANDS);
+
+        while !commands.is_closed() {
+            let batch = match self.poll().await {
+                Ok(batch) => batch,
+                Err(e) => {
+                    warn!("Command poll failed: {:#}", e);
+                    tokio::time::sleep(POLL_RETRY_DELAY).await;
+                    continue;
+                }
+            };
+
+            for envelope in batch {
+                let id = envelope.id.clone();
+                match accept(envelope, Utc::now(), &mut seen) {
+                    Ok(command) => {
+                        debug!("Received command {}: {:?}", command.id, command.command);
+                        let _ = commands.send(command);
+                    }
+                    Err(reason) => {
+                        warn!("Rejected command {}: {}", id, reason);
+                        self.ack(&id, AckStatus::Rejected, Some(&reason)).await;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn poll(&self) -> Result<Vec<CommandEnvelope>> {
+        #[cfg(feature = "nats")]
+        let batch = match &self.nats {
+            Some(nats) => self.poll_nats(nats).await?,
+            None => self.poll_http().await?,
+        };
+        #[cfg(not(feature = "nats"))]
+        let batch = self.poll_http().await?;
+        let Some(batch) = batch else {
+            return Ok(Vec::new());
+        };
+
+        self.http_client
+            .verify_signature(
+                &batch.body,
+                &batch.timestamp,
+                &batch.nonce,
+                &batch.signature,
+            )
+            .context("Dropping command batch with a bad signature")?;
+        let batch: CommandBatch =
+            serde_json::from_slice(&batch.body).context("Failed to parse command batch")?;
+        Ok(batch.commands)
+    }
+
+    #[cfg(feature = "nats")]
+    async fn poll_nats(&self, nats: &NatsTransport) -> Result<Option<SignedBatch>> {
+        let Some((headers, body)) = nats.next_command_batch(self.wait).await? else {
+            return Ok(None);
+        };
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .map(|value| value.as_str().to_string())
+                .with_context(|| format!("Command batch has no {} header", name))
+        };
+        Ok(Some(SignedBatch {
+            timestamp: header("X-Timestamp")?,
+            nonce: header("X-Nonce")?,
+            signature: header("X-Signature")?,
+            body,
+        }))
+    }
+
+    async fn poll_http(&self) -> Result<Option<SignedBatch>> {
+        let endpoint = format!("commands?wait={}", self.wait.as_secs());
+        let response = self
+            .http_client
+            .get_with_timeout(&endpoint, self.request_timeout)
+            .await?;
+
+        if response.status() == StatusCode::NO_CONTENT {
+            return Ok(None);
+        }
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(anyhow::anyhow!("Backend returned {}: {}", status, body));
+        }
+
+        let header = |name: &str| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_string())
+                .with_context(|| format!("Command batch has no {} header", name))
+        };
+        let timestamp = header("X-Timestamp")?;
+        let nonce = header("X-Nonce")?;
+        let signature = header("X-Signature")?;
+        let body = response
+            .bytes()
+            .await
+            .context("Failed to read command batch")?;
+        Ok(Some(SignedBatch {
+            timestamp,
+            nonce,
+            signature,
+            body: body.to_vec(),
+        }))
+    }
+
+    /// Reports a command's progress to the backend. Failures are only logged;
+    /// the backend times out commands it never hears back about.
+    pub async fn ack(&self, id: &str, status: AckStatus, message: Option<&str>) {
+        let ack = CommandAck {
+            id,
+            status,
+            message,
+            timestamp: Utc::now(),
+        };
+        #[cfg(feature = "nats")]
+        if let Some(nats) = &self.nats {
+            if let Err(e) = nats.publish("acks", &ack).await {
+                warn!("Failed to acknowledge command {}: {:#}", id, e);
+            }
+            return;
+        }
+
+        let endpoint = format!("commands/{}/ack", id);
+        match self.http_client.post(&endpoint, &ack).await {
+            Ok(response) if response.status().is_success() => {}
+            Ok(response) => warn!(
+                "Backend returned {} for ack of command {}",
+                response.status(),
+                id
+            ),
+            Err(e) => warn!("Failed to acknowledge command {}: {:#}", id, e),
+        }
+    }
+}
+
+/// Checks a signed envelope for expiry, replay and a known command type.
+fn accept(
+    envelope: CommandEnvelope,
+    now: DateTime<Utc>,
+    seen: &mut VecDeque<String>,
+) -> std::result::Result<VerifiedCommand, String> {
+    if seen.contains(&envelope.id) {
+        return Err("already received".to_string());
+    }
+    if envelope.expires_at <= now {
+        return Err(format!("expired at {}", envelope.expires_at));
+    }
+    let command = serde_json::from_value(envelope.command)
+        .map_err(|e| format!("unsupported command: {}", e))?;
+
+    if seen.len() == SEEN_COMMANDS {
+        seen.pop_front();
+    }
+    seen.push_back(envelope.id.clone());
+    Ok(VerifiedCommand {
+        id: envelope.id,
+        command,
+    })
+}
+
+/// Carries out a verified command, returning a short result for the ack.
+/// `config_path` is the file apt holds are mirrored into, as `hold` does.
+pub async fn dispatch(
+    config: &AgentConfig,
+    config_path: Option<&Path>,
+    command: &AgentCommand,
+) -> Result<String> {
+    match command {
+        AgentCommand::RunNow => {
+            crate::run_updates(config, true).await?;
+            Ok("Update run completed".to_string())
+        }
+        AgentCommand::HoldPackage { package, snap } => {
+            set_package_held(config, config_path, package, *snap, true).await?;
+            Ok(format!("Held {}", package))
+        }
+        AgentCommand::UnholdPackage { package, snap } => {
+            set_package_held(config, config_path, package, *snap, false).await?;
+            Ok(format!("Released {}", package))
+        }
+        AgentCommand::CancelReboot => {
+            crate::reboot::cancel(config)?;
+            Ok("Reboot cancelled".to_string())
+        }
+        AgentCommand::Pause {
+            until,
+            hours,
+            days,
+            reason,
+        } => {
+            let until = crate::pause::pause_end(until.as_deref(), *hours, *days)?;
+            let state = PauseManager::new(config).pause(until, reason.clone())?;
+            Ok(format!("Updates {}", state.describe()))
+        }
+        AgentCommand::Resume => {
+            if PauseManager::new(config).resume()? {
+                Ok("Updates resumed".to_string())
+            } else {
+                Ok("Updates were not paused".to_string())
+            }
+        }
+        AgentCommand::DebugEnable {
+            minutes,
+            level,
+            http_capture,
+        } => {
+            let window = DebugWindowManager::new(config).enable(
+                *minutes,
+                level.as_deref().unwrap_or("debug"),
+                *http_capture,
+            )?;
+            Ok(format!("Debug window open, {}", window.describe()))
+        }
+        AgentCommand::DebugDisable => {
+            if DebugWindowManager::new(config).disable()? {
+                Ok("Debug window closed".to_string())
+            } else {
+                Ok("No debug window was open".to_string())
+            }
+        }
+        AgentCommand::ReleaseUpgrade => {
+            if !config.release_upgrade.allow_remote {
+                return Err(anyhow::anyhow!(
+                    "Remote release upgrades are disabled, set release_upgrade.allow_remote = true"
+                ));
+            }
+            let release = ReleaseUpgrader::new(config)?.upgrade().await?;
+            Ok(format!("Upgraded to {}", release))
+        }
+    }
+}
+
+async fn set_package_held(
+    config: &AgentConfig,
+    config_path: Option<&Path>,
+    package: &str,
+    snap: bool,
+    held: bool,
+) -> Result<()> {
+    let update_manager = UpdateManager::new(config.clone())
+        .with_context(|| "Failed to initialize update manager")?;
+    update_manager.set_package_held(package, snap, held).await?;
+
+    if !snap {
+        match config_path {
+            Some(path) => {
+                AgentConfig::set_package_excluded(path, package, held)?;
+            }
+            None => warn!("No TOML config file found, excluded_packages not updated"),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn envelope(
+        id: &str,
+        command: serde_json::Value,
+        expires_in: chrono::Duration,
+    ) -> CommandEnvelope {
+        CommandEnvelope {
+            id: id.to_string(),
+            expires_at: Utc::now() + expires_in,
+            command,
+        }
+    }
+
+    #[test]
+    fn test_accept_rejects_replayed_expired_and_unknown_commands() {
+        let minute = chrono::Duration::minutes(1);
+        let mut seen = VecDeque::new();
+
+        let hold = serde_json::json!({"type": "hold_package", "package": "nginx"});
+        let accepted = accept(envelope("c1", hold.clone(), minute), Utc::now(), &mut seen).unwrap();
+        assert_eq!(
+            accepted.command,
+            AgentCommand::HoldPackage {
+                package: "nginx".to_string(),
+                snap: false
+            }
+        );
+
+        assert!(accept(envelope("c1", hold, minute), Utc::now(), &mut seen).is_err());
+        let run_now = serde_json::json!({"type": "run_now"});
+        assert!(accept(envelope("c2", run_now, -minute), Utc::now(), &mut seen).is_err());
+        let unknown = serde_json::json!({"type": "format_disk"});
+        assert!(accept(envelope("c3", unknown, minute), Utc::now(), &mut seen).is_err());
+        assert_eq!(seen, ["c1"]);
+    }
+}
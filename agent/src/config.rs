@@ -1,7 +1,10 @@
 use anyhow::{Context, Result};
-use config::{Config, ConfigError, Environment, File};
+use config::{
+    builder::DefaultState, Config, ConfigBuilder, ConfigError, Environment, File, FileFormat,
+};
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct AgentConfig {
@@ -11,14 +14,172 @@ pub struct AgentConfig {
     pub logging: LoggingConfig,
     pub metrics: MetricsConfig,
     pub enrollment: EnrollmentConfig,
+    #[serde(default)]
+    pub state: StateConfig,
+    #[serde(default)]
+    pub snapshot: SnapshotConfig,
+    #[serde(default)]
+    pub coordination: CoordinationConfig,
+    #[serde(default)]
+    pub i18n: I18nConfig,
+    #[serde(default)]
+    pub motd: MotdConfig,
+    #[serde(default)]
+    pub policy: PolicyConfig,
+    #[serde(default)]
+    pub daemon: DaemonConfig,
+    #[serde(default)]
+    pub unattended_upgrades: UnattendedUpgradesConfig,
+    #[serde(default)]
+    pub beacon: BeaconConfig,
+    #[serde(default)]
+    pub reporting: ReportingConfig,
+    #[serde(default)]
+    pub client_cert: ClientCertConfig,
+    #[serde(default)]
+    pub commands: CommandsConfig,
+    #[serde(default)]
+    pub local_api: LocalApiConfig,
+    #[serde(default)]
+    pub dbus: DbusConfig,
+    #[serde(default)]
+    pub guards: GuardsConfig,
+    #[serde(default)]
+    pub power: PowerConfig,
+    #[serde(default)]
+    pub graphics: GraphicsConfig,
+    #[serde(default)]
+    pub risk: RiskConfig,
+    #[serde(default)]
+    pub telemetry: TelemetryConfig,
+    #[serde(default)]
+    pub sbom: SbomConfig,
+    #[serde(default)]
+    pub scanner: ScannerConfig,
+    #[serde(default)]
+    pub disk_space: DiskSpaceConfig,
+    #[serde(default)]
+    pub needrestart: NeedrestartConfig,
+    #[serde(default)]
+    pub nats: NatsConfig,
+    #[serde(default)]
+    pub mqtt: MqttConfig,
+    #[serde(default)]
+    pub s3: S3Config,
+    #[serde(default)]
+    pub remote_write: RemoteWriteConfig,
+    #[serde(default)]
+    pub notifications: NotificationsConfig,
+    #[serde(default)]
+    pub release_upgrade: ReleaseUpgradeConfig,
+    #[serde(default)]
+    pub runtime: RuntimeConfig,
+    #[serde(default)]
+    pub audit: AuditConfig,
+    #[serde(default)]
+    pub platform: PlatformConfig,
+    #[serde(default)]
+    pub log_shipping: LogShippingConfig,
+    /// Files the configuration was read from, fragments last; filled in by
+    /// `load` and `load_from_file`
+    #[serde(skip)]
+    pub loaded_from: Vec<PathBuf>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct BackendConfig {
-    pub url: String,
+    pub url: BackendUrls,
     pub timeout_seconds: u64,
     pub retry_attempts: u32,
     pub retry_delay_seconds: u64,
+    /// `http://`, `https://`, `socks5://` or `socks5h://` proxy for backend
+    /// requests. Without it `HTTPS_PROXY`/`NO_PROXY` are honored.
+    pub proxy_url: Option<String>,
+    /// File holding `user:password` for the proxy
+    pub proxy_credentials_file: Option<PathBuf>,
+    /// Path prepended to backend endpoints, "/api/v1" by default
+    pub api_prefix: Option<String>,
+    /// Full paths replacing `api_prefix` + name for single endpoints, keyed
+    /// by one of `BACKEND_ENDPOINTS`
+    #[serde(default)]
+    pub endpoints: BTreeMap<String, String>,
+    /// How reports and operator commands travel; enrollment, policy and
+    /// the other requests always use HTTP
+    #[serde(default)]
+    pub transport: Transport,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    #[default]
+    Http,
+    /// Publish to the broker in `[nats]`
+    Nats,
+    /// Publish reports to the broker in `[mqtt]`; operator commands aren't
+    /// available
+    Mqtt,
+    /// Upload reports to the `[s3]` bucket, without a backend; operator
+    /// commands aren't available
+    S3,
+}
+
+/// Backend endpoints that `backend.endpoints` can override.
+pub const BACKEND_ENDPOINTS: &[&str] = &[
+    "agent-panics",
+    "alerts/disk-space",
+    "beacon",
+    "calendar",
+    "certificate/renew",
+    "commands",
+    "enroll",
+    "health",
+    "policy",
+    "release-upgrade",
+    "report",
+    "rotate-key",
+    "runs/started",
+    "sbom",
+];
+
+impl BackendConfig {
+    pub fn api_prefix(&self) -> &str {
+        self.api_prefix.as_deref().unwrap_or("/api/v1")
+    }
+}
+
+/// `backend.url` as a single URL or a list tried in order, so reports still
+/// reach a standby when the primary backend is down.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum BackendUrls {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl BackendUrls {
+    pub fn as_slice(&self) -> &[String] {
+        match self {
+            BackendUrls::One(url) => std::slice::from_ref(url),
+            BackendUrls::Many(urls) => urls,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.as_slice().is_empty()
+    }
+}
+
+impl From<&str> for BackendUrls {
+    fn from(url: &str) -> Self {
+        BackendUrls::One(url.to_string())
+    }
+}
+
+impl std::fmt::Display for BackendUrls {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.as_slice().join(", "))
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -30,6 +191,68 @@ pub struct SecurityConfig {
     pub hmac_secret_file: Option<PathBuf>,
     pub verify_server_cert: bool,
     pub use_mtls: bool,
+    /// Read `api_key_file` and `hmac_secret_file` from systemd credentials
+    /// (`LoadCredential=`) by file name instead of from their paths
+    #[serde(default)]
+    pub credentials_from_systemd: bool,
+    /// How far the `X-Timestamp` of a signed backend message may be from
+    /// the local clock (default 300 seconds)
+    #[serde(default)]
+    pub max_clock_skew_seconds: Option<u64>,
+    /// How the API key is sent to the backend
+    #[serde(default)]
+    pub auth_header_style: AuthHeaderStyle,
+    /// Header carrying the bare API key with `auth_header_style = "custom"`
+    #[serde(default)]
+    pub auth_header_name: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuthHeaderStyle {
+    /// `Authorization: Bearer <key>`
+    #[default]
+    Bearer,
+    /// `X-API-Key: <key>`
+    XApiKey,
+    /// `<auth_header_name>: <key>`
+    Custom,
+}
+
+/// Set by systemd for units with `LoadCredential=`
+const CREDENTIALS_DIRECTORY_ENV: &str = "CREDENTIALS_DIRECTORY";
+
+/// Where enrollment stores secrets for `LoadCredential=` to pick up
+pub const SYSTEMD_CREDSTORE: &str = "/etc/credstore";
+
+impl SecurityConfig {
+    pub fn max_clock_skew(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.max_clock_skew_seconds.unwrap_or(300))
+    }
+
+    /// Path a secret configured as `path` is read from. With
+    /// `credentials_from_systemd` that's the credential of the same file
+    /// name, or the credstore copy when not running under the unit (e.g.
+    /// `ua-agent test` from a shell).
+    pub fn credential_path(&self, path: &Path) -> PathBuf {
+        let credentials_dir = std::env::var_os(CREDENTIALS_DIRECTORY_ENV).map(PathBuf::from);
+        self.resolve_credential(path, credentials_dir.as_deref())
+    }
+
+    fn resolve_credential(&self, path: &Path, credentials_dir: Option<&Path>) -> PathBuf {
+        let Some(name) = path.file_name().filter(|_| self.credentials_from_systemd) else {
+            return path.to_path_buf();
+        };
+        credentials_dir
+            .unwrap_or(Path::new(SYSTEMD_CREDSTORE))
+            .join(name)
+    }
+
+    /// Where enrollment writes the API key. `$CREDENTIALS_DIRECTORY` is
+    /// read-only, so with `credentials_from_systemd` it goes to the credstore.
+    pub fn api_key_store_path(&self) -> PathBuf {
+        self.resolve_credential(&self.api_key_file, None)
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -37,10 +260,208 @@ pub struct UpdateConfig {
     pub dry_run: bool,
     pub auto_reboot: bool,
     pub reboot_delay_minutes: u32,
+    /// A single daily window; superseded by `maintenance_windows`
     pub maintenance_window_start: Option<String>,
     pub maintenance_window_end: Option<String>,
+    /// Windows such as "Sat,Sun 02:00-05:00" or "Mon-Fri 22:00-01:00"; runs
+    /// start inside any of them
+    #[serde(default)]
+    pub maintenance_windows: Vec<String>,
+    /// Scheduled runs wait a random 0..=splay_seconds before starting, so a
+    /// fleet on the same schedule doesn't hit mirrors at the same moment
+    #[serde(default)]
+    pub splay_seconds: u64,
+    /// Start times derived from the host ID, in place of the random splay
+    #[serde(default)]
+    pub slots: SlotSchedule,
+    #[serde(default)]
+    pub watchdog: StallWatchdog,
     pub excluded_packages: Vec<String>,
     pub update_sources: UpdateSources,
+    #[serde(default)]
+    pub rollback_on_failure: bool,
+    #[serde(default)]
+    pub resource_limits: ResourceLimits,
+    /// Extra apt suites treated as security pockets, for derivatives that
+    /// publish security fixes in their own archive
+    #[serde(default)]
+    pub security_pockets: Vec<String>,
+    #[serde(default)]
+    pub mode: UpdateMode,
+    /// `full-upgrade` also installs new dependencies and removes packages
+    /// that conflict, so kernels and library transitions aren't held back
+    #[serde(default)]
+    pub upgrade_mode: UpgradeMode,
+    /// Whether the apt phase runs apt-get, reads the apt cache through
+    /// libapt-pkg, or goes through PackageKit
+    #[serde(default)]
+    pub package_backend: PackageBackend,
+    /// Packages a full-upgrade must never remove, on top of the Ubuntu
+    /// metapackages, openssh-server and sudo
+    #[serde(default)]
+    pub protected_packages: Vec<String>,
+    /// Packages an upgrade may remove; any other removal aborts the run.
+    /// A trailing `*` matches a prefix, e.g. "linux-modules-extra-*"
+    #[serde(default)]
+    pub allowed_removals: Vec<String>,
+    /// systemd units stopped before packages are upgraded
+    #[serde(default)]
+    pub stop_services: Vec<String>,
+    /// Start `stop_services` again once the upgrade has finished
+    #[serde(default)]
+    pub start_after: bool,
+    /// apt's archive cache (`Dir::Cache::Archives`) for downloaded .debs,
+    /// e.g. a persistent partition or network mount shared across hosts
+    #[serde(default)]
+    pub apt_cache_dir: Option<PathBuf>,
+    /// Fetch deltas with `debdelta-upgrade` before upgrading, where debdelta
+    /// and a delta server are set up
+    #[serde(default)]
+    pub debdelta: bool,
+    /// Hold off downloads while NetworkManager reports the connection as
+    /// metered, e.g. on cellular
+    #[serde(default)]
+    pub respect_metered: bool,
+    /// With respect_metered, let apt download at up to this many KB/s on a
+    /// metered connection instead of deferring the run
+    #[serde(default)]
+    pub metered_rate_limit_kbps: Option<u32>,
+    #[serde(default)]
+    pub snap: SnapConfig,
+    #[serde(default)]
+    pub flatpak: FlatpakConfig,
+}
+
+/// Flatpak phase settings.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct FlatpakConfig {
+    /// `[[updates.flatpak.remotes]]`, added when missing
+    pub remotes: Vec<FlatpakRemote>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct FlatpakRemote {
+    pub name: String,
+    /// Repository or `.flatpakrepo` URL for `flatpak remote-add`; without
+    /// one the remote must already exist
+    pub url: Option<String>,
+    /// Update refs installed from this remote
+    pub update: bool,
+}
+
+/// `[updates.slots]`: `window` split into slots of `slot_minutes`, with
+/// each host starting scheduled runs in the slot its host ID hashes to, so
+/// a fleet spreads evenly over the window without coordinating. Usually
+/// sent by the backend policy.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SlotSchedule {
+    /// e.g. "01:00-05:00" or "Sat,Sun 02:00-06:00"; off without one
+    pub window: Option<String>,
+    pub slot_minutes: u32,
+}
+
+impl Default for SlotSchedule {
+    fn default() -> Self {
+        Self {
+            window: None,
+            slot_minutes: 5,
+        }
+    }
+}
+
+/// `[updates.watchdog]`: stall detection for package commands, on top of
+/// their hard timeouts.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct StallWatchdog {
+    /// Terminate a command that printed nothing, changed nothing in dpkg's
+    /// database and downloaded nothing for this long; 0 turns it off
+    pub stall_minutes: u64,
+}
+
+impl Default for StallWatchdog {
+    fn default() -> Self {
+        Self { stall_minutes: 20 }
+    }
+}
+
+impl Default for FlatpakRemote {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            url: None,
+            update: true,
+        }
+    }
+}
+
+/// Snap phase settings.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SnapConfig {
+    /// Per-snap settings, `[updates.snap.packages.<name>]`
+    pub packages: BTreeMap<String, SnapPackageConfig>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SnapPackageConfig {
+    /// Channel to track, e.g. "latest/stable" or "22/candidate"
+    pub channel: Option<String>,
+    /// Hold the snap so refreshes skip it (`snap refresh --hold`)
+    pub hold: bool,
+}
+
+/// Whether the agent applies updates or only reports what it would do.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpdateMode {
+    /// Install updates, snapshot, reboot as configured
+    #[default]
+    Manage,
+    /// Never modify the system; report pending updates, reboot-required
+    /// and held packages only
+    Observe,
+}
+
+/// The apt-get command the apt phase runs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UpgradeMode {
+    /// Never installs or removes packages, holding back upgrades that
+    /// would
+    #[default]
+    Upgrade,
+    /// `apt-get full-upgrade` (dist-upgrade)
+    FullUpgrade,
+}
+
+impl UpgradeMode {
+    pub fn apt_command(self) -> &'static str {
+        match self {
+            UpgradeMode::Upgrade => "upgrade",
+            UpgradeMode::FullUpgrade => "full-upgrade",
+        }
+    }
+}
+
+/// What installs apt package updates.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PackageBackend {
+    /// apt-get, with the agent taking the dpkg lock itself
+    #[default]
+    Apt,
+    /// The PackageKit daemon over D-Bus, for workstations where desktop
+    /// update tools use it too
+    Packagekit,
+    /// apt-get for installs, with the upgradable list and download sizes
+    /// read from the apt cache through libapt-pkg; needs the `libapt`
+    /// feature
+    Libapt,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -51,6 +472,30 @@ pub struct UpdateSources {
     pub firmware: bool,
 }
 
+/// Limits applied to package manager child processes so updates don't starve
+/// the foreground workload. cgroup properties are applied through a transient
+/// `systemd-run --scope` unit, which with `accounting` also runs in a slice
+/// whose totals are reported.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ResourceLimits {
+    /// CPU niceness (-20..=19)
+    pub nice: Option<i32>,
+    /// "idle", "best-effort" or "realtime"
+    pub ionice_class: Option<String>,
+    /// Priority within the ionice class (0..=7)
+    pub ionice_level: Option<u8>,
+    /// systemd CPUQuota, e.g. "50%"
+    pub cpu_quota: Option<String>,
+    /// systemd MemoryMax, e.g. "512M"
+    pub memory_max: Option<String>,
+    /// systemd IOWeight (1..=10000)
+    pub io_weight: Option<u32>,
+    /// Measure the CPU time, peak memory, disk IO and network traffic of
+    /// each run's package commands through cgroup accounting
+    pub accounting: bool,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct LoggingConfig {
     pub level: String,
@@ -75,167 +520,1967 @@ pub struct EnrollmentConfig {
     pub enrollment_url: String,
 }
 
-impl Default for AgentConfig {
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct StateConfig {
+    /// Directory for agent-owned runtime state (pause marker, history, ...)
+    pub dir: PathBuf,
+    /// Number of runs kept in the local history store
+    pub history_limit: usize,
+}
+
+impl Default for StateConfig {
     fn default() -> Self {
         Self {
-            backend: BackendConfig {
-                url: "http://localhost:8080".to_string(),
-                timeout_seconds: 30,
-                retry_attempts: 3,
-                retry_delay_seconds: 5,
-            },
-            security: SecurityConfig {
-                api_key_file: PathBuf::from("/etc/ubuntu-auto-update/auth.token"),
-                cert_file: None,
-                key_file: None,
-                ca_file: None,
-                hmac_secret_file: Some(PathBuf::from("/etc/ubuntu-auto-update/hmac.key")),
-                verify_server_cert: true,
-                use_mtls: false,
-            },
-            updates: UpdateConfig {
-                dry_run: false,
-                auto_reboot: false,
-                reboot_delay_minutes: 5,
-                maintenance_window_start: None,
-                maintenance_window_end: None,
-                excluded_packages: vec![],
-                update_sources: UpdateSources {
-                    apt: true,
-                    snap: true,
-                    flatpak: false,
-                    firmware: false,
-                },
-            },
-            logging: LoggingConfig {
-                level: "info".to_string(),
-                format: "json".to_string(),
-                file: Some(PathBuf::from("/var/log/ubuntu-auto-update/agent.log")),
-                max_size_mb: 100,
-                max_files: 5,
-            },
-            metrics: MetricsConfig {
-                enabled: true,
-                port: Some(9100),
-                textfile_path: Some(PathBuf::from("/var/lib/node_exporter/textfile_collector")),
-                collect_system_metrics: true,
-            },
-            enrollment: EnrollmentConfig {
-                token_file: PathBuf::from("/etc/ubuntu-auto-update/enrollment.token"),
-                host_id_file: PathBuf::from("/etc/ubuntu-auto-update/host.id"),
-                enrollment_url: "http://localhost:8080/api/v1/enroll".to_string(),
-            },
+            dir: PathBuf::from("/var/lib/ubuntu-auto-update"),
+            history_limit: 500,
         }
     }
 }
 
-impl AgentConfig {
-    pub fn load() -> Result<Self> {
-        let config_paths = [
-            "/etc/ubuntu-auto-update/agent.toml",
-            "/etc/ubuntu-auto-update/agent.yaml",
-            "./agent.toml",
-            "./agent.yaml",
-        ];
-
-        let mut builder = Config::builder();
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SnapshotConfig {
+    /// Take a filesystem snapshot before applying updates
+    pub enabled: bool,
+    /// "auto", "timeshift", "snapper", "btrfs" or "lvm"
+    pub backend: String,
+    pub btrfs_snapshot_dir: PathBuf,
+    /// Thin logical volume holding the root filesystem, e.g. "vg0/root"
+    pub lvm_volume: Option<String>,
+    /// Number of agent-created snapshots remembered for rollback; older
+    /// ones are deleted, except snapper's, which it cleans up itself
+    pub keep: usize,
+}
 
-        // Try to load from config files
-        for path in &config_paths {
-            if std::path::Path::new(path).exists() {
-                builder = builder.add_source(File::with_name(path).required(false));
-                tracing::info!("Loading configuration from {}", path);
-            }
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            backend: "auto".to_string(),
+            btrfs_snapshot_dir: PathBuf::from("/.snapshots/ubuntu-auto-update"),
+            lvm_volume: None,
+            keep: 5,
         }
+    }
+}
 
-        // Override with environment variables
-        builder = builder.add_source(
-            Environment::with_prefix("UA")
-                .prefix_separator("_")
-                .separator("__"),
-        );
-
-        let config = builder.build()?;
-        let agent_config: AgentConfig = config.try_deserialize()?;
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CoordinationConfig {
+    /// Local application endpoint POSTed before updating; a non-2xx reply defers the run
+    pub enter_url: Option<String>,
+    /// Endpoint POSTed after the run (defaults to enter_url with action "exit")
+    pub exit_url: Option<String>,
+    pub timeout_seconds: u64,
+    /// Run anyway when the application endpoint cannot be reached
+    pub proceed_if_unreachable: bool,
+}
 
-        Ok(agent_config)
+impl Default for CoordinationConfig {
+    fn default() -> Self {
+        Self {
+            enter_url: None,
+            exit_url: None,
+            timeout_seconds: 10,
+            proceed_if_unreachable: false,
+        }
     }
+}
 
-    pub fn validate(&self) -> Result<(), ConfigError> {
-        // Validate URLs
-        if self.backend.url.is_empty() {
-            return Err(ConfigError::Message(
-                "Backend URL cannot be empty".to_string(),
-            ));
-        }
+/// Caution mode for graphics stack updates (mesa, nvidia, xorg, wayland
+/// compositors), whose breakage is the most visible failure on kiosks.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct GraphicsConfig {
+    /// Hold graphics stack updates back unless allowed below
+    pub caution: bool,
+    /// Install them in the regular maintenance window anyway
+    pub allow_updates: bool,
+    /// Separate window ("HH:MM") in which graphics updates are installed
+    pub window_start: Option<String>,
+    pub window_end: Option<String>,
+}
 
-        // Validate timeouts
-        if self.backend.timeout_seconds == 0 {
-            return Err(ConfigError::Message(
-                "Backend timeout must be > 0".to_string(),
-            ));
-        }
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RiskLevel {
+    /// Leaf applications
+    #[default]
+    Low,
+    /// Shared libraries and services
+    Medium,
+    /// Kernel, libc, systemd, bootloader and the graphics stack
+    High,
+}
 
-        // Validate log level
-        if !["trace", "debug", "info", "warn", "error"].contains(&self.logging.level.as_str()) {
-            return Err(ConfigError::Message(format!(
-                "Invalid log level: {}",
-                self.logging.level
-            )));
-        }
+/// Per-package risk scoring and when each risk level may be applied.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RiskConfig {
+    /// Checked in order before the built-in rules
+    pub rules: Vec<RiskRule>,
+    /// Days ("sat", "sun", ...) high risk updates are applied on; any day
+    /// when empty
+    pub high_risk_days: Vec<String>,
+    pub medium_risk_days: Vec<String>,
+}
 
-        // Validate log format
-        if !["json", "text"].contains(&self.logging.format.as_str()) {
-            return Err(ConfigError::Message(format!(
-                "Invalid log format: {}",
-                self.logging.format
-            )));
-        }
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RiskRule {
+    /// Package name, with `*` matching any run of characters
+    pub pattern: String,
+    pub level: RiskLevel,
+}
 
-        Ok(())
-    }
+/// OpenTelemetry span export over OTLP/HTTP.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TelemetryConfig {
+    pub enabled: bool,
+    /// Traces endpoint, e.g. "http://otel-collector:4318/v1/traces". When
+    /// unset `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT`/`OTEL_EXPORTER_OTLP_ENDPOINT`
+    /// apply, falling back to localhost.
+    pub endpoint: Option<String>,
+    pub service_name: String,
+}
 
-    pub fn save_default_config(path: &str) -> Result<()> {
-        let config = AgentConfig::default();
-        let toml_string = toml::to_string(&config)?;
-        std::fs::write(path, toml_string)?;
-        Ok(())
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            endpoint: None,
+            service_name: "ubuntu-auto-update-agent".to_string(),
+        }
     }
+}
 
-    pub fn load_from_file(path: &std::path::Path) -> Result<Self> {
-        let content = std::fs::read_to_string(path)
-            .with_context(|| format!("Failed to read config file: {:?}", path))?;
-        let config: AgentConfig = toml::from_str(&content)
-            .with_context(|| format!("Failed to parse config file: {:?}", path))?;
-        config.validate()?;
-        Ok(config)
+/// Broker for `backend.transport = "nats"`. Reports go to
+/// `<subject_prefix>.reports.<host>`, commands are read from
+/// `<subject_prefix>.commands.<host>` and acknowledged on
+/// `<subject_prefix>.acks.<host>`, where host is the enrolled host ID.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct NatsConfig {
+    /// e.g. "nats://nats.example.com:4222" or "tls://nats.example.com:4222"
+    pub url: String,
+    /// `.creds` file with the user JWT and NKey seed
+    pub credentials_file: Option<PathBuf>,
+    /// CA for the broker's certificate; setting it requires TLS
+    pub ca_file: Option<PathBuf>,
+    pub subject_prefix: String,
+    /// Publish through JetStream and wait for the stream to store each
+    /// message. Commands always need JetStream.
+    pub jetstream: bool,
+    /// Stream over `<subject_prefix>.>`, created when missing
+    pub stream: String,
+}
+
+impl Default for NatsConfig {
+    fn default() -> Self {
+        Self {
+            url: "nats://localhost:4222".to_string(),
+            credentials_file: None,
+            ca_file: None,
+            subject_prefix: "ubuntu-auto-update".to_string(),
+            jetstream: true,
+            stream: "UBUNTU_AUTO_UPDATE".to_string(),
+        }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// MQTT broker for `backend.transport = "mqtt"`. Reports are published to
+/// `<topic_prefix>/reports/<host>` and, in daemon mode, retained
+/// availability to `<topic_prefix>/availability/<host>`, where host is the
+/// enrolled host ID.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MqttConfig {
+    /// "mqtts://broker.example.com:8883", or "mqtt://" without TLS
+    pub url: String,
+    pub username: Option<String>,
+    pub password_file: Option<PathBuf>,
+    /// CA for the broker's certificate; the system CAs otherwise
+    pub ca_file: Option<PathBuf>,
+    /// Client certificate and key (PEM) for brokers that authenticate
+    /// hosts by certificate
+    pub cert_file: Option<PathBuf>,
+    pub key_file: Option<PathBuf>,
+    pub topic_prefix: String,
+    /// How often the daemon republishes "online"
+    pub heartbeat_interval_seconds: u64,
+}
 
-    #[test]
-    fn test_default_config_validation() {
-        let config = AgentConfig::default();
-        assert!(config.validate().is_ok());
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            url: "mqtts://localhost:8883".to_string(),
+            username: None,
+            password_file: None,
+            ca_file: None,
+            cert_file: None,
+            key_file: None,
+            topic_prefix: "ubuntu-auto-update".to_string(),
+            heartbeat_interval_seconds: 300,
+        }
     }
+}
 
-    #[test]
-    fn test_config_serialization() {
-        let config = AgentConfig::default();
-        let serialized = toml::to_string(&config).unwrap();
-        let deserialized: AgentConfig = toml::from_str(&serialized).unwrap();
+/// Reports, SBOMs and run logs uploaded straight to an S3-compatible bucket
+/// (AWS, MinIO, Ceph RGW) under `<prefix><kind>/<timestamp>.json`, next to
+/// the backend or, with `backend.transport = "s3"`, instead of it.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct S3Config {
+    pub enabled: bool,
+    /// e.g. "https://s3.eu-central-1.amazonaws.com" or "https://minio.lan:9000"
+    pub endpoint: String,
+    pub region: String,
+    pub bucket: String,
+    /// Key prefix; `{hostname}` and `{host_id}` are filled in
+    pub prefix: String,
+    pub access_key_id: String,
+    pub secret_access_key_file: PathBuf,
+    /// `<endpoint>/<bucket>/<key>` rather than `<bucket>.<endpoint>/<key>`,
+    /// as MinIO expects
+    pub path_style: bool,
+    /// CA certificate for an endpoint with a private CA
+    pub ca_file: Option<PathBuf>,
+}
 
-        assert_eq!(config.backend.url, deserialized.backend.url);
-        assert_eq!(config.logging.level, deserialized.logging.level);
+impl Default for S3Config {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            endpoint: String::new(),
+            region: "us-east-1".to_string(),
+            bucket: String::new(),
+            prefix: "{hostname}/".to_string(),
+            access_key_id: String::new(),
+            secret_access_key_file: PathBuf::from("/etc/ubuntu-auto-update/s3.secret"),
+            path_style: true,
+            ca_file: None,
+        }
     }
+}
 
-    #[test]
+/// Pushing the agent's metrics to a Prometheus remote_write endpoint
+/// (Prometheus, Mimir, VictoriaMetrics) after each run, for hosts nothing
+/// scrapes. Needs `metrics.enabled`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RemoteWriteConfig {
+    pub enabled: bool,
+    /// e.g. "https://mimir.example.com/api/v1/push"
+    pub url: String,
+    pub bearer_token_file: Option<PathBuf>,
+    /// Extra request headers, e.g. `X-Scope-OrgID` for a Mimir tenant
+    pub headers: BTreeMap<String, String>,
+    /// `job` label on every series; `instance` is the hostname
+    pub job: String,
+    /// Labels added to every series, e.g. site or ring
+    pub labels: BTreeMap<String, String>,
+}
+
+impl Default for RemoteWriteConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            url: String::new(),
+            bearer_token_file: None,
+            headers: BTreeMap::new(),
+            job: "ubuntu-auto-update".to_string(),
+            labels: BTreeMap::new(),
+        }
+    }
+}
+
+/// Notifications sent straight from the host, without the backend.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct NotificationsConfig {
+    /// healthchecks.io-style ping URL: pinged with `/start` when a run
+    /// begins, bare on success and with `/fail` on failure
+    pub healthcheck_url: Option<String>,
+    /// Told about every finished run
+    pub webhooks: Vec<Webhook>,
+    /// Overrides for the text of webhooks, the MOTD summary and the reboot
+    /// wall message
+    pub templates: NotificationTemplates,
+}
+
+/// `[notifications.templates]`: minijinja templates replacing the built-in
+/// notification text, by name: "title" and "message" for webhooks, "motd",
+/// "wall", and any a webhook names as its `template`.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct NotificationTemplates {
+    /// Directory of `<name>.j2` files
+    pub dir: Option<PathBuf>,
+    /// Templates by name; these win over files in `dir`
+    pub inline: BTreeMap<String, String>,
+    /// Available to every template as `vars`, e.g. a customer or support
+    /// contact
+    pub vars: BTreeMap<String, String>,
+}
+
+/// A chat or push webhook for `[[notifications.webhooks]]`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Webhook {
+    pub url: String,
+    /// "generic" (the run summary as JSON), "slack", "teams" or "ntfy"
+    pub format: String,
+    /// Skip runs that succeeded
+    pub failures_only: bool,
+    /// Sent as `Authorization: Bearer`, e.g. an ntfy access token
+    pub token_file: Option<PathBuf>,
+    /// Template rendering the whole request body instead of `format`;
+    /// posted as JSON if the result parses as JSON, as text otherwise
+    pub template: Option<String>,
+}
+
+impl Default for Webhook {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            format: "generic".to_string(),
+            failures_only: false,
+            token_file: None,
+            template: None,
+        }
+    }
+}
+
+/// `ua-agent release-upgrade`: moving to the next Ubuntu release with
+/// do-release-upgrade. Off by default, since it can't be undone without a
+/// snapshot.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ReleaseUpgradeConfig {
+    pub enabled: bool,
+    /// Accept the `release_upgrade` command from the backend
+    pub allow_remote: bool,
+    /// Abort when the pre-upgrade snapshot can't be created
+    pub require_snapshot: bool,
+    /// do-release-upgrade is killed after this long
+    pub timeout_minutes: u64,
+}
+
+impl Default for ReleaseUpgradeConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            allow_remote: false,
+            require_snapshot: true,
+            timeout_minutes: 360,
+        }
+    }
+}
+
+/// What the agent keeps running besides updating and reporting.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuntimeProfile {
+    Full,
+    /// For devices with little memory: no Prometheus metrics (registry,
+    /// textfile, remote_write) and no sysinfo host metrics in reports
+    Minimal,
+}
+
+impl Default for RuntimeProfile {
+    /// `minimal` in builds with the `minimal` feature
+    fn default() -> Self {
+        if cfg!(feature = "minimal") {
+            Self::Minimal
+        } else {
+            Self::Full
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RuntimeConfig {
+    pub profile: RuntimeProfile,
+}
+
+/// Append-only log of every external command the agent runs, summarized
+/// in each report.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AuditConfig {
+    pub enabled: bool,
+    /// Defaults to `<state.dir>/audit.jsonl`
+    pub file: Option<PathBuf>,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            file: None,
+        }
+    }
+}
+
+/// Architecture and board specific behavior, see `platform.rs`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PlatformConfig {
+    /// Change defaults that don't suit the hardware, e.g. snaps on riscv64
+    /// and timeouts on Raspberry Pis
+    pub adjust: bool,
+}
+
+impl Default for PlatformConfig {
+    fn default() -> Self {
+        Self { adjust: true }
+    }
+}
+
+/// Where `[log_shipping]` sends log events.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogShippingTarget {
+    /// The backend's `logs` endpoint
+    #[default]
+    Backend,
+    /// A Loki push endpoint, `loki_url`
+    Loki,
+}
+
+/// Batches the agent's log events and sends them off the host, so a failed
+/// kiosk can be debugged without SSH.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LogShippingConfig {
+    pub enabled: bool,
+    pub target: LogShippingTarget,
+    /// e.g. "https://loki.example.com/loki/api/v1/push"
+    pub loki_url: Option<String>,
+    /// Least severe level shipped; the global `logging.level` applies first
+    pub level: String,
+    pub batch_size: usize,
+    pub flush_interval_seconds: u64,
+    /// Events queued at most while the sink is slow; later ones are
+    /// dropped and counted
+    pub max_queue: usize,
+}
+
+impl Default for LogShippingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            target: LogShippingTarget::Backend,
+            loki_url: None,
+            level: "info".to_string(),
+            batch_size: 200,
+            flush_interval_seconds: 10,
+            max_queue: 5000,
+        }
+    }
+}
+
+/// Scheduled SBOM upload after update runs.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SbomConfig {
+    /// POST an SBOM to the backend every `interval_hours`
+    pub upload: bool,
+    pub interval_hours: u64,
+    /// Format of uploaded SBOMs, and of `sbom` without `--format`
+    pub format: SbomFormat,
+}
+
+impl Default for SbomConfig {
+    fn default() -> Self {
+        Self {
+            upload: false,
+            interval_hours: 168,
+            format: SbomFormat::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SbomFormat {
+    /// CycloneDX 1.5 JSON
+    #[default]
+    Cyclonedx,
+    /// SPDX 2.3 JSON
+    Spdx,
+}
+
+/// External vulnerability scanner run after updates.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ScannerConfig {
+    /// argv of a scanner printing trivy or osv-scanner JSON, run without a
+    /// shell; `{sbom}` is replaced with the path of a fresh SBOM. Disabled
+    /// when empty.
+    pub command: Vec<String>,
+    pub timeout_seconds: u64,
+}
+
+impl Default for ScannerConfig {
+    fn default() -> Self {
+        Self {
+            command: vec![],
+            timeout_seconds: 600,
+        }
+    }
+}
+
+/// Restarting services left on replaced libraries after an upgrade, using
+/// needrestart's list, instead of asking for a reboot.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct NeedrestartConfig {
+    pub enabled: bool,
+    /// Units never restarted, e.g. a kiosk's session; an outdated one keeps
+    /// the reboot
+    pub exclude: Vec<String>,
+}
+
+/// Free space monitoring between runs in daemon mode. A path is low when
+/// either threshold is crossed.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct DiskSpaceConfig {
+    pub enabled: bool,
+    pub paths: Vec<PathBuf>,
+    pub min_free_percent: u64,
+    /// Room for another kernel and initrd on /boot; also the floor the
+    /// pre-flight check keeps free on every `preflight_paths` filesystem
+    pub min_free_mb: u64,
+    /// Check free space against apt's estimates before upgrading and abort
+    /// the apt phase when it would run out
+    pub preflight: bool,
+    pub preflight_paths: Vec<PathBuf>,
+}
+
+impl Default for DiskSpaceConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            paths: vec![PathBuf::from("/boot"), PathBuf::from("/var")],
+            min_free_percent: 10,
+            min_free_mb: 200,
+            preflight: true,
+            preflight_paths: vec![
+                PathBuf::from("/"),
+                PathBuf::from("/var"),
+                PathBuf::from("/boot"),
+            ],
+        }
+    }
+}
+
+/// Pre-update checks; a failing check defers the run.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct GuardsConfig {
+    pub checks: Vec<GuardCheck>,
+}
+
+/// Deferring runs on battery power, where losing power halfway through
+/// dpkg leaves the package database broken. Hosts without a battery are
+/// unaffected.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PowerConfig {
+    pub enabled: bool,
+    /// Defer while on battery below this charge
+    pub min_battery_percent: u8,
+    /// Defer whenever on battery, whatever the charge
+    pub require_ac: bool,
+    /// Hold a logind shutdown/sleep inhibitor while packages are installed
+    pub inhibit: bool,
+}
+
+impl Default for PowerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            min_battery_percent: 50,
+            require_ac: false,
+            inhibit: true,
+        }
+    }
+}
+
+/// One guard: either a command that must exit 0, or a file (e.g. a backup
+/// job's success stamp) that must have been modified recently.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct GuardCheck {
+    pub name: String,
+    /// argv of a check script, run without a shell
+    pub command: Vec<String>,
+    pub timeout_seconds: u64,
+    pub path: Option<PathBuf>,
+    pub max_age_hours: Option<u64>,
+}
+
+impl Default for GuardCheck {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            command: vec![],
+            timeout_seconds: 60,
+            path: None,
+            max_age_hours: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct I18nConfig {
+    /// CLI language ("en", "de", "es", ...) or "auto" to follow LANG/LC_*
+    pub locale: String,
+}
+
+impl Default for I18nConfig {
+    fn default() -> Self {
+        Self {
+            locale: "auto".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MotdConfig {
+    /// Write a run summary shown at SSH login
+    pub enabled: bool,
+    /// Generated update-motd.d script
+    pub path: PathBuf,
+    /// Disable the stock update-notifier "updates available" and
+    /// "restart required" MOTD parts so they don't duplicate ours
+    pub replace_update_notifier: bool,
+}
+
+impl Default for MotdConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: PathBuf::from("/etc/update-motd.d/90-ubuntu-auto-update"),
+            replace_update_notifier: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PolicyConfig {
+    /// Fetch /api/v1/policy/{host_id} before each run and let it override
+    /// the maintenance window, excluded packages, sources and auto_reboot
+    pub enabled: bool,
+    /// How the policy's excluded_packages combine with the local list
+    pub excluded_packages_merge: ExclusionMerge,
+    /// Where each effective excluded package came from, filled in when a
+    /// policy is applied; `None` means all of them are local
+    #[serde(skip)]
+    pub applied_exclusions: Option<Vec<ExcludedPackage>>,
+}
+
+impl Default for PolicyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            excluded_packages_merge: ExclusionMerge::default(),
+            applied_exclusions: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExclusionMerge {
+    /// Exclude packages listed locally or by the backend
+    Union,
+    /// The backend's list replaces the local one
+    #[default]
+    BackendWins,
+    /// The backend's list only applies while the local one is empty
+    LocalWins,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExclusionSource {
+    Local,
+    Backend,
+    Both,
+}
+
+/// An effective excluded package and which list it came from, sent in
+/// reports for auditing.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ExcludedPackage {
+    pub package: String,
+    pub source: ExclusionSource,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct DaemonConfig {
+    /// Minimum time between update runs in daemon mode
+    pub interval_minutes: u64,
+    /// How often a pending run re-checks the maintenance window
+    pub check_interval_seconds: u64,
+    /// Reload when the config file changes, not only on SIGHUP
+    pub watch_config: bool,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        Self {
+            interval_minutes: 1440,
+            check_interval_seconds: 60,
+            watch_config: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct UnattendedUpgradesConfig {
+    /// What to do when unattended-upgrades is also enabled: "report" (warn
+    /// and keep updating), "take_ownership" (disable APT::Periodic and the
+    /// apt timers) or "passive" (leave apt to unattended-upgrades and only
+    /// report)
+    pub policy: String,
+}
+
+impl Default for UnattendedUpgradesConfig {
+    fn default() -> Self {
+        Self {
+            policy: "report".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct BeaconConfig {
+    /// POST health beacons to the backend after runs that changed packages
+    pub enabled: bool,
+    pub interval_minutes: u64,
+    /// How long after the run beacons keep being sent
+    pub duration_hours: u64,
+}
+
+impl Default for BeaconConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_minutes: 15,
+            duration_hours: 24,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CommandsConfig {
+    /// Long-poll the backend for operator commands in daemon mode
+    pub enabled: bool,
+    /// How long the backend may hold a poll open before answering
+    pub poll_seconds: u64,
+}
+
+impl Default for CommandsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            poll_seconds: 60,
+        }
+    }
+}
+
+/// HTTP/JSON API for software on the same host, served in daemon mode.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LocalApiConfig {
+    pub enabled: bool,
+    /// Unix socket, mode 0660; anyone who can connect may use the API
+    pub socket_path: PathBuf,
+    /// Group that owns the socket, e.g. the kiosk app's
+    pub socket_group: Option<String>,
+    /// Also listen on this loopback address, e.g. "127.0.0.1:8765"; needs
+    /// token_file
+    pub listen: Option<String>,
+    /// Bearer token TCP clients must send
+    pub token_file: Option<PathBuf>,
+}
+
+impl Default for LocalApiConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            socket_path: PathBuf::from("/run/ubuntu-auto-update/api.sock"),
+            socket_group: None,
+            listen: None,
+            token_file: None,
+        }
+    }
+}
+
+/// `org.ubuntuautoupdate.Agent1` on the system bus, served in daemon mode
+/// for desktop integrations. Access is governed by the bus policy.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct DbusConfig {
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ReportingConfig {
+    /// "full", or "minimal" to strip hostnames, command output and free-text
+    /// messages from reports, keeping only counts, hashes and status
+    pub profile: String,
+    /// age X25519 recipients ("age1...") reports are encrypted to before
+    /// upload, so only the holders of the matching identities can read them
+    pub encrypt_to: Vec<String>,
+    /// Destinations that get a copy of every report, next to the backend
+    pub sinks: Vec<ReportSink>,
+}
+
+impl Default for ReportingConfig {
+    fn default() -> Self {
+        Self {
+            profile: "full".to_string(),
+            encrypt_to: vec![],
+            sinks: vec![],
+        }
+    }
+}
+
+/// A report archive outside the management backend, e.g. a bucket or a
+/// second backend's report endpoint. It receives the same (redacted,
+/// encrypted) JSON the backend does.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ReportSink {
+    pub name: String,
+    /// `{hostname}`, `{host_id}` and `{timestamp}` are filled in, so every
+    /// report can go to its own object
+    pub url: String,
+    /// "put" or "post"
+    pub method: String,
+    /// Sent as `Authorization: Bearer`; leave unset for URLs that carry
+    /// their own credentials
+    pub token_file: Option<PathBuf>,
+}
+
+impl Default for ReportSink {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            url: String::new(),
+            method: "put".to_string(),
+            token_file: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ClientCertConfig {
+    /// Have the backend sign an mTLS client certificate during `enroll`,
+    /// written to security.cert_file / security.key_file
+    pub enabled: bool,
+    /// Request a new certificate this many days before the current one expires
+    pub renew_before_days: u32,
+}
+
+impl Default for ClientCertConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            renew_before_days: 30,
+        }
+    }
+}
+
+impl Default for AgentConfig {
+    fn default() -> Self {
+        Self {
+            backend: BackendConfig {
+                url: "http://localhost:8080".into(),
+                timeout_seconds: 30,
+                retry_attempts: 3,
+                retry_delay_seconds: 5,
+                proxy_url: None,
+                proxy_credentials_file: None,
+                api_prefix: None,
+                endpoints: BTreeMap::new(),
+                transport: Transport::Http,
+            },
+            security: SecurityConfig {
+                api_key_file: PathBuf::from("/etc/ubuntu-auto-update/auth.token"),
+                cert_file: None,
+                key_file: None,
+                ca_file: None,
+                hmac_secret_file: Some(PathBuf::from("/etc/ubuntu-auto-update/hmac.key")),
+                verify_server_cert: true,
+                use_mtls: false,
+                credentials_from_systemd: false,
+                max_clock_skew_seconds: None,
+                auth_header_style: AuthHeaderStyle::Bearer,
+                auth_header_name: None,
+            },
+            updates: UpdateConfig {
+                dry_run: false,
+                auto_reboot: false,
+                reboot_delay_minutes: 5,
+                maintenance_window_start: None,
+                maintenance_window_end: None,
+                maintenance_windows: Vec::new(),
+                splay_seconds: 0,
+                slots: SlotSchedule::default(),
+                watchdog: StallWatchdog::default(),
+                excluded_packages: vec![],
+                update_sources: UpdateSources {
+                    apt: true,
+                    snap: true,
+                    flatpak: false,
+                    firmware: false,
+                },
+                rollback_on_failure: false,
+                resource_limits: ResourceLimits::default(),
+                security_pockets: vec![],
+                mode: UpdateMode::Manage,
+                upgrade_mode: UpgradeMode::Upgrade,
+                package_backend: PackageBackend::Apt,
+                protected_packages: vec![],
+                allowed_removals: vec![],
+                stop_services: vec![],
+                start_after: false,
+                apt_cache_dir: None,
+                debdelta: false,
+                respect_metered: false,
+                metered_rate_limit_kbps: None,
+                snap: SnapConfig::default(),
+                flatpak: FlatpakConfig::default(),
+            },
+            logging: LoggingConfig {
+                level: "info".to_string(),
+                format: "json".to_string(),
+                file: Some(PathBuf::from("/var/log/ubuntu-auto-update/agent.log")),
+                max_size_mb: 100,
+                max_files: 5,
+            },
+            metrics: MetricsConfig {
+                enabled: true,
+                port: Some(9100),
+                textfile_path: Some(PathBuf::from("/var/lib/node_exporter/textfile_collector")),
+                collect_system_metrics: true,
+            },
+            enrollment: EnrollmentConfig {
+                token_file: PathBuf::from("/etc/ubuntu-auto-update/enrollment.token"),
+                host_id_file: PathBuf::from("/etc/ubuntu-auto-update/host.id"),
+                enrollment_url: "http://localhost:8080/api/v1/enroll".to_string(),
+            },
+            state: StateConfig::default(),
+            snapshot: SnapshotConfig::default(),
+            coordination: CoordinationConfig::default(),
+            i18n: I18nConfig::default(),
+            motd: MotdConfig::default(),
+            policy: PolicyConfig::default(),
+            daemon: DaemonConfig::default(),
+            unattended_upgrades: UnattendedUpgradesConfig::default(),
+            beacon: BeaconConfig::default(),
+            reporting: ReportingConfig::default(),
+            client_cert: ClientCertConfig::default(),
+            commands: CommandsConfig::default(),
+            local_api: LocalApiConfig::default(),
+            dbus: DbusConfig::default(),
+            guards: GuardsConfig::default(),
+            power: PowerConfig::default(),
+            graphics: GraphicsConfig::default(),
+            risk: RiskConfig::default(),
+            telemetry: TelemetryConfig::default(),
+            sbom: SbomConfig::default(),
+            scanner: ScannerConfig::default(),
+            disk_space: DiskSpaceConfig::default(),
+            needrestart: NeedrestartConfig::default(),
+            nats: NatsConfig::default(),
+            mqtt: MqttConfig::default(),
+            s3: S3Config::default(),
+            remote_write: RemoteWriteConfig::default(),
+            notifications: NotificationsConfig::default(),
+            release_upgrade: ReleaseUpgradeConfig::default(),
+            runtime: RuntimeConfig::default(),
+            audit: AuditConfig::default(),
+            platform: PlatformConfig::default(),
+            log_shipping: LogShippingConfig::default(),
+            loaded_from: Vec::new(),
+        }
+    }
+}
+
+impl AgentConfig {
+    pub fn load() -> Result<Self> {
+        let config_paths = [
+            "/etc/ubuntu-auto-update/agent.toml",
+            "/etc/ubuntu-auto-update/agent.yaml",
+            "./agent.toml",
+            "./agent.yaml",
+        ];
+
+        let mut builder = Config::builder();
+        let mut loaded_from = Vec::new();
+
+        // Try to load from config files
+        for path in &config_paths {
+            if std::path::Path::new(path).exists() {
+                builder = builder.add_source(File::with_name(path).required(false));
+                tracing::info!("Loading configuration from {}", path);
+                loaded_from.push(PathBuf::from(path));
+            }
+        }
+
+        // Fragments override the main file but not the environment
+        let main_path = Path::new("/etc/ubuntu-auto-update/agent.toml");
+        builder = Self::add_drop_ins(builder, main_path)?;
+        loaded_from.extend(Self::drop_in_files(main_path)?);
+
+        // Override with environment variables
+        builder = builder.add_source(
+            Environment::with_prefix("UA")
+                .prefix_separator("_")
+                .separator("__"),
+        );
+
+        let config = builder.build()?;
+        let mut agent_config: AgentConfig = config.try_deserialize()?;
+        agent_config.loaded_from = loaded_from;
+
+        Ok(agent_config)
+    }
+
+    /// Turns off what the minimal profile leaves out, and metrics in builds
+    /// without the `metrics` feature whatever the profile. Runs before
+    /// `validate`, so the settings it overrides needn't be changed by hand.
+    pub fn apply_profile(&mut self) {
+        if self.runtime.profile == RuntimeProfile::Minimal || !cfg!(feature = "metrics") {
+            self.metrics.enabled = false;
+            self.metrics.collect_system_metrics = false;
+            self.metrics.textfile_path = None;
+            self.remote_write.enabled = false;
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        // Validate URLs
+        if self.backend.url.is_empty() {
+            return Err(ConfigError::Message(
+                "Backend URL cannot be empty".to_string(),
+            ));
+        }
+        if self.backend.url.as_slice().iter().any(String::is_empty) {
+            return Err(ConfigError::Message(
+                "Backend URLs cannot be empty".to_string(),
+            ));
+        }
+
+        if let Some(proxy_url) = &self.backend.proxy_url {
+            let scheme = proxy_url.split_once("://").map(|(scheme, _)| scheme);
+            if !matches!(scheme, Some("http" | "https" | "socks5" | "socks5h")) {
+                return Err(ConfigError::Message(format!(
+                    "Unsupported proxy URL {}, expected http://, https://, socks5:// or socks5h://",
+                    proxy_url
+                )));
+            }
+        }
+
+        let prefix = self.backend.api_prefix();
+        if !prefix.is_empty() && (!prefix.starts_with('/') || prefix.ends_with('/')) {
+            return Err(ConfigError::Message(format!(
+                "backend.api_prefix must start and not end with '/': {}",
+                prefix
+            )));
+        }
+        for (name, path) in &self.backend.endpoints {
+            if !BACKEND_ENDPOINTS.contains(&name.as_str()) {
+                return Err(ConfigError::Message(format!(
+                    "Unknown backend endpoint {}, expected one of: {}",
+                    name,
+                    BACKEND_ENDPOINTS.join(", ")
+                )));
+            }
+            if !path.starts_with('/') {
+                return Err(ConfigError::Message(format!(
+                    "backend.endpoints.{} must be an absolute path: {}",
+                    name, path
+                )));
+            }
+        }
+
+        // Validate timeouts
+        if self.backend.timeout_seconds == 0 {
+            return Err(ConfigError::Message(
+                "Backend timeout must be > 0".to_string(),
+            ));
+        }
+
+        // Validate log level
+        if !["trace", "debug", "info", "warn", "error"].contains(&self.logging.level.as_str()) {
+            return Err(ConfigError::Message(format!(
+                "Invalid log level: {}",
+                self.logging.level
+            )));
+        }
+
+        // Validate log format
+        if !["json", "text"].contains(&self.logging.format.as_str()) {
+            return Err(ConfigError::Message(format!(
+                "Invalid log format: {}",
+                self.logging.format
+            )));
+        }
+
+        // Validate resource limits
+        let limits = &self.updates.resource_limits;
+        if limits.nice.is_some_and(|nice| !(-20..=19).contains(&nice)) {
+            return Err(ConfigError::Message(
+                "updates.resource_limits.nice must be between -20 and 19".to_string(),
+            ));
+        }
+        if let Some(class) = &limits.ionice_class {
+            if !["idle", "best-effort", "realtime"].contains(&class.as_str()) {
+                return Err(ConfigError::Message(format!(
+                    "Invalid ionice class: {}",
+                    class
+                )));
+            }
+        }
+        if limits.ionice_level.is_some_and(|level| level > 7) {
+            return Err(ConfigError::Message(
+                "updates.resource_limits.ionice_level must be between 0 and 7".to_string(),
+            ));
+        }
+        if limits
+            .io_weight
+            .is_some_and(|weight| !(1..=10000).contains(&weight))
+        {
+            return Err(ConfigError::Message(
+                "updates.resource_limits.io_weight must be between 1 and 10000".to_string(),
+            ));
+        }
+
+        if let Some(unit) = self
+            .updates
+            .stop_services
+            .iter()
+            .find(|unit| unit.is_empty() || unit.starts_with('-'))
+        {
+            return Err(ConfigError::Message(format!(
+                "Invalid unit in updates.stop_services: {:?}",
+                unit
+            )));
+        }
+
+        if let Some(dir) = &self.updates.apt_cache_dir {
+            if !dir.is_absolute() {
+                return Err(ConfigError::Message(format!(
+                    "updates.apt_cache_dir must be an absolute path: {:?}",
+                    dir
+                )));
+            }
+        }
+
+        if self.updates.metered_rate_limit_kbps == Some(0) {
+            return Err(ConfigError::Message(
+                "updates.metered_rate_limit_kbps must be greater than 0".to_string(),
+            ));
+        }
+
+        // Validate snapshot backend
+        if !["auto", "timeshift", "snapper", "btrfs", "lvm"]
+            .contains(&self.snapshot.backend.as_str())
+        {
+            return Err(ConfigError::Message(format!(
+                "Invalid snapshot backend: {}",
+                self.snapshot.backend
+            )));
+        }
+
+        if self.updates.rollback_on_failure && !self.snapshot.enabled {
+            return Err(ConfigError::Message(
+                "updates.rollback_on_failure requires snapshot.enabled".to_string(),
+            ));
+        }
+
+        if !["report", "take_ownership", "passive"]
+            .contains(&self.unattended_upgrades.policy.as_str())
+        {
+            return Err(ConfigError::Message(format!(
+                "Invalid unattended_upgrades.policy: {}",
+                self.unattended_upgrades.policy
+            )));
+        }
+
+        if self.daemon.interval_minutes == 0 || self.daemon.check_interval_seconds == 0 {
+            return Err(ConfigError::Message(
+                "daemon.interval_minutes and daemon.check_interval_seconds must be greater than 0"
+                    .to_string(),
+            ));
+        }
+
+        if !["full", "minimal"].contains(&self.reporting.profile.as_str()) {
+            return Err(ConfigError::Message(format!(
+                "Invalid reporting.profile: {}",
+                self.reporting.profile
+            )));
+        }
+
+        for recipient in &self.reporting.encrypt_to {
+            recipient.parse::<age::x25519::Recipient>().map_err(|e| {
+                ConfigError::Message(format!(
+                    "Invalid reporting.encrypt_to recipient {}: {}",
+                    recipient, e
+                ))
+            })?;
+        }
+
+        for sink in &self.reporting.sinks {
+            let valid_url = sink.url.starts_with("https://") || sink.url.starts_with("http://");
+            if sink.name.is_empty()
+                || !valid_url
+                || !["put", "post"].contains(&sink.method.as_str())
+            {
+                return Err(ConfigError::Message(format!(
+                    "Invalid report sink {:?}: needs a name, an http(s) url and method \"put\" or \"post\"",
+                    sink.name
+                )));
+            }
+        }
+
+        if self.client_cert.enabled
+            && (self.security.cert_file.is_none() || self.security.key_file.is_none())
+        {
+            return Err(ConfigError::Message(
+                "client_cert.enabled requires security.cert_file and security.key_file".to_string(),
+            ));
+        }
+
+        if self.beacon.interval_minutes == 0 || self.beacon.duration_hours == 0 {
+            return Err(ConfigError::Message(
+                "beacon.interval_minutes and beacon.duration_hours must be greater than 0"
+                    .to_string(),
+            ));
+        }
+
+        if self.release_upgrade.timeout_minutes == 0 {
+            return Err(ConfigError::Message(
+                "release_upgrade.timeout_minutes must be greater than 0".to_string(),
+            ));
+        }
+
+        if self.security.max_clock_skew_seconds == Some(0) {
+            return Err(ConfigError::Message(
+                "security.max_clock_skew_seconds must be greater than 0".to_string(),
+            ));
+        }
+
+        // Commands are only trusted when the backend signs them
+        match (
+            self.security.auth_header_style,
+            &self.security.auth_header_name,
+        ) {
+            (AuthHeaderStyle::Custom, None) => {
+                return Err(ConfigError::Message(
+                    "security.auth_header_style = \"custom\" requires security.auth_header_name"
+                        .to_string(),
+                ));
+            }
+            (AuthHeaderStyle::Custom, Some(name))
+                if reqwest::header::HeaderName::from_bytes(name.as_bytes()).is_err() =>
+            {
+                return Err(ConfigError::Message(format!(
+                    "security.auth_header_name is not a valid header name: {}",
+                    name
+                )));
+            }
+            _ => {}
+        }
+
+        if self.commands.enabled && self.security.hmac_secret_file.is_none() {
+            return Err(ConfigError::Message(
+                "commands.enabled requires security.hmac_secret_file".to_string(),
+            ));
+        }
+        let updates = &self.updates;
+        if updates.package_backend == PackageBackend::Libapt && !cfg!(feature = "libapt") {
+            return Err(ConfigError::Message(
+                "updates.package_backend = \"libapt\" needs an agent built with the libapt feature"
+                    .to_string(),
+            ));
+        }
+        if updates.package_backend == PackageBackend::Packagekit && !cfg!(feature = "dbus") {
+            return Err(ConfigError::Message(
+                "updates.package_backend = \"packagekit\" needs an agent built with the dbus feature"
+                    .to_string(),
+            ));
+        }
+        for spec in &updates.maintenance_windows {
+            if let Err(e) = spec.parse::<crate::window::Window>() {
+                return Err(ConfigError::Message(format!(
+                    "Invalid maintenance window {:?}: {}",
+                    spec, e
+                )));
+            }
+        }
+        if let Some(spec) = &updates.slots.window {
+            if let Err(e) = spec.parse::<crate::window::Window>() {
+                return Err(ConfigError::Message(format!(
+                    "Invalid updates.slots.window {:?}: {}",
+                    spec, e
+                )));
+            }
+            if updates.slots.slot_minutes == 0 {
+                return Err(ConfigError::Message(
+                    "updates.slots.slot_minutes must be greater than 0".to_string(),
+                ));
+            }
+        }
+        match (
+            &updates.maintenance_window_start,
+            &updates.maintenance_window_end,
+        ) {
+            (Some(_), Some(_)) if !updates.maintenance_windows.is_empty() => {
+                return Err(ConfigError::Message(
+                    "Set either updates.maintenance_windows or maintenance_window_start/end"
+                        .to_string(),
+                ));
+            }
+            (Some(start), Some(end)) => {
+                if let Err(e) = crate::window::Window::daily(start, end) {
+                    return Err(ConfigError::Message(format!(
+                        "Invalid maintenance window: {}",
+                        e
+                    )));
+                }
+            }
+            (None, None) => {}
+            _ => return Err(ConfigError::Message(
+                "updates.maintenance_window_start and maintenance_window_end must be set together"
+                    .to_string(),
+            )),
+        }
+
+        match (&self.graphics.window_start, &self.graphics.window_end) {
+            (Some(start), Some(end)) => {
+                for time in [start, end] {
+                    if chrono::NaiveTime::parse_from_str(time, "%H:%M").is_err() {
+                        return Err(ConfigError::Message(format!(
+                            "Invalid graphics window time: {}",
+                            time
+                        )));
+                    }
+                }
+            }
+            (None, None) => {}
+            _ => {
+                return Err(ConfigError::Message(
+                    "graphics.window_start and graphics.window_end must be set together"
+                        .to_string(),
+                ))
+            }
+        }
+
+        for day in self
+            .risk
+            .high_risk_days
+            .iter()
+            .chain(&self.risk.medium_risk_days)
+        {
+            if day.parse::<chrono::Weekday>().is_err() {
+                return Err(ConfigError::Message(format!(
+                    "Invalid day in risk schedule: {}",
+                    day
+                )));
+            }
+        }
+
+        if self.sbom.upload && self.sbom.interval_hours == 0 {
+            return Err(ConfigError::Message(
+                "sbom.interval_hours must be > 0".to_string(),
+            ));
+        }
+
+        if self.disk_space.min_free_percent > 100 {
+            return Err(ConfigError::Message(
+                "disk_space.min_free_percent must be <= 100".to_string(),
+            ));
+        }
+
+        if !self.scanner.command.is_empty() && self.scanner.timeout_seconds == 0 {
+            return Err(ConfigError::Message(
+                "scanner.timeout_seconds must be > 0".to_string(),
+            ));
+        }
+
+        if self.log_shipping.enabled {
+            let shipping = &self.log_shipping;
+            if crate::logging::parse_log_level(&shipping.level).is_err() {
+                return Err(ConfigError::Message(format!(
+                    "Invalid log_shipping.level: {}",
+                    shipping.level
+                )));
+            }
+            if shipping.target == LogShippingTarget::Loki && shipping.loki_url.is_none() {
+                return Err(ConfigError::Message(
+                    "log_shipping.target = \"loki\" needs log_shipping.loki_url".to_string(),
+                ));
+            }
+            if shipping.batch_size == 0
+                || shipping.flush_interval_seconds == 0
+                || shipping.max_queue == 0
+            {
+                return Err(ConfigError::Message(
+                    "log_shipping.batch_size, flush_interval_seconds and max_queue must be > 0"
+                        .to_string(),
+                ));
+            }
+        }
+
+        if self.telemetry.enabled && !cfg!(feature = "otlp") {
+            return Err(ConfigError::Message(
+                "telemetry.enabled needs an agent built with the otlp feature".to_string(),
+            ));
+        }
+        if let Some(endpoint) = &self.telemetry.endpoint {
+            if !endpoint.starts_with("http://") && !endpoint.starts_with("https://") {
+                return Err(ConfigError::Message(format!(
+                    "Invalid telemetry endpoint {}, expected an http(s) URL",
+                    endpoint
+                )));
+            }
+        }
+
+        for (name, snap) in &self.updates.snap.packages {
+            if snap.channel.as_deref().is_some_and(str::is_empty) {
+                return Err(ConfigError::Message(format!(
+                    "updates.snap.packages.{}.channel cannot be empty",
+                    name
+                )));
+            }
+        }
+
+        for remote in &self.updates.flatpak.remotes {
+            if remote.name.is_empty() || remote.name.contains(char::is_whitespace) {
+                return Err(ConfigError::Message(format!(
+                    "Invalid flatpak remote name: {:?}",
+                    remote.name
+                )));
+            }
+        }
+
+        if self.backend.transport == Transport::Nats {
+            if !cfg!(feature = "nats") {
+                return Err(ConfigError::Message(
+                    "backend.transport = \"nats\" needs an agent built with the nats feature"
+                        .to_string(),
+                ));
+            }
+            let nats = &self.nats;
+            if nats.url.is_empty() {
+                return Err(ConfigError::Message("nats.url cannot be empty".to_string()));
+            }
+            let valid_token = |token: &str| {
+                !token.is_empty()
+                    && !token.contains(|c: char| c.is_whitespace() || matches!(c, '*' | '>'))
+            };
+            if !nats.subject_prefix.split('.').all(valid_token) {
+                return Err(ConfigError::Message(format!(
+                    "Invalid nats.subject_prefix: {}",
+                    nats.subject_prefix
+                )));
+            }
+            if nats.jetstream && (!valid_token(&nats.stream) || nats.stream.contains('.')) {
+                return Err(ConfigError::Message(format!(
+                    "Invalid nats.stream name: {}",
+                    nats.stream
+                )));
+            }
+            if self.commands.enabled && !nats.jetstream {
+                return Err(ConfigError::Message(
+                    "commands.enabled with backend.transport = \"nats\" requires nats.jetstream"
+                        .to_string(),
+                ));
+            }
+        }
+
+        if self.backend.transport == Transport::Mqtt {
+            if !cfg!(feature = "mqtt") {
+                return Err(ConfigError::Message(
+                    "backend.transport = \"mqtt\" needs an agent built with the mqtt feature"
+                        .to_string(),
+                ));
+            }
+            let mqtt = &self.mqtt;
+            if !mqtt.url.starts_with("mqtt://") && !mqtt.url.starts_with("mqtts://") {
+                return Err(ConfigError::Message(format!(
+                    "mqtt.url must be an mqtt:// or mqtts:// URL, got {:?}",
+                    mqtt.url
+                )));
+            }
+            let valid_level = |level: &str| !level.is_empty() && !level.contains(['+', '#']);
+            if !mqtt.topic_prefix.split('/').all(valid_level) {
+                return Err(ConfigError::Message(format!(
+                    "Invalid mqtt.topic_prefix: {}",
+                    mqtt.topic_prefix
+                )));
+            }
+            if mqtt.cert_file.is_some() != mqtt.key_file.is_some() {
+                return Err(ConfigError::Message(
+                    "mqtt.cert_file and mqtt.key_file must be set together".to_string(),
+                ));
+            }
+            if mqtt.heartbeat_interval_seconds == 0 {
+                return Err(ConfigError::Message(
+                    "mqtt.heartbeat_interval_seconds must be greater than 0".to_string(),
+                ));
+            }
+            if self.commands.enabled {
+                return Err(ConfigError::Message(
+                    "commands.enabled is not available with backend.transport = \"mqtt\""
+                        .to_string(),
+                ));
+            }
+        }
+
+        if self.s3.enabled {
+            let s3 = &self.s3;
+            let valid_endpoint =
+                s3.endpoint.starts_with("https://") || s3.endpoint.starts_with("http://");
+            if !valid_endpoint || s3.bucket.is_empty() || s3.access_key_id.is_empty() {
+                return Err(ConfigError::Message(
+                    "s3.enabled requires an http(s) endpoint, bucket and access_key_id".to_string(),
+                ));
+            }
+        }
+        if self.backend.transport == Transport::S3 {
+            if !self.s3.enabled {
+                return Err(ConfigError::Message(
+                    "backend.transport = \"s3\" requires s3.enabled".to_string(),
+                ));
+            }
+            if self.commands.enabled {
+                return Err(ConfigError::Message(
+                    "commands.enabled is not available with backend.transport = \"s3\"".to_string(),
+                ));
+            }
+        }
+
+        if self.remote_write.enabled {
+            let url = &self.remote_write.url;
+            if !(url.starts_with("https://") || url.starts_with("http://")) {
+                return Err(ConfigError::Message(
+                    "remote_write.enabled requires an http(s) url".to_string(),
+                ));
+            }
+            if !self.metrics.enabled {
+                return Err(ConfigError::Message(
+                    "remote_write.enabled requires metrics.enabled".to_string(),
+                ));
+            }
+        }
+
+        if let Some(url) = &self.notifications.healthcheck_url {
+            if !(url.starts_with("https://") || url.starts_with("http://")) {
+                return Err(ConfigError::Message(
+                    "notifications.healthcheck_url must be an http(s) URL".to_string(),
+                ));
+            }
+        }
+        for webhook in &self.notifications.webhooks {
+            let valid_url =
+                webhook.url.starts_with("https://") || webhook.url.starts_with("http://");
+            if !valid_url
+                || !["generic", "slack", "teams", "ntfy"].contains(&webhook.format.as_str())
+            {
+                return Err(ConfigError::Message(
+                    "Invalid notifications.webhooks entry: needs an http(s) url and format \"generic\", \"slack\", \"teams\" or \"ntfy\"".to_string(),
+                ));
+            }
+        }
+
+        let templates = &self.notifications.templates;
+        let overridden = templates.dir.is_some()
+            || !templates.inline.is_empty()
+            || self
+                .notifications
+                .webhooks
+                .iter()
+                .any(|webhook| webhook.template.is_some());
+        if overridden && !cfg!(feature = "templates") {
+            return Err(ConfigError::Message(
+                "notifications.templates and webhook templates need an agent built with the templates feature"
+                    .to_string(),
+            ));
+        }
+        #[cfg(feature = "templates")]
+        for (name, source) in &templates.inline {
+            if let Err(e) = minijinja::Environment::new().template_from_str(source) {
+                return Err(ConfigError::Message(format!(
+                    "Invalid notifications.templates.inline.{}: {}",
+                    name, e
+                )));
+            }
+        }
+
+        for check in &self.guards.checks {
+            let valid = match (&check.path, check.command.is_empty()) {
+                (Some(_), true) => check.max_age_hours.is_some(),
+                (None, false) => check.timeout_seconds > 0,
+                _ => false,
+            };
+            if check.name.is_empty() || !valid {
+                return Err(ConfigError::Message(format!(
+                    "Invalid guard {:?}: needs a name and either command or path with max_age_hours",
+                    check.name
+                )));
+            }
+        }
+
+        if self.power.min_battery_percent > 100 {
+            return Err(ConfigError::Message(
+                "power.min_battery_percent must be at most 100".to_string(),
+            ));
+        }
+
+        if self.dbus.enabled && !cfg!(feature = "dbus") {
+            return Err(ConfigError::Message(
+                "dbus.enabled needs an agent built with the dbus feature".to_string(),
+            ));
+        }
+
+        if let Some(listen) = &self.local_api.listen {
+            let loopback = listen
+                .parse::<std::net::SocketAddr>()
+                .is_ok_and(|addr| addr.ip().is_loopback());
+            if !loopback {
+                return Err(ConfigError::Message(format!(
+                    "local_api.listen must be a loopback address with a port, got {:?}",
+                    listen
+                )));
+            }
+            if self.local_api.token_file.is_none() {
+                return Err(ConfigError::Message(
+                    "local_api.listen requires local_api.token_file".to_string(),
+                ));
+            }
+        }
+
+        if self.commands.poll_seconds == 0 {
+            return Err(ConfigError::Message(
+                "commands.poll_seconds must be greater than 0".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the TOML config file `load()` would pick up, if any.
+    pub fn find_config_file() -> Option<PathBuf> {
+        ["/etc/ubuntu-auto-update/agent.toml", "./agent.toml"]
+            .iter()
+            .map(PathBuf::from)
+            .find(|path| path.exists())
+    }
+
+    /// Adds or removes a package in `updates.excluded_packages` of a TOML
+    /// config file, keeping its comments and layout. Returns whether the
+    /// file changed.
+    pub fn set_package_excluded(path: &Path, package: &str, excluded: bool) -> Result<bool> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file: {:?}", path))?;
+        let mut document: toml_edit::DocumentMut = content
+            .parse()
+            .with_context(|| format!("Failed to parse config file: {:?}", path))?;
+
+        let updates = document["updates"]
+            .or_insert(toml_edit::table())
+            .as_table_like_mut()
+            .ok_or_else(|| anyhow::anyhow!("[updates] in {:?} is not a table", path))?;
+        let list = updates
+            .entry("excluded_packages")
+            .or_insert(toml_edit::value(toml_edit::Array::new()))
+            .as_array_mut()
+            .ok_or_else(|| {
+                anyhow::anyhow!("updates.excluded_packages in {:?} is not an array", path)
+            })?;
+
+        let position = list.iter().position(|v| v.as_str() == Some(package));
+        match (position, excluded) {
+            (None, true) => list.push(package),
+            (Some(index), false) => {
+                list.remove(index);
+            }
+            _ => return Ok(false),
+        }
+
+        std::fs::write(path, document.to_string())
+            .with_context(|| format!("Failed to write config file: {:?}", path))?;
+        Ok(true)
+    }
+
+    pub fn save_default_config(path: &str) -> Result<()> {
+        let config = AgentConfig::default();
+        let toml_string = toml::to_string(&config)?;
+        std::fs::write(path, toml_string)?;
+        Ok(())
+    }
+
+    pub fn load_from_file(path: &std::path::Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file: {:?}", path))?;
+        let builder = Config::builder().add_source(File::from_str(&content, FileFormat::Toml));
+        let mut config: AgentConfig = Self::add_drop_ins(builder, path)?
+            .build()
+            .and_then(Config::try_deserialize)
+            .with_context(|| format!("Failed to parse config file: {:?}", path))?;
+        config.validate()?;
+        config.loaded_from = std::iter::once(path.to_path_buf())
+            .chain(Self::drop_in_files(path)?)
+            .collect();
+        Ok(config)
+    }
+
+    /// Fragments in `<config>.d/*.toml`, in lexical order.
+    pub fn drop_in_files(config_path: &Path) -> Result<Vec<PathBuf>> {
+        let mut dir = config_path.as_os_str().to_owned();
+        dir.push(".d");
+        let dir = PathBuf::from(dir);
+        if !dir.is_dir() {
+            return Ok(vec![]);
+        }
+
+        let mut files: Vec<PathBuf> = std::fs::read_dir(&dir)
+            .with_context(|| format!("Failed to read drop-in directory {:?}", dir))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext == "toml"))
+            .collect();
+        files.sort();
+        Ok(files)
+    }
+
+    fn add_drop_ins(
+        mut builder: ConfigBuilder<DefaultState>,
+        config_path: &Path,
+    ) -> Result<ConfigBuilder<DefaultState>> {
+        for fragment in Self::drop_in_files(config_path)? {
+            tracing::info!("Loading configuration fragment from {:?}", fragment);
+            builder = builder.add_source(File::from(fragment).format(FileFormat::Toml));
+        }
+        Ok(builder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_config_validation() {
+        let config = AgentConfig::default();
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_config_serialization() {
+        let config = AgentConfig::default();
+        let serialized = toml::to_string(&config).unwrap();
+        let deserialized: AgentConfig = toml::from_str(&serialized).unwrap();
+
+        assert_eq!(config.backend.url, deserialized.backend.url);
+        assert_eq!(config.logging.level, deserialized.logging.level);
+    }
+
+    #[test]
+    fn test_backend_url_accepts_list() {
+        let mut value = toml::Value::try_from(AgentConfig::default()).unwrap();
+        let backend = value["backend"].as_table_mut().unwrap();
+        backend.insert(
+            "url".to_string(),
+            toml::Value::try_from(["https://primary.example.com", "https://standby.example.com"])
+                .unwrap(),
+        );
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("agent.toml");
+        std::fs::write(&path, toml::to_string(&value).unwrap()).unwrap();
+        let config = AgentConfig::load_from_file(&path).unwrap();
+
+        assert_eq!(config.backend.url.as_slice().len(), 2);
+        assert_eq!(
+            config.backend.url.to_string(),
+            "https://primary.example.com, https://standby.example.com"
+        );
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_state_section_is_optional() {
+        let mut value = toml::Value::try_from(AgentConfig::default()).unwrap();
+        value.as_table_mut().unwrap().remove("state");
+        let config: AgentConfig = value.try_into().unwrap();
+        assert_eq!(config.state.dir, StateConfig::default().dir);
+    }
+
+    #[test]
+    fn test_drop_in_fragments_merge_in_order() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("agent.toml");
+        std::fs::write(&path, toml::to_string(&AgentConfig::default()).unwrap()).unwrap();
+
+        let drop_in_dir = temp_dir.path().join("agent.toml.d");
+        std::fs::create_dir(&drop_in_dir).unwrap();
+        std::fs::write(
+            drop_in_dir.join("20-updates.toml"),
+            "[updates]\nauto_reboot = false\n",
+        )
+        .unwrap();
+        std::fs::write(
+            drop_in_dir.join("10-updates.toml"),
+            "[updates]\nauto_reboot = true\nexcluded_packages = [\"nginx\"]\n",
+        )
+        .unwrap();
+        std::fs::write(drop_in_dir.join("30-ignored.toml.bak"), "not toml").unwrap();
+
+        let config = AgentConfig::load_from_file(&path).unwrap();
+        assert!(!config.updates.auto_reboot);
+        assert_eq!(config.updates.excluded_packages, vec!["nginx"]);
+        assert_eq!(config.backend.url, AgentConfig::default().backend.url);
+    }
+
+    #[test]
+    fn test_set_package_excluded_preserves_comments() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("agent.toml");
+        std::fs::write(
+            &path,
+            "# managed by hand\n[updates]\ndry_run = false # keep\nexcluded_packages = []\n",
+        )
+        .unwrap();
+
+        assert!(AgentConfig::set_package_excluded(&path, "nginx", true).unwrap());
+        assert!(!AgentConfig::set_package_excluded(&path, "nginx", true).unwrap());
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(content.contains("# managed by hand"));
+        assert!(content.contains("dry_run = false # keep"));
+        assert!(content.contains("excluded_packages = [\"nginx\"]"));
+
+        assert!(AgentConfig::set_package_excluded(&path, "nginx", false).unwrap());
+        assert!(std::fs::read_to_string(&path)
+            .unwrap()
+            .contains("excluded_packages = []"));
+    }
+
+    #[test]
+    fn test_update_mode_parses_lowercase() {
+        let mut value = toml::Value::try_from(AgentConfig::default()).unwrap();
+        value["updates"].as_table_mut().unwrap().remove("mode");
+        let config: AgentConfig = value.clone().try_into().unwrap();
+        assert_eq!(config.updates.mode, UpdateMode::Manage);
+
+        value["updates"]
+            .as_table_mut()
+            .unwrap()
+            .insert("mode".to_string(), "observe".into());
+        let config: AgentConfig = value.clone().try_into().unwrap();
+        assert_eq!(config.updates.mode, UpdateMode::Observe);
+
+        value["updates"]["mode"] = toml::Value::String("audit".to_string());
+        assert!(value.try_into::<AgentConfig>().is_err());
+    }
+
+    #[test]
+    fn test_credentials_from_systemd() {
+        let mut security = AgentConfig::default().security;
+        let credentials_dir = Path::new("/run/credentials/ua-agent.service");
+        assert_eq!(
+            security.resolve_credential(&security.api_key_file, Some(credentials_dir)),
+            security.api_key_file
+        );
+
+        security.credentials_from_systemd = true;
+        assert_eq!(
+            security.resolve_credential(&security.api_key_file, Some(credentials_dir)),
+            credentials_dir.join("auth.token")
+        );
+        assert_eq!(
+            security.api_key_store_path(),
+            PathBuf::from("/etc/credstore/auth.token")
+        );
+    }
+
+    #[test]
     fn test_invalid_log_level() {
         let mut config = AgentConfig::default();
         config.logging.level = "invalid".to_string();
         assert!(config.validate().is_err());
     }
+
+    #[test]
+    fn test_libapt_backend_needs_feature() {
+        let mut config = AgentConfig::default();
+        config.updates.package_backend = PackageBackend::Libapt;
+        assert_eq!(config.validate().is_ok(), cfg!(feature = "libapt"));
+    }
+
+    #[test]
+    fn test_optional_features_need_their_build() {
+        let mut config = AgentConfig::default();
+        config.backend.transport = Transport::Mqtt;
+        assert_eq!(config.validate().is_ok(), cfg!(feature = "mqtt"));
+
+        let mut config = AgentConfig::default();
+        config.dbus.enabled = true;
+        assert_eq!(config.validate().is_ok(), cfg!(feature = "dbus"));
+
+        let mut config = AgentConfig::default();
+        config
+            .notifications
+            .templates
+            .inline
+            .insert("title".to_string(), "{{ hostname }}".to_string());
+        assert_eq!(config.validate().is_ok(), cfg!(feature = "templates"));
+    }
+
+    #[test]
+    fn test_empty_maintenance_window() {
+        let mut config = AgentConfig::default();
+        config.updates.maintenance_windows = vec!["Sat 02:00-02:00".to_string()];
+        assert!(config.validate().is_err());
+
+        // Still accepted in the legacy pair, which always allowed it
+        config.updates.maintenance_windows.clear();
+        config.updates.maintenance_window_start = Some("02:00".to_string());
+        config.updates.maintenance_window_end = Some("02:00".to_string());
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_minimal_profile_turns_off_metrics() {
+        let mut config = AgentConfig::default();
+        config.metrics.enabled = true;
+        config.metrics.textfile_path = Some(PathBuf::from("/var/lib/node_exporter"));
+        config.remote_write.enabled = true;
+        config.runtime.profile = RuntimeProfile::Minimal;
+        config.apply_profile();
+        assert!(!config.metrics.enabled);
+        assert!(!config.metrics.collect_system_metrics);
+        assert_eq!(config.metrics.textfile_path, None);
+        assert!(!config.remote_write.enabled);
+    }
 }
@@ -0,0 +1,233 @@
+use anyhow::{Context, Result};
+use reqwest::{Method, StatusCode};
+use serde::Serialize;
+use std::future::Future;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+use crate::config::CoordinationConfig;
+use crate::http_client::SecureHttpClient;
+
+#[derive(Debug, Serialize)]
+struct MaintenanceRequest<'a> {
+    agent: &'a str,
+    action: &'a str,
+    hostname: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum EnterOutcome {
+    /// The application acknowledged and is ready for updates
+    Entered,
+    /// The application asked us to come back later
+    Refused(String),
+    /// The endpoint could not be reached
+    Unreachable(String),
+}
+
+/// Talks to the local application's maintenance endpoints around an update run.
+pub struct AppCoordinator {
+    http_client: SecureHttpClient,
+    config: CoordinationConfig,
+}
+
+impl AppCoordinator {
+    /// Returns `None` when no enter endpoint is configured.
+    pub fn new(config: &CoordinationConfig, http_client: &SecureHttpClient) -> Option<Self> {
+        config.enter_url.as_ref()?;
+
+        Some(Self {
+            http_client: http_client.for_subsystem("coordination"),
+            config: config.clone(),
+        })
+    }
+
+    pub async fn enter(&self) -> EnterOutcome {
+        let Some(url) = &self.config.enter_url else {
+            return EnterOutcome::Entered;
+        };
+
+        info!("Asking application to enter maintenance mode: {}", url);
+        match self.notify(url, "enter").await {
+            Ok((status, body)) => classify_enter_response(status, &body),
+            Err(e) => EnterOutcome::Unreachable(e.to_string()),
+        }
+    }
+
+    pub async fn exit(&self) {
+        // Without a dedicated exit URL the enter endpoint is told to "exit"
+        let Some(url) = self
+            .config
+            .exit_url
+            .as_ref()
+            .or(self.config.enter_url.as_ref())
+        else {
+            return;
+        };
+
+        match self.notify(url, "exit").await {
+            Ok((status, _)) if status.is_success() => {
+                info!("Application left maintenance mode")
+            }
+            Ok((status, body)) => warn!(
+                "Application rejected maintenance exit: {} - {}",
+                status, body
+            ),
+            Err(e) => warn!("Failed to notify application of maintenance exit: {}", e),
+        }
+    }
+
+    /// Whether an unreachable endpoint should still let the run proceed.
+    pub fn proceed_if_unreachable(&self) -> bool {
+        self.config.proceed_if_unreachable
+    }
+
+    async fn notify(&self, url: &str, action: &str) -> Result<(StatusCode, String)> {
+        let request = MaintenanceRequest {
+            agent: "ubuntu-auto-update",
+            action,
+            hostname: crate::host::hostname(),
+        };
+
+        debug!("Sending maintenance {} request to: {}", action, url);
+        let request = self
+            .http_client
+            .external(Method::POST, url)
+            .timeout(Duration::from_secs(self.config.timeout_seconds))
+            .json(&request);
+        let response = self
+            .http_client
+            .send_external(request)
+            .await
+            .with_context(|| format!("Failed to reach {}", url))?;
+
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        Ok((status, body))
+    }
+}
+
+/// Runs `work` while the application is in maintenance mode and sends
+/// `exit` once it finishes, also when it returns early with an error.
+pub async fn in_maintenance<T>(
+    coordinator: Option<&AppCoordinator>,
+    work: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    let result = work.await;
+    if let Some(coordinator) = coordinator {
+        coordinator.exit().await;
+    }
+    result
+}
+
+fn classify_enter_response(status: StatusCode, body: &str) -> EnterOutcome {
+    if status.is_success() {
+        EnterOutcome::Entered
+    } else {
+        let reason = body.trim();
+        EnterOutcome::Refused(if reason.is_empty() {
+            status.to_string()
+        } else {
+            format!("{} - {}", status, reason)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AgentConfig;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_classify_enter_response() {
+        assert_eq!(
+            classify_enter_response(StatusCode::OK, ""),
+            EnterOutcome::Entered
+        );
+        assert_eq!(
+            classify_enter_response(StatusCode::CONFLICT, "transaction in progress\n"),
+            EnterOutcome::Refused("409 Conflict - transaction in progress".to_string())
+        );
+        assert_eq!(
+            classify_enter_response(StatusCode::SERVICE_UNAVAILABLE, ""),
+            EnterOutcome::Refused("503 Service Unavailable".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_enter_honors_refusal() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = socket.read(&mut buf).await;
+            let _ = socket
+                .write_all(b"HTTP/1.1 423 Locked\r\ncontent-length: 4\r\n\r\nbusy")
+                .await;
+        });
+
+        let config = CoordinationConfig {
+            enter_url: Some(format!("http://{}/maintenance/enter", addr)),
+            ..CoordinationConfig::default()
+        };
+        let http_client = SecureHttpClient::new(&AgentConfig::default()).unwrap();
+        let coordinator = AppCoordinator::new(&config, &http_client).unwrap();
+
+        assert_eq!(
+            coordinator.enter().await,
+            EnterOutcome::Refused("423 Locked - busy".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_exit_after_failed_work() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let received = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            // Headers and the JSON body may arrive in separate reads
+            while !request.ends_with(b"}") {
+                let read = socket.read(&mut buf).await.unwrap();
+                if read == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..read]);
+            }
+            let _ = socket
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                .await;
+            String::from_utf8_lossy(&request).into_owned()
+        });
+
+        let config = CoordinationConfig {
+            enter_url: Some(format!("http://{}/maintenance/enter", addr)),
+            exit_url: Some(format!("http://{}/maintenance/exit", addr)),
+            ..CoordinationConfig::default()
+        };
+        let http_client = SecureHttpClient::new(&AgentConfig::default()).unwrap();
+        let coordinator = AppCoordinator::new(&config, &http_client).unwrap();
+
+        let result: Result<()> = in_maintenance(Some(&coordinator), async {
+            Err(anyhow::anyhow!(
+                "Refusing to update without a rollback snapshot"
+            ))
+        })
+        .await;
+        assert!(result.is_err());
+
+        let request = received.await.unwrap();
+        assert!(request.starts_with("POST /maintenance/exit"));
+        assert!(request.contains(r#""action":"exit""#));
+    }
+
+    #[test]
+    fn test_disabled_without_enter_url() {
+        let http_client = SecureHttpClient::new(&AgentConfig::default()).unwrap();
+        assert!(AppCoordinator::new(&CoordinationConfig::default(), &http_client).is_none());
+    }
+}
@@ -0,0 +1,277 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::SystemTime;
+use tracing::{debug, warn};
+
+use crate::config::AgentConfig;
+use crate::rollback::command_exists;
+
+const KERN_LOG: &str = "/var/log/kern.log";
+
+/// Raw pstore mount, and where systemd-pstore archives its records
+const PSTORE_DIRS: &[&str] = &["/sys/fs/pstore", "/var/lib/systemd/pstore"];
+
+/// kern.log lines that start an oops, BUG or panic report
+const OOPS_MARKERS: &[&str] = &[
+    "Oops:",
+    "BUG:",
+    "kernel BUG at",
+    "general protection fault",
+    "Kernel panic - not syncing",
+];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct CrashCounts {
+    pub kernel_oopses: u64,
+    pub pstore_records: u64,
+    pub coredumps: u64,
+}
+
+impl CrashCounts {
+    fn add(&mut self, other: &CrashCounts) {
+        self.kernel_oopses += other.kernel_oopses;
+        self.pstore_records += other.pstore_records;
+        self.coredumps += other.coredumps;
+    }
+
+    pub fn any(&self) -> bool {
+        *self != CrashCounts::default()
+    }
+}
+
+/// Crash evidence found since the previous report.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CrashSummary {
+    pub detected: bool,
+    /// Start of the period covered; `None` on the first scan
+    pub since: Option<DateTime<Utc>>,
+    #[serde(flatten)]
+    pub counts: CrashCounts,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct MonitorState {
+    last_scan: Option<DateTime<Utc>>,
+    kern_log_inode: u64,
+    kern_log_offset: u64,
+    /// Found since the last report
+    unreported: CrashCounts,
+    unreported_since: Option<DateTime<Utc>>,
+    /// Everything found since the agent started monitoring, for the
+    /// Prometheus counters
+    totals: CrashCounts,
+}
+
+/// Watches kern.log, pstore and systemd-coredump for crashes so fleets can
+/// correlate instability with update batches. Only evidence newer than the
+/// first scan is counted.
+pub struct CrashMonitor {
+    path: PathBuf,
+}
+
+impl CrashMonitor {
+    pub fn new(config: &AgentConfig) -> Self {
+        Self {
+            path: config.state.dir.join("crash-monitor.json"),
+        }
+    }
+
+    /// Picks up new crash evidence and returns the running totals.
+    pub fn scan(&self) -> Result<CrashCounts> {
+        let mut state = self.load()?;
+        let now = Utc::now();
+
+        match state.last_scan {
+            Some(last_scan) => {
+                let found = CrashCounts {
+                    kernel_oopses: scan_kern_log(Path::new(KERN_LOG), &mut state)?,
+                    pstore_records: PSTORE_DIRS
+                        .iter()
+                        .map(|dir| count_pstore_records(Path::new(dir), last_scan))
+                        .sum(),
+                    coredumps: count_coredumps(last_scan),
+                };
+                if found.any() {
+                    warn!("Crash evidence since {}: {:?}", last_scan, found);
+                }
+                state.unreported.add(&found);
+                state.totals.add(&found);
+            }
+            None => {
+                // Baseline: skip whatever was logged before monitoring started
+                scan_kern_log(Path::new(KERN_LOG), &mut state)?;
+                state.unreported_since = Some(now);
+            }
+        }
+
+        state.last_scan = Some(now);
+        self.save(&state)?;
+        Ok(state.totals)
+    }
+
+    /// Scans, then returns and clears everything not yet reported.
+    pub fn take_unreported(&self) -> Result<CrashSummary> {
+        self.scan()?;
+        let mut state = self.load()?;
+
+        let summary = CrashSummary {
+            detected: state.unreported.any(),
+            since: state.unreported_since,
+            counts: state.unreported,
+        };
+        state.unreported = CrashCounts::default();
+        state.unreported_since = state.last_scan;
+        self.save(&state)?;
+        Ok(summary)
+    }
+
+    fn load(&self) -> Result<MonitorState> {
+        if !self.path.exists() {
+            return Ok(MonitorState::default());
+        }
+        let content = fs::read_to_string(&self.path)
+            .with_context(|| format!("Failed to read crash monitor state {:?}", self.path))?;
+        serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse crash monitor state {:?}", self.path))
+    }
+
+    fn save(&self, state: &MonitorState) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory: {:?}", parent))?;
+        }
+        fs::write(&self.path, serde_json::to_string_pretty(state)?)
+            .with_context(|| format!("Failed to write crash monitor state {:?}", self.path))
+    }
+}
+
+/// Counts oops markers appended to kern.log since the saved offset,
+/// starting over when the log was rotated.
+fn scan_kern_log(path: &Path, state: &mut MonitorState) -> Result<u64> {
+    let Ok(mut file) = fs::File::open(path) else {
+        debug!("{:?} not available, skipping oops scan", path);
+        return Ok(0);
+    };
+    let metadata = file.metadata()?;
+
+    if metadata.ino() != state.kern_log_inode || metadata.len() < state.kern_log_offset {
+        state.kern_log_inode = metadata.ino();
+        state.kern_log_offset = 0;
+    }
+
+    file.seek(SeekFrom::Start(state.kern_log_offset))?;
+    let mut appended = Vec::new();
+    file.read_to_end(&mut appended)
+        .with_context(|| format!("Failed to read {:?}", path))?;
+    state.kern_log_offset += appended.len() as u64;
+
+    Ok(count_oops_markers(&String::from_utf8_lossy(&appended)))
+}
+
+fn count_oops_markers(log: &str) -> u64 {
+    log.lines()
+        .filter(|line| OOPS_MARKERS.iter().any(|marker| line.contains(marker)))
+        .count() as u64
+}
+
+/// Counts dmesg records newer than `since`, including one level of
+/// systemd-pstore's per-crash subdirectories.
+fn count_pstore_records(dir: &Path, since: DateTime<Utc>) -> u64 {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return 0;
+    };
+    let since = SystemTime::from(since);
+
+    entries
+        .flatten()
+        .map(|entry| {
+            let path = entry.path();
+            if path.is_dir() {
+                count_pstore_records(&path, since.into())
+            } else {
+                let is_new = entry
+                    .metadata()
+                    .and_then(|metadata| metadata.modified())
+                    .is_ok_and(|modified| modified > since);
+                let is_dmesg = entry.file_name().to_string_lossy().starts_with("dmesg-");
+                u64::from(is_new && is_dmesg)
+            }
+        })
+        .sum()
+}
+
+fn count_coredumps(since: DateTime<Utc>) -> u64 {
+    if !command_exists("coredumpctl") {
+        return 0;
+    }
+    // Exits non-zero when there are no matches
+    crate::audit::output(Command::new("coredumpctl").args([
+        "list",
+        "--no-legend",
+        "--no-pager",
+        &format!("--since=@{}", since.timestamp()),
+    ]))
+    .map(|output| {
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .count() as u64
+    })
+    .unwrap_or_else(|e| {
+        debug!("Failed to run coredumpctl: {}", e);
+        0
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_count_oops_markers() {
+        let log = "\
+Oct 16 02:14:01 web01 kernel: [  12.3] BUG: unable to handle page fault for address: 0000000000001000
+Oct 16 02:14:01 web01 kernel: [  12.3] #PF: supervisor read access in kernel mode
+Oct 16 02:14:01 web01 kernel: [  12.3] Oops: 0000 [#1] PREEMPT SMP NOPTI
+Oct 16 02:15:44 web01 kernel: [ 115.0] e1000e: eth0 NIC Link is Up
+";
+        assert_eq!(count_oops_markers(log), 2);
+        assert_eq!(count_oops_markers(""), 0);
+    }
+
+    #[test]
+    fn test_kern_log_scan_resumes_and_handles_rotation() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("kern.log");
+        fs::write(&path, "kernel: Oops: 0002 [#1] SMP\n").unwrap();
+
+        let mut state = MonitorState::default();
+        assert_eq!(scan_kern_log(&path, &mut state).unwrap(), 1);
+        assert_eq!(scan_kern_log(&path, &mut state).unwrap(), 0);
+
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        writeln!(
+            file,
+            "kernel: general protection fault, probably for non-canonical address"
+        )
+        .unwrap();
+        assert_eq!(scan_kern_log(&path, &mut state).unwrap(), 1);
+
+        // logrotate replaces the file; the new one is read from the start
+        fs::remove_file(&path).unwrap();
+        fs::write(
+            &path,
+            "kernel: Kernel panic - not syncing: Fatal exception\n",
+        )
+        .unwrap();
+        assert_eq!(scan_kern_log(&path, &mut state).unwrap(), 1);
+    }
+}
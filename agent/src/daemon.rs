@@ -0,0 +1,452 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+use tracing::{debug, error, info, warn};
+
+use crate::beacon::BeaconManager;
+use crate::commands::{dispatch, AckStatus, AgentCommand, CommandChannel, VerifiedCommand};
+use crate::config::AgentConfig;
+#[cfg(feature = "mqtt")]
+use crate::config::Transport;
+use crate::diskspace::DiskSpaceMonitor;
+use crate::history::HistoryStore;
+use crate::http_client::SecureHttpClient;
+use crate::local_api::{LocalApi, LocalRequest};
+use crate::updater::UpdateManager;
+use crate::ConfigSource;
+
+/// Editors save through rename/truncate bursts; wait for them to settle.
+const RELOAD_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Long-running mode: runs an update cycle every `daemon.interval_minutes`
+/// once the maintenance window allows, reloads the configuration on SIGHUP
+/// or when the config file changes, carries out operator commands when
+/// `commands.enabled` is set and serves the local API when
+/// `local_api.enabled` is set and the D-Bus API when `dbus.enabled` is set.
+pub struct Daemon {
+    source: ConfigSource,
+    config: AgentConfig,
+    /// Covers runs that failed before anything was written to the history
+    last_attempt: Option<DateTime<Utc>>,
+    /// When the due run may start, picked once it falls due
+    splay_until: Option<DateTime<Utc>>,
+    /// Set while serving the D-Bus API, to signal finished runs
+    #[cfg(feature = "dbus")]
+    dbus: Option<zbus::Connection>,
+    /// Backend settings only change on restart, so this outlives reloads
+    http_client: SecureHttpClient,
+}
+
+impl Daemon {
+    pub fn new(source: ConfigSource, config: AgentConfig, http_client: SecureHttpClient) -> Self {
+        Self {
+            source,
+            config,
+            http_client,
+            last_attempt: None,
+            splay_until: None,
+            #[cfg(feature = "dbus")]
+            dbus: None,
+        }
+    }
+
+    pub async fn run(mut self) -> Result<()> {
+        let mut sighup = signal(SignalKind::hangup()).context("Failed to listen for SIGHUP")?;
+        let mut sigterm =
+            signal(SignalKind::terminate()).context("Failed to listen for SIGTERM")?;
+
+        let (changed_tx, mut changed_rx) = mpsc::unbounded_channel();
+        let _watcher = match self.source.watch_path() {
+            Some(path) if self.config.daemon.watch_config => watch_config_file(&path, changed_tx)
+                .map_err(|e| warn!("Not watching {:?} for changes: {:#}", path, e))
+                .ok(),
+            _ => None,
+        };
+
+        let (command_tx, mut command_rx) = mpsc::unbounded_channel();
+        let command_channel = if self.config.commands.enabled {
+            match CommandChannel::new(&self.config, &self.http_client).await {
+                Ok(channel) => {
+                    tokio::spawn(channel.clone().run(command_tx));
+                    info!("Polling the backend for operator commands");
+                    Some(channel)
+                }
+                Err(e) => {
+                    warn!("Not polling for operator commands: {:#}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        #[cfg(feature = "mqtt")]
+        if self.config.backend.transport == Transport::Mqtt {
+            tokio::spawn(crate::mqtt::keep_available(self.config.clone()));
+        }
+
+        let (local_tx, mut local_rx) = mpsc::unbounded_channel();
+        #[cfg(feature = "dbus")]
+        if self.config.dbus.enabled {
+            match crate::dbus::serve(&self.config, local_tx.clone()).await {
+                Ok(connection) => self.dbus = Some(connection),
+                Err(e) => warn!("Not serving the D-Bus API: {:#}", e),
+            }
+        }
+        if self.config.local_api.enabled {
+            if let Err(e) = LocalApi::new(&self.config, local_tx).and_then(LocalApi::spawn) {
+                warn!("Not serving the local API: {:#}", e);
+            }
+        }
+
+        let mut disk_space = DiskSpaceMonitor::new(&self.http_client);
+
+        info!(
+            "Daemon started, running updates every {} minutes",
+            self.config.daemon.interval_minutes
+        );
+
+        loop {
+            crate::panics::set_phase("daemon");
+            if self.run_is_due() {
+                self.run_if_in_window().await;
+            }
+            if let Err(e) = BeaconManager::new(&self.config)
+                .send_if_due(&self.config, &self.http_client)
+                .await
+            {
+                warn!("Failed to send health beacon: {:#}", e);
+            }
+            if self.config.disk_space.enabled {
+                disk_space.check(&self.config).await;
+            }
+
+            let check_interval = Duration::from_secs(self.config.daemon.check_interval_seconds);
+            tokio::select! {
+                _ = tokio::time::sleep(check_interval) => {}
+                _ = sighup.recv() => {
+                    info!("Received SIGHUP, reloading configuration");
+                    self.reload();
+                }
+                Some(()) = changed_rx.recv() => {
+                    tokio::time::sleep(RELOAD_DEBOUNCE).await;
+                    while changed_rx.try_recv().is_ok() {}
+                    info!("Configuration file changed, reloading");
+                    self.reload();
+                }
+                Some(command) = command_rx.recv() => {
+                    if let Some(channel) = &command_channel {
+                        self.handle_command(channel, command).await;
+                    }
+                }
+                Some(LocalRequest::Run { force }) = local_rx.recv() => {
+                    info!("Update run requested locally (force: {})", force);
+                    self.last_attempt = Some(Utc::now());
+                    let result = crate::run_updates(&self.config, force).await;
+                    if let Err(e) = &result {
+                        error!("Requested update run failed: {:#}", e);
+                    }
+                    self.run_completed(result.is_ok()).await;
+                }
+                _ = sigterm.recv() => {
+                    info!("Received SIGTERM, stopping daemon");
+                    return Ok(());
+                }
+                _ = tokio::signal::ctrl_c() => {
+                    info!("Interrupted, stopping daemon");
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Whether the last run is older than the run interval.
+    fn run_is_due(&self) -> bool {
+        let interval = chrono::Duration::minutes(self.config.daemon.interval_minutes as i64);
+        let last_recorded = match HistoryStore::new(&self.config).last() {
+            Ok(last) => last.map(|record| record.timestamp),
+            Err(e) => {
+                warn!("Failed to read run history: {}", e);
+                None
+            }
+        };
+
+        match last_recorded.max(self.last_attempt) {
+            Some(last) => Utc::now() - last >= interval,
+            None => true,
+        }
+    }
+
+    async fn run_if_in_window(&mut self) {
+        match UpdateManager::new(self.config.clone()) {
+            Ok(manager) if !manager.is_in_maintenance_window() => {
+                debug!("Run pending, waiting for the maintenance window");
+                return;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                error!("Failed to initialize update manager: {}", e);
+                return;
+            }
+        }
+
+        let splay_until = match self.splay_until {
+            Some(splay_until) => splay_until,
+            None => {
+                let delay = crate::schedule::splay(&self.config);
+                if !delay.is_zero() {
+                    info!("Splaying the run, starting in {}s", delay.as_secs());
+                }
+                let splay_until =
+                    Utc::now() + chrono::Duration::from_std(delay).unwrap_or_default();
+                self.splay_until = Some(splay_until);
+                splay_until
+            }
+        };
+        if Utc::now() < splay_until {
+            return;
+        }
+        self.splay_until = None;
+
+        self.last_attempt = Some(Utc::now());
+        let result = crate::run_updates(&self.config, false).await;
+        if let Err(e) = &result {
+            error!("Scheduled update run failed: {:#}", e);
+        }
+        self.run_completed(result.is_ok()).await;
+    }
+
+    async fn handle_command(&mut self, channel: &CommandChannel, command: VerifiedCommand) {
+        info!("Carrying out command {}: {:?}", command.id, command.command);
+        channel.ack(&command.id, AckStatus::Accepted, None).await;
+
+        if command.command == AgentCommand::RunNow {
+            self.last_attempt = Some(Utc::now());
+        }
+        let config_path = self.source.watch_path();
+        let (status, message) =
+            match dispatch(&self.config, config_path.as_deref(), &command.command).await {
+                Ok(message) => (AckStatus::Succeeded, message),
+                Err(e) => {
+                    error!("Command {} failed: {:#}", command.id, e);
+                    (AckStatus::Failed, format!("{:#}", e))
+                }
+            };
+        channel.ack(&command.id, status, Some(&message)).await;
+        if command.command == AgentCommand::RunNow {
+            self.run_completed(status == AckStatus::Succeeded).await;
+        }
+    }
+
+    async fn run_completed(
+        &self,
+        #[cfg_attr(not(feature = "dbus"), allow(unused_variables))] success: bool,
+    ) {
+        #[cfg(feature = "dbus")]
+        if let Some(connection) = &self.dbus {
+            crate::dbus::run_completed(connection, &self.config, success).await;
+        }
+    }
+
+    fn reload(&mut self) {
+        match self.source.reload() {
+            Ok(mut new) => {
+                // Same path fallbacks as at startup, so they don't read as changes
+                crate::sandbox::adapt(&mut new);
+                self.config = apply_reload(&self.config, new)
+            }
+            Err(e) => warn!("Keeping current configuration, reload failed: {:#}", e),
+        }
+    }
+}
+
+/// Takes the reloaded configuration, except for settings that only take
+/// effect at startup, which keep their current values until a restart.
+fn apply_reload(current: &AgentConfig, mut new: AgentConfig) -> AgentConfig {
+    let restart_only = [
+        ("backend", section_changed(&current.backend, &new.backend)),
+        (
+            "security",
+            section_changed(&current.security, &new.security),
+        ),
+        (
+            "enrollment",
+            section_changed(&current.enrollment, &new.enrollment),
+        ),
+        ("metrics", section_changed(&current.metrics, &new.metrics)),
+        ("state", section_changed(&current.state, &new.state)),
+        (
+            "commands",
+            section_changed(&current.commands, &new.commands),
+        ),
+        (
+            "telemetry",
+            section_changed(&current.telemetry, &new.telemetry),
+        ),
+        ("nats", section_changed(&current.nats, &new.nats)),
+        ("mqtt", section_changed(&current.mqtt, &new.mqtt)),
+        (
+            "local_api",
+            section_changed(&current.local_api, &new.local_api),
+        ),
+        ("dbus", section_changed(&current.dbus, &new.dbus)),
+        ("runtime", section_changed(&current.runtime, &new.runtime)),
+        ("audit", section_changed(&current.audit, &new.audit)),
+        (
+            "log_shipping",
+            section_changed(&current.log_shipping, &new.log_shipping),
+        ),
+        (
+            "logging.format/file",
+            current.logging.format != new.logging.format
+                || current.logging.file != new.logging.file,
+        ),
+    ];
+    for (section, changed) in restart_only {
+        if changed {
+            warn!("Changes to [{}] take effect after a restart", section);
+        }
+    }
+
+    new.backend = current.backend.clone();
+    new.security = current.security.clone();
+    new.enrollment = current.enrollment.clone();
+    new.metrics = current.metrics.clone();
+    new.state = current.state.clone();
+    new.commands = current.commands.clone();
+    new.telemetry = current.telemetry.clone();
+    new.nats = current.nats.clone();
+    new.mqtt = current.mqtt.clone();
+    new.local_api = current.local_api.clone();
+    new.dbus = current.dbus.clone();
+    new.runtime = current.runtime.clone();
+    new.audit = current.audit.clone();
+    new.log_shipping = current.log_shipping.clone();
+    new.logging.format = current.logging.format.clone();
+    new.logging.file = current.logging.file.clone();
+
+    if new.logging.level != current.logging.level {
+        if let Err(e) = crate::logging::set_log_level(&new.logging.level) {
+            warn!("Failed to apply log level {}: {}", new.logging.level, e);
+        }
+    }
+    if new.updates.maintenance_window_start != current.updates.maintenance_window_start
+        || new.updates.maintenance_window_end != current.updates.maintenance_window_end
+        || new.updates.maintenance_windows != current.updates.maintenance_windows
+    {
+        if new.updates.maintenance_windows.is_empty() {
+            info!(
+                "Maintenance window now {:?} - {:?}",
+                new.updates.maintenance_window_start, new.updates.maintenance_window_end
+            );
+        } else {
+            info!(
+                "Maintenance windows now {}",
+                new.updates.maintenance_windows.join(", ")
+            );
+        }
+    }
+
+    info!("Configuration reloaded");
+    new
+}
+
+fn section_changed<T: serde::Serialize>(current: &T, new: &T) -> bool {
+    serde_json::to_value(current).ok() != serde_json::to_value(new).ok()
+}
+
+/// Watches the config file's directory, since editors and config management
+/// tools usually replace the file rather than write it in place, plus its
+/// `.d` drop-in directory.
+fn watch_config_file(
+    path: &Path,
+    changed: mpsc::UnboundedSender<()>,
+) -> Result<RecommendedWatcher> {
+    let file_name = path
+        .file_name()
+        .with_context(|| format!("Invalid config path: {:?}", path))?
+        .to_owned();
+    let dir: PathBuf = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."))
+        .to_path_buf();
+    let mut drop_in_dir = path.as_os_str().to_owned();
+    drop_in_dir.push(".d");
+    let drop_in_dir = PathBuf::from(drop_in_dir);
+
+    let fragments = drop_in_dir.clone();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if let Ok(event) = event {
+            let relevant =
+                event.kind.is_modify() || event.kind.is_create() || event.kind.is_remove();
+            if relevant
+                && event.paths.iter().any(|p| {
+                    p.file_name() == Some(file_name.as_os_str()) || p.parent() == Some(&fragments)
+                })
+            {
+                let _ = changed.send(());
+            }
+        }
+    })
+    .context("Failed to create config file watcher")?;
+
+    watcher
+        .watch(&dir, RecursiveMode::NonRecursive)
+        .with_context(|| format!("Failed to watch {:?}", dir))?;
+    if drop_in_dir.is_dir() {
+        watcher
+            .watch(&drop_in_dir, RecursiveMode::NonRecursive)
+            .with_context(|| format!("Failed to watch {:?}", drop_in_dir))?;
+    }
+    debug!("Watching {:?} for configuration changes", path);
+    Ok(watcher)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reload_keeps_restart_only_settings() {
+        let current = AgentConfig::default();
+        let mut new = AgentConfig::default();
+        new.backend.url = "https://elsewhere.example.com".into();
+        new.logging.format = "text".to_string();
+        new.updates.maintenance_window_start = Some("01:00".to_string());
+        new.updates.maintenance_window_end = Some("03:00".to_string());
+        new.updates.excluded_packages = vec!["postgresql-16".to_string()];
+
+        let applied = apply_reload(&current, new);
+
+        assert_eq!(applied.backend.url, current.backend.url);
+        assert_eq!(applied.logging.format, current.logging.format);
+        assert_eq!(
+            applied.updates.maintenance_window_start.as_deref(),
+            Some("01:00")
+        );
+        assert_eq!(applied.updates.excluded_packages, vec!["postgresql-16"]);
+    }
+
+    #[tokio::test]
+    async fn test_watcher_reports_config_changes() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("agent.toml");
+        std::fs::write(&path, "").unwrap();
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let _watcher = watch_config_file(&path, tx).unwrap();
+
+        std::fs::write(temp_dir.path().join("other.toml"), "").unwrap();
+        std::fs::write(&path, "[updates]\n").unwrap();
+
+        tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .expect("config change was not reported")
+            .unwrap();
+    }
+}
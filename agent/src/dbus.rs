@@ -0,0 +1,98 @@
+use anyhow::{Context, Result};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+use zbus::object_server::SignalEmitter;
+use zbus::{fdo, interface, Connection};
+
+use crate::config::AgentConfig;
+use crate::history::HistoryStore;
+use crate::local_api::LocalRequest;
+use crate::updater::UpdateManager;
+
+pub const BUS_NAME: &str = "org.ubuntuautoupdate.Agent1";
+
+pub const OBJECT_PATH: &str = "/org/ubuntuautoupdate/Agent1";
+
+/// The daemon's D-Bus object on the system bus, for GNOME shell extensions
+/// and kiosk UIs. Who may call what is up to the bus policy shipped in
+/// `dbus/org.ubuntuautoupdate.Agent1.conf`.
+struct Agent1 {
+    config: AgentConfig,
+    requests: mpsc::UnboundedSender<LocalRequest>,
+}
+
+#[interface(name = "org.ubuntuautoupdate.Agent1")]
+impl Agent1 {
+    /// Same JSON as `status --json`.
+    async fn get_status(&self) -> fdo::Result<String> {
+        let status = crate::agent_status(&self.config).map_err(failed)?;
+        serde_json::to_string(&status).map_err(|e| fdo::Error::Failed(e.to_string()))
+    }
+
+    /// Pending apt, snap and flatpak updates as JSON, like `list-updates
+    /// --json`, from the existing apt cache.
+    async fn pending_updates(&self) -> fdo::Result<String> {
+        let pending = UpdateManager::new(self.config.clone())
+            .map_err(failed)?
+            .list_pending_updates(false)
+            .await
+            .map_err(failed)?;
+        serde_json::to_string(&pending).map_err(|e| fdo::Error::Failed(e.to_string()))
+    }
+
+    /// Queues a run on the daemon loop; `RunCompleted` follows when it ends.
+    async fn run_now(&self, force: bool) -> fdo::Result<()> {
+        self.requests
+            .send(LocalRequest::Run { force })
+            .map_err(|_| fdo::Error::Failed("The daemon is shutting down".to_string()))
+    }
+
+    #[zbus(signal)]
+    async fn run_completed(
+        emitter: &SignalEmitter<'_>,
+        success: bool,
+        packages_updated: u64,
+        reboot_required: bool,
+    ) -> zbus::Result<()>;
+}
+
+fn failed(e: anyhow::Error) -> fdo::Error {
+    fdo::Error::Failed(format!("{:#}", e))
+}
+
+/// Claims `BUS_NAME` on the system bus and serves the agent object. Runs
+/// requested over D-Bus go through `requests`, like the local API's.
+pub async fn serve(
+    config: &AgentConfig,
+    requests: mpsc::UnboundedSender<LocalRequest>,
+) -> Result<Connection> {
+    let agent = Agent1 {
+        config: config.clone(),
+        requests,
+    };
+    let connection = zbus::connection::Builder::system()
+        .context("Failed to connect to the system bus")?
+        .name(BUS_NAME)?
+        .serve_at(OBJECT_PATH, agent)?
+        .build()
+        .await
+        .with_context(|| format!("Failed to claim {} on the system bus", BUS_NAME))?;
+    info!("Serving {} on the system bus", BUS_NAME);
+    Ok(connection)
+}
+
+/// Emits `RunCompleted` for the run the daemon just finished, with the
+/// counts it recorded in the history.
+pub async fn run_completed(connection: &Connection, config: &AgentConfig, success: bool) {
+    let (packages_updated, reboot_required) = match HistoryStore::new(config).last() {
+        Ok(Some(record)) => (record.packages_updated, record.reboot_required),
+        _ => (0, false),
+    };
+    let emitted = async {
+        let emitter = SignalEmitter::new(connection, OBJECT_PATH)?;
+        Agent1::run_completed(&emitter, success, packages_updated, reboot_required).await
+    };
+    if let Err(e) = emitted.await {
+        warn!("Failed to emit RunCompleted on D-Bus: {}", e);
+    }
+}
@@ -0,0 +1,101 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// apt's archive cache when `updates.apt_cache_dir` isn't set.
+pub const DEFAULT_ARCHIVES: &str = "/var/cache/apt/archives";
+
+/// What `debdelta-upgrade` saved before the apt upgrade.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DeltaSavings {
+    /// .debs rebuilt from deltas into the archive cache
+    pub debs: u64,
+    pub deb_bytes: u64,
+    /// Size of the deltas downloaded; `None` when debdelta's statistics
+    /// couldn't be read
+    pub delta_bytes: Option<u64>,
+    pub bytes_saved: Option<u64>,
+}
+
+/// Sizes of the .debs in an archive cache directory.
+pub fn cached_debs(dir: &Path) -> HashMap<PathBuf, u64> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return HashMap::new();
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "deb"))
+        .filter_map(|path| {
+            let size = path.metadata().ok()?.len();
+            Some((path, size))
+        })
+        .collect()
+}
+
+/// Compares the archive cache before and after `debdelta-upgrade`; the .debs
+/// that appeared were rebuilt from deltas.
+pub fn savings(
+    before: &HashMap<PathBuf, u64>,
+    after: &HashMap<PathBuf, u64>,
+    output: &str,
+) -> DeltaSavings {
+    let rebuilt: Vec<u64> = after
+        .iter()
+        .filter(|(path, _)| !before.contains_key(*path))
+        .map(|(_, size)| *size)
+        .collect();
+    let deb_bytes = rebuilt.iter().sum();
+    let delta_bytes = parse_delta_bytes(output);
+    DeltaSavings {
+        debs: rebuilt.len() as u64,
+        deb_bytes,
+        delta_bytes,
+        bytes_saved: delta_bytes.map(|delta_bytes| deb_bytes.saturating_sub(delta_bytes)),
+    }
+}
+
+/// Reads the downloaded delta size from debdelta-upgrade's closing
+/// statistics, e.g. " downloaded deltas, size 2.1MB time 8sec ...".
+fn parse_delta_bytes(output: &str) -> Option<u64> {
+    let re = Regex::new(r"deltas, size ([0-9.]+)\s*([kMG]?B)").ok()?;
+    let captures = re.captures(output)?;
+    let size: f64 = captures.get(1)?.as_str().parse().ok()?;
+    let multiplier = match captures.get(2)?.as_str() {
+        "kB" => 1_000,
+        "MB" => 1_000_000,
+        "GB" => 1_000_000_000,
+        _ => 1,
+    };
+    Some((size * multiplier as f64) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_savings() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        std::fs::write(temp_dir.path().join("old_1.0_amd64.deb"), vec![0; 100]).unwrap();
+        std::fs::write(temp_dir.path().join("lock"), "").unwrap();
+        let before = cached_debs(temp_dir.path());
+        assert_eq!(before.len(), 1);
+
+        std::fs::write(temp_dir.path().join("libc6_2.39_amd64.deb"), vec![0; 3_000]).unwrap();
+        std::fs::write(temp_dir.path().join("bash_5.2_amd64.deb"), vec![0; 2_000]).unwrap();
+        let after = cached_debs(temp_dir.path());
+
+        let output = "Delta-upgrade statistics:\n \
+                      downloaded deltas, size 1.2kB time 2sec speed 600B/sec\n \
+                      total resulting debs, size 5kB time 3sec virtual speed 1.6kB/sec\n";
+        let savings = savings(&before, &after, output);
+        assert_eq!(savings.debs, 2);
+        assert_eq!(savings.deb_bytes, 5_000);
+        assert_eq!(savings.delta_bytes, Some(1_200));
+        assert_eq!(savings.bytes_saved, Some(3_800));
+
+        assert_eq!(super::savings(&before, &after, "").bytes_saved, None);
+    }
+}
@@ -0,0 +1,192 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+use crate::config::AgentConfig;
+
+/// Longest window `debug enable` accepts, so a forgotten one can't keep a
+/// device logging at trace level for days
+pub const MAX_MINUTES: u32 = 24 * 60;
+
+/// How often running agents look for a window starting or ending
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Whether backend requests are logged in full; see `http_client`
+static HTTP_CAPTURE: AtomicBool = AtomicBool::new(false);
+
+/// A temporary raise of the log level for field debugging, reverted
+/// automatically at `until`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DebugWindow {
+    pub enabled_at: DateTime<Utc>,
+    pub until: DateTime<Utc>,
+    /// Level in force until then, e.g. "debug" or "trace"
+    pub level: String,
+    /// Also log backend requests and responses, credentials redacted
+    pub http_capture: bool,
+}
+
+impl DebugWindow {
+    pub fn describe(&self) -> String {
+        format!(
+            "logging at {}{} until {}",
+            self.level,
+            if self.http_capture {
+                " with HTTP capture"
+            } else {
+                ""
+            },
+            self.until.to_rfc3339()
+        )
+    }
+}
+
+/// Keeps the debug window in the state directory, where the daemon and
+/// every later run pick it up, like the pause marker.
+pub struct DebugWindowManager {
+    path: PathBuf,
+}
+
+impl DebugWindowManager {
+    pub fn new(config: &AgentConfig) -> Self {
+        Self {
+            path: config.state.dir.join("debug.json"),
+        }
+    }
+
+    /// Returns the open window, clearing it once it has ended.
+    pub fn load(&self) -> Result<Option<DebugWindow>> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        let content = fs::read_to_string(&self.path)
+            .with_context(|| format!("Failed to read debug window from {:?}", self.path))?;
+        let window: DebugWindow = serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse debug window in {:?}", self.path))?;
+
+        if Utc::now() < window.until {
+            Ok(Some(window))
+        } else {
+            debug!("Debug window ended");
+            self.disable()?;
+            Ok(None)
+        }
+    }
+
+    /// Opens a window of `minutes`, replacing any open one.
+    pub fn enable(&self, minutes: u32, level: &str, http_capture: bool) -> Result<DebugWindow> {
+        if minutes == 0 || minutes > MAX_MINUTES {
+            return Err(anyhow::anyhow!(
+                "Debug windows last 1 to {} minutes, got {}",
+                MAX_MINUTES,
+                minutes
+            ));
+        }
+        crate::logging::parse_log_level(level)?;
+        let enabled_at = Utc::now();
+        let window = DebugWindow {
+            enabled_at,
+            until: enabled_at + chrono::Duration::minutes(minutes.into()),
+            level: level.to_lowercase(),
+            http_capture,
+        };
+
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory: {:?}", parent))?;
+        }
+        fs::write(&self.path, serde_json::to_string_pretty(&window)?)
+            .with_context(|| format!("Failed to write debug window to {:?}", self.path))?;
+        Ok(window)
+    }
+
+    /// Closes the window early. Returns whether one was open.
+    pub fn disable(&self) -> Result<bool> {
+        if !self.path.exists() {
+            return Ok(false);
+        }
+        fs::remove_file(&self.path)
+            .with_context(|| format!("Failed to remove debug window {:?}", self.path))?;
+        Ok(true)
+    }
+}
+
+pub fn http_capture() -> bool {
+    HTTP_CAPTURE.load(Ordering::Relaxed)
+}
+
+/// Applies the debug window now and then every `CHECK_INTERVAL` for the
+/// life of the process, so a window opened by `debug enable` or an
+/// operator command reaches a running daemon, and one that ends mid-run
+/// reverts without waiting for the run to finish.
+pub fn watch(config: &AgentConfig) {
+    let manager = DebugWindowManager::new(config);
+    let mut applied = apply(&manager, None);
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(CHECK_INTERVAL).await;
+            applied = apply(&manager, applied);
+        }
+    });
+}
+
+fn apply(manager: &DebugWindowManager, applied: Option<DebugWindow>) -> Option<DebugWindow> {
+    let window = match manager.load() {
+        Ok(window) => window,
+        Err(e) => {
+            warn!("Failed to read debug window: {:#}", e);
+            return applied;
+        }
+    };
+    if window == applied {
+        return applied;
+    }
+
+    HTTP_CAPTURE.store(
+        window.as_ref().is_some_and(|window| window.http_capture),
+        Ordering::Relaxed,
+    );
+    let level = window.as_ref().map(|window| window.level.as_str());
+    if let Err(e) = crate::logging::override_log_level(level) {
+        warn!("Failed to apply debug window: {:#}", e);
+    }
+    match &window {
+        Some(window) => info!("Debug window open, {}", window.describe()),
+        None => info!("Debug window closed"),
+    }
+    window
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window_expires() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut config = AgentConfig::default();
+        config.state.dir = temp_dir.path().to_path_buf();
+        let manager = DebugWindowManager::new(&config);
+
+        assert!(manager.enable(0, "trace", false).is_err());
+        assert!(manager.enable(MAX_MINUTES + 1, "trace", false).is_err());
+        assert!(manager.enable(30, "verbose", false).is_err());
+
+        let window = manager.enable(30, "TRACE", true).unwrap();
+        assert_eq!(window.level, "trace");
+        assert_eq!(manager.load().unwrap(), Some(window.clone()));
+
+        let ended = DebugWindow {
+            until: Utc::now() - chrono::Duration::minutes(1),
+            ..window
+        };
+        fs::write(&manager.path, serde_json::to_string(&ended).unwrap()).unwrap();
+        assert_eq!(manager.load().unwrap(), None);
+        assert!(!manager.path.exists());
+    }
+}
@@ -0,0 +1,351 @@
+#[cfg(feature = "metrics")]
+use anyhow::Context;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+#[cfg(feature = "metrics")]
+use prometheus::{GaugeVec, Opts, Registry, TextEncoder};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+#[cfg(feature = "sysinfo")]
+use sysinfo::{DiskExt, System, SystemExt};
+use tracing::{debug, info, warn};
+
+use crate::config::{AgentConfig, DiskSpaceConfig};
+use crate::http_client::SecureHttpClient;
+use crate::privacy::Redactor;
+
+/// Free space on the filesystem holding a watched path.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct FilesystemUsage {
+    pub mount_point: PathBuf,
+    pub free_bytes: u64,
+    pub total_bytes: u64,
+}
+
+impl FilesystemUsage {
+    pub fn free_percent(&self) -> f64 {
+        if self.total_bytes == 0 {
+            return 100.0;
+        }
+        self.free_bytes as f64 / self.total_bytes as f64 * 100.0
+    }
+
+    pub fn is_low(&self, config: &DiskSpaceConfig) -> bool {
+        self.free_percent() < config.min_free_percent as f64
+            || self.free_bytes < config.min_free_mb * 1024 * 1024
+    }
+}
+
+/// Free space against what an upgrade needs on the filesystem holding one
+/// of `disk_space.preflight_paths`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SpaceCheck {
+    pub path: PathBuf,
+    pub mount_point: PathBuf,
+    pub free_bytes: u64,
+    /// `min_free_mb` plus apt's estimates for what lands on this filesystem
+    pub required_bytes: u64,
+    pub sufficient: bool,
+}
+
+/// Checks each pre-flight path against `min_free_mb` plus the bytes in
+/// `needs` that land on its filesystem, e.g. apt's installed size for
+/// /usr and its download size for the archive cache.
+pub fn preflight(
+    config: &DiskSpaceConfig,
+    mounts: &[FilesystemUsage],
+    needs: &[(&Path, u64)],
+) -> Vec<SpaceCheck> {
+    config
+        .preflight_paths
+        .iter()
+        .filter_map(|path| {
+            let usage = filesystem_for(path, mounts)?;
+            let needed: u64 = needs
+                .iter()
+                .filter(|(need, _)| {
+                    filesystem_for(need, mounts)
+                        .is_some_and(|mount| mount.mount_point == usage.mount_point)
+                })
+                .map(|(_, bytes)| bytes)
+                .sum();
+            let required_bytes = config.min_free_mb * 1024 * 1024 + needed;
+            Some(SpaceCheck {
+                path: path.clone(),
+                mount_point: usage.mount_point.clone(),
+                free_bytes: usage.free_bytes,
+                required_bytes,
+                sufficient: usage.free_bytes >= required_bytes,
+            })
+        })
+        .collect()
+}
+
+/// POSTed to `/api/v1/alerts/disk-space` when a watched filesystem runs
+/// low, and again once it has recovered.
+#[derive(Debug, Serialize)]
+struct DiskSpaceAlert {
+    hostname: String,
+    agent_version: String,
+    timestamp: DateTime<Utc>,
+    path: PathBuf,
+    mount_point: PathBuf,
+    free_bytes: u64,
+    total_bytes: u64,
+    free_percent: f64,
+    low: bool,
+}
+
+/// Watches `disk_space.paths` between runs in daemon mode. A full /boot
+/// makes the next kernel update fail, so the backend hears about it as
+/// soon as a threshold is crossed rather than from the failed run.
+pub struct DiskSpaceMonitor {
+    http_client: SecureHttpClient,
+    /// Paths currently below a threshold, so each crossing alerts once
+    low: HashSet<PathBuf>,
+}
+
+impl DiskSpaceMonitor {
+    pub fn new(http_client: &SecureHttpClient) -> Self {
+        Self {
+            http_client: http_client.for_subsystem("disk_space"),
+            low: HashSet::new(),
+        }
+    }
+
+    pub async fn check(&mut self, config: &AgentConfig) {
+        let mounts = mounted_filesystems();
+        let mut usages = Vec::new();
+        for path in &config.disk_space.paths {
+            let Some(usage) = filesystem_for(path, &mounts) else {
+                debug!("No filesystem found for {:?}", path);
+                continue;
+            };
+            let low = usage.is_low(&config.disk_space);
+            if low != self.low.contains(path) {
+                if low {
+                    warn!(
+                        "Low disk space on {:?}: {} MiB free ({:.1}%)",
+                        path,
+                        usage.free_bytes / 1024 / 1024,
+                        usage.free_percent()
+                    );
+                    self.low.insert(path.clone());
+                } else {
+                    info!("Disk space on {:?} recovered", path);
+                    self.low.remove(path);
+                }
+                if let Err(e) = self.send_alert(config, path, usage, low).await {
+                    warn!("Failed to send disk space alert: {:#}", e);
+                }
+            }
+            usages.push((path, usage.clone(), low));
+        }
+
+        if let Err(e) = write_textfile_metrics(config, &usages) {
+            warn!("Failed to write disk space metrics: {:#}", e);
+        }
+    }
+
+    async fn send_alert(
+        &self,
+        config: &AgentConfig,
+        path: &Path,
+        usage: &FilesystemUsage,
+        low: bool,
+    ) -> Result<()> {
+        let hostname = crate::host::hostname();
+        let alert = DiskSpaceAlert {
+            hostname: match Redactor::new(config) {
+                Some(redactor) => redactor.hash(&hostname),
+                None => hostname,
+            },
+            agent_version: env!("CARGO_PKG_VERSION").to_string(),
+            timestamp: Utc::now(),
+            path: path.to_path_buf(),
+            mount_point: usage.mount_point.clone(),
+            free_bytes: usage.free_bytes,
+            total_bytes: usage.total_bytes,
+            free_percent: usage.free_percent(),
+            low,
+        };
+
+        let response = self.http_client.post("alerts/disk-space", &alert).await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(anyhow::anyhow!(
+                "Backend returned {} for disk space alert: {}",
+                status,
+                body
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sysinfo")]
+pub(crate) fn mounted_filesystems() -> Vec<FilesystemUsage> {
+    let mut system = System::new();
+    system.refresh_disks_list();
+    system
+        .disks()
+        .iter()
+        .map(|disk| FilesystemUsage {
+            mount_point: disk.mount_point().to_path_buf(),
+            free_bytes: disk.available_space(),
+            total_bytes: disk.total_space(),
+        })
+        .collect()
+}
+
+/// Without sysinfo: every mount in /proc/self/mounts that statvfs(3)
+/// reports blocks for, which leaves out proc, sysfs and the like.
+#[cfg(not(feature = "sysinfo"))]
+pub(crate) fn mounted_filesystems() -> Vec<FilesystemUsage> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let mounts = std::fs::read_to_string("/proc/self/mounts").unwrap_or_default();
+    mounts
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .filter_map(|mount_point| {
+            // Blanks in mount points are octal escapes
+            let mount_point =
+                PathBuf::from(mount_point.replace("\\040", " ").replace("\\011", "\t"));
+            let path = CString::new(mount_point.as_os_str().as_bytes()).ok()?;
+            // SAFETY: statvfs only writes to the struct passed in
+            let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+            if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+                return None;
+            }
+            let block_size = stat.f_frsize as u64;
+            let total_bytes = stat.f_blocks as u64 * block_size;
+            (total_bytes > 0).then(|| FilesystemUsage {
+                mount_point,
+                free_bytes: stat.f_bavail as u64 * block_size,
+                total_bytes,
+            })
+        })
+        .collect()
+}
+
+/// The mount with the longest mount point that contains `path`.
+pub(crate) fn filesystem_for<'a>(
+    path: &Path,
+    mounts: &'a [FilesystemUsage],
+) -> Option<&'a FilesystemUsage> {
+    mounts
+        .iter()
+        .filter(|mount| path.starts_with(&mount.mount_point))
+        .max_by_key(|mount| mount.mount_point.components().count())
+}
+
+/// Writes `ubuntu-auto-update-disk-space.prom` next to the run metrics, so
+/// the gauges stay current between runs.
+#[cfg(feature = "metrics")]
+fn write_textfile_metrics(
+    config: &AgentConfig,
+    usages: &[(&PathBuf, FilesystemUsage, bool)],
+) -> Result<()> {
+    let Some(dir) = &config.metrics.textfile_path else {
+        return Ok(());
+    };
+
+    let registry = Registry::new();
+    let free_bytes = GaugeVec::new(
+        Opts::new(
+            "ubuntu_auto_update_filesystem_free_bytes",
+            "Free space on the filesystem holding a watched path",
+        ),
+        &["path"],
+    )?;
+    let low_space = GaugeVec::new(
+        Opts::new(
+            "ubuntu_auto_update_filesystem_low_space",
+            "Whether free space is below the disk_space thresholds (1 = yes, 0 = no)",
+        ),
+        &["path"],
+    )?;
+    registry.register(Box::new(free_bytes.clone()))?;
+    registry.register(Box::new(low_space.clone()))?;
+
+    for (path, usage, low) in usages {
+        let path = path.to_string_lossy();
+        free_bytes
+            .with_label_values(&[&path])
+            .set(usage.free_bytes as f64);
+        low_space
+            .with_label_values(&[&path])
+            .set(if *low { 1.0 } else { 0.0 });
+    }
+
+    let textfile_path = dir.join("ubuntu-auto-update-disk-space.prom");
+    let metrics = TextEncoder::new().encode_to_string(&registry.gather())?;
+    std::fs::write(&textfile_path, metrics)
+        .with_context(|| format!("Failed to write textfile: {:?}", textfile_path))
+}
+
+#[cfg(not(feature = "metrics"))]
+fn write_textfile_metrics(
+    _config: &AgentConfig,
+    _usages: &[(&PathBuf, FilesystemUsage, bool)],
+) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watched_path_uses_its_filesystem_thresholds() {
+        let mount = |mount_point: &str, free_mb: u64, total_mb: u64| FilesystemUsage {
+            mount_point: PathBuf::from(mount_point),
+            free_bytes: free_mb * 1024 * 1024,
+            total_bytes: total_mb * 1024 * 1024,
+        };
+        let mounts = vec![mount("/", 40_000, 100_000), mount("/boot", 150, 1_000)];
+        let config = DiskSpaceConfig::default();
+
+        let var = filesystem_for(Path::new("/var"), &mounts).unwrap();
+        assert_eq!(var.mount_point, PathBuf::from("/"));
+        assert!(!var.is_low(&config));
+
+        // 15% free, but not enough for another kernel and initrd
+        let boot = filesystem_for(Path::new("/boot"), &mounts).unwrap();
+        assert_eq!(boot.mount_point, PathBuf::from("/boot"));
+        assert!(boot.is_low(&config));
+
+        assert!(mount("/", 5_000, 100_000).is_low(&config));
+    }
+
+    #[test]
+    fn test_preflight_adds_apt_estimates_per_filesystem() {
+        let mount = |mount_point: &str, free_mb: u64| FilesystemUsage {
+            mount_point: PathBuf::from(mount_point),
+            free_bytes: free_mb * 1024 * 1024,
+            total_bytes: 100_000 * 1024 * 1024,
+        };
+        let mounts = vec![mount("/", 900), mount("/var", 5_000), mount("/boot", 150)];
+        let config = DiskSpaceConfig::default();
+        let mib = 1024 * 1024;
+        let needs = [
+            (Path::new("/usr"), 800 * mib),
+            (Path::new("/var/cache/apt/archives"), 300 * mib),
+        ];
+
+        let checks = preflight(&config, &mounts, &needs);
+        let [root, var, boot] = checks.as_slice() else {
+            panic!("expected three checks: {:?}", checks);
+        };
+        assert_eq!(root.required_bytes, 1_000 * mib);
+        assert!(!root.sufficient);
+        assert_eq!(var.required_bytes, 500 * mib);
+        assert!(var.sufficient);
+        assert_eq!(boot.mount_point, PathBuf::from("/boot"));
+        assert!(!boot.sufficient);
+    }
+}
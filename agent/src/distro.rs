@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+use std::fs;
+use tracing::debug;
+
+/// The fields of /etc/os-release the agent cares about.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DistroInfo {
+    pub id: String,
+    pub id_like: Vec<String>,
+    pub version_codename: Option<String>,
+    /// Set by Ubuntu derivatives (Mint, Pop!_OS, elementary, Neon, ...)
+    pub ubuntu_codename: Option<String>,
+    /// e.g. "Ubuntu 22.04.4 LTS"
+    pub pretty_name: Option<String>,
+}
+
+impl DistroInfo {
+    pub fn detect() -> Self {
+        match fs::read_to_string("/etc/os-release") {
+            Ok(content) => Self::parse(&content),
+            Err(e) => {
+                debug!("Failed to read /etc/os-release: {}", e);
+                Self::default()
+            }
+        }
+    }
+
+    pub fn parse(content: &str) -> Self {
+        let fields: HashMap<&str, String> = content
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .map(|(key, value)| (key.trim(), value.trim().trim_matches('"').to_string()))
+            .collect();
+        let field = |key: &str| fields.get(key).filter(|v| !v.is_empty()).cloned();
+
+        Self {
+            id: field("ID").unwrap_or_default(),
+            id_like: field("ID_LIKE")
+                .map(|v| v.split_whitespace().map(str::to_string).collect())
+                .unwrap_or_default(),
+            version_codename: field("VERSION_CODENAME"),
+            ubuntu_codename: field("UBUNTU_CODENAME"),
+            pretty_name: field("PRETTY_NAME"),
+        }
+    }
+
+    /// Codename of the Ubuntu release the archive pockets are named after.
+    /// Derivatives such as Mint ("virginia") carry their own codename in
+    /// VERSION_CODENAME, but their security updates still come from
+    /// "jammy-security".
+    pub fn base_codename(&self) -> Option<&str> {
+        self.ubuntu_codename
+            .as_deref()
+            .or(self.version_codename.as_deref())
+    }
+
+    /// The derivative's own codename, when it differs from the Ubuntu base.
+    pub fn derivative_codename(&self) -> Option<&str> {
+        match (self.version_codename.as_deref(), self.base_codename()) {
+            (Some(own), Some(base)) if own != base => Some(own),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pocket {
+    Release,
+    Updates,
+    Security,
+    Backports,
+    Proposed,
+    /// The derivative's own archive (Mint "virginia", elementary "horus", ...)
+    Derivative,
+    Other,
+}
+
+/// Maps apt suite names to Ubuntu pockets, accounting for derivatives that
+/// rename or add archives on top of Ubuntu's.
+#[derive(Debug, Clone)]
+pub struct PocketMap {
+    base: Option<String>,
+    derivative: Option<String>,
+    extra_security: Vec<String>,
+}
+
+impl PocketMap {
+    /// `extra_security` lists additional suites to treat as security
+    /// pockets (`updates.security_pockets`).
+    pub fn new(distro: &DistroInfo, extra_security: &[String]) -> Self {
+        Self {
+            base: distro.base_codename().map(str::to_string),
+            derivative: distro.derivative_codename().map(str::to_string),
+            extra_security: extra_security.to_vec(),
+        }
+    }
+
+    pub fn classify(&self, suite: &str) -> Pocket {
+        if self.extra_security.iter().any(|s| s == suite) {
+            return Pocket::Security;
+        }
+        if self.derivative.as_deref() == Some(suite) {
+            return Pocket::Derivative;
+        }
+
+        // Without a known base codename, fall back to the pocket suffix alone
+        let pocket = match self.base.as_deref() {
+            Some(base) if suite == base => return Pocket::Release,
+            Some(base) => match suite.strip_prefix(base) {
+                Some(rest) => rest,
+                None => return Pocket::Other,
+            },
+            None => suite.rfind('-').map_or("", |i| &suite[i..]),
+        };
+
+        match pocket {
+            // Ubuntu Pro ESM pockets: jammy-infra-security, jammy-apps-security
+            "-security" | "-infra-security" | "-apps-security" => Pocket::Security,
+            "-updates" | "-infra-updates" | "-apps-updates" => Pocket::Updates,
+            "-backports" => Pocket::Backports,
+            "-proposed" => Pocket::Proposed,
+            _ => Pocket::Other,
+        }
+    }
+
+    /// Whether any suite in an apt origin list ("jammy-updates,jammy-security")
+    /// is a security pocket.
+    pub fn is_security(&self, origin: &str) -> bool {
+        origin
+            .split(',')
+            .any(|suite| self.classify(suite) == Pocket::Security)
+    }
+}
+
+impl Default for PocketMap {
+    fn default() -> Self {
+        Self::new(&DistroInfo::default(), &[])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINT_OS_RELEASE: &str = r#"NAME="Linux Mint"
+VERSION="21.3 (Virginia)"
+PRETTY_NAME="Linux Mint 21.3"
+ID=linuxmint
+ID_LIKE="ubuntu debian"
+VERSION_CODENAME=virginia
+UBUNTU_CODENAME=jammy
+"#;
+
+    #[test]
+    fn test_parse_os_release() {
+        let distro = DistroInfo::parse(MINT_OS_RELEASE);
+        assert_eq!(distro.id, "linuxmint");
+        assert_eq!(distro.id_like, vec!["ubuntu", "debian"]);
+        assert_eq!(distro.base_codename(), Some("jammy"));
+        assert_eq!(distro.derivative_codename(), Some("virginia"));
+        assert_eq!(distro.pretty_name.as_deref(), Some("Linux Mint 21.3"));
+
+        let pop = DistroInfo::parse("ID=pop\nVERSION_CODENAME=jammy\nUBUNTU_CODENAME=jammy\n");
+        assert_eq!(pop.base_codename(), Some("jammy"));
+        assert_eq!(pop.derivative_codename(), None);
+    }
+
+    #[test]
+    fn test_classify_derivative_pockets() {
+        let pockets = PocketMap::new(&DistroInfo::parse(MINT_OS_RELEASE), &[]);
+
+        assert_eq!(pockets.classify("jammy-security"), Pocket::Security);
+        assert_eq!(pockets.classify("jammy-infra-security"), Pocket::Security);
+        assert_eq!(pockets.classify("jammy-updates"), Pocket::Updates);
+        assert_eq!(pockets.classify("jammy"), Pocket::Release);
+        assert_eq!(pockets.classify("virginia"), Pocket::Derivative);
+        assert_eq!(pockets.classify("virginia-security"), Pocket::Other);
+        assert!(pockets.is_security("jammy-updates,jammy-security"));
+        assert!(!pockets.is_security("virginia"));
+    }
+
+    #[test]
+    fn test_extra_security_pockets_and_fallback() {
+        let distro = DistroInfo::parse("ID=neon\nVERSION_CODENAME=jammy\nUBUNTU_CODENAME=jammy\n");
+        let pockets = PocketMap::new(&distro, &["neon-security".to_string()]);
+        assert!(pockets.is_security("neon-security"));
+        assert!(!pockets.is_security("jammy"));
+
+        let unknown = PocketMap::default();
+        assert!(unknown.is_security("noble-security"));
+        assert!(!unknown.is_security("noble-updates"));
+    }
+}
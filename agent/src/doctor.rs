@@ -0,0 +1,398 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+
+use crate::config::AgentConfig;
+use crate::diskspace::{filesystem_for, mounted_filesystems};
+use crate::http_client::SecureHttpClient;
+use crate::privileges::{Operation, Privileges};
+use crate::sandbox::SandboxStatus;
+
+/// Locks apt and dpkg take with `fcntl` while they change packages
+pub const DPKG_LOCKS: &[&str] = &[
+    "/var/lib/dpkg/lock-frontend",
+    "/var/lib/dpkg/lock",
+    "/var/lib/apt/lists/lock",
+];
+
+/// Units shipped in `agent/systemd`
+const AGENT_UNITS: &[&str] = &[
+    "ubuntu-auto-update-agent.service",
+    "ubuntu-auto-update-agentd.service",
+    "ubuntu-auto-update-agent-refresh.service",
+    "ubuntu-auto-update-agent-beacon.service",
+];
+
+/// Either of these drives update runs
+const SCHEDULING_UNITS: &[&str] = &[
+    "ubuntu-auto-update-agent.timer",
+    "ubuntu-auto-update-agentd.service",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Pass,
+    Warn,
+    Fail,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DoctorCheck {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    pub message: String,
+}
+
+impl DoctorCheck {
+    fn pass(name: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: CheckStatus::Pass,
+            message: message.into(),
+        }
+    }
+
+    fn warn(name: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: CheckStatus::Warn,
+            message: message.into(),
+        }
+    }
+
+    fn fail(name: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: CheckStatus::Fail,
+            message: message.into(),
+        }
+    }
+}
+
+/// Runs every check in order. `config_error` is why loading the config
+/// failed when the defaults are in use instead.
+pub async fn run_checks(
+    config: &AgentConfig,
+    config_error: Option<&str>,
+    sandbox: &SandboxStatus,
+) -> Vec<DoctorCheck> {
+    let mut checks = vec![
+        check_root(),
+        check_config(config, config_error),
+        check_sandbox(config, sandbox),
+        check_locks(),
+    ];
+    checks.extend(check_disk_space(config));
+    checks.push(check_key_files(config));
+    checks.extend(check_backend(config).await);
+    checks.push(check_systemd_units());
+    checks
+}
+
+fn check_root() -> DoctorCheck {
+    match Privileges::current().require(Operation::SystemUpdates) {
+        Ok(()) => DoctorCheck::pass("root", "running as root with CAP_DAC_OVERRIDE"),
+        Err(e) => DoctorCheck::fail("root", e.to_string()),
+    }
+}
+
+fn check_config(config: &AgentConfig, config_error: Option<&str>) -> DoctorCheck {
+    if let Some(error) = config_error {
+        return DoctorCheck::fail("config", format!("failed to load: {}", error));
+    }
+    match config.validate() {
+        Ok(()) => DoctorCheck::pass("config", "configuration is valid"),
+        Err(e) => DoctorCheck::fail("config", e.to_string()),
+    }
+}
+
+fn check_sandbox(config: &AgentConfig, sandbox: &SandboxStatus) -> DoctorCheck {
+    let warnings = sandbox.warnings(config);
+    if warnings.is_empty() {
+        DoctorCheck::pass("sandbox", "no restrictions that affect the agent")
+    } else {
+        DoctorCheck::warn("sandbox", warnings.join("; "))
+    }
+}
+
+fn check_locks() -> DoctorCheck {
+    let mut held = Vec::new();
+    for lock in DPKG_LOCKS {
+        match lock_holder(Path::new(lock)) {
+            Ok(Some(pid)) => held.push(format!("{} (pid {})", lock, pid)),
+            Ok(None) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return DoctorCheck::warn("dpkg_lock", format!("{}: {}", lock, e)),
+        }
+    }
+    if held.is_empty() {
+        DoctorCheck::pass("dpkg_lock", "apt and dpkg locks are free")
+    } else {
+        DoctorCheck::warn("dpkg_lock", format!("held: {}", held.join(", ")))
+    }
+}
+
+/// PID of the process holding a write lock on `path`, if any.
+fn lock_holder(path: &Path) -> std::io::Result<Option<libc::pid_t>> {
+    let file = std::fs::File::open(path)?;
+    // SAFETY: flock is plain data and fully initialized by zeroing
+    let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+    lock.l_type = libc::F_WRLCK as libc::c_short;
+    lock.l_whence = libc::SEEK_SET as libc::c_short;
+    // SAFETY: the descriptor stays open for the call and `lock` outlives it
+    let rc = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETLK, &mut lock) };
+    if rc == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok((lock.l_type != libc::F_UNLCK as libc::c_short).then_some(lock.l_pid))
+}
+
+fn check_disk_space(config: &AgentConfig) -> Vec<DoctorCheck> {
+    let mounts = mounted_filesystems();
+    ["/", "/boot"]
+        .into_iter()
+        .map(|path| {
+            let Some(usage) = filesystem_for(Path::new(path), &mounts) else {
+                return DoctorCheck::warn("disk_space", format!("{}: no filesystem found", path));
+            };
+            let message = format!(
+                "{}: {} MiB free ({:.1}%)",
+                path,
+                usage.free_bytes / 1024 / 1024,
+                usage.free_percent()
+            );
+            if usage.is_low(&config.disk_space) {
+                DoctorCheck::fail("disk_space", message)
+            } else {
+                DoctorCheck::pass("disk_space", message)
+            }
+        })
+        .collect()
+}
+
+/// Secrets must not be readable by group or others.
+fn check_key_files(config: &AgentConfig) -> DoctorCheck {
+    let security = &config.security;
+    let api_key_file = security.credential_path(&security.api_key_file);
+    let mut files = vec![api_key_file.clone()];
+    files.extend(
+        security
+            .hmac_secret_file
+            .iter()
+            .map(|path| security.credential_path(path)),
+    );
+    files.extend(security.key_file.iter().cloned());
+
+    let mut problems = Vec::new();
+    for file in &files {
+        match std::fs::metadata(file) {
+            Ok(metadata) if metadata.permissions().mode() & 0o077 != 0 => problems.push(format!(
+                "{} has mode {:o}",
+                file.display(),
+                metadata.permissions().mode() & 0o777
+            )),
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => problems.push(format!("{}: {}", file.display(), e)),
+        }
+    }
+
+    if !problems.is_empty() {
+        DoctorCheck::fail(
+            "key_permissions",
+            format!("{}, expected 600", problems.join(", ")),
+        )
+    } else if !api_key_file.exists() {
+        DoctorCheck::warn(
+            "key_permissions",
+            format!("{} not found, run enroll first", api_key_file.display()),
+        )
+    } else {
+        DoctorCheck::pass("key_permissions", "key files are readable by owner only")
+    }
+}
+
+/// Reachability, the TLS chain and clock skew, all from one health request.
+async fn check_backend(config: &AgentConfig) -> Vec<DoctorCheck> {
+    let http_client = match SecureHttpClient::new(config) {
+        Ok(client) => client.for_subsystem("doctor"),
+        Err(e) => return vec![DoctorCheck::fail("backend", format!("{:#}", e))],
+    };
+    let uses_tls = config
+        .backend
+        .url
+        .as_slice()
+        .iter()
+        .any(|url| url.starts_with("https://"));
+
+    let response = match http_client
+        .get_with_timeout(
+            "health",
+            Duration::from_secs(config.backend.timeout_seconds),
+        )
+        .await
+    {
+        Ok(response) => response,
+        Err(e) => {
+            let error = format!("{:#}", e);
+            let mut checks = vec![DoctorCheck::fail("backend", error.clone())];
+            if uses_tls && error.to_lowercase().contains("certificate") {
+                checks.push(DoctorCheck::fail("tls", error));
+            }
+            return checks;
+        }
+    };
+
+    let mut checks = Vec::new();
+    let status = response.status();
+    checks.push(if status.is_success() {
+        DoctorCheck::pass(
+            "backend",
+            format!("{} responded {}", config.backend.url, status),
+        )
+    } else {
+        DoctorCheck::warn(
+            "backend",
+            format!("{} responded {}", config.backend.url, status),
+        )
+    });
+
+    checks.push(if !uses_tls {
+        DoctorCheck::warn("tls", "backend is not using HTTPS")
+    } else if !config.security.verify_server_cert {
+        DoctorCheck::warn("tls", "security.verify_server_cert is disabled")
+    } else {
+        check_client_certificate(config)
+            .unwrap_or_else(|| DoctorCheck::pass("tls", "certificate chain verified"))
+    });
+
+    let backend_time = response
+        .headers()
+        .get(reqwest::header::DATE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| DateTime::parse_from_rfc2822(value).ok());
+    checks.push(match backend_time {
+        Some(backend_time) => clock_skew_check(
+            Utc::now() - backend_time.with_timezone(&Utc),
+            config.security.max_clock_skew(),
+        ),
+        None => DoctorCheck::warn("clock_skew", "backend sent no Date header"),
+    });
+    checks
+}
+
+/// A problem with the mTLS client certificate, if one is configured.
+fn check_client_certificate(config: &AgentConfig) -> Option<DoctorCheck> {
+    let cert_file = config.security.cert_file.as_ref()?;
+    let pem = match std::fs::read(cert_file) {
+        Ok(pem) => pem,
+        Err(e) => return Some(DoctorCheck::fail("tls", format!("{:?}: {}", cert_file, e))),
+    };
+    match crate::enrollment::certificate_expiry(&pem) {
+        Ok(expiry) if expiry <= Utc::now() => Some(DoctorCheck::fail(
+            "tls",
+            format!("client certificate expired at {}", expiry),
+        )),
+        Ok(_) => None,
+        Err(e) => Some(DoctorCheck::fail("tls", format!("{:#}", e))),
+    }
+}
+
+/// Signed backend messages are rejected past `max_skew`, so warn well
+/// before that.
+fn clock_skew_check(skew: chrono::Duration, max_skew: Duration) -> DoctorCheck {
+    // The Date header only has second resolution
+    let seconds = skew.num_seconds().unsigned_abs();
+    let message = format!("local clock is {}s off the backend", seconds);
+    if seconds > max_skew.as_secs() {
+        DoctorCheck::fail(
+            "clock_skew",
+            format!("{}, limit is {}s", message, max_skew.as_secs()),
+        )
+    } else if seconds > max_skew.as_secs() / 2 {
+        DoctorCheck::warn("clock_skew", message)
+    } else {
+        DoctorCheck::pass("clock_skew", message)
+    }
+}
+
+fn check_systemd_units() -> DoctorCheck {
+    let failed: Vec<&str> = AGENT_UNITS
+        .iter()
+        .copied()
+        .filter(|unit| systemctl_succeeds("is-failed", unit))
+        .collect();
+    if !failed.is_empty() {
+        return DoctorCheck::fail("systemd", format!("failed: {}", failed.join(", ")));
+    }
+    match SCHEDULING_UNITS
+        .iter()
+        .find(|unit| systemctl_succeeds("is-active", unit))
+    {
+        Some(unit) => DoctorCheck::pass("systemd", format!("{} is active", unit)),
+        None => DoctorCheck::warn(
+            "systemd",
+            format!("neither {} is active", SCHEDULING_UNITS.join(" nor ")),
+        ),
+    }
+}
+
+fn systemctl_succeeds(verb: &str, unit: &str) -> bool {
+    crate::audit::status(Command::new("systemctl").args([verb, "--quiet", unit]))
+        .is_ok_and(|status| status.success())
+}
+
+/// Overall result: the worst status of any check.
+pub fn overall(checks: &[DoctorCheck]) -> CheckStatus {
+    checks
+        .iter()
+        .map(|check| check.status)
+        .max()
+        .unwrap_or(CheckStatus::Pass)
+}
+
+pub fn print_json(checks: &[DoctorCheck]) -> Result<()> {
+    #[derive(Serialize)]
+    struct Report<'a> {
+        status: CheckStatus,
+        checks: &'a [DoctorCheck],
+    }
+    let report = Report {
+        status: overall(checks),
+        checks,
+    };
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lock_holder_and_clock_skew() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let lock = temp_dir.path().join("lock-frontend");
+        std::fs::write(&lock, "").unwrap();
+        assert_eq!(lock_holder(&lock).unwrap(), None);
+        assert!(lock_holder(&temp_dir.path().join("missing")).is_err());
+
+        let max = Duration::from_secs(300);
+        let status = |seconds| clock_skew_check(chrono::Duration::seconds(seconds), max).status;
+        assert_eq!(status(2), CheckStatus::Pass);
+        assert_eq!(status(-200), CheckStatus::Warn);
+        assert_eq!(status(-301), CheckStatus::Fail);
+
+        let checks = vec![
+            DoctorCheck::pass("root", ""),
+            DoctorCheck::warn("dpkg_lock", ""),
+        ];
+        assert_eq!(overall(&checks), CheckStatus::Warn);
+    }
+}
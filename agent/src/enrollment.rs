@@ -1,11 +1,16 @@
 use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use rcgen::{CertificateParams, DistinguishedName, DnType, KeyPair};
 use serde::{Deserialize, Serialize};
 use std::fs;
-use tracing::{debug, info};
+use std::io::Write;
+use std::path::Path;
+use tracing::{debug, info, warn};
 use uuid::Uuid;
 
 use crate::config::AgentConfig;
-use crate::http_client::SecureHttpClient;
+use crate::http_client::{SecureHttpClient, PREVIOUS_API_KEY_FILE};
+use crate::privacy::Redactor;
 
 #[derive(Debug, Serialize)]
 struct EnrollmentRequest {
@@ -15,6 +20,9 @@ struct EnrollmentRequest {
     agent_version: String,
     os_version: String,
     architecture: String,
+    /// PEM CSR for an mTLS client certificate ([client_cert] enabled)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    csr: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -23,6 +31,36 @@ struct EnrollmentResponse {
     host_id: String,
     success: bool,
     message: Option<String>,
+    /// PEM client certificate signed from the enrollment CSR
+    #[serde(default)]
+    client_certificate: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct RenewCertificateRequest {
+    host_id: String,
+    csr: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct RenewCertificateResponse {
+    certificate: String,
+}
+
+/// Fresh client key and the CSR for it, not yet written to disk.
+struct CertificateRequest {
+    key_pem: String,
+    csr_pem: String,
+}
+
+#[derive(Debug, Serialize)]
+struct RotateKeyRequest {
+    host_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct RotateKeyResponse {
+    api_key: String,
 }
 
 pub struct EnrollmentManager {
@@ -31,14 +69,11 @@ pub struct EnrollmentManager {
 }
 
 impl EnrollmentManager {
-    pub fn new(config: &AgentConfig) -> Result<Self> {
-        let http_client = SecureHttpClient::new(config)
-            .with_context(|| "Failed to create HTTP client for enrollment")?;
-
-        Ok(Self {
+    pub fn new(config: &AgentConfig, http_client: &SecureHttpClient) -> Self {
+        Self {
             config: config.clone(),
-            http_client,
-        })
+            http_client: http_client.for_subsystem("enrollment"),
+        }
     }
 
     pub async fn enroll(&self, token: &str, hostname: Option<&str>) -> Result<()> {
@@ -48,19 +83,29 @@ impl EnrollmentManager {
         let host_id = self.get_or_create_host_id()?;
 
         // Get system information
-        let hostname = hostname.map(|h| h.to_string()).unwrap_or_else(|| {
-            gethostname::gethostname()
-                .into_string()
-                .unwrap_or_else(|_| "unknown".to_string())
-        });
+        let hostname = hostname
+            .map(|h| h.to_string())
+            .unwrap_or_else(crate::host::hostname);
+
+        let hostname = match Redactor::new(&self.config) {
+            Some(redactor) => redactor.hash(&hostname),
+            None => hostname,
+        };
+
+        let cert_request = if self.config.client_cert.enabled {
+            Some(generate_certificate_request(&host_id)?)
+        } else {
+            None
+        };
 
         let enrollment_request = EnrollmentRequest {
             enrollment_token: token.to_string(),
             hostname,
             host_id: host_id.clone(),
             agent_version: env!("CARGO_PKG_VERSION").to_string(),
-            os_version: self.get_os_version()?,
+            os_version: crate::host::os_version(),
             architecture: std::env::consts::ARCH.to_string(),
+            csr: cert_request.as_ref().map(|request| request.csr_pem.clone()),
         };
 
         debug!("Sending enrollment request for host ID: {}", host_id);
@@ -68,7 +113,7 @@ impl EnrollmentManager {
         // Send enrollment request
         let response = self
             .http_client
-            .post("/api/v1/enroll", &enrollment_request)
+            .post("enroll", &enrollment_request)
             .await
             .with_context(|| "Failed to send enrollment request")?;
 
@@ -98,6 +143,15 @@ impl EnrollmentManager {
         self.save_api_key(&enrollment_response.api_key)
             .with_context(|| "Failed to save API key")?;
 
+        if let Some(cert_request) = &cert_request {
+            match &enrollment_response.client_certificate {
+                Some(certificate) => self
+                    .save_client_cert(&cert_request.key_pem, certificate)
+                    .with_context(|| "Failed to save client certificate")?,
+                None => warn!("Backend did not issue a client certificate"),
+            }
+        }
+
         // Update host ID if backend provided one
         if enrollment_response.host_id != host_id {
             self.save_host_id(&enrollment_response.host_id)
@@ -108,6 +162,132 @@ impl EnrollmentManager {
         Ok(())
     }
 
+    /// Replaces the API key with a new one issued by the backend. The
+    /// current key is kept as a fallback until the backend accepts the new
+    /// one, so a rotation the backend failed to record can't lock the host out.
+    pub async fn rotate_key(&self) -> Result<()> {
+        let security = &self.config.security;
+        let current_key_file = security.credential_path(&security.api_key_file);
+        let current_key = fs::read(&current_key_file).with_context(|| {
+            format!(
+                "Failed to read API key from {:?}; is the agent enrolled?",
+                current_key_file
+            )
+        })?;
+
+        let request = RotateKeyRequest {
+            host_id: self.get_or_create_host_id()?,
+        };
+        let response = self
+            .http_client
+            .post("rotate-key", &request)
+            .await
+            .with_context(|| "Failed to send key rotation request")?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let error_text = response.text().await.unwrap_or_default();
+            return Err(anyhow::anyhow!(
+                "Key rotation failed with status {}: {}",
+                status,
+                error_text
+            ));
+        }
+
+        let rotated: RotateKeyResponse = response
+            .json()
+            .await
+            .with_context(|| "Failed to parse key rotation response")?;
+
+        write_secret(
+            &self.config.state.dir.join(PREVIOUS_API_KEY_FILE),
+            &current_key,
+        )
+        .with_context(|| "Failed to keep the previous API key")?;
+        self.save_api_key(&rotated.api_key)
+            .with_context(|| "Failed to save API key")?;
+
+        info!("API key rotated; the previous key is kept until the backend accepts the new one");
+        Ok(())
+    }
+
+    /// Requests a new client certificate when the current one expires within
+    /// `client_cert.renew_before_days`. Returns whether it was renewed; the
+    /// new certificate is used from the next HTTP client on.
+    pub async fn renew_client_cert_if_due(&self) -> Result<bool> {
+        let client_cert = &self.config.client_cert;
+        let (Some(cert_file), true) = (&self.config.security.cert_file, client_cert.enabled) else {
+            return Ok(false);
+        };
+        if !cert_file.exists() {
+            return Ok(false);
+        }
+
+        let expires_at = certificate_expiry(
+            &fs::read(cert_file).with_context(|| format!("Failed to read {:?}", cert_file))?,
+        )?;
+        let renew_before = Duration::days(client_cert.renew_before_days as i64);
+        if expires_at - Utc::now() > renew_before {
+            debug!("Client certificate valid until {}", expires_at);
+            return Ok(false);
+        }
+
+        info!("Client certificate expires {}, renewing", expires_at);
+        let host_id = self.get_or_create_host_id()?;
+        let cert_request = generate_certificate_request(&host_id)?;
+        let response = self
+            .http_client
+            .post(
+                "certificate/renew",
+                &RenewCertificateRequest {
+                    host_id,
+                    csr: cert_request.csr_pem.clone(),
+                },
+            )
+            .await
+            .with_context(|| "Failed to send certificate renewal request")?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let error_text = response.text().await.unwrap_or_default();
+            return Err(anyhow::anyhow!(
+                "Certificate renewal failed with status {}: {}",
+                status,
+                error_text
+            ));
+        }
+
+        let renewed: RenewCertificateResponse = response
+            .json()
+            .await
+            .with_context(|| "Failed to parse certificate renewal response")?;
+        self.save_client_cert(&cert_request.key_pem, &renewed.certificate)?;
+        Ok(true)
+    }
+
+    fn save_client_cert(&self, key_pem: &str, cert_pem: &str) -> Result<()> {
+        let security = &self.config.security;
+        let (Some(cert_file), Some(key_file)) = (&security.cert_file, &security.key_file) else {
+            return Err(anyhow::anyhow!(
+                "client_cert requires security.cert_file and security.key_file"
+            ));
+        };
+
+        // Refuse anything that isn't a certificate before replacing a working one
+        let expires_at = certificate_expiry(cert_pem.as_bytes())?;
+
+        write_secret(key_file, key_pem.as_bytes())
+            .with_context(|| format!("Failed to write client key to {:?}", key_file))?;
+        write_secret(cert_file, cert_pem.as_bytes())
+            .with_context(|| format!("Failed to write client certificate to {:?}", cert_file))?;
+
+        info!(
+            "Saved client certificate to {:?}, valid until {}",
+            cert_file, expires_at
+        );
+        Ok(())
+    }
+
     fn get_or_create_host_id(&self) -> Result<String> {
         let host_id_file = &self.config.enrollment.host_id_file;
 
@@ -155,55 +335,80 @@ impl EnrollmentManager {
     }
 
     fn save_api_key(&self, api_key: &str) -> Result<()> {
-        let api_key_file = &self.config.security.api_key_file;
-
-        // Create directory if it doesn't exist
-        if let Some(parent) = api_key_file.parent() {
-            fs::create_dir_all(parent)
-                .with_context(|| format!("Failed to create directory: {:?}", parent))?;
-        }
-
-        fs::write(api_key_file, api_key)
+        let api_key_file = &self.config.security.api_key_store_path();
+        write_secret(api_key_file, api_key.as_bytes())
             .with_context(|| format!("Failed to write API key to {:?}", api_key_file))?;
 
-        // Set restrictive permissions (readable only by root/owner)
-        #[cfg(unix)]
-        {
-            use std::os::unix::fs::PermissionsExt;
-            let mut perms = fs::metadata(api_key_file)?.permissions();
-            perms.set_mode(0o600);
-            fs::set_permissions(api_key_file, perms)?;
+        if self.config.security.credentials_from_systemd {
+            info!(
+                "Saved API key to {:?}; pass it to the agent units with LoadCredential={}:{}",
+                api_key_file,
+                api_key_file
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy(),
+                api_key_file.display()
+            );
+        } else {
+            debug!("Saved API key to {:?}", api_key_file);
         }
-
-        debug!("Saved API key to {:?}", api_key_file);
         Ok(())
     }
+}
 
-    fn get_os_version(&self) -> Result<String> {
-        let output = std::process::Command::new("lsb_release")
-            .args(["-ds"])
-            .output()
-            .with_context(|| "Failed to get OS version")?;
+/// Generates an ECDSA P-256 key and a CSR naming the host ID.
+fn generate_certificate_request(host_id: &str) -> Result<CertificateRequest> {
+    let key_pair = KeyPair::generate().context("Failed to generate client key")?;
+
+    let mut params = CertificateParams::default();
+    let mut name = DistinguishedName::new();
+    name.push(DnType::CommonName, host_id);
+    params.distinguished_name = name;
+
+    let csr = params
+        .serialize_request(&key_pair)
+        .context("Failed to create certificate signing request")?;
+    Ok(CertificateRequest {
+        key_pem: key_pair.serialize_pem(),
+        csr_pem: csr
+            .pem()
+            .context("Failed to encode certificate signing request")?,
+    })
+}
 
-        if output.status.success() {
-            Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
-        } else {
-            // Fallback to /etc/os-release
-            if let Ok(os_release) = fs::read_to_string("/etc/os-release") {
-                for line in os_release.lines() {
-                    if line.starts_with("PRETTY_NAME=") {
-                        let version = line
-                            .trim_start_matches("PRETTY_NAME=")
-                            .trim_matches('"')
-                            .to_string();
-                        return Ok(version);
-                    }
-                }
-            }
+pub(crate) fn certificate_expiry(pem: &[u8]) -> Result<DateTime<Utc>> {
+    let (_, pem) = x509_parser::pem::parse_x509_pem(pem)
+        .map_err(|e| anyhow::anyhow!("Invalid certificate PEM: {}", e))?;
+    let cert = pem
+        .parse_x509()
+        .map_err(|e| anyhow::anyhow!("Invalid certificate: {}", e))?;
+    DateTime::from_timestamp(cert.validity().not_after.timestamp(), 0)
+        .context("Certificate expiry out of range")
+}
 
-            Ok("Unknown".to_string())
-        }
+/// Writes a secret readable only by its owner, replacing any existing file
+/// atomically so a crash can't leave a truncated key behind.
+fn write_secret(path: &Path, secret: &[u8]) -> Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory: {:?}", parent))?;
     }
+
+    let tmp_path = path.with_extension("tmp");
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)
+        .with_context(|| format!("Failed to create {:?}", tmp_path))?;
+    file.write_all(secret)
+        .and_then(|()| file.sync_all())
+        .with_context(|| format!("Failed to write {:?}", tmp_path))?;
+    fs::rename(&tmp_path, path).with_context(|| format!("Failed to replace {:?}", path))?;
+    Ok(())
 }
 
 #[cfg(test)]
@@ -229,6 +434,43 @@ mod tests {
         assert!(host_id_file.to_string_lossy().contains("host.id"));
     }
 
+    #[test]
+    fn test_write_secret_replaces_with_owner_only_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("auth.token");
+        fs::write(&path, "old-key").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_secret(&path, b"new-key").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new-key");
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        assert!(!temp_dir.path().join("auth.tmp").exists());
+    }
+
+    #[test]
+    fn test_certificate_request_and_expiry() {
+        let request = generate_certificate_request("host-1").unwrap();
+        assert!(request
+            .csr_pem
+            .starts_with("-----BEGIN CERTIFICATE REQUEST-----"));
+        assert!(request.key_pem.contains("PRIVATE KEY"));
+
+        let key_pair = KeyPair::from_pem(&request.key_pem).unwrap();
+        let mut params = CertificateParams::new(vec!["host-1".to_string()]).unwrap();
+        params.not_after = rcgen::date_time_ymd(2031, 5, 1);
+        let cert = params.self_signed(&key_pair).unwrap();
+
+        let expires_at = certificate_expiry(cert.pem().as_bytes()).unwrap();
+        assert_eq!(expires_at.to_rfc3339(), "2031-05-01T00:00:00+00:00");
+        assert!(certificate_expiry(b"not a certificate").is_err());
+    }
+
     #[test]
     fn test_os_version_parsing() {
         let _config = AgentConfig::default();
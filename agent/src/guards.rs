@@ -0,0 +1,111 @@
+use chrono::{DateTime, Utc};
+use std::path::Path;
+use std::time::Duration;
+use tracing::{debug, info};
+
+use crate::config::{GuardCheck, GuardsConfig};
+
+/// Runs the `[[guards.checks]]` in order before an update and returns why
+/// the run should be deferred, if any check fails. Meant for single-host
+/// servers where e.g. replication lag or a missed backup makes patching
+/// risky; the checks themselves are site-specific scripts or stamp files.
+pub async fn check_guards(config: &GuardsConfig) -> Option<String> {
+    for check in &config.checks {
+        let result = match &check.path {
+            Some(path) => check_file_age(path, check.max_age_hours.unwrap_or(0), Utc::now()),
+            None => run_check(check).await,
+        };
+        match result {
+            Ok(()) => debug!("Guard {} passed", check.name),
+            Err(reason) => return Some(format!("guard {} failed: {}", check.name, reason)),
+        }
+    }
+    if !config.checks.is_empty() {
+        info!("All {} update guards passed", config.checks.len());
+    }
+    None
+}
+
+async fn run_check(check: &GuardCheck) -> Result<(), String> {
+    let output = tokio::time::timeout(
+        Duration::from_secs(check.timeout_seconds),
+        crate::audit::output_async(
+            tokio::process::Command::new(&check.command[0])
+                .args(&check.command[1..])
+                .kill_on_drop(true),
+        ),
+    )
+    .await
+    .map_err(|_| format!("timed out after {}s", check.timeout_seconds))?
+    .map_err(|e| format!("failed to run {}: {}", check.command[0], e))?;
+
+    if output.status.success() {
+        return Ok(());
+    }
+
+    // Check scripts usually explain themselves on their last line
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let explanation = [stderr.trim(), stdout.trim()]
+        .into_iter()
+        .find(|text| !text.is_empty())
+        .and_then(|text| text.lines().last())
+        .unwrap_or("no output");
+    Err(format!("{} ({})", output.status, explanation))
+}
+
+fn check_file_age(path: &Path, max_age_hours: u64, now: DateTime<Utc>) -> Result<(), String> {
+    let modified: DateTime<Utc> = std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .map_err(|e| format!("{}: {}", path.display(), e))?
+        .into();
+
+    let age = now - modified;
+    if age > chrono::Duration::hours(max_age_hours as i64) {
+        return Err(format!(
+            "{} last updated {}h ago, limit is {}h",
+            path.display(),
+            age.num_hours(),
+            max_age_hours
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_file_age() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let stamp = temp_dir.path().join("backup-ok");
+        std::fs::write(&stamp, "").unwrap();
+
+        assert!(check_file_age(&stamp, 24, Utc::now()).is_ok());
+        let later = Utc::now() + chrono::Duration::hours(25);
+        assert!(check_file_age(&stamp, 24, later).is_err());
+        assert!(check_file_age(&temp_dir.path().join("missing"), 24, Utc::now()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_first_failing_guard_defers() {
+        let check = |name: &str, script: &str| GuardCheck {
+            name: name.to_string(),
+            command: vec!["sh".to_string(), "-c".to_string(), script.to_string()],
+            ..GuardCheck::default()
+        };
+        let mut config = GuardsConfig {
+            checks: vec![check("ok", "exit 0")],
+        };
+        assert_eq!(check_guards(&config).await, None);
+
+        config
+            .checks
+            .push(check("pg-lag", "echo 'lag 120s > 30s' >&2; exit 1"));
+        config.checks.push(check("never-run", "exit 1"));
+        let reason = check_guards(&config).await.unwrap();
+        assert!(reason.starts_with("guard pg-lag failed"), "{}", reason);
+        assert!(reason.contains("lag 120s > 30s"), "{}", reason);
+    }
+}
@@ -0,0 +1,100 @@
+use anyhow::{Context, Result};
+use reqwest::Method;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+use crate::config::AgentConfig;
+use crate::http_client::SecureHttpClient;
+
+/// Pings are best effort and must not hold up a run
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// healthchecks.io caps the request body it stores at 100 KiB
+const MAX_BODY: usize = 100 * 1024;
+
+/// Where a run is, in the ping URL's terms.
+pub enum Ping<'a> {
+    Start,
+    Success(&'a str),
+    Failure(&'a str),
+}
+
+/// Pings `notifications.healthcheck_url`, if set. The start is a GET to
+/// `<url>/start`; the end a POST to `<url>` or `<url>/fail` carrying a
+/// short summary, which the check shows as the ping's body. Both carry the
+/// run id as `rid`, so the service measures how long the run took. A
+/// failed ping is logged and otherwise ignored.
+pub async fn ping(
+    config: &AgentConfig,
+    http_client: &SecureHttpClient,
+    run_id: &str,
+    ping: Ping<'_>,
+) {
+    let Some(base) = &config.notifications.healthcheck_url else {
+        return;
+    };
+    let http_client = http_client.for_subsystem("healthcheck");
+    if let Err(e) = send(&http_client, base, run_id, &ping).await {
+        warn!("Failed to ping healthcheck: {:#}", e);
+    }
+}
+
+async fn send(
+    http_client: &SecureHttpClient,
+    base: &str,
+    run_id: &str,
+    ping: &Ping<'_>,
+) -> Result<()> {
+    let url = ping_url(base, ping, run_id);
+    let request = match ping {
+        Ping::Start => http_client.external(Method::GET, &url),
+        Ping::Success(body) | Ping::Failure(body) => {
+            let body: String = body.chars().take(MAX_BODY).collect();
+            http_client.external(Method::POST, &url).body(body)
+        }
+    };
+    let response = http_client
+        .send_external(request.timeout(TIMEOUT))
+        .await
+        .with_context(|| format!("Failed to reach {}", base))?;
+    if !response.status().is_success() {
+        return Err(anyhow::anyhow!(
+            "Healthcheck returned {}",
+            response.status()
+        ));
+    }
+    debug!("Pinged healthcheck {}", url);
+    Ok(())
+}
+
+fn ping_url(base: &str, ping: &Ping, run_id: &str) -> String {
+    let suffix = match ping {
+        Ping::Start => "/start",
+        Ping::Success(_) => "",
+        Ping::Failure(_) => "/fail",
+    };
+    format!("{}{}?rid={}", base.trim_end_matches('/'), suffix, run_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ping_url() {
+        let base = "https://hc-ping.com/5f1c2e7a/";
+        let rid = "0b6b0c5e-3d6e-4d55-9d7c-1f3c5e1b8a9d";
+        assert_eq!(
+            ping_url(base, &Ping::Start, rid),
+            format!("https://hc-ping.com/5f1c2e7a/start?rid={}", rid)
+        );
+        assert_eq!(
+            ping_url(base, &Ping::Success("3 packages updated"), rid),
+            format!("https://hc-ping.com/5f1c2e7a?rid={}", rid)
+        );
+        assert_eq!(
+            ping_url(base, &Ping::Failure("dpkg failed"), rid),
+            format!("https://hc-ping.com/5f1c2e7a/fail?rid={}", rid)
+        );
+    }
+}
@@ -0,0 +1,330 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use tracing::{debug, warn};
+
+use crate::config::AgentConfig;
+
+/// One update run as persisted in the local history store. Raw package
+/// manager output is left out to keep the store small.
+///
+/// Records are hash-chained: `hash` is the SHA-256 of the record's line as
+/// written without the trailing `hash` field, and that line includes the
+/// previous record's hash as `prev_hash`. Editing, removing or reordering a
+/// record breaks the chain from that point on.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunRecord {
+    pub timestamp: DateTime<Utc>,
+    pub success: bool,
+    pub duration_seconds: f64,
+    pub packages_updated: u64,
+    pub packages_available: u64,
+    pub bytes_downloaded: u64,
+    pub reboot_required: bool,
+    pub error_message: Option<String>,
+    pub skipped_reason: Option<String>,
+    /// Packages the run upgraded or installed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub packages: Vec<String>,
+    /// Set by `HistoryStore::append`; `None` for records from before the
+    /// chain was introduced
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prev_hash: Option<String>,
+    /// Must stay the last field, see above
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+/// Latest link of the run hash chain, included in reports so the backend
+/// can tell when on-device history was rewritten between two reports.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChainHead {
+    pub hash: Option<String>,
+    /// Chained records currently in the store
+    pub length: usize,
+    /// Whether every stored record matches its hash and links to the one
+    /// before it
+    pub intact: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct HistoryFilter {
+    pub failed_only: bool,
+    pub since: Option<DateTime<Utc>>,
+    pub limit: Option<usize>,
+}
+
+pub struct HistoryStore {
+    path: PathBuf,
+    max_entries: usize,
+}
+
+impl HistoryStore {
+    pub fn new(config: &AgentConfig) -> Self {
+        Self {
+            path: config.state.dir.join("history.jsonl"),
+            max_entries: config.state.history_limit,
+        }
+    }
+
+    pub fn append(&self, record: &RunRecord) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory: {:?}", parent))?;
+        }
+
+        let record = RunRecord {
+            prev_hash: self.last()?.and_then(|last| last.hash),
+            hash: None,
+            ..record.clone()
+        };
+        let body = serde_json::to_string(&record)?;
+        let hash = chain_hash(&body);
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("Failed to open history file {:?}", self.path))?;
+        writeln!(file, "{}", chained_line(&body, &hash))
+            .with_context(|| format!("Failed to write history file {:?}", self.path))?;
+
+        self.prune()?;
+        debug!("Recorded run in history {:?}", self.path);
+        Ok(())
+    }
+
+    /// Returns matching records, oldest first.
+    pub fn list(&self, filter: &HistoryFilter) -> Result<Vec<RunRecord>> {
+        let mut records: Vec<RunRecord> = self
+            .read_all()?
+            .into_iter()
+            .filter(|r| !filter.failed_only || !r.success)
+            .filter(|r| filter.since.is_none_or(|since| r.timestamp >= since))
+            .collect();
+
+        if let Some(limit) = filter.limit {
+            if records.len() > limit {
+                records.drain(..records.len() - limit);
+            }
+        }
+        Ok(records)
+    }
+
+    pub fn last(&self) -> Result<Option<RunRecord>> {
+        Ok(self.read_all()?.pop())
+    }
+
+    /// Verifies the hash chain and returns its head. Records from before
+    /// chaining may precede the chain; the first record kept after pruning
+    /// starts it.
+    pub fn chain_head(&self) -> Result<ChainHead> {
+        let mut head = ChainHead {
+            intact: true,
+            ..ChainHead::default()
+        };
+        if !self.path.exists() {
+            return Ok(head);
+        }
+
+        let content = fs::read_to_string(&self.path)
+            .with_context(|| format!("Failed to read history file {:?}", self.path))?;
+        for (number, line) in content.lines().enumerate() {
+            // Torn writes are skipped like in read_all; dropping a valid
+            // record still shows up in the next record's prev_hash
+            let Ok(record) = serde_json::from_str::<RunRecord>(line) else {
+                continue;
+            };
+            let Some(hash) = record.hash else {
+                if head.length > 0 {
+                    warn!("History record {} is not chained", number + 1);
+                    head.intact = false;
+                }
+                continue;
+            };
+
+            // Undo chained_line to get back the hashed body
+            let body_matches = line
+                .strip_suffix(&chained_line("}", &hash))
+                .is_some_and(|body| chain_hash(&format!("{}}}", body)) == hash);
+            let links = head.length == 0 || record.prev_hash == head.hash;
+            if !body_matches || !links {
+                warn!("History record {} breaks the hash chain", number + 1);
+                head.intact = false;
+            }
+            head.hash = Some(hash);
+            head.length += 1;
+        }
+        Ok(head)
+    }
+
+    fn read_all(&self) -> Result<Vec<RunRecord>> {
+        if !self.path.exists() {
+            return Ok(vec![]);
+        }
+
+        let content = fs::read_to_string(&self.path)
+            .with_context(|| format!("Failed to read history file {:?}", self.path))?;
+
+        // Skip lines that fail to parse (e.g. a write torn by power loss)
+        Ok(content
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect())
+    }
+
+    fn prune(&self) -> Result<()> {
+        let content = fs::read_to_string(&self.path)?;
+        let lines: Vec<&str> = content.lines().collect();
+        if lines.len() <= self.max_entries {
+            return Ok(());
+        }
+
+        let kept = lines[lines.len() - self.max_entries..].join("\n") + "\n";
+        fs::write(&self.path, kept)
+            .with_context(|| format!("Failed to prune history file {:?}", self.path))
+    }
+}
+
+fn chain_hash(body: &str) -> String {
+    format!("{:x}", Sha256::digest(body.as_bytes()))
+}
+
+/// Appends `"hash"` as the last field of the serialized record `body`.
+fn chained_line(body: &str, hash: &str) -> String {
+    format!("{},\"hash\":\"{}\"}}", &body[..body.len() - 1], hash)
+}
+
+/// Parses `--since` values: a relative age like "7d", "12h" or "30m", or an
+/// absolute time accepted by `pause --until`.
+pub fn parse_since(value: &str) -> Result<DateTime<Utc>> {
+    let relative = value
+        .strip_suffix('d')
+        .map(|n| (n, 86_400))
+        .or_else(|| value.strip_suffix('h').map(|n| (n, 3_600)))
+        .or_else(|| value.strip_suffix('m').map(|n| (n, 60)));
+
+    if let Some((amount, unit_seconds)) = relative {
+        if let Ok(amount) = amount.parse::<i64>() {
+            return Ok(Utc::now() - chrono::Duration::seconds(amount * unit_seconds));
+        }
+    }
+
+    crate::pause::parse_until(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn record(success: bool, age_hours: i64) -> RunRecord {
+        RunRecord {
+            timestamp: Utc::now() - chrono::Duration::hours(age_hours),
+            success,
+            duration_seconds: 12.5,
+            packages_updated: 3,
+            packages_available: 3,
+            bytes_downloaded: 1024,
+            reboot_required: false,
+            error_message: (!success).then(|| "APT: lock held".to_string()),
+            skipped_reason: None,
+            packages: Vec::new(),
+            prev_hash: None,
+            hash: None,
+        }
+    }
+
+    fn store(dir: &std::path::Path, limit: usize) -> HistoryStore {
+        let mut config = AgentConfig::default();
+        config.state.dir = dir.to_path_buf();
+        config.state.history_limit = limit;
+        HistoryStore::new(&config)
+    }
+
+    #[test]
+    fn test_history_filtering() {
+        let temp_dir = tempdir().unwrap();
+        let store = store(temp_dir.path(), 100);
+
+        store.append(&record(true, 72)).unwrap();
+        store.append(&record(false, 48)).unwrap();
+        store.append(&record(true, 1)).unwrap();
+
+        assert_eq!(store.list(&HistoryFilter::default()).unwrap().len(), 3);
+
+        let failed = HistoryFilter {
+            failed_only: true,
+            ..HistoryFilter::default()
+        };
+        assert_eq!(store.list(&failed).unwrap().len(), 1);
+
+        let recent = HistoryFilter {
+            since: Some(parse_since("2d").unwrap() + chrono::Duration::minutes(1)),
+            ..HistoryFilter::default()
+        };
+        assert_eq!(store.list(&recent).unwrap().len(), 1);
+
+        assert!(store.last().unwrap().unwrap().success);
+    }
+
+    #[test]
+    fn test_history_is_pruned() {
+        let temp_dir = tempdir().unwrap();
+        let store = store(temp_dir.path(), 2);
+
+        store.append(&record(false, 3)).unwrap();
+        store.append(&record(true, 2)).unwrap();
+        store.append(&record(true, 1)).unwrap();
+
+        let records = store.list(&HistoryFilter::default()).unwrap();
+        assert_eq!(records.len(), 2);
+        assert!(records.iter().all(|r| r.success));
+    }
+
+    #[test]
+    fn test_hash_chain_detects_tampering() {
+        let temp_dir = tempdir().unwrap();
+        let store = store(temp_dir.path(), 100);
+        assert_eq!(store.chain_head().unwrap().length, 0);
+
+        // A record from before chaining doesn't count against the chain
+        let legacy = serde_json::to_string(&record(true, 4)).unwrap();
+        fs::write(&store.path, format!("{}\n", legacy)).unwrap();
+        store.append(&record(true, 3)).unwrap();
+        store.append(&record(false, 2)).unwrap();
+        store.append(&record(true, 1)).unwrap();
+
+        let head = store.chain_head().unwrap();
+        assert!(head.intact);
+        assert_eq!(head.length, 3);
+        assert_eq!(head.hash, store.last().unwrap().unwrap().hash);
+
+        // Hide the failed run by flipping its result
+        let content = fs::read_to_string(&store.path).unwrap();
+        let edited = content.replacen("\"success\":false", "\"success\":true", 1);
+        fs::write(&store.path, &edited).unwrap();
+        assert!(!store.chain_head().unwrap().intact);
+
+        // ...or by dropping it
+        let lines: Vec<&str> = content.lines().collect();
+        let dropped = [lines[0], lines[1], lines[3]].join("\n");
+        fs::write(&store.path, dropped).unwrap();
+        assert!(!store.chain_head().unwrap().intact);
+    }
+
+    #[test]
+    fn test_parse_since() {
+        let since = parse_since("12h").unwrap();
+        let expected = Utc::now() - chrono::Duration::hours(12);
+        assert!((since - expected).num_seconds().abs() < 5);
+
+        assert!(parse_since("2024-01-01").is_ok());
+        assert!(parse_since("yesterday").is_err());
+    }
+}
@@ -0,0 +1,90 @@
+use chrono::{DateTime, Utc};
+use std::fs;
+use std::process::Command;
+#[cfg(feature = "sysinfo")]
+use sysinfo::{System, SystemExt};
+use tracing::debug;
+
+use crate::distro::DistroInfo;
+
+/// The host's name, from gethostname(2) or, when that comes back empty or
+/// isn't UTF-8, from the kernel directly.
+pub fn hostname() -> String {
+    gethostname::gethostname()
+        .into_string()
+        .ok()
+        .filter(|name| !name.is_empty())
+        .or_else(|| read_trimmed("/proc/sys/kernel/hostname"))
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// e.g. "Ubuntu 22.04.4 LTS", from `lsb_release -ds` where it's installed
+/// and /etc/os-release otherwise.
+pub fn os_version() -> String {
+    match crate::audit::output(Command::new("lsb_release").arg("-ds")) {
+        Ok(output) if output.status.success() => {
+            return String::from_utf8_lossy(&output.stdout).trim().to_string();
+        }
+        Ok(_) => debug!("lsb_release failed, reading /etc/os-release"),
+        Err(e) => debug!("Failed to run lsb_release: {}", e),
+    }
+    DistroInfo::detect()
+        .pretty_name
+        .unwrap_or_else(|| "Unknown".to_string())
+}
+
+/// The running kernel's release, e.g. "6.8.0-45-generic".
+pub fn kernel_version() -> String {
+    let release = read_trimmed("/proc/sys/kernel/osrelease");
+    #[cfg(feature = "sysinfo")]
+    let release = release.or_else(|| System::new().kernel_version());
+    release.unwrap_or_else(|| "Unknown".to_string())
+}
+
+/// From the `btime` line of /proc/stat, or sysinfo where /proc/stat can't
+/// be read.
+pub fn boot_time() -> Option<DateTime<Utc>> {
+    let btime = fs::read_to_string("/proc/stat").ok().and_then(|stat| {
+        stat.lines()
+            .find_map(|line| line.strip_prefix("btime "))?
+            .trim()
+            .parse()
+            .ok()
+    });
+    #[cfg(feature = "sysinfo")]
+    let btime = btime.or_else(|| Some(System::new().boot_time() as i64));
+    btime
+        .filter(|seconds| *seconds > 0)
+        .and_then(|seconds| DateTime::from_timestamp(seconds, 0))
+}
+
+/// Seconds since boot, from /proc/uptime, or sysinfo where it can't be
+/// read.
+pub fn uptime_seconds() -> u64 {
+    let uptime = read_trimmed("/proc/uptime")
+        .and_then(|uptime| uptime.split_whitespace().next()?.parse::<f64>().ok())
+        .map(|seconds| seconds as u64);
+    #[cfg(feature = "sysinfo")]
+    let uptime = uptime.or_else(|| Some(System::new().uptime()));
+    uptime.unwrap_or_default()
+}
+
+fn read_trimmed(path: &str) -> Option<String> {
+    fs::read_to_string(path)
+        .ok()
+        .map(|content| content.trim().to_string())
+        .filter(|content| !content.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_facts() {
+        assert!(!hostname().is_empty());
+        assert_ne!(kernel_version(), "Unknown");
+        let booted_at = boot_time().unwrap();
+        assert!(booted_at < Utc::now());
+    }
+}
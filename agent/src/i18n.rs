@@ -0,0 +1,182 @@
+use fluent_bundle::concurrent::FluentBundle;
+use fluent_bundle::{FluentArgs, FluentResource};
+use std::sync::OnceLock;
+use tracing::warn;
+use unic_langid::LanguageIdentifier;
+
+/// Bundled translations; English is the fallback for missing messages.
+const RESOURCES: &[(&str, &str)] = &[
+    ("en", include_str!("locales/en.ftl")),
+    ("de", include_str!("locales/de.ftl")),
+    ("es", include_str!("locales/es.ftl")),
+];
+
+const FALLBACK: &str = "en";
+
+struct Localizer {
+    bundle: FluentBundle<FluentResource>,
+    fallback: FluentBundle<FluentResource>,
+}
+
+static LOCALIZER: OnceLock<Localizer> = OnceLock::new();
+
+/// Selects the CLI language. `configured` is the `i18n.locale` setting;
+/// "auto" follows LC_ALL / LC_MESSAGES / LANG.
+pub fn init(configured: &str) {
+    let env_locale = ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .filter_map(|name| std::env::var(name).ok())
+        .find(|value| !value.is_empty());
+
+    let locale = resolve_locale(configured, env_locale.as_deref());
+    let _ = LOCALIZER.set(Localizer::new(locale));
+}
+
+/// Formats a message for the active locale. Prefer the `t!` macro.
+pub fn message(key: &str, args: Option<&FluentArgs>) -> String {
+    let localizer = LOCALIZER.get_or_init(|| Localizer::new(FALLBACK));
+
+    [&localizer.bundle, &localizer.fallback]
+        .into_iter()
+        .find_map(|bundle| {
+            let pattern = bundle.get_message(key)?.value()?;
+            let mut errors = vec![];
+            let text = bundle.format_pattern(pattern, args, &mut errors);
+            if !errors.is_empty() {
+                warn!("Errors formatting message {}: {:?}", key, errors);
+            }
+            Some(text.into_owned())
+        })
+        .unwrap_or_else(|| key.to_string())
+}
+
+/// Translates a message key, e.g. `t!("hold-done", package = name)`.
+macro_rules! t {
+    ($key:expr) => {
+        $crate::i18n::message($key, None)
+    };
+    ($key:expr, $($name:ident = $value:expr),+ $(,)?) => {{
+        let mut args = fluent_bundle::FluentArgs::new();
+        $(args.set(stringify!($name), $value);)+
+        $crate::i18n::message($key, Some(&args))
+    }};
+}
+pub(crate) use t;
+
+/// Localized "yes"/"no".
+pub fn yes_no(value: bool) -> String {
+    message(if value { "yes" } else { "no" }, None)
+}
+
+fn resolve_locale(configured: &str, env_locale: Option<&str>) -> &'static str {
+    let requested = if configured == "auto" {
+        env_locale.unwrap_or(FALLBACK)
+    } else {
+        configured
+    };
+
+    let language = language_of(requested);
+    RESOURCES
+        .iter()
+        .map(|(lang, _)| *lang)
+        .find(|lang| *lang == language)
+        .unwrap_or(FALLBACK)
+}
+
+/// Extracts the language subtag from "de_DE.UTF-8", "es-MX", "C", ...
+fn language_of(locale: &str) -> String {
+    let tag = locale
+        .split(['.', '@'])
+        .next()
+        .unwrap_or_default()
+        .replace('_', "-");
+
+    tag.parse::<LanguageIdentifier>()
+        .map(|id| id.language.as_str().to_string())
+        .unwrap_or_default()
+}
+
+impl Localizer {
+    fn new(locale: &str) -> Self {
+        Self {
+            bundle: build_bundle(locale),
+            fallback: build_bundle(FALLBACK),
+        }
+    }
+}
+
+fn build_bundle(locale: &str) -> FluentBundle<FluentResource> {
+    let (lang, source) = RESOURCES
+        .iter()
+        .find(|(lang, _)| *lang == locale)
+        .unwrap_or(&RESOURCES[0]);
+
+    let id: LanguageIdentifier = lang.parse().expect("bundled locale id is valid");
+    let mut bundle = FluentBundle::new_concurrent(vec![id]);
+    // Unicode isolation marks show up as garbage in many terminals
+    bundle.set_use_isolating(false);
+
+    let resource =
+        FluentResource::try_new(source.to_string()).unwrap_or_else(|(resource, errors)| {
+            warn!("Errors parsing {} translations: {:?}", lang, errors);
+            resource
+        });
+    if let Err(errors) = bundle.add_resource(resource) {
+        warn!("Errors loading {} translations: {:?}", lang, errors);
+    }
+    bundle
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_locale() {
+        assert_eq!(resolve_locale("auto", Some("de_DE.UTF-8")), "de");
+        assert_eq!(resolve_locale("auto", Some("es_MX.UTF-8@euro")), "es");
+        assert_eq!(resolve_locale("auto", Some("C")), "en");
+        assert_eq!(resolve_locale("auto", None), "en");
+        assert_eq!(resolve_locale("de", Some("es_ES.UTF-8")), "de");
+        assert_eq!(resolve_locale("ja", None), "en");
+    }
+
+    #[test]
+    fn test_plural_and_arguments() {
+        let bundle = build_bundle("en");
+        let format = |count: u64| {
+            let mut args = FluentArgs::new();
+            args.set("count", count);
+            args.set("security", 0);
+            let pattern = bundle
+                .get_message("updates-summary")
+                .unwrap()
+                .value()
+                .unwrap();
+            bundle
+                .format_pattern(pattern, Some(&args), &mut vec![])
+                .into_owned()
+        };
+
+        assert_eq!(format(1), "1 pending update, 0 security");
+        assert_eq!(format(3), "3 pending updates, 0 security");
+    }
+
+    #[test]
+    fn test_translations_define_every_english_message() {
+        let english_ids: Vec<&str> = RESOURCES[0]
+            .1
+            .lines()
+            .filter_map(|line| line.split_once(" = ").map(|(id, _)| id))
+            .filter(|id| !id.starts_with([' ', '#']))
+            .collect();
+        assert!(!english_ids.is_empty());
+
+        for (lang, _) in &RESOURCES[1..] {
+            let bundle = build_bundle(lang);
+            for id in &english_ids {
+                assert!(bundle.has_message(id), "{} is missing {}", lang, id);
+            }
+        }
+    }
+}
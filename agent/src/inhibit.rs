@@ -0,0 +1,117 @@
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::{Child, Command};
+use tracing::{debug, warn};
+
+use crate::audit::CommandAudit;
+use crate::rollback::command_exists;
+
+/// How long systemd-inhibit gets to take the lock from logind before the
+/// run goes ahead, and to fail if logind refuses
+const SETTLE: Duration = Duration::from_millis(250);
+
+/// A logind block inhibitor on shutdown and sleep, held for as long as this
+/// value lives.
+///
+/// logind hands the lock out as a file descriptor over D-Bus, so it's held
+/// by a systemd-inhibit child running `cat` on a pipe from the agent.
+/// Dropping the value closes the pipe; cat and systemd-inhibit exit and
+/// logind releases the lock, also when the agent itself dies.
+pub struct Inhibitor {
+    _child: Child,
+    /// Recorded once the lock is released
+    audit: Option<CommandAudit>,
+}
+
+impl Inhibitor {
+    /// Blocks shutdown, reboot and suspend until dropped. `None` where
+    /// logind isn't available, e.g. in containers; the run goes ahead.
+    pub async fn acquire(why: &str) -> Option<Self> {
+        if !command_exists("systemd-inhibit") {
+            debug!("systemd-inhibit not found, not taking a shutdown inhibitor");
+            return None;
+        }
+        let mut command = Command::new("systemd-inhibit");
+        command.args([
+            "--what=shutdown:sleep",
+            "--who=ubuntu-auto-update",
+            &format!("--why={}", why),
+            "--mode=block",
+            "cat",
+        ]);
+        hold(command).await
+    }
+}
+
+impl Drop for Inhibitor {
+    fn drop(&mut self) {
+        if let Some(audit) = self.audit.take() {
+            audit.finish(None, None);
+        }
+    }
+}
+
+async fn hold(mut command: Command) -> Option<Inhibitor> {
+    let mut audit = CommandAudit::of(command.as_std());
+    let mut child = match command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => {
+            warn!("Failed to start systemd-inhibit: {}", e);
+            audit.finish(None, Some(e.to_string()));
+            return None;
+        }
+    };
+
+    tokio::time::sleep(SETTLE).await;
+    match child.try_wait() {
+        Ok(None) => {
+            debug!("Holding shutdown/sleep inhibitor");
+            Some(Inhibitor {
+                _child: child,
+                audit: Some(audit),
+            })
+        }
+        Ok(Some(status)) => {
+            let stderr = child
+                .wait_with_output()
+                .await
+                .map(|output| String::from_utf8_lossy(&output.stderr).trim().to_string())
+                .unwrap_or_default();
+            audit.output(stderr.as_bytes());
+            audit.finish(Some(status), None);
+            warn!(
+                "Failed to take shutdown/sleep inhibitor ({}): {}",
+                status, stderr
+            );
+            None
+        }
+        Err(e) => {
+            warn!("Failed to check systemd-inhibit: {}", e);
+            audit.finish(None, Some(e.to_string()));
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_inhibitor_released_on_drop() {
+        assert!(hold(Command::new("false")).await.is_none());
+
+        let mut inhibitor = hold(Command::new("cat")).await.unwrap();
+        assert!(inhibitor._child.try_wait().unwrap().is_none());
+
+        // Closing the pipe is what ends the helper
+        drop(inhibitor._child.stdin.take());
+        let status = inhibitor._child.wait().await.unwrap();
+        assert!(status.success(), "cat exited with {}", status);
+    }
+}
@@ -0,0 +1,173 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use std::process::Command;
+use tracing::{debug, info};
+
+use crate::history::RunRecord;
+
+/// Prefix of the log line written at the end of every run, followed by the
+/// run record as JSON.
+const RUN_SUMMARY_MARKER: &str = "Run summary: ";
+
+/// Units whose journal is searched for run summaries.
+const AGENT_UNITS: &[&str] = &[
+    "ubuntu-auto-update-agent.service",
+    "ubuntu-auto-update-agentd.service",
+];
+
+/// Journal entries read back, newest first, before giving up.
+const JOURNAL_LINES: &str = "5000";
+
+/// Logs `record` in a form `last_run` can find again, so `status` still has
+/// something to show when the state directory was wiped.
+pub fn log_run_summary(record: &RunRecord) {
+    match serde_json::to_string(record) {
+        Ok(json) => info!("{}{}", RUN_SUMMARY_MARKER, json),
+        Err(e) => debug!("Failed to serialize run summary: {}", e),
+    }
+}
+
+/// The most recent run summary the agent's units logged to the systemd
+/// journal, if journalctl is available and one is still retained.
+pub fn last_run() -> Option<RunRecord> {
+    let mut command = Command::new("journalctl");
+    for unit in AGENT_UNITS {
+        command.args(["--unit", unit]);
+    }
+    command.args([
+        "--output=json",
+        "--output-fields=MESSAGE",
+        "--reverse",
+        "--no-pager",
+        "--lines",
+        JOURNAL_LINES,
+    ]);
+    let output = crate::audit::output(&mut command).ok()?;
+    if !output.status.success() {
+        debug!(
+            "journalctl failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        return None;
+    }
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(parse_journal_entry)
+}
+
+/// Everything the agent's units logged since `since`, oldest first, for
+/// the log bundle uploaded next to a run's report.
+pub fn messages_since(since: DateTime<Utc>) -> Result<Vec<String>> {
+    let mut command = Command::new("journalctl");
+    for unit in AGENT_UNITS {
+        command.args(["--unit", unit]);
+    }
+    command.args([
+        "--output=json",
+        "--output-fields=MESSAGE",
+        "--no-pager",
+        "--since",
+        &format!("@{}", since.timestamp()),
+    ]);
+    let output = crate::audit::output(&mut command).context("Failed to run journalctl")?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "journalctl failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(journal_message)
+        .collect())
+}
+
+/// Extracts a run summary from one `journalctl --output=json` line.
+fn parse_journal_entry(line: &str) -> Option<RunRecord> {
+    let message = journal_message(line)?;
+    let (_, summary) = message.split_once(RUN_SUMMARY_MARKER)?;
+    serde_json::Deserializer::from_str(summary)
+        .into_iter::<RunRecord>()
+        .next()?
+        .ok()
+}
+
+/// The log message of one `journalctl --output=json` line.
+fn journal_message(line: &str) -> Option<String> {
+    let entry: Value = serde_json::from_str(line).ok()?;
+    // journalctl emits messages with control characters (the ANSI colours
+    // of text logs) as byte arrays rather than strings
+    let message = match entry.get("MESSAGE")? {
+        Value::String(message) => message.clone(),
+        Value::Array(bytes) => {
+            let bytes: Vec<u8> = bytes
+                .iter()
+                .filter_map(|byte| byte.as_u64().map(|byte| byte as u8))
+                .collect();
+            String::from_utf8_lossy(&bytes).into_owned()
+        }
+        _ => return None,
+    };
+
+    // With logging.format = "json" the message is inside the log line's JSON
+    match serde_json::from_str::<Value>(&message) {
+        Ok(log_line) => log_line
+            .pointer("/fields/message")
+            .and_then(Value::as_str)
+            .map(str::to_string),
+        Err(_) => Some(message),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_journal_entry() {
+        let summary = r#"{"timestamp":"2026-10-01T03:15:00Z","success":true,"duration_seconds":42.5,"packages_updated":3,"packages_available":3,"bytes_downloaded":1024,"reboot_required":false,"error_message":null,"skipped_reason":null}"#;
+
+        let text = format!(
+            "\u{1b}[2m2026-10-01T03:15:00Z\u{1b}[0m \u{1b}[32m INFO\u{1b}[0m ua_agent::journal: {}{}",
+            RUN_SUMMARY_MARKER, summary
+        );
+        let bytes: Vec<u8> = text.bytes().collect();
+        let entry = serde_json::json!({ "MESSAGE": bytes }).to_string();
+        let record = parse_journal_entry(&entry).unwrap();
+        assert_eq!(record.packages_updated, 3);
+        assert!(record.success);
+
+        let json_log = serde_json::json!({
+            "level": "INFO",
+            "fields": { "message": format!("{}{}", RUN_SUMMARY_MARKER, summary) },
+        })
+        .to_string();
+        let entry = serde_json::json!({ "MESSAGE": json_log }).to_string();
+        assert_eq!(parse_journal_entry(&entry).unwrap().duration_seconds, 42.5);
+
+        let unrelated = serde_json::json!({ "MESSAGE": "Starting update run" }).to_string();
+        assert!(parse_journal_entry(&unrelated).is_none());
+    }
+
+    #[test]
+    fn test_journal_message() {
+        let text = serde_json::json!({ "MESSAGE": "Starting update run" }).to_string();
+        assert_eq!(journal_message(&text).unwrap(), "Starting update run");
+
+        let json_log = serde_json::json!({
+            "level": "WARN",
+            "fields": { "message": "Failed to read apt history" },
+        })
+        .to_string();
+        let entry = serde_json::json!({ "MESSAGE": json_log }).to_string();
+        assert_eq!(
+            journal_message(&entry).unwrap(),
+            "Failed to read apt history"
+        );
+
+        assert!(journal_message(r#"{"__CURSOR":"s=1"}"#).is_none());
+    }
+}
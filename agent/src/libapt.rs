@@ -0,0 +1,50 @@
+use anyhow::{Context, Result};
+use rust_apt::cache::PackageSort;
+use rust_apt::new_cache;
+
+use crate::config::RiskLevel;
+use crate::distro::PocketMap;
+use crate::updater::PendingUpdate;
+
+/// Upgradable packages read from the apt cache through libapt-pkg, with
+/// `updates.package_backend = "libapt"`. Origins are the archives the
+/// candidate is published in, as `apt list --upgradable` prints them.
+pub fn upgradable(pockets: &PocketMap) -> Result<Vec<PendingUpdate>> {
+    let cache = new_cache!().context("Failed to open the apt cache")?;
+    let sort = PackageSort::default().upgradable().names();
+
+    Ok(cache
+        .packages(&sort)
+        .filter_map(|package| {
+            let candidate = package.candidate()?;
+            let origin = candidate
+                .package_files()
+                .filter_map(|file| file.archive().map(str::to_string))
+                .filter(|archive| archive != "now")
+                .collect::<Vec<_>>()
+                .join(",");
+            Some(PendingUpdate {
+                source: "apt".to_string(),
+                package: package.name().to_string(),
+                current_version: package
+                    .installed()
+                    .map(|installed| installed.version().to_string()),
+                candidate_version: candidate.version().to_string(),
+                security: pockets.is_security(&origin),
+                origin,
+                risk: RiskLevel::default(),
+            })
+        })
+        .collect())
+}
+
+/// Bytes to download for upgrading every upgradable package.
+pub fn download_size() -> Result<u64> {
+    let cache = new_cache!().context("Failed to open the apt cache")?;
+    let sort = PackageSort::default().upgradable();
+    Ok(cache
+        .packages(&sort)
+        .filter_map(|package| package.candidate())
+        .map(|candidate| candidate.size())
+        .sum())
+}
@@ -0,0 +1,428 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::ffi::CString;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, UnixListener};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+use crate::config::AgentConfig;
+use crate::history::{HistoryFilter, HistoryStore};
+use crate::pause::PauseManager;
+
+/// Requests larger than this are refused
+const MAX_REQUEST_BYTES: usize = 64 * 1024;
+
+/// A client has this long to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Work the API hands to the daemon loop, so a run requested locally never
+/// overlaps a scheduled one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LocalRequest {
+    Run { force: bool },
+}
+
+/// Small HTTP/JSON API for software on the same host, e.g. a kiosk app that
+/// wants to pause updates during a session or start a run when it is idle.
+/// It listens on a unix socket, whose mode and group decide who may use it,
+/// and optionally on a loopback TCP address that requires a bearer token.
+#[derive(Clone)]
+pub struct LocalApi {
+    config: AgentConfig,
+    token: Option<String>,
+    requests: mpsc::UnboundedSender<LocalRequest>,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: Option<String>,
+    authorization: Option<String>,
+    body: Vec<u8>,
+}
+
+struct Response {
+    status: u16,
+    body: Value,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct RunBody {
+    /// Run outside the maintenance window and despite a pause
+    force: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct PauseBody {
+    until: Option<String>,
+    hours: Option<u32>,
+    days: Option<u32>,
+    reason: Option<String>,
+}
+
+impl LocalApi {
+    pub fn new(
+        config: &AgentConfig,
+        requests: mpsc::UnboundedSender<LocalRequest>,
+    ) -> Result<Self> {
+        let token = match &config.local_api.token_file {
+            Some(path) => Some(
+                std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read local API token from {:?}", path))?
+                    .trim()
+                    .to_string(),
+            ),
+            None => None,
+        };
+        Ok(Self {
+            config: config.clone(),
+            token,
+            requests,
+        })
+    }
+
+    /// Binds the socket (and TCP address, if configured) and serves
+    /// connections in the background.
+    pub fn spawn(self) -> Result<()> {
+        let settings = &self.config.local_api;
+        let socket_path = &settings.socket_path;
+        if let Some(parent) = socket_path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory: {:?}", parent))?;
+        }
+        // A socket left behind by a daemon that didn't shut down cleanly
+        if socket_path.exists() {
+            std::fs::remove_file(socket_path)
+                .with_context(|| format!("Failed to remove stale socket {:?}", socket_path))?;
+        }
+        let unix = UnixListener::bind(socket_path)
+            .with_context(|| format!("Failed to bind local API socket {:?}", socket_path))?;
+        restrict_socket(socket_path, settings.socket_group.as_deref())?;
+        info!("Local API listening on {:?}", socket_path);
+
+        let api = self.clone();
+        tokio::spawn(async move {
+            loop {
+                match unix.accept().await {
+                    Ok((stream, _)) => {
+                        // Reaching the socket is the authorization
+                        tokio::spawn(api.clone().serve(stream, false));
+                    }
+                    Err(e) => warn!("Local API socket accept failed: {}", e),
+                }
+            }
+        });
+
+        if let Some(listen) = &settings.listen {
+            let tcp = std::net::TcpListener::bind(listen)
+                .with_context(|| format!("Failed to bind local API address {}", listen))?;
+            tcp.set_nonblocking(true)?;
+            let tcp = TcpListener::from_std(tcp)?;
+            info!("Local API listening on {}", listen);
+            let api = self.clone();
+            tokio::spawn(async move {
+                loop {
+                    match tcp.accept().await {
+                        Ok((stream, _)) => {
+                            tokio::spawn(api.clone().serve(stream, true));
+                        }
+                        Err(e) => warn!("Local API accept failed: {}", e),
+                    }
+                }
+            });
+        }
+        Ok(())
+    }
+
+    async fn serve<S: AsyncRead + AsyncWrite + Unpin>(self, stream: S, needs_token: bool) {
+        let (reader, mut writer) = tokio::io::split(stream);
+        let response = match tokio::time::timeout(REQUEST_TIMEOUT, read_request(reader)).await {
+            Ok(Ok(request)) if needs_token && !self.authorized(&request) => {
+                error_response(401, "missing or invalid bearer token")
+            }
+            Ok(Ok(request)) => {
+                debug!("Local API {} {}", request.method, request.path);
+                self.handle(&request)
+            }
+            Ok(Err(e)) => error_response(400, &format!("{:#}", e)),
+            Err(_) => error_response(408, "request timed out"),
+        };
+        if let Err(e) = write_response(&mut writer, &response).await {
+            debug!("Failed to write local API response: {}", e);
+        }
+    }
+
+    fn authorized(&self, request: &Request) -> bool {
+        let Some(token) = &self.token else {
+            return false;
+        };
+        request
+            .authorization
+            .as_deref()
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|given| constant_time_eq(given.trim().as_bytes(), token.as_bytes()))
+    }
+
+    fn handle(&self, request: &Request) -> Response {
+        let result = match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/status") => self.status(),
+            ("GET", "/history") => self.history(request.query.as_deref()),
+            ("POST", "/run") => self.run(&request.body),
+            ("POST", "/pause") => self.pause(&request.body),
+            ("DELETE", "/pause") => PauseManager::new(&self.config)
+                .resume()
+                .map(|resumed| ok(json!({ "resumed": resumed }))),
+            (_, "/status" | "/history" | "/run" | "/pause") => {
+                return error_response(405, "method not allowed")
+            }
+            _ => return error_response(404, "not found"),
+        };
+        result.unwrap_or_else(|e| {
+            // A body that doesn't parse is the client's mistake
+            let status = if e.downcast_ref::<serde_json::Error>().is_some() {
+                400
+            } else {
+                500
+            };
+            error_response(status, &format!("{:#}", e))
+        })
+    }
+
+    /// Same as `status --json`.
+    fn status(&self) -> Result<Response> {
+        let status = crate::agent_status(&self.config)?;
+        Ok(ok(serde_json::to_value(status)?))
+    }
+
+    /// `?failed=true&since=7d&limit=10`, like the history subcommand.
+    fn history(&self, query: Option<&str>) -> Result<Response> {
+        let mut filter = HistoryFilter {
+            failed_only: false,
+            since: None,
+            limit: None,
+        };
+        for (key, value) in query
+            .unwrap_or("")
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+        {
+            match key {
+                "failed" => filter.failed_only = value == "true",
+                "since" => filter.since = Some(crate::history::parse_since(value)?),
+                "limit" => filter.limit = Some(value.parse().context("Invalid limit")?),
+                _ => {}
+            }
+        }
+        let records = HistoryStore::new(&self.config).list(&filter)?;
+        Ok(ok(serde_json::to_value(records)?))
+    }
+
+    fn run(&self, body: &[u8]) -> Result<Response> {
+        let body: RunBody = parse_body(body)?;
+        self.requests
+            .send(LocalRequest::Run { force: body.force })
+            .context("The daemon is shutting down")?;
+        Ok(Response {
+            status: 202,
+            body: json!({ "queued": true, "force": body.force }),
+        })
+    }
+
+    fn pause(&self, body: &[u8]) -> Result<Response> {
+        let body: PauseBody = parse_body(body)?;
+        let until = crate::pause::pause_end(body.until.as_deref(), body.hours, body.days)?;
+        let state = PauseManager::new(&self.config).pause(until, body.reason)?;
+        Ok(ok(serde_json::to_value(state)?))
+    }
+}
+
+fn ok(body: Value) -> Response {
+    Response { status: 200, body }
+}
+
+fn error_response(status: u16, message: &str) -> Response {
+    Response {
+        status,
+        body: json!({ "error": message }),
+    }
+}
+
+/// An empty body means all defaults.
+fn parse_body<T: Default + for<'de> Deserialize<'de>>(body: &[u8]) -> Result<T> {
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Ok(T::default());
+    }
+    serde_json::from_slice(body).context("Invalid JSON body")
+}
+
+/// Reads one HTTP/1.1 request: request line, headers and a
+/// Content-Length body. Connections carry a single request.
+async fn read_request<R: AsyncRead + Unpin>(reader: R) -> Result<Request> {
+    let mut reader = BufReader::new(reader.take(MAX_REQUEST_BYTES as u64));
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(anyhow::anyhow!("Malformed request line"));
+    };
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path.to_string(), Some(query.to_string())),
+        None => (target.to_string(), None),
+    };
+    let method = method.to_string();
+
+    let mut content_length = 0;
+    let mut authorization = None;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 {
+            return Err(anyhow::anyhow!("Request headers ended early"));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            return Err(anyhow::anyhow!("Malformed header"));
+        };
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => {
+                content_length = value.trim().parse().context("Invalid Content-Length")?
+            }
+            "authorization" => authorization = Some(value.trim().to_string()),
+            _ => {}
+        }
+    }
+    if content_length > MAX_REQUEST_BYTES {
+        return Err(anyhow::anyhow!("Request body too large"));
+    }
+    let mut body = vec![0; content_length];
+    reader
+        .read_exact(&mut body)
+        .await
+        .context("Request body ended early")?;
+
+    Ok(Request {
+        method,
+        path,
+        query,
+        authorization,
+        body,
+    })
+}
+
+async fn write_response<W: AsyncWrite + Unpin>(writer: &mut W, response: &Response) -> Result<()> {
+    let body = serde_json::to_vec(&response.body)?;
+    let reason = match response.status {
+        200 => "OK",
+        202 => "Accepted",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        _ => "Internal Server Error",
+    };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason,
+        body.len()
+    );
+    writer.write_all(head.as_bytes()).await?;
+    writer.write_all(&body).await?;
+    writer.shutdown().await?;
+    Ok(())
+}
+
+/// Mode 0660, and the group of the software allowed to use the API.
+fn restrict_socket(path: &Path, group: Option<&str>) -> Result<()> {
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o660))
+        .with_context(|| format!("Failed to set permissions on {:?}", path))?;
+    let Some(group) = group else {
+        return Ok(());
+    };
+    let name = CString::new(group)?;
+    // SAFETY: getgrnam gets a NUL-terminated name and returns NULL or a
+    // pointer to a static entry that is read before the next call
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if entry.is_null() {
+        return Err(anyhow::anyhow!(
+            "Unknown group {:?} in local_api.socket_group",
+            group
+        ));
+    }
+    // SAFETY: entry was checked for NULL above
+    let gid = unsafe { (*entry).gr_gid };
+    std::os::unix::fs::chown(path, None, Some(gid))
+        .with_context(|| format!("Failed to change the group of {:?}", path))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn request(api: &LocalApi, raw: &str, needs_token: bool) -> String {
+        let (mut client, server) = tokio::io::duplex(MAX_REQUEST_BYTES);
+        client.write_all(raw.as_bytes()).await.unwrap();
+        api.clone().serve(server, needs_token).await;
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_pause_run_and_auth() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut config = AgentConfig::default();
+        config.state.dir = temp_dir.path().to_path_buf();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let api = LocalApi {
+            config: config.clone(),
+            token: Some("s3cret".to_string()),
+            requests: tx,
+        };
+
+        let body = r#"{"hours": 2, "reason": "kiosk session"}"#;
+        let response = request(
+            &api,
+            &format!(
+                "POST /pause HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            ),
+            false,
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        let pause = PauseManager::new(&config).load().unwrap().unwrap();
+        assert_eq!(pause.reason.as_deref(), Some("kiosk session"));
+
+        let response = request(&api, "POST /run HTTP/1.1\r\n\r\n", true).await;
+        assert!(response.starts_with("HTTP/1.1 401 "), "{}", response);
+        assert!(rx.try_recv().is_err());
+
+        let response = request(
+            &api,
+            "POST /run HTTP/1.1\r\nAuthorization: Bearer s3cret\r\nContent-Length: 15\r\n\r\n{\"force\": true}",
+            true,
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 202 "), "{}", response);
+        assert_eq!(rx.try_recv().unwrap(), LocalRequest::Run { force: true });
+
+        let response = request(&api, "GET /run HTTP/1.1\r\n\r\n", false).await;
+        assert!(response.starts_with("HTTP/1.1 405 "), "{}", response);
+    }
+}
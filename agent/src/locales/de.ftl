@@ -0,0 +1,134 @@
+## Shared
+yes = ja
+no = nein
+
+## status
+status-title = Status des Ubuntu-Auto-Update-Agenten
+status-version = Version: { $version }
+status-backend = Backend-URL: { $url }
+status-enrolled = Status: Registriert
+status-not-enrolled = Status: Nicht registriert
+status-updates-active = Updates: aktiv
+status-updates-observe = Updates: nur beobachten, es werden keine Änderungen vorgenommen
+status-updates-paused-until = Updates: pausiert bis { $until }
+status-updates-paused-indefinitely = Updates: unbefristet pausiert
+status-updates-unknown = Updates: unbekannt ({ $error })
+status-debug = Debug-Protokollierung: { $level } bis { $until }
+status-unattended-conflict = unattended-upgrades: aktiviert ({ $timers }), kann mit dem Agenten um die apt-Sperre konkurrieren
+status-reboot-scheduled = Neustart: geplant für { $time }
+status-timer-healthy = Timer: im Plan, nächster Lauf { $next }
+status-timer-missed-down = Timer: { $count } geplante Läufe verpasst, während der Host aus war
+status-timer-missed-up = Timer: { $count } geplante Läufe verpasst, obwohl der Host lief, { $unit } prüfen
+status-timer-inactive = Timer: { $unit } ist nicht aktiv
+status-timer-not-found = Timer: { $unit } ist nicht installiert
+status-last-update = Letztes Update:
+status-time = Zeitpunkt: { $time }
+status-duration = Dauer: { $seconds } s
+status-success = Erfolgreich: { $value }
+status-exit-code = Exit-Code: { $code }
+status-packages-updated = Aktualisierte Pakete: { $count }
+status-packages-available = Verfügbare Pakete: { $count }
+status-reboot-required = Neustart erforderlich: { $value }
+status-error = Fehler: { $error }
+status-skipped = Übersprungen: { $reason }
+status-no-runs = Keine früheren Läufe aufgezeichnet
+status-from-journal = (aus dem systemd-Journal wiederhergestellt, lokaler Verlauf fehlt)
+
+## pause / resume
+pause-until = Updates pausiert bis { $until }
+pause-indefinitely = Updates unbefristet pausiert
+pause-reason = Grund: { $reason }
+resume-done = Updates fortgesetzt
+resume-not-paused = Updates waren nicht pausiert
+schedule-none = Keine Wartungs- oder Neustartfenster in den nächsten { $days } Tagen
+reboot-cancelled = Für { $time } geplanter Neustart abgebrochen
+reboot-cancelled-other = Ausstehendes Herunterfahren abgebrochen; der Agent hatte keinen Neustart geplant
+
+## debug
+debug-enabled = Protokollierung auf { $level } bis { $until }
+debug-http-capture = Anfragen an das Backend und Antworten werden ebenfalls protokolliert, Zugangsdaten geschwärzt
+debug-disabled = Debug-Protokollierung beendet, wieder auf der konfigurierten Stufe
+debug-not-enabled = Debug-Protokollierung war nicht eingeschaltet
+
+## rollback
+rollback-none = Keine Snapshots aufgezeichnet
+rollback-done = Auf Snapshot { $id } zurückgesetzt; zum Abschluss bitte neu starten
+release-upgrade-available = Ubuntu { $release } ist verfügbar; zum Aktualisieren mit --yes ausführen
+release-upgrade-none = Keine neue Ubuntu-Version verfügbar
+release-upgrade-done = Auf Ubuntu { $release } aktualisiert; zum Abschluss bitte neu starten
+
+## list-updates
+updates-none = Keine ausstehenden Updates
+updates-column-source = QUELLE
+updates-column-package = PAKET
+updates-column-current = AKTUELL
+updates-column-candidate = KANDIDAT
+updates-column-origin = HERKUNFT
+updates-column-security = SICHERHEIT
+updates-column-risk = RISIKO
+risk-low = niedrig
+risk-medium = mittel
+risk-high = hoch
+updates-summary = { $count ->
+        [one] 1 ausstehendes Update
+       *[other] { $count } ausstehende Updates
+    }, davon { $security } Sicherheitsupdates
+
+## hold / unhold
+hold-done = { $package } zurückgehalten
+unhold-done = { $package } freigegeben
+hold-config-updated = excluded_packages in { $path } aktualisiert
+
+## history
+history-none = Keine Läufe aufgezeichnet
+history-column-time = ZEIT
+history-column-result = ERGEBNIS
+history-column-duration = DAUER
+history-column-updated = AKTUALISIERT
+history-column-available = VERFÜGBAR
+history-column-reboot = NEUSTART
+history-column-details = DETAILS
+history-result-ok = ok
+history-result-failed = fehlgeschlagen
+history-result-skipped = übersprungen
+history-chain-intact = Hash-Kette des Verlaufs intakt: { $length } Einträge, Kopf { $hash }
+
+## test
+test-reachable = ✓ Backend erreichbar
+test-status = Status: { $status }
+test-response-time = Antwortzeit: { $ms } ms
+test-healthy = ✓ Backend ist betriebsbereit
+test-unhealthy = ⚠ Backend meldet einen Fehlerstatus
+test-unreachable = ✗ Backend nicht erreichbar: { $error }
+
+download-complete = { $path } heruntergeladen ({ $bytes } Bytes, SHA-256 geprüft)
+
+doctor-title = Ubuntu Auto-Update Agent Diagnose
+doctor-pass = ✓ OK
+doctor-warn = ⚠ WARNUNG
+doctor-fail = ✗ FEHLER
+doctor-summary = { $passed } bestanden, { $warnings } Warnungen, { $failed } fehlgeschlagen
+
+## metrics
+metrics-disabled = Metrikerfassung ist deaktiviert
+
+## motd
+motd-last-run-ok = Ubuntu Auto-Update: letzter Lauf { $time } erfolgreich, { $updated ->
+        [one] 1 Paket aktualisiert
+       *[other] { $updated } Pakete aktualisiert
+    }
+motd-last-run-failed = Ubuntu Auto-Update: letzter Lauf { $time } fehlgeschlagen: { $error }
+motd-last-run-skipped = Ubuntu Auto-Update: letzter Lauf { $time } übersprungen: { $reason }
+motd-pending = { $count ->
+        [one] 1 Aktualisierung ausstehend
+       *[other] { $count } Aktualisierungen ausstehend
+    }
+motd-reboot-required = *** Neustart des Systems erforderlich ***
+
+## notifications
+notify-title-ok = Aktualisierungen auf { $hostname } installiert
+notify-title-failed = Aktualisierung auf { $hostname } fehlgeschlagen
+notify-summary = { $updated } Paket(e) aktualisiert, { $available } noch verfügbar
+notify-reboot-required = Neustart erforderlich
+notify-error = Fehler: { $error }
+notify-wall-reboot = Geplanter Neustart nach Systemaktualisierungen
@@ -0,0 +1,134 @@
+## Shared
+yes = yes
+no = no
+
+## status
+status-title = Ubuntu Auto-Update Agent Status
+status-version = Version: { $version }
+status-backend = Backend URL: { $url }
+status-enrolled = Status: Enrolled
+status-not-enrolled = Status: Not enrolled
+status-updates-active = Updates: active
+status-updates-observe = Updates: observe only, no changes are made
+status-updates-paused-until = Updates: paused until { $until }
+status-updates-paused-indefinitely = Updates: paused indefinitely
+status-updates-unknown = Updates: unknown ({ $error })
+status-debug = Debug logging: { $level } until { $until }
+status-unattended-conflict = unattended-upgrades: enabled ({ $timers }), may contend with the agent for the apt lock
+status-reboot-scheduled = Reboot: scheduled for { $time }
+status-timer-healthy = Timer: on schedule, next run { $next }
+status-timer-missed-down = Timer: { $count } scheduled runs missed while the host was down
+status-timer-missed-up = Timer: { $count } scheduled runs missed while the host was up, check { $unit }
+status-timer-inactive = Timer: { $unit } is not active
+status-timer-not-found = Timer: { $unit } is not installed
+status-last-update = Last Update:
+status-time = Time: { $time }
+status-duration = Duration: { $seconds }s
+status-success = Success: { $value }
+status-exit-code = Exit Code: { $code }
+status-packages-updated = Packages Updated: { $count }
+status-packages-available = Packages Available: { $count }
+status-reboot-required = Reboot Required: { $value }
+status-error = Error: { $error }
+status-skipped = Skipped: { $reason }
+status-no-runs = No previous runs recorded
+status-from-journal = (recovered from the systemd journal, local history is missing)
+
+## pause / resume
+pause-until = Updates paused until { $until }
+pause-indefinitely = Updates paused indefinitely
+pause-reason = Reason: { $reason }
+resume-done = Updates resumed
+resume-not-paused = Updates were not paused
+schedule-none = No maintenance or reboot windows in the next { $days } days
+reboot-cancelled = Cancelled the reboot scheduled for { $time }
+reboot-cancelled-other = Cancelled any pending shutdown; the agent had no reboot scheduled
+
+## debug
+debug-enabled = Logging at { $level } until { $until }
+debug-http-capture = Backend requests and responses are logged too, with credentials redacted
+debug-disabled = Debug logging turned off, back to the configured level
+debug-not-enabled = Debug logging was not turned on
+
+## rollback
+rollback-none = No snapshots recorded
+rollback-done = Rolled back to snapshot { $id }; reboot to complete the restore
+release-upgrade-available = Ubuntu { $release } is available; run with --yes to upgrade
+release-upgrade-none = No new Ubuntu release is available
+release-upgrade-done = Upgraded to Ubuntu { $release }; reboot to finish the upgrade
+
+## list-updates
+updates-none = No pending updates
+updates-column-source = SOURCE
+updates-column-package = PACKAGE
+updates-column-current = CURRENT
+updates-column-candidate = CANDIDATE
+updates-column-origin = ORIGIN
+updates-column-security = SECURITY
+updates-column-risk = RISK
+risk-low = low
+risk-medium = medium
+risk-high = high
+updates-summary = { $count ->
+        [one] 1 pending update
+       *[other] { $count } pending updates
+    }, { $security } security
+
+## hold / unhold
+hold-done = Held { $package }
+unhold-done = Released { $package }
+hold-config-updated = Updated excluded_packages in { $path }
+
+## history
+history-none = No runs recorded
+history-column-time = TIME
+history-column-result = RESULT
+history-column-duration = DURATION
+history-column-updated = UPDATED
+history-column-available = AVAILABLE
+history-column-reboot = REBOOT
+history-column-details = DETAILS
+history-result-ok = ok
+history-result-failed = failed
+history-result-skipped = skipped
+history-chain-intact = History hash chain intact: { $length } records, head { $hash }
+
+## test
+test-reachable = ✓ Backend reachable
+test-status = Status: { $status }
+test-response-time = Response time: { $ms }ms
+test-healthy = ✓ Backend is healthy
+test-unhealthy = ⚠ Backend returned non-success status
+test-unreachable = ✗ Failed to reach backend: { $error }
+
+download-complete = Downloaded { $path } ({ $bytes } bytes, SHA-256 verified)
+
+doctor-title = Ubuntu Auto-Update Agent Doctor
+doctor-pass = ✓ PASS
+doctor-warn = ⚠ WARN
+doctor-fail = ✗ FAIL
+doctor-summary = { $passed } passed, { $warnings } warnings, { $failed } failed
+
+## metrics
+metrics-disabled = Metrics collection is disabled
+
+## motd
+motd-last-run-ok = Ubuntu Auto-Update: last run { $time } succeeded, { $updated ->
+        [one] 1 package updated
+       *[other] { $updated } packages updated
+    }
+motd-last-run-failed = Ubuntu Auto-Update: last run { $time } failed: { $error }
+motd-last-run-skipped = Ubuntu Auto-Update: last run { $time } skipped: { $reason }
+motd-pending = { $count ->
+        [one] 1 update is pending
+       *[other] { $count } updates are pending
+    }
+motd-reboot-required = *** System restart required ***
+
+## notifications
+notify-title-ok = Updates applied on { $hostname }
+notify-title-failed = Update run failed on { $hostname }
+notify-summary = { $updated } package(s) updated, { $available } still available
+notify-reboot-required = reboot required
+notify-error = Error: { $error }
+notify-wall-reboot = Scheduled reboot after system updates
@@ -0,0 +1,134 @@
+## Shared
+yes = sí
+no = no
+
+## status
+status-title = Estado del agente Ubuntu Auto-Update
+status-version = Versión: { $version }
+status-backend = URL del backend: { $url }
+status-enrolled = Estado: Registrado
+status-not-enrolled = Estado: No registrado
+status-updates-active = Actualizaciones: activas
+status-updates-observe = Actualizaciones: solo observación, no se realizan cambios
+status-updates-paused-until = Actualizaciones: en pausa hasta { $until }
+status-updates-paused-indefinitely = Actualizaciones: en pausa indefinida
+status-updates-unknown = Actualizaciones: desconocido ({ $error })
+status-debug = Registro de depuración: { $level } hasta { $until }
+status-unattended-conflict = unattended-upgrades: activado ({ $timers }), puede competir con el agente por el bloqueo de apt
+status-reboot-scheduled = Reinicio: programado para { $time }
+status-timer-healthy = Temporizador: según lo previsto, próxima ejecución { $next }
+status-timer-missed-down = Temporizador: { $count } ejecuciones programadas perdidas mientras el host estaba apagado
+status-timer-missed-up = Temporizador: { $count } ejecuciones programadas perdidas con el host encendido, revise { $unit }
+status-timer-inactive = Temporizador: { $unit } no está activo
+status-timer-not-found = Temporizador: { $unit } no está instalado
+status-last-update = Última actualización:
+status-time = Hora: { $time }
+status-duration = Duración: { $seconds } s
+status-success = Correcta: { $value }
+status-exit-code = Código de salida: { $code }
+status-packages-updated = Paquetes actualizados: { $count }
+status-packages-available = Paquetes disponibles: { $count }
+status-reboot-required = Reinicio necesario: { $value }
+status-error = Error: { $error }
+status-skipped = Omitida: { $reason }
+status-no-runs = No hay ejecuciones anteriores registradas
+status-from-journal = (recuperado del journal de systemd, falta el historial local)
+
+## pause / resume
+pause-until = Actualizaciones en pausa hasta { $until }
+pause-indefinitely = Actualizaciones en pausa indefinida
+pause-reason = Motivo: { $reason }
+resume-done = Actualizaciones reanudadas
+resume-not-paused = Las actualizaciones no estaban en pausa
+schedule-none = No hay ventanas de mantenimiento ni de reinicio en los próximos { $days } días
+reboot-cancelled = Cancelado el reinicio programado para { $time }
+reboot-cancelled-other = Cancelado cualquier apagado pendiente; el agente no tenía ningún reinicio programado
+
+## debug
+debug-enabled = Registrando en { $level } hasta { $until }
+debug-http-capture = También se registran las solicitudes y respuestas del backend, con las credenciales ocultas
+debug-disabled = Registro de depuración desactivado, de vuelta al nivel configurado
+debug-not-enabled = El registro de depuración no estaba activado
+
+## rollback
+rollback-none = No hay instantáneas registradas
+rollback-done = Restaurada la instantánea { $id }; reinicie para completar la restauración
+release-upgrade-available = Ubuntu { $release } está disponible; ejecute con --yes para actualizar
+release-upgrade-none = No hay ninguna versión nueva de Ubuntu disponible
+release-upgrade-done = Actualizado a Ubuntu { $release }; reinicie para completar la actualización
+
+## list-updates
+updates-none = No hay actualizaciones pendientes
+updates-column-source = ORIGEN
+updates-column-package = PAQUETE
+updates-column-current = ACTUAL
+updates-column-candidate = CANDIDATA
+updates-column-origin = REPOSITORIO
+updates-column-security = SEGURIDAD
+updates-column-risk = RIESGO
+risk-low = bajo
+risk-medium = medio
+risk-high = alto
+updates-summary = { $count ->
+        [one] 1 actualización pendiente
+       *[other] { $count } actualizaciones pendientes
+    }, { $security } de seguridad
+
+## hold / unhold
+hold-done = { $package } retenido
+unhold-done = { $package } liberado
+hold-config-updated = excluded_packages actualizado en { $path }
+
+## history
+history-none = No hay ejecuciones registradas
+history-column-time = HORA
+history-column-result = RESULTADO
+history-column-duration = DURACIÓN
+history-column-updated = ACTUALIZADOS
+history-column-available = DISPONIBLES
+history-column-reboot = REINICIO
+history-column-details = DETALLES
+history-result-ok = ok
+history-result-failed = fallida
+history-result-skipped = omitida
+history-chain-intact = Cadena de hashes del historial intacta: { $length } registros, cabeza { $hash }
+
+## test
+test-reachable = ✓ Backend accesible
+test-status = Estado: { $status }
+test-response-time = Tiempo de respuesta: { $ms } ms
+test-healthy = ✓ El backend funciona correctamente
+test-unhealthy = ⚠ El backend devolvió un estado de error
+test-unreachable = ✗ No se pudo contactar con el backend: { $error }
+
+download-complete = { $path } descargado ({ $bytes } bytes, SHA-256 verificado)
+
+doctor-title = Diagnóstico del agente Ubuntu Auto-Update
+doctor-pass = ✓ OK
+doctor-warn = ⚠ AVISO
+doctor-fail = ✗ ERROR
+doctor-summary = { $passed } correctas, { $warnings } avisos, { $failed } fallidas
+
+## metrics
+metrics-disabled = La recopilación de métricas está desactivada
+
+## motd
+motd-last-run-ok = Ubuntu Auto-Update: última ejecución { $time } correcta, { $updated ->
+        [one] 1 paquete actualizado
+       *[other] { $updated } paquetes actualizados
+    }
+motd-last-run-failed = Ubuntu Auto-Update: última ejecución { $time } fallida: { $error }
+motd-last-run-skipped = Ubuntu Auto-Update: última ejecución { $time } omitida: { $reason }
+motd-pending = { $count ->
+        [one] 1 actualización pendiente
+       *[other] { $count } actualizaciones pendientes
+    }
+motd-reboot-required = *** Se requiere reiniciar el sistema ***
+
+## notifications
+notify-title-ok = Actualizaciones aplicadas en { $hostname }
+notify-title-failed = Falló la actualización en { $hostname }
+notify-summary = { $updated } paquete(s) actualizado(s), { $available } aún disponible(s)
+notify-reboot-required = se requiere reiniciar
+notify-error = Error: { $error }
+notify-wall-reboot = Reinicio programado tras las actualizaciones del sistema
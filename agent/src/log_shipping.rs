@@ -0,0 +1,338 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::sync::{mpsc, Notify};
+use tracing::field::{Field, Visit};
+use tracing::{warn, Event, Instrument, Level, Subscriber};
+use tracing_subscriber::layer::Context as LayerContext;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+use crate::config::{AgentConfig, LogShippingConfig, LogShippingTarget};
+use crate::http_client::SecureHttpClient;
+use crate::privacy::Redactor;
+
+/// Name of the span the shipper sends from. Events inside it, such as the
+/// HTTP client's own request logging, are never shipped, or every batch
+/// would produce the next one.
+const SHIPPER_SPAN: &str = "log_shipping";
+
+/// How long the process waits at exit for the last batch
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Set by `start`; until then the layer drops events
+static SENDER: OnceLock<mpsc::Sender<LogRecord>> = OnceLock::new();
+/// Events dropped because the queue was full, reported with the next batch
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+static SHUTDOWN: Notify = Notify::const_new();
+static FLUSHED: Notify = Notify::const_new();
+
+/// One log event as shipped.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LogRecord {
+    pub timestamp: DateTime<Utc>,
+    pub level: String,
+    pub target: String,
+    pub message: String,
+    /// The event's other fields
+    #[serde(skip_serializing_if = "Map::is_empty")]
+    pub fields: Map<String, Value>,
+}
+
+/// Body of a POST to the backend's `logs` endpoint.
+#[derive(Debug, Serialize)]
+struct LogBatch<'a> {
+    hostname: &'a str,
+    agent_version: &'a str,
+    /// Events lost to a full queue since the previous batch
+    dropped: u64,
+    records: &'a [LogRecord],
+}
+
+/// Tracing layer that queues events at or above `log_shipping.level` for
+/// the shipper started by `start`.
+pub struct ShippingLayer {
+    level: Level,
+}
+
+/// The layer for `setup_logging`, or `None` with `log_shipping.enabled`
+/// off.
+pub fn layer(config: &LogShippingConfig) -> Result<Option<ShippingLayer>> {
+    if !config.enabled {
+        return Ok(None);
+    }
+    Ok(Some(ShippingLayer {
+        level: crate::logging::parse_log_level(&config.level)?,
+    }))
+}
+
+impl<S> Layer<S> for ShippingLayer
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    fn on_event(&self, event: &Event<'_>, ctx: LayerContext<'_, S>) {
+        if *event.metadata().level() > self.level {
+            return;
+        }
+        let Some(sender) = SENDER.get() else {
+            return;
+        };
+        if ctx
+            .event_scope(event)
+            .is_some_and(|mut scope| scope.any(|span| span.name() == SHIPPER_SPAN))
+        {
+            return;
+        }
+        if sender.try_send(LogRecord::from_event(event)).is_err() {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl LogRecord {
+    fn from_event(event: &Event<'_>) -> Self {
+        let mut visitor = FieldVisitor::default();
+        event.record(&mut visitor);
+        Self {
+            timestamp: Utc::now(),
+            level: event.metadata().level().as_str().to_lowercase(),
+            target: event.metadata().target().to_string(),
+            message: visitor.message,
+            fields: visitor.fields,
+        }
+    }
+}
+
+#[derive(Default)]
+struct FieldVisitor {
+    message: String,
+    fields: Map<String, Value>,
+}
+
+impl FieldVisitor {
+    fn insert(&mut self, field: &Field, value: Value) {
+        match (field.name(), value) {
+            ("message", Value::String(message)) => self.message = message,
+            (name, value) => {
+                self.fields.insert(name.to_string(), value);
+            }
+        }
+    }
+}
+
+impl Visit for FieldVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.insert(field, Value::from(format!("{:?}", value)));
+    }
+}
+
+/// Starts shipping queued events in batches of `log_shipping.batch_size`,
+/// at least every `flush_interval_seconds`. Does nothing unless the layer
+/// is installed.
+pub fn start(config: &AgentConfig, http_client: &SecureHttpClient) {
+    let shipping = &config.log_shipping;
+    if !shipping.enabled || SENDER.get().is_some() {
+        return;
+    }
+    let client = http_client.for_subsystem("log_shipping");
+    let hostname = crate::host::hostname();
+    let shipper = Shipper {
+        client,
+        hostname: match Redactor::new(config) {
+            Some(redactor) => redactor.hash(&hostname),
+            None => hostname,
+        },
+        target: shipping.target,
+        loki_url: shipping.loki_url.clone(),
+    };
+
+    let (sender, receiver) = mpsc::channel(shipping.max_queue);
+    let _ = SENDER.set(sender);
+    let batch_size = shipping.batch_size;
+    let interval = Duration::from_secs(shipping.flush_interval_seconds);
+    tokio::spawn(
+        async move { shipper.run(receiver, batch_size, interval).await }
+            .instrument(tracing::info_span!(SHIPPER_SPAN)),
+    );
+}
+
+/// Sends what is still queued before the process exits.
+pub async fn shutdown() {
+    if SENDER.get().is_none() {
+        return;
+    }
+    SHUTDOWN.notify_one();
+    let _ = tokio::time::timeout(SHUTDOWN_TIMEOUT, FLUSHED.notified()).await;
+}
+
+struct Shipper {
+    client: SecureHttpClient,
+    hostname: String,
+    target: LogShippingTarget,
+    loki_url: Option<String>,
+}
+
+impl Shipper {
+    async fn run(self, mut receiver: mpsc::Receiver<LogRecord>, size: usize, interval: Duration) {
+        let mut batch = Vec::with_capacity(size);
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            let shutting_down = tokio::select! {
+                record = receiver.recv() => match record {
+                    Some(record) => {
+                        batch.push(record);
+                        if batch.len() < size {
+                            continue;
+                        }
+                        false
+                    }
+                    None => true,
+                },
+                _ = ticker.tick() => false,
+                _ = SHUTDOWN.notified() => {
+                    while let Ok(record) = receiver.try_recv() {
+                        batch.push(record);
+                    }
+                    true
+                }
+            };
+            for chunk in batch.chunks(size) {
+                if let Err(e) = self.send(chunk).await {
+                    warn!("Failed to ship {} log events: {:#}", chunk.len(), e);
+                }
+            }
+            batch.clear();
+            if shutting_down {
+                FLUSHED.notify_one();
+                return;
+            }
+        }
+    }
+
+    async fn send(&self, records: &[LogRecord]) -> Result<()> {
+        let dropped = DROPPED.swap(0, Ordering::Relaxed);
+        if records.is_empty() && dropped == 0 {
+            return Ok(());
+        }
+        let response = match (self.target, &self.loki_url) {
+            (LogShippingTarget::Loki, Some(url)) => {
+                let body = loki_push(&self.hostname, records, dropped);
+                self.client.post_to(url, &body).await?
+            }
+            _ => {
+                let batch = LogBatch {
+                    hostname: &self.hostname,
+                    agent_version: env!("CARGO_PKG_VERSION"),
+                    dropped,
+                    records,
+                };
+                self.client.post("logs", &batch).await?
+            }
+        };
+        if !response.status().is_success() {
+            return Err(anyhow::anyhow!("Log sink returned {}", response.status()));
+        }
+        Ok(())
+    }
+}
+
+/// A Loki push request with one stream per level. Timestamps are
+/// nanoseconds as strings; the other fields go into the line as JSON.
+fn loki_push(hostname: &str, records: &[LogRecord], dropped: u64) -> Value {
+    let mut streams: BTreeMap<&str, Vec<Value>> = BTreeMap::new();
+    for record in records {
+        let line = json!({
+            "target": record.target,
+            "message": record.message,
+            "fields": record.fields,
+        });
+        streams.entry(&record.level).or_default().push(json!([
+            record
+                .timestamp
+                .timestamp_nanos_opt()
+                .unwrap_or_default()
+                .to_string(),
+            line.to_string(),
+        ]));
+    }
+    if dropped > 0 {
+        streams.entry("warn").or_default().push(json!([
+            Utc::now()
+                .timestamp_nanos_opt()
+                .unwrap_or_default()
+                .to_string(),
+            json!({ "message": format!("{} log events dropped, queue full", dropped) }).to_string(),
+        ]));
+    }
+    json!({
+        "streams": streams
+            .into_iter()
+            .map(|(level, values)| json!({
+                "stream": { "job": "ua-agent", "host": hostname, "level": level },
+                "values": values,
+            }))
+            .collect::<Vec<_>>(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_loki_push_groups_by_level() {
+        let record = |level: &str, message: &str| LogRecord {
+            timestamp: DateTime::from_timestamp(1_700_000_000, 5).unwrap(),
+            level: level.to_string(),
+            target: "ua_agent::updater".to_string(),
+            message: message.to_string(),
+            fields: Map::new(),
+        };
+        let body = loki_push(
+            "kiosk-7",
+            &[
+                record("info", "Starting update run"),
+                record("error", "apt-get failed"),
+                record("info", "Update run finished"),
+            ],
+            2,
+        );
+
+        let streams = body["streams"].as_array().unwrap();
+        assert_eq!(streams.len(), 3);
+        assert_eq!(streams[0]["stream"]["level"], "error");
+        assert_eq!(streams[1]["stream"]["host"], "kiosk-7");
+        let info = streams[1]["values"].as_array().unwrap();
+        assert_eq!(info.len(), 2);
+        assert_eq!(info[0][0], "1700000000000000005");
+        let line: Value = serde_json::from_str(info[0][1].as_str().unwrap()).unwrap();
+        assert_eq!(line["message"], "Starting update run");
+        assert_eq!(streams[2]["stream"]["level"], "warn");
+    }
+}
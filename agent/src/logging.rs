@@ -1,20 +1,46 @@
 use anyhow::{Context, Result};
 use std::path::Path;
+use std::sync::{Mutex, OnceLock};
 use tracing::Level;
-use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Registry};
+use tracing_subscriber::{
+    fmt, layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Registry,
+};
 
-use crate::config::LoggingConfig;
+use crate::config::{LogShippingConfig, LoggingConfig, TelemetryConfig};
 
-pub fn setup_logging(config: &LoggingConfig) -> Result<()> {
-    let level = parse_log_level(&config.level)?;
+/// Lets a config reload change the log level of the running subscriber.
+static FILTER_HANDLE: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
 
-    let env_filter = EnvFilter::builder()
-        .with_default_directive(level.into())
-        .from_env_lossy()
-        .add_directive("reqwest=warn".parse().unwrap())
-        .add_directive("rustls=warn".parse().unwrap());
+/// The configured level and a debug window's, which wins while open.
+static LEVELS: Mutex<Levels> = Mutex::new(Levels {
+    configured: String::new(),
+    window: None,
+});
+
+struct Levels {
+    configured: String,
+    window: Option<String>,
+}
+
+impl Levels {
+    fn effective(&self) -> &str {
+        self.window.as_deref().unwrap_or(&self.configured)
+    }
+}
+
+pub fn setup_logging(
+    config: &LoggingConfig,
+    #[cfg_attr(not(feature = "otlp"), allow(unused_variables))] telemetry: &TelemetryConfig,
+    shipping: &LogShippingConfig,
+) -> Result<()> {
+    let (env_filter, handle) = reload::Layer::new(build_filter(&config.level)?);
+    let _ = FILTER_HANDLE.set(handle);
+    LEVELS.lock().unwrap().configured = config.level.clone();
 
     let subscriber = Registry::default().with(env_filter);
+    #[cfg(feature = "otlp")]
+    let subscriber = subscriber.with(crate::telemetry::otlp_layer(telemetry)?);
+    let subscriber = subscriber.with(crate::log_shipping::layer(shipping)?);
 
     // Compose the per-format layers inline. The earlier helper used a
     // generic `F: Layer<S>`, which is too loose to call `.with_writer()`
@@ -78,6 +104,51 @@ pub fn setup_logging(config: &LoggingConfig) -> Result<()> {
     Ok(())
 }
 
+/// Applies a new log level without restarting. Format and file changes still
+/// need a restart since the layers are fixed at startup. While a debug
+/// window is open its level stays in force, and this one follows it.
+pub fn set_log_level(level: &str) -> Result<()> {
+    parse_log_level(level)?;
+    let mut levels = LEVELS.lock().unwrap();
+    levels.configured = level.to_string();
+    if levels.window.is_none() {
+        reload_filter(level)?;
+        tracing::info!("Log level changed to {}", level);
+    }
+    Ok(())
+}
+
+/// Raises the level for a debug window, or with `None` goes back to the
+/// configured one.
+pub fn override_log_level(level: Option<&str>) -> Result<()> {
+    let mut levels = LEVELS.lock().unwrap();
+    let previous = levels.window.clone();
+    levels.window = level.map(str::to_string);
+    if let Err(e) = reload_filter(levels.effective()) {
+        levels.window = previous;
+        return Err(e);
+    }
+    Ok(())
+}
+
+fn reload_filter(level: &str) -> Result<()> {
+    let filter = build_filter(level)?;
+    let handle = FILTER_HANDLE
+        .get()
+        .context("Logging has not been initialized")?;
+    handle.reload(filter).context("Failed to reload log filter")
+}
+
+fn build_filter(level: &str) -> Result<EnvFilter> {
+    let level = parse_log_level(level)?;
+
+    Ok(EnvFilter::builder()
+        .with_default_directive(level.into())
+        .from_env_lossy()
+        .add_directive("reqwest=warn".parse().unwrap())
+        .add_directive("rustls=warn".parse().unwrap()))
+}
+
 // make_file_writer builds a rolling daily file appender plus a non-blocking
 // writer, then leaks the worker guard so the background flush thread keeps
 // running for the life of the process. (Returning the guard would force every
@@ -104,7 +175,7 @@ fn make_file_writer(
     Ok(non_blocking)
 }
 
-fn parse_log_level(level: &str) -> Result<Level> {
+pub fn parse_log_level(level: &str) -> Result<Level> {
     match level.to_lowercase().as_str() {
         "trace" => Ok(Level::TRACE),
         "debug" => Ok(Level::DEBUG),
@@ -1,23 +1,135 @@
+mod accounting;
+mod anomaly;
+mod apt_history;
+mod attestation;
+mod audit;
+mod beacon;
+mod calendar;
+mod commands;
 mod config;
+mod coordination;
+mod crash;
+mod daemon;
+#[cfg(feature = "dbus")]
+mod dbus;
+mod debdelta;
+mod debug_window;
+mod diskspace;
+mod distro;
+mod doctor;
 mod enrollment;
+mod guards;
+mod healthcheck;
+mod history;
+mod host;
 mod http_client;
+mod i18n;
+mod inhibit;
+mod journal;
+#[cfg(feature = "libapt")]
+mod libapt;
+mod local_api;
+mod log_shipping;
 mod logging;
+mod metered;
 mod metrics;
+mod motd;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+#[cfg(feature = "nats")]
+mod nats;
+mod needrestart;
+#[cfg(feature = "dbus")]
+mod packagekit;
+mod panics;
+mod pause;
+mod platform;
+mod policy;
+mod power;
+mod privacy;
+mod privileges;
+mod pro;
+mod reboot;
+mod release;
+#[cfg(feature = "metrics")]
+mod remote_write;
+mod risk;
+mod rollback;
+mod run_events;
+mod runlock;
+mod s3;
+mod sandbox;
+mod sbom;
+mod scanner;
+mod schedule;
+mod services;
+mod sinks;
+mod snapd;
+#[cfg(feature = "otlp")]
+mod telemetry;
+mod templates;
+mod unattended;
+mod units;
 mod updater;
+mod watchdog;
+mod webhook;
+mod window;
 
 use anyhow::{Context, Result};
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
 use std::time::{Duration, Instant};
 use tracing::{debug, error, info, warn};
 
-use crate::config::AgentConfig;
+use crate::accounting::ResourceUsage;
+use crate::anomaly::Anomaly;
+use crate::apt_history::{AptHistory, AptTransaction};
+use crate::attestation::ConfigAttestation;
+use crate::audit::AuditSummary;
+use crate::beacon::BeaconManager;
+use crate::config::{
+    AgentConfig, ExcludedPackage, RiskLevel, RuntimeProfile, SbomFormat, Transport, UpdateMode,
+};
+use crate::coordination::{AppCoordinator, EnterOutcome};
+use crate::crash::{CrashMonitor, CrashSummary};
+use crate::daemon::Daemon;
+use crate::debdelta::DeltaSavings;
+use crate::debug_window::{DebugWindow, DebugWindowManager};
+use crate::diskspace::SpaceCheck;
+use crate::doctor::CheckStatus;
 use crate::enrollment::EnrollmentManager;
+use crate::healthcheck::Ping;
+use crate::history::{ChainHead, HistoryFilter, HistoryStore, RunRecord};
 use crate::http_client::SecureHttpClient;
+use crate::i18n::{t, yes_no};
 use crate::logging::setup_logging;
 use crate::metrics::MetricsCollector;
-use crate::updater::{UpdateManager, UpdateResults as UpdaterUpdateResults};
+use crate::motd::MotdWriter;
+#[cfg(feature = "mqtt")]
+use crate::mqtt::{Availability, MqttTransport};
+#[cfg(feature = "nats")]
+use crate::nats::NatsTransport;
+use crate::pause::{PauseManager, PauseState};
+use crate::platform::{Platform, PlatformReport};
+use crate::policy::PolicySync;
+use crate::privacy::{seal, Redactor, SealedReport};
+use crate::pro::UbuntuProStatus;
+use crate::reboot::{RebootEvent, RebootTracker, ScheduledReboot};
+use crate::rollback::{RollbackOutcome, SnapshotManager, SnapshotRecord};
+use crate::run_events::RunStarted;
+use crate::runlock::{AlreadyRunning, RunLock};
+use crate::sandbox::SandboxStatus;
+use crate::scanner::ScanSummary;
+use crate::schedule::{TimerHealth, TimerState};
+use crate::services::{ServiceQuiesce, ServiceTransition};
+use crate::unattended::{CoexistencePolicy, UnattendedUpgradesStatus};
+use crate::updater::{
+    CommandFailure, DryRunEstimate, FlatpakChange, HeldPackage, PendingUpdate, SnapStatus,
+    UpdateManager, UpdateResults as UpdaterUpdateResults,
+};
+use crate::webhook::RunNotice;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -46,9 +158,60 @@ struct Cli {
 enum Commands {
     /// Run system updates and report to backend
     Run {
-        /// Force run even during maintenance window
+        /// Force run even during maintenance window or while paused
         #[arg(long)]
         force: bool,
+        /// Run on battery power regardless of the [power] settings
+        #[arg(long)]
+        ignore_power: bool,
+    },
+    /// Run continuously, updating every daemon.interval_minutes and
+    /// reloading the configuration on SIGHUP or file change
+    Daemon,
+    /// Pause updates on this host until resumed or the pause expires
+    Pause {
+        /// Resume automatically at this time (RFC 3339 or "YYYY-MM-DD HH:MM")
+        #[arg(long, conflicts_with_all = ["hours", "days"])]
+        until: Option<String>,
+        /// Resume automatically after this many hours
+        #[arg(long)]
+        hours: Option<u32>,
+        /// Resume automatically after this many days, plus any --hours
+        #[arg(long)]
+        days: Option<u32>,
+        /// Reason recorded with the pause and sent to the backend
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    /// Resume updates after a pause
+    Resume,
+    /// Cancel the reboot scheduled after an update run
+    CancelReboot,
+    /// Raise log verbosity for a while, reverting automatically
+    Debug {
+        #[command(subcommand)]
+        action: DebugAction,
+    },
+    /// Show or export this host's upcoming maintenance and reboot windows
+    Schedule {
+        #[command(subcommand)]
+        action: ScheduleAction,
+    },
+    /// Roll back to a snapshot taken before an update run
+    Rollback {
+        /// Snapshot ID to restore (defaults to the most recent one)
+        #[arg(long)]
+        snapshot: Option<String>,
+        /// List snapshots created by the agent instead of rolling back
+        #[arg(long)]
+        list: bool,
+    },
+    /// Upgrade to the next Ubuntu release with do-release-upgrade; needs
+    /// release_upgrade.enabled
+    ReleaseUpgrade {
+        /// Carry out the upgrade instead of only checking for a new release
+        #[arg(long)]
+        yes: bool,
     },
     /// Enroll this agent with the backend
     Enroll {
@@ -58,21 +221,116 @@ enum Commands {
         #[arg(long)]
         hostname: Option<String>,
     },
+    /// Replace the API key with a new one from the backend
+    RotateKey,
     /// Generate default configuration file
     GenerateConfig {
         /// Output path for configuration file
         #[arg(short, long, default_value = "/etc/ubuntu-auto-update/agent.toml")]
         output: PathBuf,
     },
+    /// Generate systemd service and timer units sandboxed for the current
+    /// configuration
+    GenerateUnits {
+        /// Write the units into this directory, e.g. /etc/systemd/system,
+        /// instead of printing them
+        #[arg(short, long)]
+        output_dir: Option<PathBuf>,
+        /// Path of the agent binary in ExecStart=
+        #[arg(long, default_value = "/usr/local/bin/ua-agent")]
+        binary: PathBuf,
+    },
+    /// Refresh package caches and list pending updates without applying them
+    ListUpdates {
+        /// Print machine-readable JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+    /// Hold a package so updates skip it, and add it to excluded_packages
+    Hold {
+        package: String,
+        /// The package is a snap (held through snapd rather than apt-mark)
+        #[arg(long)]
+        snap: bool,
+    },
+    /// Release a held package and remove it from excluded_packages
+    Unhold {
+        package: String,
+        /// The package is a snap
+        #[arg(long)]
+        snap: bool,
+    },
+    /// Re-check pending updates and reboot state and report them to the
+    /// backend; run by the apt hook after packages change outside the agent
+    Refresh,
+    /// Print a CycloneDX SBOM of installed packages
+    Sbom {
+        /// Write to this file instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Also upload it to the backend
+        #[arg(long)]
+        upload: bool,
+        /// Document format; defaults to sbom.format
+        #[arg(long, value_enum)]
+        format: Option<SbomFormat>,
+    },
+    /// Download a file from the backend, e.g. an agent binary or offline
+    /// package bundle, resuming a partial download and checking its SHA-256
+    Download {
+        /// Endpoint under backend.api_prefix, e.g. "bundles/2024-06.tar"
+        endpoint: String,
+        /// Expected SHA-256 of the file, hex encoded
+        #[arg(long)]
+        sha256: String,
+        /// Where to save the file
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Send a post-update health beacon if one is due; run by the beacon
+    /// timer after runs that changed packages
+    Beacon,
+    /// Show previous update runs from the local history store
+    History {
+        /// Only show failed runs
+        #[arg(long)]
+        failed: bool,
+        /// Only show runs since a time or age (e.g. "7d", "12h", "2024-01-01")
+        #[arg(long)]
+        since: Option<String>,
+        /// Show at most this many of the most recent runs
+        #[arg(short = 'n', long)]
+        limit: Option<usize>,
+        /// Print machine-readable JSON
+        #[arg(long)]
+        json: bool,
+        /// Check the history hash chain instead of listing runs
+        #[arg(long)]
+        verify: bool,
+    },
     /// Show agent status and metrics
-    Status,
+    Status {
+        /// Output format
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+        /// Same as --format json
+        #[arg(long)]
+        json: bool,
+    },
     /// Export Prometheus metrics
     Metrics,
     /// Test connectivity to backend
     Test,
+    /// Check root, apt locks, disk space, config, key permissions, backend
+    /// reachability, TLS, clock skew and systemd units
+    Doctor {
+        /// Print machine-readable JSON
+        #[arg(long)]
+        json: bool,
+    },
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct HostReport {
     pub hostname: String,
     pub agent_version: String,
@@ -80,6 +338,50 @@ struct HostReport {
     pub update_results: UpdateResults,
     pub system_info: SystemInfo,
     pub metrics: serde_json::Value,
+    pub pause: Option<PauseState>,
+    pub snapshot: Option<SnapshotRecord>,
+    pub rollback: Option<RollbackOutcome>,
+    pub held_packages: Vec<HeldPackage>,
+    pub policy_version: Option<String>,
+    /// Digest of the effective configuration and the files it came from
+    pub config_attestation: Option<ConfigAttestation>,
+    /// "run" for agent update runs, "apt-hook" for out-of-band refreshes
+    pub trigger: String,
+    pub unattended_upgrades: UnattendedUpgradesStatus,
+    pub mode: UpdateMode,
+    /// Filled in when the agent only observes the host
+    pub pending_updates: Vec<PendingUpdate>,
+    /// Outcome of the last reboot the agent scheduled, reported once
+    pub reboot_event: Option<RebootEvent>,
+    /// Kernel oopses, pstore records and coredumps since the last report
+    pub crashes: CrashSummary,
+    /// Stops and starts of `updates.stop_services` around the upgrade
+    pub service_transitions: Vec<ServiceTransition>,
+    /// Graphics stack updates held back by `graphics.caution`
+    pub graphics_deferred: Vec<String>,
+    /// Updates held back because their risk level isn't scheduled today
+    pub risk_deferred: Vec<String>,
+    /// Head of the local run history hash chain
+    pub history_chain: ChainHead,
+    /// Findings of `scanner.command` after the run
+    pub vulnerabilities: Option<ScanSummary>,
+    /// Health of the agent's systemd timer; `None` without systemd
+    pub timer: Option<TimerHealth>,
+    /// Effective `excluded_packages` and whether each came from the local
+    /// config, the backend policy or both
+    pub excluded_packages: Vec<ExcludedPackage>,
+    /// Matches the run's start event; `None` for runs that never started
+    /// updating, like skipped runs and observe-only reports
+    pub run_id: Option<String>,
+    /// How the run stands out from this host's earlier runs
+    pub anomalies: Vec<Anomaly>,
+    /// apt transactions made outside the agent since it last reported them
+    pub external_changes: Vec<AptTransaction>,
+    /// External commands the agent ran since its last report; `None` with
+    /// `audit.enabled = false`
+    pub command_audit: Option<AuditSummary>,
+    /// Architecture, board and the defaults changed for them
+    pub platform: PlatformReport,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -92,11 +394,32 @@ struct UpdateResults {
     pub reboot_required: bool,
     pub error_message: Option<String>,
     pub apt_output: String,
-    pub snap_output: Option<String>,
-    pub flatpak_output: Option<String>,
+    #[serde(default)]
+    pub snaps: Vec<SnapStatus>,
+    #[serde(default)]
+    pub flatpaks: Vec<FlatpakChange>,
+    #[serde(default)]
+    pub debdelta: Option<DeltaSavings>,
+    #[serde(default)]
+    pub removals_refused: Vec<String>,
+    #[serde(default)]
+    pub disk_space: Vec<SpaceCheck>,
+    #[serde(default)]
+    pub dry_run: Option<DryRunEstimate>,
+    #[serde(default)]
+    pub services_restarted: Vec<ServiceTransition>,
+    #[serde(default)]
+    pub reboot_required_packages: Vec<String>,
+    /// CPU, memory, disk and network use of the package commands
+    #[serde(default)]
+    pub resource_usage: Option<ResourceUsage>,
+    /// "slow" or "stalled" when a package command had to be terminated
+    #[serde(default)]
+    pub command_failure: Option<CommandFailure>,
+    pub skipped_reason: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct SystemInfo {
     pub os_version: String,
     pub kernel_version: String,
@@ -106,6 +429,38 @@ struct SystemInfo {
     pub memory_total_bytes: u64,
     pub memory_available_bytes: u64,
     pub disk_usage_percent: f64,
+    /// `None` without the pro client, i.e. not on Ubuntu
+    #[serde(default)]
+    pub ubuntu_pro: Option<UbuntuProStatus>,
+}
+
+impl HostReport {
+    /// Strips identifying data for the minimal reporting profile: the
+    /// hostname and error messages are hashed, command output and the pause
+    /// reason are dropped.
+    fn redacted(mut self, redactor: &Redactor) -> Self {
+        self.hostname = redactor.hash(&self.hostname);
+
+        let results = &mut self.update_results;
+        results.apt_output.clear();
+        results.error_message = redactor.hash_message(results.error_message.as_deref());
+        results.skipped_reason = redactor.hash_message(results.skipped_reason.as_deref());
+
+        if let Some(pause) = &mut self.pause {
+            pause.reason = None;
+        }
+        if let Some(rollback) = &mut self.rollback {
+            rollback.error_message = redactor.hash_message(rollback.error_message.as_deref());
+        }
+        for transition in &mut self.service_transitions {
+            transition.error_message = redactor.hash_message(transition.error_message.as_deref());
+        }
+        for transaction in &mut self.external_changes {
+            transaction.commandline = None;
+            transaction.requested_by = redactor.hash_message(transaction.requested_by.as_deref());
+        }
+        self
+    }
 }
 
 #[tokio::main]
@@ -113,53 +468,226 @@ async fn main() -> Result<()> {
     let args = Cli::parse();
 
     // Load configuration
-    let mut config = if let Some(config_path) = &args.config {
-        AgentConfig::load_from_file(config_path)
-            .with_context(|| format!("Failed to load config from {:?}", config_path))?
-    } else {
-        AgentConfig::load().unwrap_or_else(|e| {
+    let source = ConfigSource::from_cli(&args);
+    // doctor reports a broken config instead of stopping at it
+    let mut config_error = None;
+    let config = match source.read() {
+        Ok(config) => config,
+        Err(e) if source.path.is_none() || matches!(args.command, Commands::Doctor { .. }) => {
             eprintln!("Warning: Failed to load config, using defaults: {}", e);
+            config_error = Some(format!("{:#}", e));
             AgentConfig::default()
-        })
+        }
+        Err(e) => return Err(e),
     };
-
-    // Apply CLI overrides
-    if let Some(backend_url) = &args.backend_url {
-        config.backend.url = backend_url.clone();
-    }
-    if args.dry_run {
-        config.updates.dry_run = true;
-    }
-
-    // Override log level based on verbosity
-    match args.verbose {
-        0 => {} // Use config default
-        1 => config.logging.level = "debug".to_string(),
-        2 => config.logging.level = "trace".to_string(),
-        _ => config.logging.level = "trace".to_string(),
-    }
-
-    // Validate configuration
-    config
-        .validate()
-        .with_context(|| "Configuration validation failed")?;
+    let mut config = source.apply_overrides(config)?;
+    let sandbox = sandbox::adapt(&mut config);
 
     // Setup logging
-    setup_logging(&config.logging).with_context(|| "Failed to setup logging")?;
+    setup_logging(&config.logging, &config.telemetry, &config.log_shipping)
+        .with_context(|| "Failed to setup logging")?;
+    i18n::init(&config.i18n.locale);
+    panics::install_hook(&config);
+    debug_window::watch(&config);
+    audit::init(&config);
 
     info!(
         "Starting Ubuntu Auto-Update Agent v{}",
         env!("CARGO_PKG_VERSION")
     );
+    for warning in sandbox.warnings(&config) {
+        warn!("Sandbox: {}", warning);
+    }
+    if config.runtime.profile == RuntimeProfile::Minimal {
+        info!("Minimal runtime profile, metrics and host metrics collection are off");
+    }
     debug!("Configuration loaded: backend={}", config.backend.url);
+    // Shared by everything that runs alongside a run or the daemon, so
+    // they go through one connection pool
+    let http_client = matches!(args.command, Commands::Run { .. } | Commands::Daemon)
+        .then(|| SecureHttpClient::new(&config))
+        .transpose()
+        .with_context(|| "Failed to initialize HTTP client")?;
+    if let Some(http_client) = &http_client {
+        if let Err(e) = panics::upload_pending(&config, http_client).await {
+            warn!("Failed to upload panic reports: {:#}", e);
+        }
+        log_shipping::start(&config, http_client);
+    }
 
-    match args.command {
+    let result = match args.command {
         Commands::GenerateConfig { output } => generate_default_config(&output).await,
-        Commands::Run { force } => run_updates(&config, force).await,
+        Commands::GenerateUnits { output_dir, binary } => {
+            generate_units(&config, output_dir, &binary)
+        }
+        Commands::Run { force, .. } => {
+            splay_scheduled_run(&config, force).await;
+            run_updates(&config, force).await
+        }
+        Commands::Daemon => {
+            let http_client = http_client.context("Failed to initialize HTTP client")?;
+            Daemon::new(source, config, http_client).run().await
+        }
+        Commands::Pause {
+            until,
+            hours,
+            days,
+            reason,
+        } => pause_updates(&config, until, hours, days, reason).await,
+        Commands::Resume => resume_updates(&config).await,
+        Commands::CancelReboot => cancel_reboot(&config).await,
+        Commands::Debug {
+            action:
+                DebugAction::Enable {
+                    minutes,
+                    level,
+                    http,
+                },
+        } => enable_debug(&config, minutes, &level, http),
+        Commands::Debug {
+            action: DebugAction::Disable,
+        } => disable_debug(&config),
+        Commands::Schedule {
+            action:
+                ScheduleAction::Export {
+                    ical,
+                    days,
+                    output,
+                    local_only,
+                },
+        } => export_schedule(&config, ical, days, output, local_only).await,
+        Commands::Rollback { snapshot, list } => rollback_updates(&config, snapshot, list).await,
+        Commands::ReleaseUpgrade { yes } => release_upgrade(&config, yes).await,
         Commands::Enroll { token, hostname } => enroll_agent(&config, &token, hostname).await,
-        Commands::Status => show_status(&config).await,
+        Commands::RotateKey => rotate_api_key(&config).await,
+        Commands::ListUpdates { json } => list_updates(&config, json).await,
+        Commands::Hold { package, snap } => {
+            set_package_held(&config, args.config.as_deref(), &package, snap, true).await
+        }
+        Commands::Unhold { package, snap } => {
+            set_package_held(&config, args.config.as_deref(), &package, snap, false).await
+        }
+        Commands::Refresh => refresh_state(&config).await,
+        Commands::Sbom {
+            output,
+            upload,
+            format,
+        } => generate_sbom(&config, output, upload, format).await,
+        Commands::Download {
+            endpoint,
+            sha256,
+            output,
+        } => download_file(&config, &endpoint, &sha256, &output).await,
+        Commands::Beacon => beacon_once(&config).await,
+        Commands::History {
+            failed,
+            since,
+            limit,
+            json,
+            verify: false,
+        } => show_history(&config, failed, since, limit, json).await,
+        Commands::History {
+            json, verify: true, ..
+        } => verify_history(&config, json),
+        Commands::Status { format, json } => {
+            if json || format == OutputFormat::Json {
+                print_status_json(&config)
+            } else {
+                show_status(&config).await
+            }
+        }
         Commands::Metrics => export_metrics(&config).await,
         Commands::Test => test_connectivity(&config).await,
+        Commands::Doctor { json } => {
+            run_doctor(&config, config_error.as_deref(), &sandbox, json).await
+        }
+    };
+
+    #[cfg(feature = "otlp")]
+    telemetry::shutdown();
+    log_shipping::shutdown().await;
+    if let Some(running) = result
+        .as_ref()
+        .err()
+        .and_then(|e| e.downcast_ref::<AlreadyRunning>())
+    {
+        eprintln!("{}", running);
+        std::process::exit(runlock::EXIT_ALREADY_RUNNING);
+    }
+    result
+}
+
+/// Where the configuration comes from and which CLI flags override it, kept
+/// so the daemon can reload with the same overrides.
+struct ConfigSource {
+    path: Option<PathBuf>,
+    backend_url: Option<String>,
+    verbose: u8,
+    dry_run: bool,
+    ignore_power: bool,
+}
+
+impl ConfigSource {
+    fn from_cli(args: &Cli) -> Self {
+        Self {
+            path: args.config.clone(),
+            backend_url: args.backend_url.clone(),
+            verbose: args.verbose,
+            dry_run: args.dry_run,
+            ignore_power: matches!(
+                args.command,
+                Commands::Run {
+                    ignore_power: true,
+                    ..
+                }
+            ),
+        }
+    }
+
+    fn read(&self) -> Result<AgentConfig> {
+        match &self.path {
+            Some(config_path) => AgentConfig::load_from_file(config_path)
+                .with_context(|| format!("Failed to load config from {:?}", config_path)),
+            None => AgentConfig::load(),
+        }
+    }
+
+    /// The file to watch for changes.
+    fn watch_path(&self) -> Option<PathBuf> {
+        self.path.clone().or_else(AgentConfig::find_config_file)
+    }
+
+    fn apply_overrides(&self, mut config: AgentConfig) -> Result<AgentConfig> {
+        // Apply CLI overrides
+        if let Some(backend_url) = &self.backend_url {
+            config.backend.url = backend_url.as_str().into();
+        }
+        if self.dry_run {
+            config.updates.dry_run = true;
+        }
+        if self.ignore_power {
+            config.power.enabled = false;
+        }
+
+        // Override log level based on verbosity
+        match self.verbose {
+            0 => {} // Use config default
+            1 => config.logging.level = "debug".to_string(),
+            2 => config.logging.level = "trace".to_string(),
+            _ => config.logging.level = "trace".to_string(),
+        }
+
+        config.apply_profile();
+
+        // Validate configuration
+        config
+            .validate()
+            .with_context(|| "Configuration validation failed")?;
+        Ok(config)
+    }
+
+    fn reload(&self) -> Result<AgentConfig> {
+        self.apply_overrides(self.read()?)
     }
 }
 
@@ -178,9 +706,32 @@ async fn generate_default_config(output_path: &PathBuf) -> Result<()> {
     Ok(())
 }
 
+/// Timer and cron runs wait out `updates.splay_seconds` or their
+/// `updates.slots` slot; runs started from a terminal or with `--force`
+/// don't.
+async fn splay_scheduled_run(config: &AgentConfig, force: bool) {
+    if force || std::io::stdin().is_terminal() {
+        return;
+    }
+    let delay = schedule::splay(config);
+    if !delay.is_zero() {
+        info!("Splaying the run, starting in {}s", delay.as_secs());
+        tokio::time::sleep(delay).await;
+    }
+}
+
+#[tracing::instrument(skip(config))]
 async fn run_updates(config: &AgentConfig, force: bool) -> Result<()> {
+    let _lock = RunLock::acquire(config)?;
     info!("Starting update run (dry_run={})", config.updates.dry_run);
+    // Before the agent touches apt, so whatever is new was done by others
+    let apt_history = AptHistory::new(config);
+    if let Err(e) = apt_history.collect() {
+        warn!("Failed to read apt history: {:#}", e);
+    }
+    panics::set_phase("preflight");
     let start_time = Instant::now();
+    let started_at = chrono::Utc::now();
 
     // Initialize metrics collector
     let metrics_collector = if config.metrics.enabled {
@@ -199,6 +750,39 @@ async fn run_updates(config: &AgentConfig, force: bool) -> Result<()> {
     // Initialize HTTP client
     let http_client =
         SecureHttpClient::new(config).with_context(|| "Failed to initialize HTTP client")?;
+    if let Some(metrics) = &metrics_collector {
+        metrics.register_http_client(&http_client)?;
+    }
+    let http_client = http_client.for_subsystem("report");
+
+    if let Err(e) = EnrollmentManager::new(config, &http_client)
+        .renew_client_cert_if_due()
+        .await
+    {
+        warn!("Failed to renew client certificate: {:#}", e);
+    }
+
+    // Overlay the backend's fleet policy on the local configuration
+    let (effective_config, policy_version) = if config.policy.enabled {
+        PolicySync::new(config, &http_client).sync(config).await
+    } else {
+        (config.clone(), None)
+    };
+    let config = &effective_config;
+
+    // Observed hosts are never modified, so the window, pause and
+    // coexistence checks below don't apply
+    if config.updates.mode == UpdateMode::Observe {
+        info!("Observe mode, reporting host state without updating");
+        let mut report =
+            observe_host(config, &http_client, metrics_collector.as_ref(), start_time).await?;
+        report.policy_version = policy_version;
+        record_history(config, &report.update_results);
+        report.history_chain = history_chain_head(config);
+        return send_report_to_backend(config, &http_client, &report)
+            .await
+            .with_context(|| "Failed to send report to backend");
+    }
 
     // Initialize update manager
     let mut update_manager = UpdateManager::new(config.clone())
@@ -210,9 +794,265 @@ async fn run_updates(config: &AgentConfig, force: bool) -> Result<()> {
         return Ok(());
     }
 
-    // Run updates
-    let update_result = update_manager.run_updates().await;
-    let duration = start_time.elapsed();
+    // Check operator pause
+    if let Some(pause) = PauseManager::new(config).load()? {
+        if force {
+            warn!("Updates are {}, running anyway (--force)", pause.describe());
+        } else {
+            warn!("Updates are {}, skipping run", pause.describe());
+            let reason = format!("Updates {}", pause.describe());
+            return report_skipped_run(
+                config,
+                &http_client,
+                reason,
+                Some(pause),
+                policy_version,
+                start_time.elapsed(),
+            )
+            .await;
+        }
+    }
+
+    // Two drivers of apt end up fighting over the dpkg lock
+    let unattended = UnattendedUpgradesStatus::detect();
+    if unattended.conflicts() {
+        match CoexistencePolicy::parse(&config.unattended_upgrades.policy)? {
+            CoexistencePolicy::Report => {
+                warn!(
+                    "{}; it may contend with the agent for the apt lock",
+                    unattended.describe()
+                )
+            }
+            CoexistencePolicy::TakeOwnership => {
+                warn!("{}, taking ownership of apt updates", unattended.describe());
+                crate::unattended::take_ownership()
+                    .with_context(|| "Failed to disable unattended-upgrades")?;
+            }
+            CoexistencePolicy::Passive => {
+                let reason = format!("Passive: {}", unattended.describe());
+                info!("{}, only reporting", reason);
+                return report_skipped_run(
+                    config,
+                    &http_client,
+                    reason,
+                    None,
+                    policy_version,
+                    start_time.elapsed(),
+                )
+                .await;
+            }
+        }
+    }
+
+    // Site-specific safety checks, e.g. replication lag or a recent backup
+    if let Some(failure) = crate::guards::check_guards(&config.guards).await {
+        if force {
+            warn!("Update {}, running anyway (--force)", failure);
+        } else {
+            let reason = format!("Deferred: {}", failure);
+            warn!("{}", reason);
+            return report_skipped_run(
+                config,
+                &http_client,
+                reason,
+                None,
+                policy_version,
+                start_time.elapsed(),
+            )
+            .await;
+        }
+    }
+
+    // Dying mid-dpkg on an empty battery corrupts the package database;
+    // dry runs leave dpkg alone
+    let power = crate::power::check_power(&config.power).filter(|_| !config.updates.dry_run);
+    if let Some(failure) = power {
+        if force {
+            warn!("Update deferred: {}, running anyway (--force)", failure);
+        } else {
+            let reason = format!("Deferred: {}", failure);
+            warn!("{}", reason);
+            return report_skipped_run(
+                config,
+                &http_client,
+                reason,
+                None,
+                policy_version,
+                start_time.elapsed(),
+            )
+            .await;
+        }
+    }
+
+    // Cellular data plans pay for every package; with a rate limit set the
+    // apt phase runs capped instead
+    let updates = &config.updates;
+    if updates.respect_metered
+        && updates.metered_rate_limit_kbps.is_none()
+        && !updates.dry_run
+        && crate::metered::connection_metered()
+    {
+        if force {
+            warn!("Connection is metered, running anyway (--force)");
+        } else {
+            let reason = "Deferred: connection is metered".to_string();
+            warn!("{}", reason);
+            return report_skipped_run(
+                config,
+                &http_client,
+                reason,
+                None,
+                policy_version,
+                start_time.elapsed(),
+            )
+            .await;
+        }
+    }
+
+    // Let the local application prepare for (or veto) the update
+    let coordinator = AppCoordinator::new(&config.coordination, &http_client);
+    if let Some(coordinator) = &coordinator {
+        let deferral = match coordinator.enter().await {
+            EnterOutcome::Entered => None,
+            EnterOutcome::Refused(reason) => Some(format!("Deferred by application: {}", reason)),
+            EnterOutcome::Unreachable(e) if coordinator.proceed_if_unreachable() => {
+                warn!(
+                    "Application maintenance endpoint unreachable, proceeding: {}",
+                    e
+                );
+                None
+            }
+            EnterOutcome::Unreachable(e) => Some(format!(
+                "Deferred: application maintenance endpoint unreachable: {}",
+                e
+            )),
+        };
+
+        if let Some(reason) = deferral {
+            warn!("{}", reason);
+            return report_skipped_run(
+                config,
+                &http_client,
+                reason,
+                None,
+                policy_version,
+                start_time.elapsed(),
+            )
+            .await;
+        }
+    }
+
+    // Everything from here to the reporting runs in the application's
+    // maintenance mode, which is left however the block returns
+    let maintenance = async {
+        // Announce the run before anything changes, so the backend can tell a
+        // host that died mid-update from one that is still busy
+        let planned = update_manager
+            .list_pending_updates(false)
+            .await
+            .unwrap_or_else(|e| {
+                warn!("Failed to list planned updates: {}", e);
+                Vec::new()
+            });
+        let run_started = RunStarted::new(config, policy_version.clone(), planned);
+        if let Err(e) = run_events::announce(config, &http_client, &run_started).await {
+            warn!("Failed to announce run start: {:#}", e);
+        }
+        healthcheck::ping(config, &http_client, &run_started.run_id, Ping::Start).await;
+
+        // Keep a user or power manager from suspending or rebooting the host
+        // halfway through dpkg; released before any reboot is scheduled
+        let inhibitor = if config.power.inhibit && !config.updates.dry_run {
+            crate::inhibit::Inhibitor::acquire("Installing updates").await
+        } else {
+            None
+        };
+
+        // Stop services that shouldn't run while their packages are replaced,
+        // before the snapshot so it captures them shut down cleanly
+        let quiesce = ServiceQuiesce::new(config);
+        let mut service_transitions = quiesce
+            .as_ref()
+            .map(|quiesce| quiesce.stop())
+            .unwrap_or_default();
+
+        // Snapshot the system so a failed upgrade can be rolled back
+        panics::set_phase("snapshot");
+        let snapshot_manager = SnapshotManager::new(config);
+        let snapshot = if config.snapshot.enabled && !config.updates.dry_run {
+            match snapshot_manager.create_snapshot("Before ubuntu-auto-update run") {
+                Ok(record) => Some(record),
+                Err(e) if config.updates.rollback_on_failure => {
+                    if let Some(quiesce) = &quiesce {
+                        quiesce.start(&service_transitions);
+                    }
+                    return Err(e.context("Refusing to update without a rollback snapshot"));
+                }
+                Err(e) => {
+                    warn!("Failed to create pre-update snapshot: {}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        // Run updates
+        let update_result = update_manager.run_updates().await;
+        let duration = start_time.elapsed();
+
+        // Roll back failed upgrades if configured
+        let update_failed = !matches!(&update_result, Ok(results) if results.success);
+        let refused = matches!(&update_result, Ok(results) if results.refused_before_changes());
+        let rollback = match &snapshot {
+            Some(record) if update_failed && !refused && config.updates.rollback_on_failure => {
+                panics::set_phase("rollback");
+                let outcome = snapshot_manager.rollback(record);
+                if let Some(e) = &outcome.error_message {
+                    error!("Rollback to snapshot {} failed: {}", record.id, e);
+                }
+                Some(outcome)
+            }
+            _ => None,
+        };
+        let rollback_reboot = rollback.as_ref().is_some_and(|r| r.reboot_required);
+        if let Err(e) = apt_history.skip_to_end() {
+            warn!("Failed to skip the run's own apt history: {:#}", e);
+        }
+
+        if let Some(quiesce) = &quiesce {
+            let started = quiesce.start(&service_transitions);
+            service_transitions.extend(started);
+        }
+        drop(inhibitor);
+        Ok((
+            run_started,
+            snapshot,
+            update_result,
+            duration,
+            rollback,
+            rollback_reboot,
+            service_transitions,
+        ))
+    };
+    let (
+        run_started,
+        snapshot,
+        update_result,
+        duration,
+        rollback,
+        rollback_reboot,
+        service_transitions,
+    ) = coordination::in_maintenance(coordinator.as_ref(), maintenance).await?;
+
+    panics::set_phase("reporting");
+    let held_packages = update_manager
+        .list_held_packages()
+        .await
+        .unwrap_or_else(|e| {
+            warn!("Failed to list held packages: {}", e);
+            Vec::new()
+        });
 
     // Collect system metrics if enabled
     let system_metrics = if let Some(metrics) = &metrics_collector {
@@ -233,6 +1073,10 @@ async fn run_updates(config: &AgentConfig, force: bool) -> Result<()> {
                 );
                 metrics.set_packages_available(results.packages_available);
                 metrics.set_reboot_required(results.reboot_required);
+                metrics.set_disk_space_preflight(&results.disk_space);
+                if let Some(usage) = &results.resource_usage {
+                    metrics.set_resource_usage(usage);
+                }
             }
             Err(_) => {
                 metrics.record_update_completion(
@@ -243,24 +1087,77 @@ async fn run_updates(config: &AgentConfig, force: bool) -> Result<()> {
                 );
             }
         }
+        record_crash_metrics(config, metrics);
+        record_pro_metrics(metrics);
 
         // Write textfile metrics
         if let Err(e) = metrics.write_textfile_metrics().await {
             warn!("Failed to write textfile metrics: {}", e);
         }
+        push_remote_write(config, &http_client, metrics).await;
+    }
+
+    // Ahead of the report, so the healthcheck hears about the run even
+    // when the backend is unreachable
+    let outcome = match &update_result {
+        Ok(results) if results.success => Ok(format!(
+            "{} package(s) updated, {} available, reboot required: {}",
+            results.packages_updated, results.packages_available, results.reboot_required
+        )),
+        Ok(results) => Err(results
+            .error_message
+            .clone()
+            .unwrap_or_else(|| "Update failed".to_string())),
+        Err(e) => Err(format!("{:#}", e)),
+    };
+    let ping = match &outcome {
+        Ok(summary) => Ping::Success(summary),
+        Err(error) => Ping::Failure(error),
+    };
+    healthcheck::ping(config, &http_client, &run_started.run_id, ping).await;
+    let notice = RunNotice::new(
+        config,
+        &run_started.run_id,
+        update_result.as_ref().ok(),
+        outcome.as_ref().err().cloned(),
+    );
+    webhook::notify(config, &http_client, &notice).await;
+    if config.s3.enabled {
+        upload_log_bundle(config, &http_client, &run_started.run_id, started_at).await;
     }
 
     // Send report to backend
     match &update_result {
         Ok(results) => {
             let converted_results = convert_updater_results(results);
-            let report = create_host_report(
+            let record = record_history(config, &converted_results);
+            if results.packages_updated > 0 && !config.updates.dry_run {
+                if let Err(e) = BeaconManager::new(config).arm(chrono::Utc::now()) {
+                    warn!("Failed to start health beacons: {}", e);
+                }
+            }
+            let mut report = create_host_report(
                 config,
                 &converted_results,
                 system_metrics.as_ref(),
                 duration,
             )?;
-            send_report_to_backend(&http_client, &report)
+            report.snapshot = snapshot;
+            report.rollback = rollback;
+            report.held_packages = held_packages;
+            report.policy_version = policy_version;
+            report.service_transitions = service_transitions;
+            report.graphics_deferred = results.graphics_deferred.clone();
+            report.risk_deferred = results.risk_deferred.clone();
+            if !config.updates.dry_run {
+                report.anomalies = anomaly::detect(config, &record);
+            }
+            report.run_id = Some(run_started.run_id.clone());
+            report.vulnerabilities = scanner::scan(config).await.unwrap_or_else(|e| {
+                warn!("Vulnerability scan failed: {:#}", e);
+                None
+            });
+            send_report_to_backend(config, &http_client, &report)
                 .await
                 .with_context(|| "Failed to send report to backend")?;
 
@@ -269,13 +1166,17 @@ async fn run_updates(config: &AgentConfig, force: bool) -> Result<()> {
                 duration.as_secs_f64()
             );
 
+            if let Err(e) = sbom::upload_if_due(config, &http_client.for_subsystem("sbom")).await {
+                warn!("Failed to upload SBOM: {:#}", e);
+            }
+
             // Handle reboot if required and enabled
-            if results.reboot_required && config.updates.auto_reboot {
+            if (results.reboot_required || rollback_reboot) && config.updates.auto_reboot {
                 info!(
                     "Reboot required, scheduling reboot in {} minutes",
                     config.updates.reboot_delay_minutes
                 );
-                schedule_reboot(config.updates.reboot_delay_minutes).await?;
+                schedule_reboot(config).await?;
             }
 
             Ok(())
@@ -293,24 +1194,711 @@ async fn run_updates(config: &AgentConfig, force: bool) -> Result<()> {
                 reboot_required: false,
                 error_message: Some(e.to_string()),
                 apt_output: String::new(),
-                snap_output: None,
-                flatpak_output: None,
+                snaps: Vec::new(),
+                flatpaks: Vec::new(),
+                debdelta: None,
+                removals_refused: Vec::new(),
+                disk_space: Vec::new(),
+                dry_run: None,
+                services_restarted: Vec::new(),
+                reboot_required_packages: Vec::new(),
+                resource_usage: None,
+                command_failure: None,
+                skipped_reason: None,
             };
+            record_history(config, &error_results);
 
-            let report =
+            let mut report =
                 create_host_report(config, &error_results, system_metrics.as_ref(), duration)?;
-            let _ = send_report_to_backend(&http_client, &report).await;
+            report.snapshot = snapshot;
+            report.rollback = rollback;
+            report.held_packages = held_packages;
+            report.policy_version = policy_version;
+            report.service_transitions = service_transitions;
+            report.run_id = Some(run_started.run_id.clone());
+            let _ = send_report_to_backend(config, &http_client, &report).await;
+
+            if rollback_reboot && config.updates.auto_reboot {
+                schedule_reboot(config).await?;
+            }
 
             Err(anyhow::anyhow!("Update failed: {}", e))
         }
     }
 }
 
+fn record_history(config: &AgentConfig, results: &UpdateResults) -> RunRecord {
+    let record = RunRecord {
+        timestamp: chrono::Utc::now(),
+        success: results.success,
+        duration_seconds: results.duration_seconds,
+        packages_updated: results.packages_updated,
+        packages_available: results.packages_available,
+        bytes_downloaded: results.bytes_downloaded,
+        reboot_required: results.reboot_required,
+        error_message: results.error_message.clone(),
+        skipped_reason: results.skipped_reason.clone(),
+        packages: crate::updater::configured_packages(&results.apt_output),
+        prev_hash: None,
+        hash: None,
+    };
+
+    journal::log_run_summary(&record);
+    if let Err(e) = HistoryStore::new(config).append(&record) {
+        warn!("Failed to record run history: {}", e);
+    }
+
+    if let Some(motd) = MotdWriter::new(&config.motd, &config.notifications.templates) {
+        if let Err(e) = motd.write(&record) {
+            warn!("Failed to update MOTD summary: {}", e);
+        }
+    }
+    record
+}
+
+async fn report_skipped_run(
+    config: &AgentConfig,
+    http_client: &SecureHttpClient,
+    reason: String,
+    pause: Option<PauseState>,
+    policy_version: Option<String>,
+    duration: Duration,
+) -> Result<()> {
+    let results = UpdateResults {
+        success: true,
+        duration_seconds: duration.as_secs_f64(),
+        packages_updated: 0,
+        packages_available: 0,
+        bytes_downloaded: 0,
+        reboot_required: false,
+        error_message: None,
+        apt_output: String::new(),
+        snaps: Vec::new(),
+        flatpaks: Vec::new(),
+        debdelta: None,
+        removals_refused: Vec::new(),
+        disk_space: Vec::new(),
+        dry_run: None,
+        services_restarted: Vec::new(),
+        reboot_required_packages: Vec::new(),
+        resource_usage: None,
+        command_failure: None,
+        skipped_reason: Some(reason),
+    };
+    record_history(config, &results);
+
+    let mut report = create_host_report(config, &results, None, duration)?;
+    report.pause = pause;
+    report.policy_version = policy_version;
+    send_report_to_backend(config, http_client, &report)
+        .await
+        .with_context(|| "Failed to send skipped-run report to backend")
+}
+
+async fn pause_updates(
+    config: &AgentConfig,
+    until: Option<String>,
+    hours: Option<u32>,
+    days: Option<u32>,
+    reason: Option<String>,
+) -> Result<()> {
+    let until = crate::pause::pause_end(until.as_deref(), hours, days)?;
+
+    let state = PauseManager::new(config)
+        .pause(until, reason)
+        .with_context(|| "Failed to pause updates")?;
+
+    match state.until {
+        Some(until) => println!("{}", t!("pause-until", until = format_local_time(until))),
+        None => println!("{}", t!("pause-indefinitely")),
+    }
+    if let Some(reason) = state.reason {
+        println!("{}", t!("pause-reason", reason = reason));
+    }
+    Ok(())
+}
+
+async fn resume_updates(config: &AgentConfig) -> Result<()> {
+    let was_paused = PauseManager::new(config)
+        .resume()
+        .with_context(|| "Failed to resume updates")?;
+
+    if was_paused {
+        println!("{}", t!("resume-done"));
+    } else {
+        println!("{}", t!("resume-not-paused"));
+    }
+    Ok(())
+}
+
+fn enable_debug(config: &AgentConfig, minutes: u32, level: &str, http: bool) -> Result<()> {
+    let window = DebugWindowManager::new(config)
+        .enable(minutes, level, http)
+        .with_context(|| "Failed to enable debug logging")?;
+
+    println!(
+        "{}",
+        t!(
+            "debug-enabled",
+            level = window.level.as_str(),
+            until = format_local_time(window.until)
+        )
+    );
+    if window.http_capture {
+        println!("{}", t!("debug-http-capture"));
+    }
+    Ok(())
+}
+
+fn disable_debug(config: &AgentConfig) -> Result<()> {
+    let was_enabled = DebugWindowManager::new(config)
+        .disable()
+        .with_context(|| "Failed to disable debug logging")?;
+
+    if was_enabled {
+        println!("{}", t!("debug-disabled"));
+    } else {
+        println!("{}", t!("debug-not-enabled"));
+    }
+    Ok(())
+}
+
+/// `beacon` subcommand, for the beacon timer without the daemon.
+async fn beacon_once(config: &AgentConfig) -> Result<()> {
+    let http_client =
+        SecureHttpClient::new(config).with_context(|| "Failed to initialize HTTP client")?;
+    BeaconManager::new(config)
+        .send_if_due(config, &http_client)
+        .await
+        .map(|_| ())
+}
+
+async fn cancel_reboot(config: &AgentConfig) -> Result<()> {
+    match crate::reboot::cancel(config)? {
+        Some(reboot) => println!(
+            "{}",
+            t!(
+                "reboot-cancelled",
+                time = format_local_time(reboot.expected_at)
+            )
+        ),
+        None => println!("{}", t!("reboot-cancelled-other")),
+    }
+    Ok(())
+}
+
+async fn rollback_updates(
+    config: &AgentConfig,
+    snapshot: Option<String>,
+    list: bool,
+) -> Result<()> {
+    let manager = SnapshotManager::new(config);
+
+    if list {
+        let records = manager.list()?;
+        if records.is_empty() {
+            println!("{}", t!("rollback-none"));
+        }
+        for record in records {
+            println!(
+                "{}  {:<9?}  {}  {}",
+                record.created_at.format("%Y-%m-%d %H:%M:%S"),
+                record.backend,
+                record.id,
+                record.description
+            );
+        }
+        return Ok(());
+    }
+
+    let _lock = RunLock::acquire(config)?;
+    let record = manager.find(snapshot.as_deref())?;
+    let outcome = manager.rollback(&record);
+    if outcome.success {
+        println!("{}", t!("rollback-done", id = record.id.as_str()));
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "Rollback to snapshot {} failed: {}",
+            record.id,
+            outcome.error_message.unwrap_or_default()
+        ))
+    }
+}
+
+fn generate_units(config: &AgentConfig, output_dir: Option<PathBuf>, binary: &Path) -> Result<()> {
+    let units = units::generate(config, binary);
+    match output_dir {
+        Some(dir) => {
+            units::write_all(&units, &dir)?;
+            info!(
+                "Wrote {} units to {:?}; run systemctl daemon-reload to pick them up",
+                units.len(),
+                dir
+            );
+        }
+        None => {
+            for unit in &units {
+                println!("# {}\n{}", unit.name, unit.contents);
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn release_upgrade(config: &AgentConfig, yes: bool) -> Result<()> {
+    let upgrader = release::ReleaseUpgrader::new(config)?;
+    if !yes {
+        match upgrader.available().await? {
+            Some(release) => println!(
+                "{}",
+                t!("release-upgrade-available", release = release.as_str())
+            ),
+            None => println!("{}", t!("release-upgrade-none")),
+        }
+        return Ok(());
+    }
+
+    let _lock = RunLock::acquire(config)?;
+    let release = upgrader.upgrade().await?;
+    println!("{}", t!("release-upgrade-done", release = release.as_str()));
+    Ok(())
+}
+
+async fn list_updates(config: &AgentConfig, json: bool) -> Result<()> {
+    let update_manager = UpdateManager::new(config.clone())
+        .with_context(|| "Failed to initialize update manager")?;
+
+    let pending = update_manager
+        .list_pending_updates(true)
+        .await
+        .with_context(|| "Failed to list pending updates")?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&pending)?);
+    } else {
+        print_pending_table(&pending);
+    }
+    Ok(())
+}
+
+fn print_pending_table(pending: &[PendingUpdate]) {
+    if pending.is_empty() {
+        println!("{}", t!("updates-none"));
+        return;
+    }
+
+    let rows: Vec<[String; 7]> = pending
+        .iter()
+        .map(|update| {
+            [
+                update.source.clone(),
+                update.package.clone(),
+                update
+                    .current_version
+                    .clone()
+                    .unwrap_or_else(|| "-".to_string()),
+                update.candidate_version.clone(),
+                update.origin.clone(),
+                if update.security {
+                    yes_no(true)
+                } else {
+                    String::new()
+                },
+                match update.risk {
+                    RiskLevel::Low => t!("risk-low"),
+                    RiskLevel::Medium => t!("risk-medium"),
+                    RiskLevel::High => t!("risk-high"),
+                },
+            ]
+        })
+        .collect();
+
+    let headers = [
+        t!("updates-column-source"),
+        t!("updates-column-package"),
+        t!("updates-column-current"),
+        t!("updates-column-candidate"),
+        t!("updates-column-origin"),
+        t!("updates-column-security"),
+        t!("updates-column-risk"),
+    ];
+    let mut widths = headers.each_ref().map(|h| h.chars().count());
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let format_row = |cells: [&str; 7]| {
+        cells
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ")
+            .trim_end()
+            .to_string()
+    };
+
+    println!("{}", format_row(headers.each_ref().map(String::as_str)));
+    for row in &rows {
+        println!("{}", format_row(row.each_ref().map(String::as_str)));
+    }
+
+    let security = pending.iter().filter(|u| u.security).count();
+    println!(
+        "\n{}",
+        t!(
+            "updates-summary",
+            count = pending.len(),
+            security = security
+        )
+    );
+}
+
+async fn set_package_held(
+    config: &AgentConfig,
+    config_path: Option<&std::path::Path>,
+    package: &str,
+    snap: bool,
+    held: bool,
+) -> Result<()> {
+    let _lock = RunLock::acquire(config)?;
+    let update_manager = UpdateManager::new(config.clone())
+        .with_context(|| "Failed to initialize update manager")?;
+    update_manager.set_package_held(package, snap, held).await?;
+
+    // Snap holds live in snapd; apt holds are mirrored into excluded_packages
+    // so the next run re-applies them even if someone runs apt-mark unhold.
+    if !snap {
+        match config_path
+            .map(|path| path.to_path_buf())
+            .or_else(AgentConfig::find_config_file)
+        {
+            Some(path) => {
+                if AgentConfig::set_package_excluded(&path, package, held)? {
+                    println!(
+                        "{}",
+                        t!("hold-config-updated", path = path.display().to_string())
+                    );
+                }
+            }
+            None => warn!("No TOML config file found, excluded_packages not updated"),
+        }
+    }
+
+    if held {
+        println!("{}", t!("hold-done", package = package));
+    } else {
+        println!("{}", t!("unhold-done", package = package));
+    }
+    Ok(())
+}
+
+async fn refresh_state(config: &AgentConfig) -> Result<()> {
+    info!("Refreshing state after external package changes");
+    let start_time = Instant::now();
+
+    let metrics = if config.metrics.enabled {
+        Some(
+            MetricsCollector::new(config.metrics.clone())
+                .with_context(|| "Failed to initialize metrics collector")?,
+        )
+    } else {
+        None
+    };
+
+    let http_client = SecureHttpClient::new(config)
+        .with_context(|| "Failed to initialize HTTP client")?
+        .for_subsystem("report");
+    let mut report = observe_host(config, &http_client, metrics.as_ref(), start_time).await?;
+    report.trigger = "apt-hook".to_string();
+    send_report_to_backend(config, &http_client, &report)
+        .await
+        .with_context(|| "Failed to send refresh report to backend")
+}
+
+/// Collects pending updates, reboot-required and held packages without
+/// changing anything on the host. Reads the existing apt cache rather than
+/// running apt-get update, which would take the apt lock (possibly still held
+/// when the apt hook fires) and write to /var/lib/apt/lists.
+async fn observe_host(
+    config: &AgentConfig,
+    http_client: &SecureHttpClient,
+    metrics: Option<&MetricsCollector>,
+    start_time: Instant,
+) -> Result<HostReport> {
+    let update_manager = UpdateManager::new(config.clone())
+        .with_context(|| "Failed to initialize update manager")?;
+
+    let pending = update_manager
+        .list_pending_updates(false)
+        .await
+        .with_context(|| "Failed to list pending updates")?;
+    let reboot_required = update_manager.check_reboot_required()?;
+    let held_packages = update_manager
+        .list_held_packages()
+        .await
+        .unwrap_or_else(|e| {
+            warn!("Failed to list held packages: {}", e);
+            Vec::new()
+        });
+
+    if let Some(metrics) = metrics {
+        metrics.set_packages_available(pending.len() as u64);
+        metrics.set_reboot_required(reboot_required);
+        record_crash_metrics(config, metrics);
+        record_pro_metrics(metrics);
+        if let Err(e) = metrics.write_textfile_metrics().await {
+            warn!("Failed to write textfile metrics: {}", e);
+        }
+        push_remote_write(config, http_client, metrics).await;
+    }
+
+    let results = UpdateResults {
+        success: true,
+        duration_seconds: start_time.elapsed().as_secs_f64(),
+        packages_updated: 0,
+        packages_available: pending.len() as u64,
+        bytes_downloaded: 0,
+        reboot_required,
+        error_message: None,
+        apt_output: String::new(),
+        snaps: Vec::new(),
+        flatpaks: Vec::new(),
+        debdelta: None,
+        removals_refused: Vec::new(),
+        disk_space: Vec::new(),
+        dry_run: None,
+        services_restarted: Vec::new(),
+        reboot_required_packages: if reboot_required {
+            crate::updater::reboot_required_packages()
+        } else {
+            Vec::new()
+        },
+        resource_usage: None,
+        command_failure: None,
+        skipped_reason: None,
+    };
+
+    let system_metrics = match metrics {
+        Some(metrics) => metrics.collect_system_metrics().await.ok(),
+        None => None,
+    };
+    let mut report = create_host_report(
+        config,
+        &results,
+        system_metrics.as_ref(),
+        start_time.elapsed(),
+    )?;
+    report.held_packages = held_packages;
+    report.pending_updates = pending;
+    Ok(report)
+}
+
+async fn export_schedule(
+    config: &AgentConfig,
+    ical: bool,
+    days: u32,
+    output: Option<PathBuf>,
+    local_only: bool,
+) -> Result<()> {
+    let hostname = crate::host::hostname();
+    let from = chrono::Local::now();
+    let pause = PauseManager::new(config).load()?;
+    let reboot = RebootTracker::new(config).scheduled()?;
+    let mut events = calendar::upcoming_windows(
+        config,
+        &hostname,
+        from,
+        days,
+        pause.as_ref(),
+        reboot.as_ref(),
+    );
+
+    if !local_only {
+        let http_client = SecureHttpClient::new(config)
+            .with_context(|| "Failed to initialize HTTP client")?
+            .for_subsystem("calendar");
+        match calendar::fetch_backend_calendar(config, &http_client).await {
+            Ok(Some(backend)) => {
+                let until = from + chrono::Duration::days(days.into());
+                events.extend(
+                    backend
+                        .into_iter()
+                        .filter(|event| event.end > from && event.start < until),
+                );
+                events.sort_by_key(|event| event.start);
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Failed to fetch the backend calendar: {:#}", e),
+        }
+    }
+
+    let text = if ical {
+        calendar::to_ical(&events, &hostname, chrono::Utc::now())
+    } else if events.is_empty() {
+        format!("{}\n", t!("schedule-none", days = days))
+    } else {
+        events
+            .iter()
+            .map(|event| {
+                format!(
+                    "{} - {}  {}\n",
+                    event
+                        .start
+                        .with_timezone(&chrono::Local)
+                        .format("%Y-%m-%d %H:%M"),
+                    event
+                        .end
+                        .with_timezone(&chrono::Local)
+                        .format("%Y-%m-%d %H:%M"),
+                    event.summary
+                )
+            })
+            .collect()
+    };
+    match &output {
+        Some(path) => std::fs::write(path, text)
+            .with_context(|| format!("Failed to write schedule to {:?}", path))?,
+        None => print!("{}", text),
+    }
+    Ok(())
+}
+
+async fn generate_sbom(
+    config: &AgentConfig,
+    output: Option<PathBuf>,
+    upload: bool,
+    format: Option<SbomFormat>,
+) -> Result<()> {
+    let sbom = sbom::generate(config, format.unwrap_or(config.sbom.format))?;
+    let json = serde_json::to_string_pretty(&sbom)?;
+    match &output {
+        Some(path) => std::fs::write(path, json + "\n")
+            .with_context(|| format!("Failed to write SBOM to {:?}", path))?,
+        None if !upload => println!("{}", json),
+        None => {}
+    }
+
+    if upload {
+        let http_client = SecureHttpClient::new(config)
+            .with_context(|| "Failed to initialize HTTP client")?
+            .for_subsystem("sbom");
+        sbom::upload(config, &http_client, &sbom).await?;
+    }
+    Ok(())
+}
+
+fn history_chain_head(config: &AgentConfig) -> ChainHead {
+    HistoryStore::new(config).chain_head().unwrap_or_else(|e| {
+        warn!("Failed to verify run history: {}", e);
+        ChainHead::default()
+    })
+}
+
+fn verify_history(config: &AgentConfig, json: bool) -> Result<()> {
+    let head = HistoryStore::new(config).chain_head()?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&head)?);
+    } else if head.intact {
+        println!(
+            "{}",
+            t!(
+                "history-chain-intact",
+                length = head.length,
+                hash = head.hash.as_deref().unwrap_or("-")
+            )
+        );
+    }
+
+    if !head.intact {
+        return Err(anyhow::anyhow!(
+            "History hash chain is broken, records were edited or removed"
+        ));
+    }
+    Ok(())
+}
+
+async fn show_history(
+    config: &AgentConfig,
+    failed: bool,
+    since: Option<String>,
+    limit: Option<usize>,
+    json: bool,
+) -> Result<()> {
+    let filter = HistoryFilter {
+        failed_only: failed,
+        since: since
+            .as_deref()
+            .map(crate::history::parse_since)
+            .transpose()?,
+        limit,
+    };
+    let records = HistoryStore::new(config).list(&filter)?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&records)?);
+        return Ok(());
+    }
+
+    if records.is_empty() {
+        println!("{}", t!("history-none"));
+        return Ok(());
+    }
+
+    println!(
+        "{:<20}  {:<7}  {:>9}  {:>7}  {:>9}  {:<6}  {}",
+        t!("history-column-time"),
+        t!("history-column-result"),
+        t!("history-column-duration"),
+        t!("history-column-updated"),
+        t!("history-column-available"),
+        t!("history-column-reboot"),
+        t!("history-column-details")
+    );
+    for record in records {
+        let result = if record.skipped_reason.is_some() {
+            t!("history-result-skipped")
+        } else if record.success {
+            t!("history-result-ok")
+        } else {
+            t!("history-result-failed")
+        };
+        let details = record
+            .error_message
+            .or(record.skipped_reason)
+            .unwrap_or_default();
+        println!(
+            "{:<20}  {:<7}  {:>8.1}s  {:>7}  {:>9}  {:<6}  {}",
+            record
+                .timestamp
+                .with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M:%S"),
+            result,
+            record.duration_seconds,
+            record.packages_updated,
+            record.packages_available,
+            yes_no(record.reboot_required),
+            details
+        );
+    }
+    Ok(())
+}
+
+fn format_local_time(time: chrono::DateTime<chrono::Utc>) -> String {
+    time.with_timezone(&chrono::Local)
+        .format("%Y-%m-%d %H:%M:%S %Z")
+        .to_string()
+}
+
 async fn enroll_agent(config: &AgentConfig, token: &str, hostname: Option<String>) -> Result<()> {
     info!("Starting agent enrollment");
 
-    let enrollment_manager = EnrollmentManager::new(config)
-        .with_context(|| "Failed to initialize enrollment manager")?;
+    let http_client =
+        SecureHttpClient::new(config).with_context(|| "Failed to initialize HTTP client")?;
+    let enrollment_manager = EnrollmentManager::new(config, &http_client);
 
     enrollment_manager
         .enroll(token, hostname.as_deref())
@@ -321,41 +1909,280 @@ async fn enroll_agent(config: &AgentConfig, token: &str, hostname: Option<String
     Ok(())
 }
 
+async fn rotate_api_key(config: &AgentConfig) -> Result<()> {
+    let http_client =
+        SecureHttpClient::new(config).with_context(|| "Failed to initialize HTTP client")?;
+    EnrollmentManager::new(config, &http_client)
+        .rotate_key()
+        .await
+        .with_context(|| "Key rotation failed")
+}
+
+#[derive(Subcommand)]
+enum ScheduleAction {
+    /// List upcoming windows, merged with the calendar the backend assigned
+    /// to this host
+    Export {
+        /// Print an iCalendar feed instead of a list
+        #[arg(long)]
+        ical: bool,
+        /// How many days ahead to include
+        #[arg(long, default_value_t = 14)]
+        days: u32,
+        /// Write to this file instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Don't fetch the backend calendar
+        #[arg(long)]
+        local_only: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum DebugAction {
+    /// Log at a higher level, in this and running agents, for a while
+    Enable {
+        /// Revert to the configured level after this many minutes
+        #[arg(long, default_value_t = 30)]
+        minutes: u32,
+        /// Level to log at meanwhile
+        #[arg(long, default_value = "debug")]
+        level: String,
+        /// Also log backend requests and responses, credentials redacted
+        #[arg(long)]
+        http: bool,
+    },
+    /// Revert to the configured level now
+    Disable,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+/// `status --json` output for monitoring scripts. Run results come from the
+/// history store, or the journal when it is missing, since Prometheus
+/// gauges reset between invocations.
+#[derive(Debug, Serialize)]
+struct AgentStatus {
+    agent_version: String,
+    backend_urls: Vec<String>,
+    enrolled: bool,
+    mode: UpdateMode,
+    /// Active pause, if any
+    pause: Option<PauseState>,
+    /// Open `debug enable` window, if any
+    debug_window: Option<DebugWindow>,
+    unattended_upgrades: UnattendedUpgradesStatus,
+    last_run: Option<RunRecord>,
+    /// `last_run` was recovered from the systemd journal
+    last_run_from_journal: bool,
+    /// /var/run/reboot-required is present
+    reboot_required: bool,
+    /// Reboot the agent scheduled that hasn't happened yet
+    scheduled_reboot: Option<ScheduledReboot>,
+    timer: Option<TimerHealth>,
+}
+
+fn is_enrolled(config: &AgentConfig) -> bool {
+    config
+        .security
+        .credential_path(&config.security.api_key_file)
+        .exists()
+}
+
+fn print_status_json(config: &AgentConfig) -> Result<()> {
+    let status = agent_status(config)?;
+    println!("{}", serde_json::to_string_pretty(&status)?);
+    Ok(())
+}
+
+fn agent_status(config: &AgentConfig) -> Result<AgentStatus> {
+    let history = HistoryStore::new(config).last()?;
+    let last_run_from_journal = history.is_none();
+    let last_run = history.or_else(journal::last_run);
+    Ok(AgentStatus {
+        agent_version: env!("CARGO_PKG_VERSION").to_string(),
+        backend_urls: config.backend.url.as_slice().to_vec(),
+        enrolled: is_enrolled(config),
+        mode: config.updates.mode,
+        pause: PauseManager::new(config).load()?,
+        debug_window: DebugWindowManager::new(config).load()?,
+        unattended_upgrades: UnattendedUpgradesStatus::detect(),
+        last_run_from_journal: last_run_from_journal && last_run.is_some(),
+        last_run,
+        reboot_required: std::path::Path::new("/var/run/reboot-required").exists(),
+        scheduled_reboot: RebootTracker::new(config).scheduled()?,
+        timer: TimerHealth::detect(None),
+    })
+}
+
 async fn show_status(config: &AgentConfig) -> Result<()> {
-    println!("Ubuntu Auto-Update Agent Status");
-    println!("================================");
-    println!("Version: {}", env!("CARGO_PKG_VERSION"));
-    println!("Backend URL: {}", config.backend.url);
-
-    // Check if enrolled
-    if config.security.api_key_file.exists() {
-        println!("Status: Enrolled");
+    let title = t!("status-title");
+    println!("{}", title);
+    println!("{}", "=".repeat(title.chars().count()));
+    println!(
+        "{}",
+        t!("status-version", version = env!("CARGO_PKG_VERSION"))
+    );
+    println!(
+        "{}",
+        t!("status-backend", url = config.backend.url.to_string())
+    );
+
+    if is_enrolled(config) {
+        println!("{}", t!("status-enrolled"));
     } else {
-        println!("Status: Not enrolled");
+        println!("{}", t!("status-not-enrolled"));
     }
 
-    // Show last metrics if available
-    if config.metrics.enabled {
+    match PauseManager::new(config).load() {
+        Ok(Some(pause)) => {
+            match pause.until {
+                Some(until) => println!(
+                    "{}",
+                    t!(
+                        "status-updates-paused-until",
+                        until = format_local_time(until)
+                    )
+                ),
+                None => println!("{}", t!("status-updates-paused-indefinitely")),
+            }
+            if let Some(reason) = pause.reason {
+                println!("  {}", t!("pause-reason", reason = reason));
+            }
+        }
+        Ok(None) if config.updates.mode == UpdateMode::Observe => {
+            println!("{}", t!("status-updates-observe"))
+        }
+        Ok(None) => println!("{}", t!("status-updates-active")),
+        Err(e) => println!("{}", t!("status-updates-unknown", error = e.to_string())),
+    }
+    if let Ok(Some(window)) = DebugWindowManager::new(config).load() {
+        println!(
+            "{}",
+            t!(
+                "status-debug",
+                level = window.level.as_str(),
+                until = format_local_time(window.until)
+            )
+        );
+    }
+
+    let unattended = UnattendedUpgradesStatus::detect();
+    if unattended.conflicts() {
+        println!(
+            "{}",
+            t!(
+                "status-unattended-conflict",
+                timers = unattended.active_timers.join(", ")
+            )
+        );
+    }
+    if let Ok(Some(reboot)) = RebootTracker::new(config).scheduled() {
+        println!(
+            "{}",
+            t!(
+                "status-reboot-scheduled",
+                time = format_local_time(reboot.expected_at)
+            )
+        );
+    }
+    if let Some(timer) = TimerHealth::detect(None) {
+        print_timer_health(&timer);
+    }
+
+    // Prefer the persisted history; Prometheus gauges reset between invocations,
+    // so with the state directory gone the journal's run summaries come next
+    let history = HistoryStore::new(config).last().ok().flatten();
+    let from_journal = history.is_none();
+    if let Some(last) = history.or_else(journal::last_run) {
+        println!("\n{}", t!("status-last-update"));
+        if from_journal {
+            println!("  {}", t!("status-from-journal"));
+        }
+        println!(
+            "  {}",
+            t!("status-time", time = format_local_time(last.timestamp))
+        );
+        println!(
+            "  {}",
+            t!(
+                "status-duration",
+                seconds = format!("{:.2}", last.duration_seconds)
+            )
+        );
+        println!("  {}", t!("status-success", value = yes_no(last.success)));
+        println!(
+            "  {}",
+            t!("status-packages-updated", count = last.packages_updated)
+        );
+        println!(
+            "  {}",
+            t!("status-packages-available", count = last.packages_available)
+        );
+        println!(
+            "  {}",
+            t!(
+                "status-reboot-required",
+                value = yes_no(last.reboot_required)
+            )
+        );
+        if let Some(error) = last.error_message {
+            println!("  {}", t!("status-error", error = error));
+        }
+        if let Some(reason) = last.skipped_reason {
+            println!("  {}", t!("status-skipped", reason = reason));
+        }
+    } else if config.metrics.enabled {
         if let Ok(metrics_collector) = MetricsCollector::new(config.metrics.clone()) {
             let update_metrics = metrics_collector.get_update_metrics();
-            println!("\nLast Update:");
+            println!("\n{}", t!("status-last-update"));
             if update_metrics.last_run_timestamp > 0 {
-                let last_run =
-                    chrono::DateTime::from_timestamp(update_metrics.last_run_timestamp as i64, 0);
-                println!("  Time: {:?}", last_run);
+                if let Some(last_run) =
+                    chrono::DateTime::from_timestamp(update_metrics.last_run_timestamp as i64, 0)
+                {
+                    println!(
+                        "  {}",
+                        t!("status-time", time = format_local_time(last_run))
+                    );
+                }
+                println!(
+                    "  {}",
+                    t!(
+                        "status-duration",
+                        seconds = format!("{:.2}", update_metrics.last_run_duration_seconds)
+                    )
+                );
                 println!(
-                    "  Duration: {:.2}s",
-                    update_metrics.last_run_duration_seconds
+                    "  {}",
+                    t!("status-exit-code", code = update_metrics.last_run_exit_code)
                 );
-                println!("  Exit Code: {}", update_metrics.last_run_exit_code);
-                println!("  Packages Updated: {}", update_metrics.packages_updated);
                 println!(
-                    "  Packages Available: {}",
-                    update_metrics.packages_available
+                    "  {}",
+                    t!(
+                        "status-packages-updated",
+                        count = update_metrics.packages_updated
+                    )
+                );
+                println!(
+                    "  {}",
+                    t!(
+                        "status-packages-available",
+                        count = update_metrics.packages_available
+                    )
+                );
+                println!(
+                    "  {}",
+                    t!(
+                        "status-reboot-required",
+                        value = yes_no(update_metrics.reboot_required)
+                    )
                 );
-                println!("  Reboot Required: {}", update_metrics.reboot_required);
             } else {
-                println!("  No previous runs recorded");
+                println!("  {}", t!("status-no-runs"));
             }
         }
     }
@@ -363,9 +2190,27 @@ async fn show_status(config: &AgentConfig) -> Result<()> {
     Ok(())
 }
 
+fn print_timer_health(timer: &TimerHealth) {
+    let line = match timer.state {
+        TimerState::Healthy => match timer.next_trigger {
+            Some(next) => t!("status-timer-healthy", next = format_local_time(next)),
+            None => t!("status-timer-inactive", unit = timer.unit.clone()),
+        },
+        TimerState::MissedWhileDown => t!("status-timer-missed-down", count = timer.missed_runs),
+        TimerState::MissedWhileUp => t!(
+            "status-timer-missed-up",
+            count = timer.missed_runs,
+            unit = timer.unit.clone()
+        ),
+        TimerState::Inactive => t!("status-timer-inactive", unit = timer.unit.clone()),
+        TimerState::NotFound => t!("status-timer-not-found", unit = timer.unit.clone()),
+    };
+    println!("{}", line);
+}
+
 async fn export_metrics(config: &AgentConfig) -> Result<()> {
     if !config.metrics.enabled {
-        println!("Metrics collection is disabled");
+        println!("{}", t!("metrics-disabled"));
         return Ok(());
     }
 
@@ -383,25 +2228,32 @@ async fn export_metrics(config: &AgentConfig) -> Result<()> {
 async fn test_connectivity(config: &AgentConfig) -> Result<()> {
     info!("Testing connectivity to backend: {}", config.backend.url);
 
-    let http_client =
-        SecureHttpClient::new(config).with_context(|| "Failed to initialize HTTP client")?;
+    let http_client = SecureHttpClient::new(config)
+        .with_context(|| "Failed to initialize HTTP client")?
+        .for_subsystem("connectivity");
 
     let start = Instant::now();
-    match http_client.get("/api/v1/health").await {
+    match http_client.get("health").await {
         Ok(response) => {
             let duration = start.elapsed();
-            println!("✓ Backend reachable");
-            println!("  Status: {}", response.status());
-            println!("  Response time: {:.2}ms", duration.as_millis());
+            println!("{}", t!("test-reachable"));
+            println!(
+                "  {}",
+                t!("test-status", status = response.status().to_string())
+            );
+            println!(
+                "  {}",
+                t!("test-response-time", ms = duration.as_millis() as u64)
+            );
 
             if response.status().is_success() {
-                println!("✓ Backend is healthy");
+                println!("{}", t!("test-healthy"));
             } else {
-                println!("⚠ Backend returned non-success status");
+                println!("{}", t!("test-unhealthy"));
             }
         }
         Err(e) => {
-            println!("✗ Failed to reach backend: {}", e);
+            println!("{}", t!("test-unreachable", error = e.to_string()));
             return Err(e);
         }
     }
@@ -409,19 +2261,78 @@ async fn test_connectivity(config: &AgentConfig) -> Result<()> {
     Ok(())
 }
 
+async fn download_file(
+    config: &AgentConfig,
+    endpoint: &str,
+    sha256: &str,
+    output: &std::path::Path,
+) -> Result<()> {
+    let http_client = SecureHttpClient::new(config)
+        .with_context(|| "Failed to initialize HTTP client")?
+        .for_subsystem("download");
+    let bytes = http_client.download(endpoint, output, sha256).await?;
+    println!(
+        "{}",
+        t!(
+            "download-complete",
+            path = output.display().to_string(),
+            bytes = bytes
+        )
+    );
+    Ok(())
+}
+
+async fn run_doctor(
+    config: &AgentConfig,
+    config_error: Option<&str>,
+    sandbox: &SandboxStatus,
+    json: bool,
+) -> Result<()> {
+    let checks = doctor::run_checks(config, config_error, sandbox).await;
+    if json {
+        doctor::print_json(&checks)?;
+    } else {
+        let title = t!("doctor-title");
+        println!("{}", title);
+        println!("{}", "=".repeat(title.chars().count()));
+        for check in &checks {
+            let label = match check.status {
+                CheckStatus::Pass => t!("doctor-pass"),
+                CheckStatus::Warn => t!("doctor-warn"),
+                CheckStatus::Fail => t!("doctor-fail"),
+            };
+            println!("{} {}: {}", label, check.name, check.message);
+        }
+        let count = |status| checks.iter().filter(|c| c.status == status).count();
+        println!();
+        println!(
+            "{}",
+            t!(
+                "doctor-summary",
+                passed = count(CheckStatus::Pass),
+                warnings = count(CheckStatus::Warn),
+                failed = count(CheckStatus::Fail)
+            )
+        );
+    }
+
+    if doctor::overall(&checks) == CheckStatus::Fail {
+        return Err(anyhow::anyhow!("One or more doctor checks failed"));
+    }
+    Ok(())
+}
+
 fn create_host_report(
-    _config: &AgentConfig,
+    config: &AgentConfig,
     update_results: &UpdateResults,
     system_metrics: Option<&crate::metrics::SystemMetrics>,
     _duration: Duration,
 ) -> Result<HostReport> {
-    let hostname = gethostname::gethostname()
-        .into_string()
-        .map_err(|_| anyhow::anyhow!("Failed to get hostname"))?;
+    let hostname = host::hostname();
 
     let system_info = SystemInfo {
-        os_version: get_os_version()?,
-        kernel_version: get_kernel_version()?,
+        os_version: host::os_version(),
+        kernel_version: host::kernel_version(),
         architecture: std::env::consts::ARCH.to_string(),
         uptime_seconds: system_metrics.map(|m| m.uptime_seconds).unwrap_or(0),
         load_average: system_metrics
@@ -440,6 +2351,7 @@ fn create_host_report(
                 }
             })
             .unwrap_or(0.0),
+        ubuntu_pro: UbuntuProStatus::detect(),
     };
 
     let metrics_json = if let Some(metrics) = system_metrics {
@@ -455,21 +2367,239 @@ fn create_host_report(
         update_results: update_results.clone(),
         system_info,
         metrics: metrics_json,
+        pause: None,
+        snapshot: None,
+        rollback: None,
+        policy_version: None,
+        config_attestation: ConfigAttestation::new(config)
+            .map_err(|e| warn!("Failed to attest the configuration: {:#}", e))
+            .ok(),
+        trigger: "run".to_string(),
+        unattended_upgrades: UnattendedUpgradesStatus::detect(),
+        held_packages: Vec::new(),
+        mode: config.updates.mode,
+        pending_updates: Vec::new(),
+        reboot_event: RebootTracker::new(config).check().unwrap_or_else(|e| {
+            warn!("Failed to check the last scheduled reboot: {}", e);
+            None
+        }),
+        crashes: CrashMonitor::new(config)
+            .take_unreported()
+            .unwrap_or_else(|e| {
+                warn!("Failed to scan for crashes: {}", e);
+                CrashSummary::default()
+            }),
+        service_transitions: Vec::new(),
+        graphics_deferred: Vec::new(),
+        risk_deferred: Vec::new(),
+        history_chain: history_chain_head(config),
+        vulnerabilities: None,
+        timer: TimerHealth::detect(
+            HistoryStore::new(config)
+                .last()
+                .ok()
+                .flatten()
+                .map(|last| last.timestamp),
+        ),
+        excluded_packages: crate::policy::exclusions(config),
+        run_id: None,
+        anomalies: Vec::new(),
+        external_changes: AptHistory::new(config).pending().unwrap_or_else(|e| {
+            warn!("Failed to load external package changes: {:#}", e);
+            Vec::new()
+        }),
+        platform: Platform::detect().report(config),
+        // Last, so it covers the commands run to build the report
+        command_audit: audit::take_summary(),
     })
 }
 
-async fn send_report_to_backend(client: &SecureHttpClient, report: &HostReport) -> Result<()> {
+fn record_crash_metrics(config: &AgentConfig, metrics: &MetricsCollector) {
+    match CrashMonitor::new(config).scan() {
+        Ok(totals) => metrics.set_crash_totals(&totals),
+        Err(e) => warn!("Failed to scan for crashes: {}", e),
+    }
+}
+
+fn record_pro_metrics(metrics: &MetricsCollector) {
+    if let Some(status) = UbuntuProStatus::detect() {
+        metrics.set_ubuntu_pro(&status);
+    }
+}
+
+/// A failed push is logged; the run's outcome doesn't depend on it.
+#[cfg(feature = "metrics")]
+async fn push_remote_write(
+    config: &AgentConfig,
+    http_client: &SecureHttpClient,
+    metrics: &MetricsCollector,
+) {
+    if !config.remote_write.enabled {
+        return;
+    }
+    if let Err(e) = remote_write::push(config, http_client, &metrics.gather()).await {
+        warn!("Failed to push metrics to remote_write endpoint: {:#}", e);
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+async fn push_remote_write(
+    _config: &AgentConfig,
+    _http_client: &SecureHttpClient,
+    _metrics: &MetricsCollector,
+) {
+}
+
+async fn send_report_to_backend(
+    config: &AgentConfig,
+    client: &SecureHttpClient,
+    report: &HostReport,
+) -> Result<()> {
     debug!("Sending report to backend for host: {}", report.hostname);
+    let external_changes = &report.external_changes;
 
-    let response = client
-        .post_with_retry(
-            "/api/v1/report",
-            report,
-            3,                      // max retries
-            Duration::from_secs(5), // retry delay
-        )
-        .await
-        .with_context(|| "Failed to send report to backend")?;
+    let redacted;
+    let report = match Redactor::new(config) {
+        Some(redactor) => {
+            redacted = report.clone().redacted(&redactor);
+            &redacted
+        }
+        None => report,
+    };
+    let sealed = seal(config, report)?;
+    if sealed.is_some() {
+        debug!(
+            "Report encrypted to {} recipient(s)",
+            config.reporting.encrypt_to.len()
+        );
+    }
+
+    // Archive sinks get the same payload, at the same time
+    let archive = async {
+        match &sealed {
+            Some(sealed) => sinks::deliver(config, client, &report.hostname, sealed).await,
+            None => sinks::deliver(config, client, &report.hostname, report).await,
+        }
+    };
+    let (result, ()) = tokio::join!(
+        deliver_report(config, client, report, sealed.as_ref()),
+        archive
+    );
+    if result.is_ok() {
+        if let Err(e) = AptHistory::new(config).acknowledge(external_changes) {
+            warn!("Failed to clear reported external package changes: {:#}", e);
+        }
+    }
+    result
+}
+
+/// A run's journal, uploaded to `<prefix>logs/` next to its report.
+#[derive(Serialize)]
+struct LogBundle<'a> {
+    run_id: &'a str,
+    started_at: chrono::DateTime<chrono::Utc>,
+    finished_at: chrono::DateTime<chrono::Utc>,
+    messages: Vec<String>,
+}
+
+/// Uploads what the agent logged during the run to the `[s3]` bucket.
+/// Skipped under the minimal reporting profile, which drops free text;
+/// failures are logged and don't fail the run.
+async fn upload_log_bundle(
+    config: &AgentConfig,
+    http_client: &SecureHttpClient,
+    run_id: &str,
+    started_at: chrono::DateTime<chrono::Utc>,
+) {
+    if Redactor::new(config).is_some() {
+        debug!("Not uploading the run's logs under the minimal reporting profile");
+        return;
+    }
+    let messages = match journal::messages_since(started_at) {
+        Ok(messages) => messages,
+        Err(e) => {
+            warn!("Failed to read the run's logs: {:#}", e);
+            return;
+        }
+    };
+    let bundle = LogBundle {
+        run_id,
+        started_at,
+        finished_at: chrono::Utc::now(),
+        messages,
+    };
+    let uploaded = match seal(config, &bundle) {
+        Ok(Some(sealed)) => s3::put_json(config, http_client, "logs", &sealed).await,
+        Ok(None) => s3::put_json(config, http_client, "logs", &bundle).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = uploaded {
+        warn!("Failed to upload the run's logs to S3: {:#}", e);
+    }
+}
+
+async fn deliver_report(
+    config: &AgentConfig,
+    client: &SecureHttpClient,
+    report: &HostReport,
+    sealed: Option<&SealedReport>,
+) -> Result<()> {
+    if config.backend.transport == Transport::S3 {
+        match sealed {
+            Some(sealed) => s3::put_json(config, client, "reports", sealed).await,
+            None => s3::put_json(config, client, "reports", report).await,
+        }
+        .with_context(|| "Failed to upload report to S3")?;
+        return Ok(());
+    }
+
+    #[cfg(feature = "nats")]
+    if config.backend.transport == Transport::Nats {
+        let nats = NatsTransport::connect(config).await?;
+        match sealed {
+            Some(sealed) => nats.publish("reports", sealed).await,
+            None => nats.publish("reports", report).await,
+        }
+        .with_context(|| "Failed to publish report to NATS")?;
+        info!("Report published to NATS");
+        return Ok(());
+    }
+
+    #[cfg(feature = "mqtt")]
+    if config.backend.transport == Transport::Mqtt {
+        let mut mqtt = MqttTransport::connect(config).await?;
+        match sealed {
+            Some(sealed) => mqtt.publish("reports", sealed, false).await,
+            None => mqtt.publish("reports", report, false).await,
+        }
+        .with_context(|| "Failed to publish report to MQTT")?;
+        // Timer-mode hosts have no daemon keeping availability current
+        if let Err(e) = mqtt
+            .publish("availability", &Availability::new("online"), true)
+            .await
+        {
+            warn!("Failed to publish availability to MQTT: {:#}", e);
+        }
+        mqtt.close().await;
+        info!("Report published to MQTT");
+        return Ok(());
+    }
+
+    let max_retries = 3;
+    let retry_delay = Duration::from_secs(5);
+    let response = match sealed {
+        Some(sealed) => {
+            client
+                .post_with_retry("report", sealed, max_retries, retry_delay)
+                .await
+        }
+        None => {
+            client
+                .post_with_retry("report", report, max_retries, retry_delay)
+                .await
+        }
+    }
+    .with_context(|| "Failed to send report to backend")?;
 
     if response.status().is_success() {
         info!("Report sent successfully to backend");
@@ -486,18 +2616,25 @@ async fn send_report_to_backend(client: &SecureHttpClient, report: &HostReport)
     Ok(())
 }
 
-async fn schedule_reboot(delay_minutes: u32) -> Result<()> {
+async fn schedule_reboot(config: &AgentConfig) -> Result<()> {
+    let delay_minutes = config.updates.reboot_delay_minutes;
     info!("Scheduling system reboot in {} minutes", delay_minutes);
 
-    let _delay_seconds = delay_minutes * 60;
-    let output = std::process::Command::new("shutdown")
-        .args([
-            "-r",
-            &format!("+{}", delay_minutes),
-            "Scheduled reboot after system updates",
-        ])
-        .output()
-        .with_context(|| "Failed to schedule reboot")?;
+    let reboot_time = chrono::Local::now() + chrono::Duration::minutes(delay_minutes.into());
+    let wall = templates::Templates::new(&config.notifications.templates).render(
+        "wall",
+        &serde_json::json!({
+            "hostname": crate::host::hostname(),
+            "delay_minutes": delay_minutes,
+            "reboot_time": reboot_time.format("%H:%M %Z").to_string(),
+        }),
+    );
+    let output = crate::audit::output(std::process::Command::new("shutdown").args([
+        "-r",
+        &format!("+{}", delay_minutes),
+        wall.trim(),
+    ]))
+    .with_context(|| "Failed to schedule reboot")?;
 
     if !output.status.success() {
         let stderr = String::from_utf8_lossy(&output.stderr);
@@ -505,20 +2642,10 @@ async fn schedule_reboot(delay_minutes: u32) -> Result<()> {
     }
 
     info!("Reboot scheduled successfully");
-    Ok(())
-}
-
-fn get_os_version() -> Result<String> {
-    let output = std::process::Command::new("lsb_release")
-        .args(["-ds"])
-        .output()
-        .with_context(|| "Failed to get OS version")?;
-
-    if output.status.success() {
-        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
-    } else {
-        Ok("Unknown".to_string())
+    if let Err(e) = RebootTracker::new(config).record_scheduled(delay_minutes) {
+        warn!("Failed to record scheduled reboot: {}", e);
     }
+    Ok(())
 }
 
 fn convert_updater_results(updater_results: &UpdaterUpdateResults) -> UpdateResults {
@@ -531,20 +2658,16 @@ fn convert_updater_results(updater_results: &UpdaterUpdateResults) -> UpdateResu
         reboot_required: updater_results.reboot_required,
         error_message: updater_results.error_message.clone(),
         apt_output: updater_results.apt_output.clone(),
-        snap_output: updater_results.snap_output.clone(),
-        flatpak_output: updater_results.flatpak_output.clone(),
-    }
-}
-
-fn get_kernel_version() -> Result<String> {
-    let output = std::process::Command::new("uname")
-        .arg("-r")
-        .output()
-        .with_context(|| "Failed to get kernel version")?;
-
-    if output.status.success() {
-        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
-    } else {
-        Ok("Unknown".to_string())
+        snaps: updater_results.snaps.clone(),
+        flatpaks: updater_results.flatpaks.clone(),
+        debdelta: updater_results.debdelta.clone(),
+        removals_refused: updater_results.removals_refused.clone(),
+        disk_space: updater_results.disk_space.clone(),
+        dry_run: updater_results.dry_run.clone(),
+        services_restarted: updater_results.services_restarted.clone(),
+        reboot_required_packages: updater_results.reboot_required_packages.clone(),
+        resource_usage: updater_results.resource_usage.clone(),
+        command_failure: updater_results.command_failure,
+        skipped_reason: None,
     }
 }
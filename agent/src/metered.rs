@@ -0,0 +1,74 @@
+use std::process::Command;
+use tracing::debug;
+
+use crate::rollback::command_exists;
+
+/// NetworkManager's NMMetered values for the primary connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Metered {
+    Unknown,
+    Yes,
+    No,
+    /// Inferred, e.g. from a phone hotspot's DHCP option or a WWAN device
+    GuessYes,
+    GuessNo,
+}
+
+/// Whether NetworkManager considers the primary connection metered, read
+/// over D-Bus with busctl. False on hosts without NetworkManager.
+pub fn connection_metered() -> bool {
+    if !command_exists("busctl") {
+        return false;
+    }
+    let output = crate::audit::output(Command::new("busctl").args([
+        "get-property",
+        "org.freedesktop.NetworkManager",
+        "/org/freedesktop/NetworkManager",
+        "org.freedesktop.NetworkManager",
+        "Metered",
+    ]));
+    let metered = match output {
+        Ok(output) if output.status.success() => {
+            parse_metered(&String::from_utf8_lossy(&output.stdout))
+        }
+        Ok(output) => {
+            debug!(
+                "NetworkManager not available: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            None
+        }
+        Err(e) => {
+            debug!("Failed to run busctl: {}", e);
+            None
+        }
+    };
+    debug!("Primary connection metered: {:?}", metered);
+    matches!(metered, Some(Metered::Yes | Metered::GuessYes))
+}
+
+/// busctl prints the property as its signature and value, e.g. "u 3".
+fn parse_metered(output: &str) -> Option<Metered> {
+    let value = output.trim().strip_prefix("u ")?;
+    match value.parse::<u32>().ok()? {
+        0 => Some(Metered::Unknown),
+        1 => Some(Metered::Yes),
+        2 => Some(Metered::No),
+        3 => Some(Metered::GuessYes),
+        4 => Some(Metered::GuessNo),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_metered() {
+        assert_eq!(parse_metered("u 3\n"), Some(Metered::GuessYes));
+        assert_eq!(parse_metered("u 4\n"), Some(Metered::GuessNo));
+        assert_eq!(parse_metered("u 0"), Some(Metered::Unknown));
+        assert_eq!(parse_metered("s \"yes\""), None);
+    }
+}
@@ -0,0 +1,82 @@
+use anyhow::Result;
+use std::convert::Infallible;
+
+use super::{SystemMetrics, UpdateMetrics};
+use crate::accounting::ResourceUsage;
+use crate::config::MetricsConfig;
+use crate::crash::CrashCounts;
+use crate::diskspace::SpaceCheck;
+use crate::http_client::SecureHttpClient;
+use crate::pro::UbuntuProStatus;
+
+/// Stands in for the Prometheus collector in builds without the `metrics`
+/// feature. `new` always fails, so none of the methods can be reached.
+pub struct MetricsCollector {
+    never: Infallible,
+}
+
+impl MetricsCollector {
+    pub fn new(_config: MetricsConfig) -> Result<Self> {
+        Err(anyhow::anyhow!(
+            "This agent was built without the metrics feature"
+        ))
+    }
+
+    pub fn register_http_client(&self, _client: &SecureHttpClient) -> Result<()> {
+        match self.never {}
+    }
+
+    pub fn record_update_start(&self) {
+        match self.never {}
+    }
+
+    pub fn record_update_completion(
+        &self,
+        _duration_secs: f64,
+        _exit_code: i32,
+        _packages_updated: u64,
+        _bytes_downloaded: f64,
+    ) {
+        match self.never {}
+    }
+
+    pub fn set_packages_available(&self, _count: u64) {
+        match self.never {}
+    }
+
+    pub fn set_reboot_required(&self, _required: bool) {
+        match self.never {}
+    }
+
+    pub fn set_crash_totals(&self, _totals: &CrashCounts) {
+        match self.never {}
+    }
+
+    pub fn set_disk_space_preflight(&self, _checks: &[SpaceCheck]) {
+        match self.never {}
+    }
+
+    pub fn set_resource_usage(&self, _usage: &ResourceUsage) {
+        match self.never {}
+    }
+
+    pub fn set_ubuntu_pro(&self, _status: &UbuntuProStatus) {
+        match self.never {}
+    }
+
+    pub async fn collect_system_metrics(&self) -> Result<SystemMetrics> {
+        match self.never {}
+    }
+
+    pub fn export_prometheus_metrics(&self) -> Result<String> {
+        match self.never {}
+    }
+
+    pub async fn write_textfile_metrics(&self) -> Result<()> {
+        match self.never {}
+    }
+
+    pub fn get_update_metrics(&self) -> UpdateMetrics {
+        match self.never {}
+    }
+}
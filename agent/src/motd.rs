@@ -0,0 +1,221 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, warn};
+
+use crate::config::{MotdConfig, NotificationTemplates};
+use crate::history::RunRecord;
+use crate::templates::Templates;
+
+/// update-notifier-common MOTD parts superseded by the agent's summary
+const UPDATE_NOTIFIER_PARTS: &[&str] = &["90-updates-available", "98-reboot-required"];
+
+const HEREDOC_END: &str = "UA_AGENT_MOTD_END";
+
+/// Longest error excerpt shown at login
+const MAX_ERROR_CHARS: usize = 120;
+
+/// Maintains an update-motd.d part summarizing the last agent run.
+pub struct MotdWriter {
+    path: PathBuf,
+    replace_update_notifier: bool,
+    templates: Templates,
+}
+
+/// What the "motd" template sees.
+#[derive(Serialize)]
+struct MotdSummary<'a> {
+    hostname: String,
+    /// Local time of the run
+    time: String,
+    success: bool,
+    /// Excerpts, like `error`
+    skipped_reason: Option<String>,
+    error: Option<String>,
+    packages_updated: u64,
+    packages_available: u64,
+    /// Updates the run left behind, e.g. in a dry run
+    pending: u64,
+    reboot_required: bool,
+    packages: &'a [String],
+}
+
+impl MotdWriter {
+    /// Returns `None` when the MOTD summary is disabled.
+    pub fn new(config: &MotdConfig, templates: &NotificationTemplates) -> Option<Self> {
+        config.enabled.then(|| Self {
+            path: config.path.clone(),
+            replace_update_notifier: config.replace_update_notifier,
+            templates: Templates::new(templates),
+        })
+    }
+
+    pub fn write(&self, record: &RunRecord) -> Result<()> {
+        let reboot_required =
+            record.reboot_required || Path::new("/var/run/reboot-required").exists();
+        let script = render_script(&render_summary(&self.templates, record, reboot_required));
+
+        let dir = self
+            .path
+            .parent()
+            .with_context(|| format!("Invalid MOTD path: {:?}", self.path))?;
+
+        // Write then rename so a concurrent login never runs a partial script
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, script)
+            .with_context(|| format!("Failed to write MOTD script {:?}", tmp_path))?;
+        fs::set_permissions(&tmp_path, fs::Permissions::from_mode(0o755))
+            .with_context(|| format!("Failed to set permissions on {:?}", tmp_path))?;
+        fs::rename(&tmp_path, &self.path)
+            .with_context(|| format!("Failed to install MOTD script {:?}", self.path))?;
+        debug!("Updated MOTD summary at {:?}", self.path);
+
+        if self.replace_update_notifier {
+            disable_update_notifier_parts(dir);
+        }
+        Ok(())
+    }
+}
+
+/// Renders the lines shown at login from the "motd" template, one bullet
+/// per non-empty line.
+fn render_summary(templates: &Templates, record: &RunRecord, reboot_required: bool) -> String {
+    let summary = MotdSummary {
+        hostname: crate::host::hostname(),
+        time: record
+            .timestamp
+            .with_timezone(&chrono::Local)
+            .format("%Y-%m-%d %H:%M %Z")
+            .to_string(),
+        success: record.success,
+        skipped_reason: record.skipped_reason.as_deref().map(excerpt),
+        error: (!record.success)
+            .then(|| excerpt(record.error_message.as_deref().unwrap_or_default())),
+        packages_updated: record.packages_updated,
+        packages_available: record.packages_available,
+        pending: record
+            .packages_available
+            .saturating_sub(record.packages_updated),
+        reboot_required,
+        packages: &record.packages,
+    };
+
+    // The bullet also keeps a line from ending the heredoc
+    templates
+        .render("motd", &summary)
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| format!(" * {}\n", line))
+        .collect()
+}
+
+fn render_script(summary: &str) -> String {
+    format!(
+        "#!/bin/sh\n\
+         # Generated by ua-agent after each run; local edits are overwritten.\n\
+         cat <<'{end}'\n\n{summary}{end}\n",
+        end = HEREDOC_END,
+        summary = summary
+    )
+}
+
+/// First line of a message, shortened so it can't end the heredoc or flood
+/// the login banner.
+fn excerpt(message: &str) -> String {
+    let first_line = message.lines().next().unwrap_or_default().trim();
+    if first_line.chars().count() > MAX_ERROR_CHARS {
+        let truncated: String = first_line.chars().take(MAX_ERROR_CHARS).collect();
+        format!("{}...", truncated)
+    } else {
+        first_line.to_string()
+    }
+}
+
+/// Clears the executable bit on stock update-notifier parts; run-parts
+/// skips non-executable files, and package upgrades preserve the mode.
+fn disable_update_notifier_parts(dir: &Path) {
+    for part in UPDATE_NOTIFIER_PARTS {
+        let path = dir.join(part);
+        let Ok(metadata) = fs::metadata(&path) else {
+            continue;
+        };
+
+        let mode = metadata.permissions().mode();
+        if mode & 0o111 == 0 {
+            continue;
+        }
+        match fs::set_permissions(&path, fs::Permissions::from_mode(mode & !0o111)) {
+            Ok(()) => info!("Disabled stock MOTD part {:?}", path),
+            Err(e) => warn!("Failed to disable stock MOTD part {:?}: {}", path, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use tempfile::tempdir;
+
+    fn record(success: bool) -> RunRecord {
+        RunRecord {
+            timestamp: Utc::now(),
+            success,
+            duration_seconds: 30.0,
+            packages_updated: 4,
+            packages_available: 6,
+            bytes_downloaded: 0,
+            reboot_required: true,
+            error_message: (!success).then(|| "APT: dpkg was interrupted\ndetails".to_string()),
+            skipped_reason: None,
+            packages: Vec::new(),
+            prev_hash: None,
+            hash: None,
+        }
+    }
+
+    #[test]
+    fn test_render_summary() {
+        let templates = Templates::new(&NotificationTemplates::default());
+        let summary = render_summary(&templates, &record(true), true);
+        assert!(summary.contains("succeeded, 4 packages updated"));
+        assert!(summary.contains("2 updates are pending"));
+        assert!(summary.contains("System restart required"));
+
+        let failed = render_summary(&templates, &record(false), false);
+        assert!(failed.contains("failed: APT: dpkg was interrupted\n"));
+        assert!(!failed.contains("details"));
+    }
+
+    #[test]
+    fn test_write_replaces_update_notifier() {
+        let temp_dir = tempdir().unwrap();
+        let stock = temp_dir.path().join("90-updates-available");
+        fs::write(&stock, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(&stock, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let config = MotdConfig {
+            enabled: true,
+            path: temp_dir.path().join("90-ubuntu-auto-update"),
+            replace_update_notifier: true,
+        };
+        MotdWriter::new(&config, &NotificationTemplates::default())
+            .unwrap()
+            .write(&record(true))
+            .unwrap();
+
+        let script = fs::read_to_string(&config.path).unwrap();
+        assert!(script.starts_with("#!/bin/sh\n"));
+        assert!(script.ends_with("UA_AGENT_MOTD_END\n"));
+        assert_eq!(
+            fs::metadata(&config.path).unwrap().permissions().mode() & 0o777,
+            0o755
+        );
+        assert_eq!(
+            fs::metadata(&stock).unwrap().permissions().mode() & 0o111,
+            0
+        );
+    }
+}
@@ -0,0 +1,294 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use rumqttc::{
+    AsyncClient, Event, EventLoop, LastWill, MqttOptions, Outgoing, Packet, QoS, TlsConfiguration,
+    Transport,
+};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ClientConfig, RootCertStore};
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+use crate::config::AgentConfig;
+
+/// Used for the broker's certificate when `mqtt.ca_file` isn't set
+const SYSTEM_CA_BUNDLE: &str = "/etc/ssl/certs/ca-certificates.crt";
+
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+
+/// Before the availability connection tries the broker again
+const RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Retained on `<topic_prefix>/availability/<host>`. The daemon's
+/// connection leaves "offline" as its last will, so the broker flips it
+/// when the host drops off.
+#[derive(Debug, Serialize)]
+pub struct Availability {
+    /// "online" or "offline"
+    pub state: &'static str,
+    pub agent_version: String,
+    /// For "offline", when the connection that left it was made
+    pub timestamp: DateTime<Utc>,
+}
+
+impl Availability {
+    pub fn new(state: &'static str) -> Self {
+        Self {
+            state,
+            agent_version: env!("CARGO_PKG_VERSION").to_string(),
+            timestamp: Utc::now(),
+        }
+    }
+}
+
+/// Connection to the broker in `[mqtt]`, used for reports when
+/// `backend.transport = "mqtt"`. Each publish is QoS 1 and returns once
+/// the broker has acknowledged it.
+pub struct MqttTransport {
+    client: AsyncClient,
+    eventloop: EventLoop,
+    prefix: String,
+    host: String,
+    timeout: Duration,
+}
+
+impl MqttTransport {
+    pub async fn connect(config: &AgentConfig) -> Result<Self> {
+        let host = host_token(config);
+        // The daemon's availability connection holds the plain ID; a second
+        // connection under it would kick that one off
+        let client_id = format!(
+            "ua-agent-{}-{}",
+            host,
+            &uuid::Uuid::new_v4().simple().to_string()[..8]
+        );
+        let options = options(config, &client_id)?;
+        let (client, eventloop) = AsyncClient::new(options, 10);
+        Ok(Self {
+            client,
+            eventloop,
+            prefix: config.mqtt.topic_prefix.clone(),
+            host,
+            timeout: Duration::from_secs(config.backend.timeout_seconds),
+        })
+    }
+
+    /// Publishes `payload` as JSON to `<prefix>/<kind>/<host>`, e.g.
+    /// `ubuntu-auto-update/reports/<host>`.
+    pub async fn publish<T: Serialize + ?Sized>(
+        &mut self,
+        kind: &str,
+        payload: &T,
+        retain: bool,
+    ) -> Result<()> {
+        let topic = topic(&self.prefix, kind, &self.host);
+        self.client
+            .publish(
+                topic.clone(),
+                QoS::AtLeastOnce,
+                retain,
+                serde_json::to_vec(payload)?,
+            )
+            .await
+            .with_context(|| format!("Failed to publish to {}", topic))?;
+        tokio::time::timeout(self.timeout, async {
+            loop {
+                match self.eventloop.poll().await? {
+                    Event::Incoming(Packet::PubAck(_)) => return Ok(()),
+                    event => debug!("MQTT: {:?}", event),
+                }
+            }
+        })
+        .await
+        .map_err(|_| anyhow::anyhow!("Broker did not acknowledge {} in time", topic))?
+        .map_err(|e: rumqttc::ConnectionError| {
+            anyhow::anyhow!("Failed to publish to {}: {}", topic, e)
+        })?;
+        debug!("Published to {}", topic);
+        Ok(())
+    }
+
+    /// Disconnects cleanly, so the broker doesn't log a dropped client.
+    pub async fn close(mut self) {
+        if self.client.disconnect().await.is_err() {
+            return;
+        }
+        let _ = tokio::time::timeout(self.timeout, async {
+            while let Ok(event) = self.eventloop.poll().await {
+                if matches!(event, Event::Outgoing(Outgoing::Disconnect)) {
+                    break;
+                }
+            }
+        })
+        .await;
+    }
+}
+
+/// Keeps a connection to the broker for the daemon's lifetime and
+/// publishes "online" availability every `mqtt.heartbeat_interval_seconds`,
+/// with "offline" as the last will. Reconnects after broker outages.
+pub async fn keep_available(config: AgentConfig) {
+    let host = host_token(&config);
+    let topic = topic(&config.mqtt.topic_prefix, "availability", &host);
+    let mut options = match options(&config, &format!("ua-agent-{}", host)) {
+        Ok(options) => options,
+        Err(e) => {
+            warn!("Not publishing MQTT availability: {:#}", e);
+            return;
+        }
+    };
+    let offline = serde_json::to_vec(&Availability::new("offline")).unwrap_or_default();
+    options.set_last_will(LastWill::new(&topic, offline, QoS::AtLeastOnce, true));
+    let (client, mut eventloop) = AsyncClient::new(options, 10);
+
+    let interval = Duration::from_secs(config.mqtt.heartbeat_interval_seconds);
+    let mut heartbeat = tokio::time::interval(interval);
+    loop {
+        tokio::select! {
+            _ = heartbeat.tick() => {
+                let online = serde_json::to_vec(&Availability::new("online")).unwrap_or_default();
+                if let Err(e) = client.try_publish(&topic, QoS::AtLeastOnce, true, online) {
+                    debug!("Skipped MQTT heartbeat: {}", e);
+                }
+            }
+            event = eventloop.poll() => match event {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    info!("Publishing availability to MQTT on {}", topic);
+                    // Right away, not at the next tick
+                    heartbeat.reset_immediately();
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("MQTT connection lost: {}", e);
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                }
+            },
+        }
+    }
+}
+
+fn options(config: &AgentConfig, client_id: &str) -> Result<MqttOptions> {
+    let mqtt = &config.mqtt;
+    let url = reqwest::Url::parse(&mqtt.url)
+        .with_context(|| format!("Invalid mqtt.url: {}", mqtt.url))?;
+    let tls = url.scheme() == "mqtts";
+    let broker = url
+        .host_str()
+        .with_context(|| format!("mqtt.url has no host: {}", mqtt.url))?;
+    let port = url.port().unwrap_or(if tls { 8883 } else { 1883 });
+
+    let mut options = MqttOptions::new(client_id, broker, port);
+    options.set_keep_alive(KEEP_ALIVE);
+    if let Some(username) = &mqtt.username {
+        let password = match &mqtt.password_file {
+            Some(path) => {
+                let path = config.security.credential_path(path);
+                fs::read_to_string(&path)
+                    .with_context(|| format!("Failed to read MQTT password from {:?}", path))?
+                    .trim()
+                    .to_string()
+            }
+            None => String::new(),
+        };
+        options.set_credentials(username, password);
+    }
+    if tls {
+        let tls_config = tls_config(config)?;
+        options.set_transport(Transport::tls_with_config(TlsConfiguration::Rustls(
+            Arc::new(tls_config),
+        )));
+    }
+    Ok(options)
+}
+
+/// With the ring provider named explicitly rather than relying on rustls
+/// picking a process default from its compiled-in providers.
+fn tls_config(config: &AgentConfig) -> Result<ClientConfig> {
+    let mqtt = &config.mqtt;
+    let ca_file = mqtt
+        .ca_file
+        .as_deref()
+        .unwrap_or(Path::new(SYSTEM_CA_BUNDLE));
+    let mut roots = RootCertStore::empty();
+    let certs = CertificateDer::pem_file_iter(ca_file)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Failed to read MQTT CA from {:?}", ca_file))?;
+    roots.add_parsable_certificates(certs);
+
+    let builder =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots);
+    match (&mqtt.cert_file, &mqtt.key_file) {
+        (Some(cert_file), Some(key_file)) => {
+            let certs = CertificateDer::pem_file_iter(cert_file)
+                .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                .with_context(|| {
+                    format!("Failed to read MQTT client certificate {:?}", cert_file)
+                })?;
+            let key_file = config.security.credential_path(key_file);
+            let key = PrivateKeyDer::from_pem_file(&key_file)
+                .with_context(|| format!("Failed to read MQTT client key {:?}", key_file))?;
+            builder
+                .with_client_auth_cert(certs, key)
+                .context("Invalid MQTT client certificate or key")
+        }
+        _ => Ok(builder.with_no_client_auth()),
+    }
+}
+
+fn topic(prefix: &str, kind: &str, host: &str) -> String {
+    format!("{}/{}/{}", prefix, kind, host)
+}
+
+/// Topic level for this host: the enrolled host ID, or the hostname with
+/// the characters MQTT reserves in topics replaced.
+fn host_token(config: &AgentConfig) -> String {
+    match fs::read_to_string(&config.enrollment.host_id_file) {
+        Ok(host_id) if !host_id.trim().is_empty() => host_id.trim().to_string(),
+        _ => sanitize_level(&crate::host::hostname()),
+    }
+}
+
+fn sanitize_level(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '/' | '+' | '#' => '_',
+            c if c.is_whitespace() || c.is_control() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_options_and_topics() {
+        assert_eq!(sanitize_level("kiosk 12/#a+b"), "kiosk_12__a_b");
+        assert_eq!(
+            topic("fleet/edge", "reports", "3f2c9a10-host"),
+            "fleet/edge/reports/3f2c9a10-host"
+        );
+
+        let mut config = AgentConfig::default();
+        config.mqtt.url = "mqtt://broker.lan".to_string();
+        let plain = options(&config, "ua-agent-test").unwrap();
+        assert_eq!(plain.broker_address(), ("broker.lan".to_string(), 1883));
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let ca = rcgen::generate_simple_self_signed(vec!["broker.lan".to_string()]).unwrap();
+        let ca_file = temp_dir.path().join("ca.pem");
+        std::fs::write(&ca_file, ca.cert.pem()).unwrap();
+        config.mqtt.url = "mqtts://broker.lan".to_string();
+        config.mqtt.ca_file = Some(ca_file);
+        let tls = options(&config, "ua-agent-test").unwrap();
+        assert_eq!(tls.broker_address(), ("broker.lan".to_string(), 8883));
+    }
+}
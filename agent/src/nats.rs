@@ -0,0 +1,189 @@
+use anyhow::{Context, Result};
+use async_nats::jetstream::{self, consumer::pull, stream};
+use async_nats::{ConnectOptions, HeaderMap};
+use futures::StreamExt;
+use serde::Serialize;
+use std::time::Duration;
+use tracing::{debug, info};
+
+use crate::config::AgentConfig;
+
+/// Connection to the broker in `[nats]`, used for reports and operator
+/// commands when `backend.transport = "nats"`.
+#[derive(Clone)]
+pub struct NatsTransport {
+    client: async_nats::Client,
+    jetstream: Option<jetstream::Context>,
+    stream: String,
+    prefix: String,
+    host: String,
+}
+
+impl NatsTransport {
+    pub async fn connect(config: &AgentConfig) -> Result<Self> {
+        let nats = &config.nats;
+        let host = host_token(config);
+
+        let mut options = ConnectOptions::new()
+            .name(format!("ua-agent/{}", host))
+            .connection_timeout(Duration::from_secs(config.backend.timeout_seconds));
+        if let Some(path) = &nats.credentials_file {
+            let path = config.security.credential_path(path);
+            options = options
+                .credentials_file(&path)
+                .await
+                .with_context(|| format!("Failed to load NATS credentials from {:?}", path))?;
+        }
+        if let Some(ca_file) = &nats.ca_file {
+            options = options
+                .add_root_certificates(ca_file.clone())
+                .require_tls(true);
+        }
+
+        let client = options
+            .connect(&nats.url)
+            .await
+            .with_context(|| format!("Failed to connect to NATS at {}", nats.url))?;
+        info!("Connected to NATS at {}", nats.url);
+
+        let jetstream = if nats.jetstream {
+            let context = jetstream::new(client.clone());
+            context
+                .get_or_create_stream(stream::Config {
+                    name: nats.stream.clone(),
+                    subjects: vec![format!("{}.>", nats.subject_prefix)],
+                    ..Default::default()
+                })
+                .await
+                .with_context(|| format!("Failed to open JetStream stream {}", nats.stream))?;
+            Some(context)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            client,
+            jetstream,
+            stream: nats.stream.clone(),
+            prefix: nats.subject_prefix.clone(),
+            host,
+        })
+    }
+
+    fn subject(&self, kind: &str) -> String {
+        format!("{}.{}.{}", self.prefix, kind, self.host)
+    }
+
+    /// Publishes `payload` as JSON to `<prefix>.<kind>.<host>`. With
+    /// JetStream this returns once the stream has stored it.
+    pub async fn publish<T: Serialize + ?Sized>(&self, kind: &str, payload: &T) -> Result<()> {
+        let subject = self.subject(kind);
+        let payload = serde_json::to_vec(payload)?;
+        match &self.jetstream {
+            Some(context) => {
+                context
+                    .publish(subject.clone(), payload.into())
+                    .await
+                    .with_context(|| format!("Failed to publish to {}", subject))?
+                    .await
+                    .with_context(|| format!("JetStream did not store message on {}", subject))?;
+            }
+            None => {
+                self.client
+                    .publish(subject.clone(), payload.into())
+                    .await
+                    .with_context(|| format!("Failed to publish to {}", subject))?;
+                self.client
+                    .flush()
+                    .await
+                    .context("Failed to flush NATS connection")?;
+            }
+        }
+        debug!("Published to {}", subject);
+        Ok(())
+    }
+
+    /// Waits up to `wait` for the next message on `<prefix>.commands.<host>`.
+    /// Reads through a durable consumer, so batches sent while the daemon
+    /// was down are still delivered once it is back.
+    pub async fn next_command_batch(&self, wait: Duration) -> Result<Option<(HeaderMap, Vec<u8>)>> {
+        let context = self
+            .jetstream
+            .as_ref()
+            .context("Commands over NATS need nats.jetstream")?;
+        let consumer = context
+            .get_stream(&self.stream)
+            .await
+            .with_context(|| format!("Failed to open JetStream stream {}", self.stream))?
+            .get_or_create_consumer(
+                &format!("commands-{}", self.host),
+                pull::Config {
+                    durable_name: Some(format!("commands-{}", self.host)),
+                    filter_subject: self.subject("commands"),
+                    ..Default::default()
+                },
+            )
+            .await
+            .context("Failed to open command consumer")?;
+
+        let mut batch = consumer
+            .fetch()
+            .max_messages(1)
+            .expires(wait)
+            .messages()
+            .await
+            .context("Failed to fetch commands")?;
+        let Some(message) = batch.next().await else {
+            return Ok(None);
+        };
+        let message = message.map_err(|e| anyhow::anyhow!("Failed to receive commands: {}", e))?;
+        // A batch that fails verification must not be redelivered either
+        message
+            .ack()
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to ack command batch: {}", e))?;
+
+        Ok(Some((
+            message.headers.clone().unwrap_or_default(),
+            message.payload.to_vec(),
+        )))
+    }
+}
+
+/// Subject token for this host: the enrolled host ID, or the hostname with
+/// the dots NATS uses as separators replaced.
+fn host_token(config: &AgentConfig) -> String {
+    match std::fs::read_to_string(&config.enrollment.host_id_file) {
+        Ok(host_id) if !host_id.trim().is_empty() => host_id.trim().to_string(),
+        _ => sanitize_token(&crate::host::hostname()),
+    }
+}
+
+fn sanitize_token(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '.' | '*' | '>' => '_',
+            c if c.is_whitespace() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_token() {
+        assert_eq!(
+            sanitize_token("kiosk-12.store.example"),
+            "kiosk-12_store_example"
+        );
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut config = AgentConfig::default();
+        config.enrollment.host_id_file = temp_dir.path().join("host_id");
+        std::fs::write(&config.enrollment.host_id_file, "3f2c9a10-host\n").unwrap();
+        assert_eq!(host_token(&config), "3f2c9a10-host");
+    }
+}
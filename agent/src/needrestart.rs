@@ -0,0 +1,178 @@
+use std::process::Command;
+use tracing::{debug, info, warn};
+
+use crate::config::NeedrestartConfig;
+use crate::rollback::command_exists;
+use crate::services::ServiceTransition;
+
+/// Units that can't be restarted without taking down the session or the
+/// bus everything else talks over; an outdated one still needs a reboot.
+const NEVER_RESTART: &[&str] = &[
+    "dbus.service",
+    "dbus-broker.service",
+    "systemd-logind.service",
+    "display-manager.service",
+    "gdm.service",
+    "gdm3.service",
+    "lightdm.service",
+    "sddm.service",
+];
+
+/// Prefixes of units never restarted: consoles, user managers and the
+/// agent itself, which would be killed mid-run.
+const NEVER_RESTART_PREFIXES: &[&str] = &[
+    "getty@",
+    "serial-getty@",
+    "user@",
+    "ubuntu-auto-update-agent",
+];
+
+/// What `needrestart -b` reports after an upgrade.
+#[derive(Debug, Default, PartialEq)]
+struct Report {
+    /// A newer kernel than the running one is installed
+    kernel_pending: bool,
+    microcode_pending: bool,
+    /// Units still running binaries or libraries that were replaced
+    services: Vec<String>,
+}
+
+/// Outcome of restarting the services needrestart flagged.
+#[derive(Debug, Default)]
+pub struct Restarts {
+    pub transitions: Vec<ServiceTransition>,
+    /// Whether a reboot is still needed: a kernel or microcode update is
+    /// pending, or an outdated unit was excluded or failed to restart
+    pub reboot_needed: bool,
+}
+
+/// Restarts the services needrestart lists as outdated. `None` when
+/// needrestart isn't installed or fails, leaving the reboot decision to
+/// /var/run/reboot-required alone.
+pub fn restart_outdated(config: &NeedrestartConfig) -> Option<Restarts> {
+    if !command_exists("needrestart") {
+        debug!("needrestart not installed, not restarting outdated services");
+        return None;
+    }
+    // List only; the agent restarts units itself to report each one
+    let output = match crate::audit::output(Command::new("needrestart").args(["-b", "-r", "l"])) {
+        Ok(output) if output.status.success() => output,
+        Ok(output) => {
+            warn!(
+                "needrestart failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            return None;
+        }
+        Err(e) => {
+            warn!("Failed to run needrestart: {}", e);
+            return None;
+        }
+    };
+    let report = parse_batch(&String::from_utf8_lossy(&output.stdout));
+    debug!("needrestart: {:?}", report);
+
+    let (restart, skipped): (Vec<&String>, Vec<&String>) = report
+        .services
+        .iter()
+        .partition(|unit| restartable(config, unit));
+    if !skipped.is_empty() {
+        info!(
+            "Not restarting outdated {}",
+            skipped
+                .iter()
+                .map(|unit| unit.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    let transitions: Vec<ServiceTransition> = restart
+        .into_iter()
+        .map(|unit| crate::services::restart(unit))
+        .collect();
+
+    let reboot_needed = report.kernel_pending
+        || report.microcode_pending
+        || !skipped.is_empty()
+        || transitions.iter().any(|transition| !transition.success)
+        || firmware_pending();
+    Some(Restarts {
+        transitions,
+        reboot_needed,
+    })
+}
+
+fn restartable(config: &NeedrestartConfig, unit: &str) -> bool {
+    !NEVER_RESTART.contains(&unit)
+        && !NEVER_RESTART_PREFIXES
+            .iter()
+            .any(|prefix| unit.starts_with(prefix))
+        && !config.exclude.iter().any(|excluded| excluded == unit)
+}
+
+/// Kernel, firmware and microcode packages only take effect after a
+/// reboot, whatever needrestart says about the running services.
+fn firmware_pending() -> bool {
+    crate::updater::reboot_required_packages()
+        .iter()
+        .any(|pkg| pkg.starts_with("linux-") || pkg.ends_with("-microcode"))
+}
+
+/// Parses batch mode output, e.g. "NEEDRESTART-KSTA: 3" and
+/// "NEEDRESTART-SVC: ssh.service". KSTA 3 is a newer kernel version, UCSTA
+/// 2 newer microcode.
+fn parse_batch(output: &str) -> Report {
+    let mut report = Report::default();
+    for line in output.lines() {
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match key.trim() {
+            "NEEDRESTART-KSTA" => report.kernel_pending = value == "3",
+            "NEEDRESTART-UCSTA" => report.microcode_pending = value == "2",
+            "NEEDRESTART-SVC" => report.services.push(value.to_string()),
+            _ => {}
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_batch() {
+        let output = "NEEDRESTART-VER: 3.6\n\
+                      NEEDRESTART-KCUR: 6.8.0-45-generic\n\
+                      NEEDRESTART-KEXP: 6.8.0-45-generic\n\
+                      NEEDRESTART-KSTA: 1\n\
+                      NEEDRESTART-UCSTA: 1\n\
+                      NEEDRESTART-SVC: cron.service\n\
+                      NEEDRESTART-SVC: dbus.service\n\
+                      NEEDRESTART-SVC: ssh.service\n\
+                      NEEDRESTART-SESS: kiosk @ session #3\n";
+        let report = parse_batch(output);
+        assert!(!report.kernel_pending);
+        assert!(!report.microcode_pending);
+        assert_eq!(
+            report.services,
+            vec!["cron.service", "dbus.service", "ssh.service"]
+        );
+
+        let config = NeedrestartConfig {
+            enabled: true,
+            exclude: vec!["ssh.service".to_string()],
+        };
+        let restart: Vec<&String> = report
+            .services
+            .iter()
+            .filter(|unit| restartable(&config, unit))
+            .collect();
+        assert_eq!(restart, vec!["cron.service"]);
+        assert!(!restartable(&config, "ubuntu-auto-update-agentd.service"));
+
+        assert!(parse_batch("NEEDRESTART-KSTA: 3\n").kernel_pending);
+    }
+}
@@ -0,0 +1,258 @@
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+use zbus::zvariant::OwnedObjectPath;
+use zbus::Connection;
+
+/// `PkFilterEnum` bit for "no filter"
+const FILTER_NONE: u64 = 1 << 1;
+/// `PkTransactionFlagEnum` bits
+const FLAG_ONLY_TRUSTED: u64 = 1 << 1;
+const FLAG_SIMULATE: u64 = 1 << 2;
+/// `PkExitEnum::Success`
+const EXIT_SUCCESS: u32 = 1;
+
+#[zbus::proxy(
+    interface = "org.freedesktop.PackageKit",
+    default_service = "org.freedesktop.PackageKit",
+    default_path = "/org/freedesktop/PackageKit"
+)]
+trait PackageKit {
+    fn create_transaction(&self) -> zbus::Result<OwnedObjectPath>;
+}
+
+#[zbus::proxy(
+    interface = "org.freedesktop.PackageKit.Transaction",
+    default_service = "org.freedesktop.PackageKit"
+)]
+trait Transaction {
+    fn refresh_cache(&self, force: bool) -> zbus::Result<()>;
+    fn get_updates(&self, filter: u64) -> zbus::Result<()>;
+    fn update_packages(&self, transaction_flags: u64, package_ids: &[&str]) -> zbus::Result<()>;
+    fn cancel(&self) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    fn package(&self, info: u32, package_id: &str, summary: &str) -> zbus::Result<()>;
+    #[zbus(signal)]
+    fn item_progress(&self, id: &str, status: u32, percentage: u32) -> zbus::Result<()>;
+    #[zbus(signal)]
+    fn error_code(&self, code: u32, details: &str) -> zbus::Result<()>;
+    #[zbus(signal)]
+    fn finished(&self, exit: u32, runtime: u32) -> zbus::Result<()>;
+}
+
+/// `PkInfoEnum`: what a `Package` signal says about a package.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PackageInfo {
+    Security,
+    /// Any other kind of update: low, enhancement, normal, bugfix,
+    /// important
+    Update,
+    Downloading,
+    Updating,
+    Installing,
+    Removing,
+    Other(u32),
+}
+
+impl From<u32> for PackageInfo {
+    fn from(info: u32) -> Self {
+        match info {
+            3..=7 => Self::Update,
+            8 => Self::Security,
+            10 => Self::Downloading,
+            11 => Self::Updating,
+            12 => Self::Installing,
+            13 => Self::Removing,
+            other => Self::Other(other),
+        }
+    }
+}
+
+/// A package as PackageKit names it: "name;version;arch;data".
+#[derive(Debug, Clone, PartialEq)]
+pub struct PackageId {
+    pub id: String,
+    pub info: PackageInfo,
+}
+
+impl PackageId {
+    pub fn name(&self) -> &str {
+        self.id.split(';').next().unwrap_or_default()
+    }
+}
+
+/// What a finished transaction reported.
+#[derive(Debug, Default)]
+pub struct TransactionLog {
+    pub packages: Vec<PackageId>,
+    pub errors: Vec<String>,
+}
+
+/// Client for the PackageKit daemon on the system bus. Transactions go
+/// through packagekitd, so they queue behind desktop update tools instead
+/// of failing on the dpkg lock.
+pub struct PackageKitClient {
+    connection: Connection,
+}
+
+impl PackageKitClient {
+    pub async fn connect() -> Result<Self> {
+        let connection = Connection::system()
+            .await
+            .context("Failed to connect to the system bus")?;
+        Ok(Self { connection })
+    }
+
+    pub async fn refresh_cache(&self, limit: Duration) -> Result<TransactionLog> {
+        self.transaction("RefreshCache", limit, |transaction| async move {
+            transaction.refresh_cache(false).await
+        })
+        .await
+    }
+
+    /// Updates for installed packages.
+    pub async fn get_updates(&self, limit: Duration) -> Result<Vec<PackageId>> {
+        let log = self
+            .transaction("GetUpdates", limit, |transaction| async move {
+                transaction.get_updates(FILTER_NONE).await
+            })
+            .await?;
+        Ok(log.packages)
+    }
+
+    /// Installs `package_ids`, or with `simulate` only reports what
+    /// installing them would do.
+    pub async fn update_packages(
+        &self,
+        package_ids: &[String],
+        simulate: bool,
+        limit: Duration,
+    ) -> Result<TransactionLog> {
+        let flags = if simulate {
+            FLAG_ONLY_TRUSTED | FLAG_SIMULATE
+        } else {
+            FLAG_ONLY_TRUSTED
+        };
+        self.transaction("UpdatePackages", limit, |transaction| async move {
+            let ids: Vec<&str> = package_ids.iter().map(String::as_str).collect();
+            transaction.update_packages(flags, &ids).await
+        })
+        .await
+    }
+
+    /// Creates a transaction, starts it with `start` and collects its
+    /// signals until it finishes. After `limit` the transaction is
+    /// cancelled and the result is a [`CommandTimedOut`].
+    ///
+    /// [`CommandTimedOut`]: crate::updater::CommandTimedOut
+    async fn transaction<'a, F, Fut>(
+        &self,
+        name: &str,
+        limit: Duration,
+        start: F,
+    ) -> Result<TransactionLog>
+    where
+        F: FnOnce(TransactionProxy<'a>) -> Fut,
+        Fut: std::future::Future<Output = zbus::Result<()>>,
+    {
+        let path = PackageKitProxy::new(&self.connection)
+            .await?
+            .create_transaction()
+            .await
+            .context("Failed to create a PackageKit transaction")?;
+        let transaction = TransactionProxy::builder(&self.connection)
+            .path(path)?
+            .build()
+            .await?;
+
+        // Subscribe before starting, or a quick transaction finishes unseen
+        let mut packages = transaction.receive_package().await?;
+        let mut progress = transaction.receive_item_progress().await?;
+        let mut errors = transaction.receive_error_code().await?;
+        let mut finished = transaction.receive_finished().await?;
+        start(transaction.clone())
+            .await
+            .with_context(|| format!("PackageKit refused {}", name))?;
+
+        let mut log = TransactionLog::default();
+        let collect = async {
+            loop {
+                tokio::select! {
+                    Some(signal) = packages.next() => {
+                        let args = signal.args()?;
+                        let package = PackageId {
+                            id: args.package_id().to_string(),
+                            info: PackageInfo::from(*args.info()),
+                        };
+                        match package.info {
+                            PackageInfo::Updating | PackageInfo::Installing | PackageInfo::Removing => {
+                                info!("[PackageKit] {:?} {}", package.info, package.id)
+                            }
+                            _ => debug!("[PackageKit {}] {:?} {}", name, package.info, package.id),
+                        }
+                        log.packages.push(package);
+                    }
+                    Some(signal) = progress.next() => {
+                        let args = signal.args()?;
+                        debug!("[PackageKit {}] {} {}%", name, args.id(), args.percentage());
+                    }
+                    Some(signal) = errors.next() => {
+                        let args = signal.args()?;
+                        warn!("PackageKit {} error {}: {}", name, args.code(), args.details());
+                        log.errors.push(args.details().to_string());
+                    }
+                    Some(signal) = finished.next() => {
+                        let args = signal.args()?;
+                        return Ok::<_, zbus::Error>((*args.exit(), *args.runtime()));
+                    }
+                    else => return Err(zbus::Error::Failure("PackageKit went away".to_string())),
+                }
+            }
+        };
+
+        let (exit, runtime) = match tokio::time::timeout(limit, collect).await {
+            Ok(result) => result.with_context(|| format!("PackageKit {} failed", name))?,
+            Err(_) => {
+                let termination = match transaction.cancel().await {
+                    Ok(()) => "cancelled the transaction".to_string(),
+                    Err(e) => format!("failed to cancel the transaction: {}", e),
+                };
+                return Err(crate::updater::CommandTimedOut {
+                    command: format!("PackageKit {}", name),
+                    timeout: limit,
+                    termination,
+                }
+                .into());
+            }
+        };
+        if exit != EXIT_SUCCESS {
+            return Err(anyhow::anyhow!(
+                "PackageKit {} failed (exit {}): {}",
+                name,
+                exit,
+                log.errors.join("; ")
+            ));
+        }
+        info!("PackageKit {} finished in {}ms", name, runtime);
+        Ok(log)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_package_ids() {
+        let package = PackageId {
+            id: "openssl;3.0.2-0ubuntu1.15;amd64;ubuntu-jammy-security".to_string(),
+            info: PackageInfo::from(8),
+        };
+        assert_eq!(package.name(), "openssl");
+        assert_eq!(package.info, PackageInfo::Security);
+        assert_eq!(PackageInfo::from(13), PackageInfo::Removing);
+        assert_eq!(PackageInfo::from(1), PackageInfo::Other(1));
+    }
+}
@@ -0,0 +1,219 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::backtrace::Backtrace;
+use std::fs;
+use std::panic::PanicHookInfo;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::config::AgentConfig;
+use crate::http_client::SecureHttpClient;
+use crate::privacy::Redactor;
+
+/// Reports kept when the backend can't be reached, so a restart loop
+/// doesn't fill the state directory
+const MAX_PENDING_REPORTS: usize = 10;
+
+/// What the agent was doing, recorded in panic reports.
+static PHASE: Mutex<&str> = Mutex::new("startup");
+
+/// Written to `<state.dir>/panics/` when the agent panics and POSTed to
+/// `/api/v1/agent-panics` on the next start.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PanicReport {
+    pub agent_version: String,
+    pub hostname: String,
+    pub timestamp: DateTime<Utc>,
+    /// Command line arguments, without the binary
+    pub command: Vec<String>,
+    pub phase: String,
+    pub thread: Option<String>,
+    pub message: Option<String>,
+    /// file:line:column of the panic
+    pub location: Option<String>,
+    pub backtrace: String,
+    /// SHA-256 of the effective configuration, to group reports by config
+    /// without uploading it
+    pub config_digest: String,
+}
+
+/// Records the phase a panic report will name, e.g. "apt" or "reporting".
+pub fn set_phase(phase: &'static str) {
+    if let Ok(mut current) = PHASE.lock() {
+        *current = phase;
+    }
+}
+
+fn current_phase() -> String {
+    PHASE
+        .lock()
+        .map(|phase| phase.to_string())
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+/// Chains a hook that writes a `PanicReport` after the default one has
+/// printed the panic, so it still reaches the journal.
+pub fn install_hook(config: &AgentConfig) {
+    let dir = reports_dir(config);
+    let config_digest = config_digest(config);
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        let report = build_report(info, &config_digest);
+        if let Err(e) = save(&dir, &report) {
+            eprintln!("Failed to write panic report to {:?}: {:#}", dir, e);
+        }
+    }));
+}
+
+fn build_report(info: &PanicHookInfo, config_digest: &str) -> PanicReport {
+    let payload = info.payload();
+    let message = payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned());
+    PanicReport {
+        agent_version: env!("CARGO_PKG_VERSION").to_string(),
+        hostname: crate::host::hostname(),
+        timestamp: Utc::now(),
+        command: std::env::args().skip(1).collect(),
+        phase: current_phase(),
+        thread: std::thread::current().name().map(String::from),
+        message,
+        location: info.location().map(ToString::to_string),
+        backtrace: Backtrace::force_capture().to_string(),
+        config_digest: config_digest.to_string(),
+    }
+}
+
+fn config_digest(config: &AgentConfig) -> String {
+    let json = serde_json::to_string(config).unwrap_or_default();
+    format!("{:x}", Sha256::digest(json.as_bytes()))
+}
+
+fn reports_dir(config: &AgentConfig) -> PathBuf {
+    config.state.dir.join("panics")
+}
+
+fn save(dir: &Path, report: &PanicReport) -> Result<()> {
+    fs::create_dir_all(dir).with_context(|| format!("Failed to create directory: {:?}", dir))?;
+    let path = dir.join(format!(
+        "panic-{}.json",
+        report.timestamp.format("%Y%m%dT%H%M%S%.3fZ")
+    ));
+    fs::write(&path, serde_json::to_string_pretty(report)?)
+        .with_context(|| format!("Failed to write {:?}", path))?;
+
+    // Oldest first, by their timestamped names
+    let pending = pending_reports(dir)?;
+    for old in pending
+        .iter()
+        .take(pending.len().saturating_sub(MAX_PENDING_REPORTS))
+    {
+        let _ = fs::remove_file(old);
+    }
+    Ok(())
+}
+
+fn pending_reports(dir: &Path) -> Result<Vec<PathBuf>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .with_context(|| format!("Failed to read {:?}", dir))?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+/// Uploads reports left by earlier panics, removing each once the backend
+/// has accepted it. The minimal reporting profile hashes the hostname and
+/// panic message.
+pub async fn upload_pending(config: &AgentConfig, http_client: &SecureHttpClient) -> Result<()> {
+    let dir = reports_dir(config);
+    let pending = pending_reports(&dir)?;
+    if pending.is_empty() {
+        return Ok(());
+    }
+    let http_client = http_client.for_subsystem("agent_panics");
+    let redactor = Redactor::new(config);
+
+    for path in pending {
+        let content =
+            fs::read_to_string(&path).with_context(|| format!("Failed to read {:?}", path))?;
+        let mut report: PanicReport = match serde_json::from_str(&content) {
+            Ok(report) => report,
+            Err(e) => {
+                warn!("Discarding unreadable panic report {:?}: {}", path, e);
+                let _ = fs::remove_file(&path);
+                continue;
+            }
+        };
+        if let Some(redactor) = &redactor {
+            report.hostname = redactor.hash(&report.hostname);
+            report.message = redactor.hash_message(report.message.as_deref());
+        }
+
+        let response = http_client
+            .post("agent-panics", &report)
+            .await
+            .with_context(|| "Failed to upload panic report")?;
+        if !response.status().is_success() {
+            return Err(anyhow::anyhow!(
+                "Backend returned {} for panic report",
+                response.status()
+            ));
+        }
+        info!(
+            "Uploaded report of the agent panic at {} during {}",
+            report.timestamp, report.phase
+        );
+        fs::remove_file(&path).with_context(|| format!("Failed to remove {:?}", path))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reports_are_capped() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let report = PanicReport {
+            agent_version: "0.1.0".to_string(),
+            hostname: "kiosk-7".to_string(),
+            timestamp: Utc::now(),
+            command: vec!["daemon".to_string()],
+            phase: "apt".to_string(),
+            thread: Some("main".to_string()),
+            message: Some("index out of bounds".to_string()),
+            location: Some("src/updater.rs:42:9".to_string()),
+            backtrace: String::new(),
+            config_digest: config_digest(&AgentConfig::default()),
+        };
+        for minutes in 0..12 {
+            let report = PanicReport {
+                timestamp: report.timestamp + chrono::Duration::minutes(minutes),
+                ..report.clone()
+            };
+            save(temp_dir.path(), &report).unwrap();
+        }
+
+        let pending = pending_reports(temp_dir.path()).unwrap();
+        assert_eq!(pending.len(), MAX_PENDING_REPORTS);
+        let oldest: PanicReport =
+            serde_json::from_str(&fs::read_to_string(&pending[0]).unwrap()).unwrap();
+        assert_eq!(
+            oldest.timestamp,
+            report.timestamp + chrono::Duration::minutes(2)
+        );
+        assert_eq!(oldest.config_digest.len(), 64);
+    }
+}
@@ -0,0 +1,214 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use tracing::{debug, info};
+
+use crate::config::AgentConfig;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PauseState {
+    pub paused_at: DateTime<Utc>,
+    pub until: Option<DateTime<Utc>>,
+    pub reason: Option<String>,
+}
+
+impl PauseState {
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        match self.until {
+            Some(until) => now < until,
+            None => true,
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        let mut text = match self.until {
+            Some(until) => format!("paused until {}", until.to_rfc3339()),
+            None => "paused indefinitely".to_string(),
+        };
+        if let Some(reason) = &self.reason {
+            text.push_str(&format!(" ({})", reason));
+        }
+        text
+    }
+}
+
+pub struct PauseManager {
+    path: PathBuf,
+}
+
+impl PauseManager {
+    pub fn new(config: &AgentConfig) -> Self {
+        Self {
+            path: config.state.dir.join("pause.json"),
+        }
+    }
+
+    /// Returns the active pause, clearing the marker if it has expired.
+    pub fn load(&self) -> Result<Option<PauseState>> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+
+        let content = fs::read_to_string(&self.path)
+            .with_context(|| format!("Failed to read pause state from {:?}", self.path))?;
+        let state: PauseState = serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse pause state in {:?}", self.path))?;
+
+        if state.is_active(Utc::now()) {
+            Ok(Some(state))
+        } else {
+            info!("Pause expired, resuming updates");
+            self.resume()?;
+            Ok(None)
+        }
+    }
+
+    pub fn pause(
+        &self,
+        until: Option<DateTime<Utc>>,
+        reason: Option<String>,
+    ) -> Result<PauseState> {
+        let state = PauseState {
+            paused_at: Utc::now(),
+            until,
+            reason,
+        };
+
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory: {:?}", parent))?;
+        }
+
+        let content = serde_json::to_string_pretty(&state)?;
+        fs::write(&self.path, content)
+            .with_context(|| format!("Failed to write pause state to {:?}", self.path))?;
+
+        debug!("Saved pause state to {:?}", self.path);
+        Ok(state)
+    }
+
+    /// Removes the pause marker. Returns whether one was present.
+    pub fn resume(&self) -> Result<bool> {
+        if !self.path.exists() {
+            return Ok(false);
+        }
+
+        fs::remove_file(&self.path)
+            .with_context(|| format!("Failed to remove pause state {:?}", self.path))?;
+        Ok(true)
+    }
+}
+
+/// End of a pause given as `--until`, or as `--days` and/or `--hours` from
+/// now; `until` wins. `None` pauses until resumed. An `until` that has
+/// already passed is an error rather than a pause that expires at once.
+pub fn pause_end(
+    until: Option<&str>,
+    hours: Option<u32>,
+    days: Option<u32>,
+) -> Result<Option<DateTime<Utc>>> {
+    if let Some(value) = until {
+        let until = parse_until(value)?;
+        if until <= Utc::now() {
+            return Err(anyhow::anyhow!(
+                "Pause end {} is in the past",
+                until.to_rfc3339()
+            ));
+        }
+        return Ok(Some(until));
+    }
+    if hours.is_none() && days.is_none() {
+        return Ok(None);
+    }
+    let length = chrono::Duration::hours(hours.unwrap_or(0).into())
+        + chrono::Duration::days(days.unwrap_or(0).into());
+    Ok(Some(Utc::now() + length))
+}
+
+/// Parses `--until` values: RFC 3339, or local "YYYY-MM-DD HH:MM" / "YYYY-MM-DD".
+pub fn parse_until(value: &str) -> Result<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+
+    let naive = NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M")
+        .or_else(|_| {
+            chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                .map(|date| date.and_hms_opt(0, 0, 0).unwrap())
+        })
+        .with_context(|| {
+            format!(
+                "Invalid time: {} (expected RFC 3339 or YYYY-MM-DD HH:MM)",
+                value
+            )
+        })?;
+
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .map(|time| time.with_timezone(&Utc))
+        .ok_or_else(|| anyhow::anyhow!("Time does not exist in local timezone: {}", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_pause_resume_roundtrip() {
+        let temp_dir = tempdir().unwrap();
+        let mut config = AgentConfig::default();
+        config.state.dir = temp_dir.path().to_path_buf();
+        let manager = PauseManager::new(&config);
+
+        assert!(manager.load().unwrap().is_none());
+
+        manager
+            .pause(None, Some("incident 42".to_string()))
+            .unwrap();
+        let state = manager.load().unwrap().unwrap();
+        assert_eq!(state.reason.as_deref(), Some("incident 42"));
+
+        assert!(manager.resume().unwrap());
+        assert!(manager.load().unwrap().is_none());
+        assert!(!manager.resume().unwrap());
+    }
+
+    #[test]
+    fn test_expired_pause_is_cleared() {
+        let temp_dir = tempdir().unwrap();
+        let mut config = AgentConfig::default();
+        config.state.dir = temp_dir.path().to_path_buf();
+        let manager = PauseManager::new(&config);
+
+        manager
+            .pause(Some(Utc::now() - chrono::Duration::hours(1)), None)
+            .unwrap();
+
+        assert!(manager.load().unwrap().is_none());
+        assert!(!temp_dir.path().join("pause.json").exists());
+    }
+
+    #[test]
+    fn test_parse_until() {
+        let parsed = parse_until("2030-01-02T03:04:05Z").unwrap();
+        assert_eq!(parsed.to_rfc3339(), "2030-01-02T03:04:05+00:00");
+
+        assert!(parse_until("2030-01-02 03:04").is_ok());
+        assert!(parse_until("2030-01-02").is_ok());
+        assert!(parse_until("tomorrow").is_err());
+
+        assert!(pause_end(None, None, None).unwrap().is_none());
+        let end = pause_end(None, Some(12), Some(2)).unwrap().unwrap();
+        let length = end - Utc::now();
+        assert!(length > chrono::Duration::hours(59) && length <= chrono::Duration::hours(60));
+        assert_eq!(
+            pause_end(Some("2030-01-02T03:04:05Z"), None, Some(2)).unwrap(),
+            Some(parsed)
+        );
+        assert!(pause_end(Some("2020-01-02T03:04:05Z"), None, None).is_err());
+    }
+}
@@ -0,0 +1,189 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use tracing::info;
+
+use crate::config::AgentConfig;
+
+/// Model string the kernel exposes on device-tree boards
+const DEVICE_TREE_MODEL: &str = "/proc/device-tree/model";
+
+/// Package commands get this many times their usual timeout on hosts with
+/// slow CPUs and SD card or eMMC storage, where flash-kernel and initramfs
+/// rebuilds alone can take many minutes
+const SLOW_HOST_TIMEOUT_FACTOR: u32 = 3;
+
+/// The hardware the agent runs on, for the defaults that only suit amd64
+/// servers.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Platform {
+    /// Debian architecture name, e.g. "arm64" or "riscv64"
+    pub arch: String,
+    /// Board model on device-tree hosts, e.g. "Raspberry Pi 4 Model B Rev 1.4"
+    pub model: Option<String>,
+}
+
+/// A setting the agent changed for this platform, and why.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlatformAdjustment {
+    pub setting: String,
+    pub value: String,
+    pub reason: String,
+}
+
+/// Sent in every report, so fleets of mixed hardware show why hosts behave
+/// differently.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlatformReport {
+    #[serde(flatten)]
+    pub platform: Platform,
+    /// Empty with `platform.adjust = false`
+    pub adjustments: Vec<PlatformAdjustment>,
+}
+
+impl Platform {
+    pub fn detect() -> Self {
+        Self {
+            arch: deb_arch(std::env::consts::ARCH).to_string(),
+            model: fs::read_to_string(DEVICE_TREE_MODEL)
+                .ok()
+                .map(|model| model.trim_end_matches('\0').trim().to_string())
+                .filter(|model| !model.is_empty()),
+        }
+    }
+
+    pub fn raspberry_pi(&self) -> bool {
+        self.model
+            .as_deref()
+            .is_some_and(|model| model.starts_with("Raspberry Pi"))
+    }
+
+    /// Multiplier for package command timeouts.
+    pub fn timeout_factor(&self) -> u32 {
+        if self.raspberry_pi() || matches!(self.arch.as_str(), "armhf" | "riscv64") {
+            SLOW_HOST_TIMEOUT_FACTOR
+        } else {
+            1
+        }
+    }
+
+    /// Changes the settings that don't suit this platform, with
+    /// `platform.adjust`, and returns what it changed.
+    pub fn adjust(&self, config: &mut AgentConfig) -> Vec<PlatformAdjustment> {
+        if !config.platform.adjust {
+            return Vec::new();
+        }
+        let mut adjustments = Vec::new();
+        let sources = &mut config.updates.update_sources;
+        if self.arch == "riscv64" && sources.snap {
+            sources.snap = false;
+            adjustments.push(PlatformAdjustment {
+                setting: "updates.update_sources.snap".to_string(),
+                value: "false".to_string(),
+                reason: "the Snap Store has next to no riscv64 snaps".to_string(),
+            });
+        }
+        if self.raspberry_pi() && sources.firmware {
+            sources.firmware = false;
+            adjustments.push(PlatformAdjustment {
+                setting: "updates.update_sources.firmware".to_string(),
+                value: "false".to_string(),
+                reason: "Raspberry Pi firmware and bootloader updates come as apt packages \
+                         (linux-firmware-raspi, rpi-eeprom), not through fwupd"
+                    .to_string(),
+            });
+        }
+        let factor = self.timeout_factor();
+        if factor > 1 {
+            adjustments.push(PlatformAdjustment {
+                setting: "package command timeouts".to_string(),
+                value: format!("x{}", factor),
+                reason: format!(
+                    "{} hosts are slow to unpack packages and rebuild initramfs",
+                    self.model.as_deref().unwrap_or(&self.arch)
+                ),
+            });
+        }
+        adjustments
+    }
+
+    pub fn report(&self, config: &AgentConfig) -> PlatformReport {
+        PlatformReport {
+            platform: self.clone(),
+            adjustments: self.adjust(&mut config.clone()),
+        }
+    }
+}
+
+/// Detects the platform and adjusts `config` for it, logging each change.
+/// Returns the factor for package command timeouts.
+pub fn adjust_for_platform(config: &mut AgentConfig) -> u32 {
+    let platform = Platform::detect();
+    for adjustment in platform.adjust(config) {
+        info!(
+            "On {}: {} set to {}, since {}",
+            platform.model.as_deref().unwrap_or(&platform.arch),
+            adjustment.setting,
+            adjustment.value,
+            adjustment.reason
+        );
+    }
+    if config.platform.adjust {
+        platform.timeout_factor()
+    } else {
+        1
+    }
+}
+
+/// Rust's name for the architecture the agent was built for, as dpkg
+/// calls it.
+fn deb_arch(arch: &str) -> &str {
+    match arch {
+        "x86_64" => "amd64",
+        "x86" => "i386",
+        "aarch64" => "arm64",
+        "arm" => "armhf",
+        "powerpc64" => "ppc64el",
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adjustments_per_platform() {
+        let mut config = AgentConfig::default();
+        config.updates.update_sources.firmware = true;
+
+        let server = Platform {
+            arch: deb_arch("x86_64").to_string(),
+            model: None,
+        };
+        assert!(server.adjust(&mut config.clone()).is_empty());
+        assert_eq!(server.timeout_factor(), 1);
+
+        let pi = Platform {
+            arch: "arm64".to_string(),
+            model: Some("Raspberry Pi 4 Model B Rev 1.4".to_string()),
+        };
+        let mut pi_config = config.clone();
+        let adjustments = pi.adjust(&mut pi_config);
+        assert_eq!(adjustments.len(), 2);
+        assert!(!pi_config.updates.update_sources.firmware);
+        assert!(pi_config.updates.update_sources.snap);
+        assert_eq!(pi.timeout_factor(), 3);
+
+        let riscv = Platform {
+            arch: "riscv64".to_string(),
+            model: Some("StarFive VisionFive 2 v1.3B".to_string()),
+        };
+        let mut riscv_config = config.clone();
+        riscv.adjust(&mut riscv_config);
+        assert!(!riscv_config.updates.update_sources.snap);
+
+        config.platform.adjust = false;
+        assert!(riscv.adjust(&mut config).is_empty());
+        assert!(config.updates.update_sources.snap);
+    }
+}
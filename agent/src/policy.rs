@@ -0,0 +1,358 @@
+use anyhow::{Context, Result};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use tracing::{debug, info, warn};
+
+use crate::config::{AgentConfig, ExcludedPackage, ExclusionMerge, ExclusionSource, SlotSchedule};
+use crate::http_client::SecureHttpClient;
+
+/// Settings pushed by the backend for one host. Fields left out of the
+/// response keep their local values.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Policy {
+    pub version: String,
+    pub maintenance_window_start: Option<String>,
+    pub maintenance_window_end: Option<String>,
+    /// Replaces the local windows, and with them a local start/end pair
+    pub maintenance_windows: Option<Vec<String>>,
+    pub excluded_packages: Option<Vec<String>>,
+    pub auto_reboot: Option<bool>,
+    pub update_sources: PolicySources,
+    /// Lets hosts in graphics caution mode install graphics stack updates
+    pub allow_graphics_updates: Option<bool>,
+    /// Replaces `updates.slots`, so hosts share the fleet's window
+    pub slots: Option<SlotSchedule>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PolicySources {
+    pub apt: Option<bool>,
+    pub snap: Option<bool>,
+    pub flatpak: Option<bool>,
+    pub firmware: Option<bool>,
+}
+
+impl Policy {
+    /// Overlays the policy on `config`, rejecting it if the merged result
+    /// doesn't validate.
+    pub fn apply(&self, config: &AgentConfig) -> Result<AgentConfig> {
+        let mut merged = config.clone();
+        let updates = &mut merged.updates;
+
+        if let Some(start) = &self.maintenance_window_start {
+            updates.maintenance_window_start = Some(start.clone());
+        }
+        if let Some(end) = &self.maintenance_window_end {
+            updates.maintenance_window_end = Some(end.clone());
+        }
+        if self.maintenance_window_start.is_some() || self.maintenance_window_end.is_some() {
+            updates.maintenance_windows.clear();
+        }
+        if let Some(windows) = &self.maintenance_windows {
+            updates.maintenance_windows = windows.clone();
+            updates.maintenance_window_start = None;
+            updates.maintenance_window_end = None;
+        }
+        if let Some(excluded) = &self.excluded_packages {
+            let local = &config.updates.excluded_packages;
+            let effective =
+                merge_exclusions(config.policy.excluded_packages_merge, local, excluded);
+            merged.policy.applied_exclusions = Some(
+                effective
+                    .iter()
+                    .map(|package| ExcludedPackage {
+                        package: package.clone(),
+                        source: match (local.contains(package), excluded.contains(package)) {
+                            (true, true) => ExclusionSource::Both,
+                            (false, true) => ExclusionSource::Backend,
+                            _ => ExclusionSource::Local,
+                        },
+                    })
+                    .collect(),
+            );
+            updates.excluded_packages = effective;
+        }
+        if let Some(auto_reboot) = self.auto_reboot {
+            updates.auto_reboot = auto_reboot;
+        }
+        if let Some(slots) = &self.slots {
+            updates.slots = slots.clone();
+        }
+        if let Some(allow) = self.allow_graphics_updates {
+            merged.graphics.allow_updates = allow;
+        }
+
+        let sources = &mut updates.update_sources;
+        let overrides = &self.update_sources;
+        sources.apt = overrides.apt.unwrap_or(sources.apt);
+        sources.snap = overrides.snap.unwrap_or(sources.snap);
+        sources.flatpak = overrides.flatpak.unwrap_or(sources.flatpak);
+        sources.firmware = overrides.firmware.unwrap_or(sources.firmware);
+
+        merged.validate().with_context(|| {
+            format!("Policy {} produces an invalid configuration", self.version)
+        })?;
+        Ok(merged)
+    }
+}
+
+fn merge_exclusions(merge: ExclusionMerge, local: &[String], backend: &[String]) -> Vec<String> {
+    match merge {
+        ExclusionMerge::Union => {
+            let mut merged = local.to_vec();
+            merged.extend(
+                backend
+                    .iter()
+                    .filter(|package| !local.contains(package))
+                    .cloned(),
+            );
+            merged
+        }
+        ExclusionMerge::BackendWins => backend.to_vec(),
+        ExclusionMerge::LocalWins if local.is_empty() => backend.to_vec(),
+        ExclusionMerge::LocalWins => local.to_vec(),
+    }
+}
+
+/// The effective excluded packages with their provenance, for reports.
+pub fn exclusions(config: &AgentConfig) -> Vec<ExcludedPackage> {
+    config.policy.applied_exclusions.clone().unwrap_or_else(|| {
+        config
+            .updates
+            .excluded_packages
+            .iter()
+            .map(|package| ExcludedPackage {
+                package: package.clone(),
+                source: ExclusionSource::Local,
+            })
+            .collect()
+    })
+}
+
+/// The last policy `PolicySync` applied, for settings needed before a run
+/// syncs, such as the splay.
+pub fn last_applied(config: &AgentConfig) -> Option<Policy> {
+    let content = fs::read_to_string(cache_path(config)).ok()?;
+    serde_json::from_str(&content)
+        .map_err(|e| warn!("Ignoring unreadable cached policy: {}", e))
+        .ok()
+}
+
+fn cache_path(config: &AgentConfig) -> PathBuf {
+    config.state.dir.join("policy.json")
+}
+
+/// Fetches the host's policy from the backend, falling back to the last
+/// policy that was applied when the backend can't be reached.
+pub struct PolicySync {
+    http_client: SecureHttpClient,
+    host_id_file: PathBuf,
+    cache_path: PathBuf,
+}
+
+impl PolicySync {
+    pub fn new(config: &AgentConfig, http_client: &SecureHttpClient) -> Self {
+        Self {
+            http_client: http_client.for_subsystem("policy"),
+            host_id_file: config.enrollment.host_id_file.clone(),
+            cache_path: cache_path(config),
+        }
+    }
+
+    /// Returns the effective configuration and the applied policy version.
+    /// Any failure leaves the local configuration in effect.
+    pub async fn sync(&self, config: &AgentConfig) -> (AgentConfig, Option<String>) {
+        let policy = match self.fetch().await {
+            Ok(policy) => policy,
+            Err(e) => {
+                warn!("Policy sync failed, using last applied policy: {:#}", e);
+                self.load_cached()
+            }
+        };
+
+        let Some(policy) = policy else {
+            return (config.clone(), None);
+        };
+
+        match policy.apply(config) {
+            Ok(merged) => {
+                info!("Applied backend policy version {}", policy.version);
+                if let Err(e) = self.save_cached(&policy) {
+                    warn!("Failed to cache policy: {}", e);
+                }
+                (merged, Some(policy.version))
+            }
+            Err(e) => {
+                warn!("Ignoring backend policy: {:#}", e);
+                (config.clone(), None)
+            }
+        }
+    }
+
+    async fn fetch(&self) -> Result<Option<Policy>> {
+        if !self.host_id_file.exists() {
+            debug!("Host not enrolled, skipping policy sync");
+            return Ok(None);
+        }
+        let host_id = fs::read_to_string(&self.host_id_file)
+            .with_context(|| format!("Failed to read host ID from {:?}", self.host_id_file))?;
+
+        let response = self
+            .http_client
+            .get(&format!("policy/{}", host_id.trim()))
+            .await?;
+
+        match response.status() {
+            StatusCode::NOT_FOUND | StatusCode::NO_CONTENT => {
+                debug!("No policy assigned to this host");
+                Ok(None)
+            }
+            status if status.is_success() => {
+                let policy = response
+                    .json::<Policy>()
+                    .await
+                    .context("Failed to parse policy response")?;
+                Ok(Some(policy))
+            }
+            status => {
+                let body = response.text().await.unwrap_or_default();
+                Err(anyhow::anyhow!(
+                    "Backend returned {} for policy: {}",
+                    status,
+                    body
+                ))
+            }
+        }
+    }
+
+    fn load_cached(&self) -> Option<Policy> {
+        let content = fs::read_to_string(&self.cache_path).ok()?;
+        serde_json::from_str(&content)
+            .map_err(|e| warn!("Ignoring unreadable cached policy: {}", e))
+            .ok()
+    }
+
+    fn save_cached(&self, policy: &Policy) -> Result<()> {
+        if let Some(parent) = self.cache_path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory: {:?}", parent))?;
+        }
+        fs::write(&self.cache_path, serde_json::to_string_pretty(policy)?)
+            .with_context(|| format!("Failed to write policy cache {:?}", self.cache_path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_policy_overrides_only_present_fields() {
+        let mut config = AgentConfig::default();
+        config.updates.excluded_packages = vec!["nginx".to_string()];
+        config.updates.auto_reboot = true;
+
+        let policy: Policy = serde_json::from_str(
+            r#"{
+                "version": "42",
+                "maintenance_window_start": "02:00",
+                "maintenance_window_end": "04:00",
+                "update_sources": {"snap": false}
+            }"#,
+        )
+        .unwrap();
+        let merged = policy.apply(&config).unwrap();
+
+        assert_eq!(
+            merged.updates.maintenance_window_start.as_deref(),
+            Some("02:00")
+        );
+        assert_eq!(merged.updates.excluded_packages, vec!["nginx"]);
+        assert!(merged.updates.auto_reboot);
+        assert!(!merged.updates.update_sources.snap);
+        assert!(merged.updates.update_sources.apt);
+    }
+
+    #[test]
+    fn test_excluded_packages_merge() {
+        let mut config = AgentConfig::default();
+        config.updates.excluded_packages = vec!["nginx".to_string(), "mysql-server".to_string()];
+        let policy = Policy {
+            version: "3".to_string(),
+            excluded_packages: Some(vec!["mysql-server".to_string(), "docker-ce".to_string()]),
+            ..Policy::default()
+        };
+        let effective = |merge| {
+            let mut config = config.clone();
+            config.policy.excluded_packages_merge = merge;
+            let merged = policy.apply(&config).unwrap();
+            exclusions(&merged)
+                .into_iter()
+                .map(|excluded| (excluded.package, excluded.source))
+                .collect::<Vec<_>>()
+        };
+
+        let package = |name: &str, source| (name.to_string(), source);
+        assert_eq!(
+            effective(ExclusionMerge::Union),
+            vec![
+                package("nginx", ExclusionSource::Local),
+                package("mysql-server", ExclusionSource::Both),
+                package("docker-ce", ExclusionSource::Backend),
+            ]
+        );
+        assert_eq!(
+            effective(ExclusionMerge::BackendWins),
+            vec![
+                package("mysql-server", ExclusionSource::Both),
+                package("docker-ce", ExclusionSource::Backend),
+            ]
+        );
+        assert_eq!(
+            effective(ExclusionMerge::LocalWins),
+            vec![
+                package("nginx", ExclusionSource::Local),
+                package("mysql-server", ExclusionSource::Both),
+            ]
+        );
+        assert_eq!(exclusions(&config).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_sync_falls_back_to_cached_policy() {
+        let temp_dir = tempdir().unwrap();
+        let mut config = AgentConfig::default();
+        config.state.dir = temp_dir.path().to_path_buf();
+        config.enrollment.host_id_file = temp_dir.path().join("host.id");
+        config.security.api_key_file = temp_dir.path().join("auth.token");
+        // Nothing listens here, so the fetch fails
+        config.backend.url = "http://127.0.0.1:9".into();
+        fs::write(&config.enrollment.host_id_file, "host-1\n").unwrap();
+
+        let http_client = SecureHttpClient::new(&config).unwrap();
+        let sync = PolicySync::new(&config, &http_client);
+
+        let (merged, version) = sync.sync(&config).await;
+        assert_eq!(version, None);
+        assert!(!merged.updates.update_sources.flatpak);
+
+        let cached = Policy {
+            version: "7".to_string(),
+            excluded_packages: Some(vec!["linux-image-generic".to_string()]),
+            ..Policy::default()
+        };
+        sync.save_cached(&cached).unwrap();
+
+        let (merged, version) = sync.sync(&config).await;
+        assert_eq!(version.as_deref(), Some("7"));
+        assert_eq!(
+            merged.updates.excluded_packages,
+            vec!["linux-image-generic"]
+        );
+    }
+}
@@ -0,0 +1,187 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use tracing::debug;
+
+use crate::config::PowerConfig;
+use crate::rollback::command_exists;
+
+const POWER_SUPPLY_DIR: &str = "/sys/class/power_supply";
+
+const UPOWER_DISPLAY_DEVICE: &str = "/org/freedesktop/UPower/devices/DisplayDevice";
+
+/// Whether the host runs from its battery (or UPS) and how full it is.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PowerState {
+    pub on_battery: bool,
+    pub battery_percent: Option<u8>,
+}
+
+impl PowerState {
+    /// Reads the power supplies from sysfs, falling back to upower's
+    /// combined display device where the kernel exposes no battery, e.g.
+    /// for a UPS managed by a userspace daemon. `None` on hosts without
+    /// batteries.
+    pub fn detect() -> Option<Self> {
+        read_power_supplies(Path::new(POWER_SUPPLY_DIR)).or_else(read_upower)
+    }
+}
+
+/// Why the run should be deferred to protect dpkg from a power loss, if it
+/// should.
+pub fn check_power(config: &PowerConfig) -> Option<String> {
+    if !config.enabled {
+        return None;
+    }
+    let state = PowerState::detect()?;
+    debug!("Power state: {:?}", state);
+    deferral(config, &state)
+}
+
+fn deferral(config: &PowerConfig, state: &PowerState) -> Option<String> {
+    if !state.on_battery {
+        return None;
+    }
+    match state.battery_percent {
+        _ if config.require_ac => Some("running on battery, power.require_ac is set".to_string()),
+        Some(percent) if percent < config.min_battery_percent => Some(format!(
+            "running on battery at {}%, below power.min_battery_percent ({}%)",
+            percent, config.min_battery_percent
+        )),
+        Some(_) => None,
+        None => Some("running on battery with an unknown charge level".to_string()),
+    }
+}
+
+/// Batteries and AC adapters under /sys/class/power_supply. Batteries with
+/// scope "Device" belong to peripherals like mice and are ignored.
+fn read_power_supplies(dir: &Path) -> Option<PowerState> {
+    let read = |supply: &Path, attribute: &str| {
+        fs::read_to_string(supply.join(attribute))
+            .map(|value| value.trim().to_string())
+            .ok()
+    };
+
+    let mut mains = Vec::new();
+    let mut batteries = Vec::new();
+    for entry in fs::read_dir(dir).ok()?.flatten() {
+        let supply = entry.path();
+        match read(&supply, "type").as_deref() {
+            Some("Mains") | Some("USB") => {
+                mains.push(read(&supply, "online").as_deref() == Some("1"))
+            }
+            Some("Battery") | Some("UPS")
+                if read(&supply, "scope").as_deref() != Some("Device") =>
+            {
+                batteries.push((
+                    read(&supply, "status").unwrap_or_default(),
+                    read(&supply, "capacity").and_then(|capacity| capacity.parse::<u8>().ok()),
+                ));
+            }
+            _ => {}
+        }
+    }
+    if batteries.is_empty() {
+        return None;
+    }
+
+    // Without an AC adapter entry, a discharging battery is the only sign
+    let on_battery = if mains.is_empty() {
+        batteries.iter().any(|(status, _)| status == "Discharging")
+    } else {
+        !mains.iter().any(|online| *online)
+    };
+    let capacities: Vec<u32> = batteries
+        .iter()
+        .filter_map(|(_, capacity)| capacity.map(u32::from))
+        .collect();
+    let battery_percent = (!capacities.is_empty())
+        .then(|| (capacities.iter().sum::<u32>() / capacities.len() as u32) as u8);
+    Some(PowerState {
+        on_battery,
+        battery_percent,
+    })
+}
+
+fn read_upower() -> Option<PowerState> {
+    if !command_exists("upower") {
+        return None;
+    }
+    let output =
+        crate::audit::output(Command::new("upower").args(["-i", UPOWER_DISPLAY_DEVICE])).ok()?;
+    parse_upower(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// `upower -i` output: "state: discharging" and "percentage: 42%". A display
+/// device that isn't present has no battery behind it.
+fn parse_upower(output: &str) -> Option<PowerState> {
+    let field = |name: &str| {
+        output.lines().find_map(|line| {
+            let (key, value) = line.trim().split_once(':')?;
+            (key.trim() == name).then(|| value.trim().to_string())
+        })
+    };
+    if field("present").as_deref() == Some("no") {
+        return None;
+    }
+    let state = field("state")?;
+    Some(PowerState {
+        on_battery: state == "discharging" || state == "pending-discharge",
+        battery_percent: field("percentage")
+            .and_then(|percentage| percentage.trim_end_matches('%').parse::<f64>().ok())
+            .map(|percentage| percentage.round() as u8),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_battery_defers_below_threshold() {
+        let sysfs = tempfile::tempdir().unwrap();
+        let supply = |name: &str, attributes: &[(&str, &str)]| {
+            let dir = sysfs.path().join(name);
+            fs::create_dir(&dir).unwrap();
+            for (attribute, value) in attributes {
+                fs::write(dir.join(attribute), format!("{}\n", value)).unwrap();
+            }
+        };
+        supply("AC", &[("type", "Mains"), ("online", "0")]);
+        supply(
+            "BAT0",
+            &[
+                ("type", "Battery"),
+                ("status", "Discharging"),
+                ("capacity", "35"),
+            ],
+        );
+        supply(
+            "hidpp_battery_0",
+            &[("type", "Battery"), ("scope", "Device"), ("capacity", "5")],
+        );
+
+        let state = read_power_supplies(sysfs.path()).unwrap();
+        assert_eq!(
+            state,
+            PowerState {
+                on_battery: true,
+                battery_percent: Some(35)
+            }
+        );
+        let config = PowerConfig::default();
+        let reason = deferral(&config, &state).unwrap();
+        assert!(reason.contains("35%"), "{}", reason);
+
+        fs::write(sysfs.path().join("AC/online"), "1\n").unwrap();
+        let state = read_power_supplies(sysfs.path()).unwrap();
+        assert_eq!(deferral(&config, &state), None);
+
+        let upower = parse_upower(
+            "  native-path:          ups\n  power supply:         yes\n  battery\n    present:             yes\n    state:               discharging\n    percentage:          82.5%\n",
+        )
+        .unwrap();
+        assert_eq!(upower.battery_percent, Some(83));
+        assert_eq!(deferral(&config, &upower), None);
+    }
+}
@@ -0,0 +1,154 @@
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::Write;
+
+use crate::config::AgentConfig;
+
+/// Replaces identifying values in outgoing reports when
+/// `reporting.profile = "minimal"`: hostnames become salted hashes and free
+/// text (command output, error messages, operator reasons) is dropped or
+/// reduced to a hash so identical failures can still be grouped.
+pub struct Redactor {
+    salt: String,
+}
+
+impl Redactor {
+    /// Returns `None` under the full reporting profile.
+    pub fn new(config: &AgentConfig) -> Option<Self> {
+        (config.reporting.profile == "minimal").then(|| Self {
+            // Salting with the host ID keeps hashes stable per host without
+            // letting the backend recover short hostnames by guessing
+            salt: fs::read_to_string(&config.enrollment.host_id_file)
+                .map(|id| id.trim().to_string())
+                .unwrap_or_default(),
+        })
+    }
+
+    pub fn hash(&self, value: &str) -> String {
+        let digest = Sha256::new()
+            .chain_update(self.salt.as_bytes())
+            .chain_update(b":")
+            .chain_update(value.as_bytes())
+            .finalize();
+        format!("sha256:{:x}", digest)
+    }
+
+    pub fn hash_message(&self, message: Option<&str>) -> Option<String> {
+        message.map(|message| self.hash(message))
+    }
+}
+
+/// Report body when `reporting.encrypt_to` is set. Only the envelope fields
+/// are readable by the backend; `payload` is the report JSON as a binary
+/// age file, base64-encoded.
+#[derive(Debug, Serialize)]
+pub struct SealedReport {
+    pub agent_version: String,
+    pub timestamp: DateTime<Utc>,
+    /// Always "age"
+    pub encryption: String,
+    pub payload: String,
+}
+
+/// Encrypts `report` to the configured age recipients. Returns `None` when
+/// no recipients are configured.
+pub fn seal<T: Serialize>(config: &AgentConfig, report: &T) -> Result<Option<SealedReport>> {
+    if config.reporting.encrypt_to.is_empty() {
+        return Ok(None);
+    }
+
+    let recipients = config
+        .reporting
+        .encrypt_to
+        .iter()
+        .map(|recipient| {
+            recipient
+                .parse::<age::x25519::Recipient>()
+                .map_err(|e| anyhow::anyhow!("Invalid age recipient {}: {}", recipient, e))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let encryptor = age::Encryptor::with_recipients(
+        recipients
+            .iter()
+            .map(|recipient| recipient as &dyn age::Recipient),
+    )
+    .context("Failed to set up report encryption")?;
+
+    let plaintext = serde_json::to_vec(report).context("Failed to serialize report")?;
+    let mut ciphertext = Vec::new();
+    let mut writer = encryptor
+        .wrap_output(&mut ciphertext)
+        .context("Failed to encrypt report")?;
+    writer
+        .write_all(&plaintext)
+        .and_then(|()| writer.finish().map(|_| ()))
+        .context("Failed to encrypt report")?;
+
+    Ok(Some(SealedReport {
+        agent_version: env!("CARGO_PKG_VERSION").to_string(),
+        timestamp: Utc::now(),
+        encryption: "age".to_string(),
+        payload: BASE64.encode(ciphertext),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_redactor_only_for_minimal_profile() {
+        let temp_dir = tempdir().unwrap();
+        let mut config = AgentConfig::default();
+        config.enrollment.host_id_file = temp_dir.path().join("host.id");
+        assert!(Redactor::new(&config).is_none());
+
+        config.reporting.profile = "minimal".to_string();
+        let unsalted = Redactor::new(&config).unwrap().hash("web01");
+        fs::write(&config.enrollment.host_id_file, "host-1\n").unwrap();
+        let redactor = Redactor::new(&config).unwrap();
+
+        let hashed = redactor.hash("web01");
+        assert!(hashed.starts_with("sha256:"));
+        assert_eq!(hashed.len(), "sha256:".len() + 64);
+        assert_eq!(hashed, redactor.hash("web01"));
+        assert_ne!(hashed, unsalted);
+        assert_eq!(redactor.hash_message(None), None);
+    }
+
+    #[test]
+    fn test_seal_roundtrip() {
+        use std::io::Read;
+
+        let mut config = AgentConfig::default();
+        let report = serde_json::json!({"hostname": "web01", "packages_updated": 3});
+        assert!(seal(&config, &report).unwrap().is_none());
+
+        let identity = age::x25519::Identity::generate();
+        config.reporting.encrypt_to = vec![identity.to_public().to_string()];
+        assert!(config.validate().is_ok());
+        let sealed = seal(&config, &report).unwrap().unwrap();
+        assert!(!sealed.payload.contains("web01"));
+
+        let ciphertext = BASE64.decode(&sealed.payload).unwrap();
+        let mut plaintext = Vec::new();
+        age::Decryptor::new(&ciphertext[..])
+            .unwrap()
+            .decrypt(std::iter::once(&identity as &dyn age::Identity))
+            .unwrap()
+            .read_to_end(&mut plaintext)
+            .unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&plaintext).unwrap(),
+            report
+        );
+
+        config.reporting.encrypt_to = vec!["age1notakey".to_string()];
+        assert!(config.validate().is_err());
+    }
+}
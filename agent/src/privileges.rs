@@ -0,0 +1,139 @@
+use anyhow::Result;
+
+/// Capabilities the agent's operations depend on, by their bit in the
+/// CapEff mask of /proc/self/status.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Capability {
+    /// dpkg and apt write root-owned files under /var/lib/dpkg and /etc
+    DacOverride = 1,
+    /// Mounting and btrfs/LVM ioctls for snapshots
+    SysAdmin = 21,
+}
+
+impl Capability {
+    fn name(self) -> &'static str {
+        match self {
+            Capability::DacOverride => "CAP_DAC_OVERRIDE",
+            Capability::SysAdmin => "CAP_SYS_ADMIN",
+        }
+    }
+}
+
+/// Something the agent does that needs more than an ordinary user has.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operation {
+    /// Installing packages; dpkg and apt also refuse to run unless uid 0
+    SystemUpdates,
+    Snapshots,
+}
+
+impl Operation {
+    fn describe(self) -> &'static str {
+        match self {
+            Operation::SystemUpdates => "system updates",
+            Operation::Snapshots => "snapshots",
+        }
+    }
+
+    fn needs_root(self) -> bool {
+        self == Operation::SystemUpdates
+    }
+
+    fn capabilities(self) -> &'static [Capability] {
+        match self {
+            Operation::SystemUpdates => &[Capability::DacOverride],
+            Operation::Snapshots => &[Capability::SysAdmin],
+        }
+    }
+}
+
+/// The effective user and capabilities of this process. Root in a unit
+/// with a narrowed CapabilityBoundingSet, or in a user namespace, can still
+/// be missing what an operation needs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Privileges {
+    euid: u32,
+    effective: u64,
+}
+
+impl Privileges {
+    pub fn current() -> Self {
+        // SAFETY: geteuid has no preconditions and cannot fail
+        let euid = unsafe { libc::geteuid() };
+        let effective = std::fs::read_to_string("/proc/self/status")
+            .ok()
+            .and_then(|status| parse_cap_eff(&status))
+            .unwrap_or(0);
+        Self { euid, effective }
+    }
+
+    pub fn has(&self, capability: Capability) -> bool {
+        self.effective & (1 << capability as u32) != 0
+    }
+
+    /// Fails with what is missing for `operation`, e.g. "system updates need
+    /// CAP_DAC_OVERRIDE, which this process does not have".
+    pub fn require(&self, operation: Operation) -> Result<()> {
+        if operation.needs_root() && self.euid != 0 {
+            return Err(anyhow::anyhow!(
+                "{} must run as root, running as uid {}",
+                operation.describe(),
+                self.euid
+            ));
+        }
+        let missing: Vec<&str> = operation
+            .capabilities()
+            .iter()
+            .filter(|capability| !self.has(**capability))
+            .map(|capability| capability.name())
+            .collect();
+        if !missing.is_empty() {
+            return Err(anyhow::anyhow!(
+                "{} need {}, which this process does not have \
+                 (check the unit's CapabilityBoundingSet= or the container's capabilities)",
+                operation.describe(),
+                missing.join(" and ")
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The CapEff line of /proc/self/status, a hex mask.
+fn parse_cap_eff(status: &str) -> Option<u64> {
+    let caps = status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))?;
+    u64::from_str_radix(caps.trim(), 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_require_reports_missing_privileges() {
+        let root = Privileges {
+            euid: 0,
+            effective: parse_cap_eff("Name:\tua-agent\nCapEff:\t000001ffffffffff\n").unwrap(),
+        };
+        assert!(root.require(Operation::SystemUpdates).is_ok());
+        assert!(root.require(Operation::Snapshots).is_ok());
+
+        // Root with CapabilityBoundingSet=CAP_DAC_OVERRIDE
+        let bounded = Privileges {
+            euid: 0,
+            effective: parse_cap_eff("CapEff:\t0000000000000002\n").unwrap(),
+        };
+        assert!(bounded.has(Capability::DacOverride));
+        let error = bounded.require(Operation::Snapshots).unwrap_err();
+        assert!(error.to_string().contains("CAP_SYS_ADMIN"));
+
+        let user = Privileges {
+            euid: 1000,
+            effective: 0,
+        };
+        let error = user.require(Operation::SystemUpdates).unwrap_err();
+        assert!(error.to_string().contains("uid 1000"));
+    }
+}
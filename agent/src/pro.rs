@@ -0,0 +1,167 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::process::Command;
+use tracing::{debug, warn};
+
+use crate::rollback::command_exists;
+
+/// Services reported as Prometheus gauges; `services` in the report has
+/// every one `pro status` lists.
+#[cfg(feature = "metrics")]
+pub const GAUGED_SERVICES: &[&str] = &["esm-infra", "esm-apps", "livepatch"];
+
+/// An Ubuntu Pro service and this machine's entitlement to it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProService {
+    pub name: String,
+    pub entitled: bool,
+    pub enabled: bool,
+}
+
+/// Ubuntu Pro attachment and ESM coverage, from `pro status` and
+/// `pro security-status`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct UbuntuProStatus {
+    pub attached: bool,
+    /// End of the attached contract
+    pub expires: Option<String>,
+    pub services: Vec<ProService>,
+    /// Security updates that only install with ESM Infra enabled
+    pub esm_infra_updates: u64,
+    /// Security updates that only install with ESM Apps enabled
+    pub esm_apps_updates: u64,
+}
+
+impl UbuntuProStatus {
+    /// `None` when the pro client (ubuntu-advantage-tools) isn't installed.
+    pub fn detect() -> Option<Self> {
+        if !command_exists("pro") {
+            return None;
+        }
+        let mut status = match pro_json("status").and_then(|json| parse_status(&json)) {
+            Ok(status) => status,
+            Err(e) => {
+                warn!("Failed to read Ubuntu Pro status: {:#}", e);
+                return None;
+            }
+        };
+        // Counted even when unattached: these are the updates the machine misses
+        match pro_json("security-status") {
+            Ok(json) => {
+                let (infra, apps) = parse_esm_updates(&json);
+                status.esm_infra_updates = infra;
+                status.esm_apps_updates = apps;
+            }
+            Err(e) => warn!("Failed to read Ubuntu Pro security status: {:#}", e),
+        }
+        Some(status)
+    }
+
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    pub fn service_enabled(&self, name: &str) -> bool {
+        self.services
+            .iter()
+            .any(|service| service.name == name && service.enabled)
+    }
+}
+
+fn pro_json(subcommand: &str) -> Result<Value> {
+    debug!("Running pro {} --format json", subcommand);
+    let output = crate::audit::output(Command::new("pro").args([subcommand, "--format", "json"]))
+        .with_context(|| format!("Failed to run pro {}", subcommand))?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "pro {} failed: {}",
+            subcommand,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    serde_json::from_slice(&output.stdout)
+        .with_context(|| format!("Failed to parse pro {} output", subcommand))
+}
+
+fn parse_status(json: &Value) -> Result<UbuntuProStatus> {
+    let attached = json
+        .get("attached")
+        .and_then(Value::as_bool)
+        .context("pro status has no attached field")?;
+    let services = json
+        .get("services")
+        .and_then(Value::as_array)
+        .map(|services| {
+            services
+                .iter()
+                .filter_map(|service| {
+                    Some(ProService {
+                        name: service.get("name")?.as_str()?.to_string(),
+                        entitled: service.get("entitled").and_then(Value::as_str) == Some("yes"),
+                        enabled: service.get("status").and_then(Value::as_str) == Some("enabled"),
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Ok(UbuntuProStatus {
+        attached,
+        expires: json
+            .get("expires")
+            .and_then(Value::as_str)
+            .map(str::to_string),
+        services,
+        esm_infra_updates: 0,
+        esm_apps_updates: 0,
+    })
+}
+
+fn parse_esm_updates(json: &Value) -> (u64, u64) {
+    let count = |key: &str| {
+        json.pointer(&format!("/summary/{}", key))
+            .and_then(Value::as_u64)
+            .unwrap_or(0)
+    };
+    (
+        count("num_esm_infra_updates"),
+        count("num_esm_apps_updates"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_pro_status() {
+        let status = serde_json::json!({
+            "attached": true,
+            "expires": "2027-06-30T00:00:00+00:00",
+            "services": [
+                {"name": "esm-infra", "entitled": "yes", "status": "enabled"},
+                {"name": "esm-apps", "entitled": "yes", "status": "disabled"},
+                {"name": "livepatch", "entitled": "no", "status": "n/a"}
+            ]
+        });
+        let mut status = parse_status(&status).unwrap();
+        assert!(status.attached);
+        assert!(status.service_enabled("esm-infra"));
+        assert!(!status.service_enabled("esm-apps"));
+        assert!(!status.services[2].entitled);
+
+        let security = serde_json::json!({
+            "summary": {"num_esm_infra_updates": 4, "num_esm_apps_updates": 11}
+        });
+        (status.esm_infra_updates, status.esm_apps_updates) = parse_esm_updates(&security);
+        assert_eq!(status.esm_apps_updates, 11);
+
+        let unattached = serde_json::json!({
+            "attached": false,
+            "expires": null,
+            "services": [{"name": "esm-infra", "available": "yes"}]
+        });
+        let unattached = parse_status(&unattached).unwrap();
+        assert!(!unattached.attached);
+        assert_eq!(unattached.expires, None);
+        assert!(!unattached.services[0].entitled);
+    }
+}
@@ -0,0 +1,270 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use tracing::{info, warn};
+
+use crate::config::AgentConfig;
+
+/// Changes on every boot
+const BOOT_ID_PATH: &str = "/proc/sys/kernel/random/boot_id";
+
+/// Slack for shutdown and boot time around the scheduled reboot
+const REBOOT_GRACE_MINUTES: i64 = 15;
+
+/// A reboot the agent scheduled with `shutdown -r`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduledReboot {
+    pub scheduled_at: DateTime<Utc>,
+    pub expected_at: DateTime<Utc>,
+    /// Boot the reboot was scheduled from
+    pub boot_id: String,
+    /// Set when an operator cancelled the reboot before it happened
+    #[serde(default)]
+    pub cancelled_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RebootOutcome {
+    /// Rebooted around the scheduled time
+    Completed,
+    /// Still on the same boot well after the scheduled time, e.g. the
+    /// shutdown was cancelled
+    NotPerformed,
+    /// Booted before the scheduled time: crash, power loss or a manual reboot
+    Early,
+    /// Booted long after the scheduled time: the shutdown hung, the host
+    /// failed to come back or lost power during the reboot
+    Delayed,
+    /// Cancelled by an operator with `cancel-reboot` or a backend command
+    Cancelled,
+}
+
+/// What happened to the last scheduled reboot, sent once in the next report.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RebootEvent {
+    pub outcome: RebootOutcome,
+    pub scheduled_for: DateTime<Utc>,
+    /// Start of the current boot, when it differs from the scheduling boot
+    pub booted_at: Option<DateTime<Utc>>,
+    pub cancelled_at: Option<DateTime<Utc>>,
+}
+
+/// Remembers reboots scheduled by the agent and checks on the next start
+/// whether they actually happened.
+pub struct RebootTracker {
+    path: PathBuf,
+}
+
+impl RebootTracker {
+    pub fn new(config: &AgentConfig) -> Self {
+        Self {
+            path: config.state.dir.join("reboot.json"),
+        }
+    }
+
+    pub fn record_scheduled(&self, delay_minutes: u32) -> Result<()> {
+        let now = Utc::now();
+        let scheduled = ScheduledReboot {
+            scheduled_at: now,
+            expected_at: now + Duration::minutes(delay_minutes as i64),
+            boot_id: current_boot_id()?,
+            cancelled_at: None,
+        };
+        self.save(&scheduled)
+    }
+
+    /// Marks the scheduled reboot as cancelled, so the next report carries
+    /// a `cancelled` event instead of it turning up as not performed.
+    /// Returns the reboot, if the agent had scheduled one.
+    pub fn record_cancelled(&self) -> Result<Option<ScheduledReboot>> {
+        let Some(mut scheduled) = self.load()? else {
+            return Ok(None);
+        };
+        if scheduled.cancelled_at.is_none() {
+            scheduled.cancelled_at = Some(Utc::now());
+            self.save(&scheduled)?;
+        }
+        Ok(Some(scheduled))
+    }
+
+    fn save(&self, scheduled: &ScheduledReboot) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory: {:?}", parent))?;
+        }
+        fs::write(&self.path, serde_json::to_string_pretty(scheduled)?)
+            .with_context(|| format!("Failed to write reboot state to {:?}", self.path))
+    }
+
+    /// The reboot scheduled by the agent and still pending, without
+    /// resolving it like `check`.
+    pub fn scheduled(&self) -> Result<Option<ScheduledReboot>> {
+        Ok(self
+            .load()?
+            .filter(|scheduled| scheduled.cancelled_at.is_none()))
+    }
+
+    fn load(&self) -> Result<Option<ScheduledReboot>> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        let content = fs::read_to_string(&self.path)
+            .with_context(|| format!("Failed to read reboot state from {:?}", self.path))?;
+        serde_json::from_str(&content)
+            .map(Some)
+            .with_context(|| format!("Failed to parse reboot state in {:?}", self.path))
+    }
+
+    /// Resolves the last scheduled reboot, if any. A reboot that is still
+    /// ahead is left pending; anything else is returned once and forgotten.
+    pub fn check(&self) -> Result<Option<RebootEvent>> {
+        let Some(scheduled) = self.load()? else {
+            return Ok(None);
+        };
+
+        let booted_at = crate::host::boot_time().context("Unknown boot time")?;
+        let Some(event) = classify(&scheduled, &current_boot_id()?, booted_at, Utc::now()) else {
+            return Ok(None);
+        };
+
+        match event.outcome {
+            RebootOutcome::Completed => info!("Scheduled reboot completed"),
+            RebootOutcome::Cancelled => {
+                info!("Reporting the cancelled reboot for {}", event.scheduled_for)
+            }
+            outcome => warn!(
+                "Scheduled reboot for {} did not go as planned: {:?}",
+                event.scheduled_for, outcome
+            ),
+        }
+        fs::remove_file(&self.path)
+            .with_context(|| format!("Failed to remove reboot state {:?}", self.path))?;
+        Ok(Some(event))
+    }
+}
+
+fn classify(
+    scheduled: &ScheduledReboot,
+    boot_id: &str,
+    booted_at: DateTime<Utc>,
+    now: DateTime<Utc>,
+) -> Option<RebootEvent> {
+    let grace = Duration::minutes(REBOOT_GRACE_MINUTES);
+
+    if scheduled.cancelled_at.is_some() {
+        return Some(RebootEvent {
+            outcome: RebootOutcome::Cancelled,
+            scheduled_for: scheduled.expected_at,
+            booted_at: None,
+            cancelled_at: scheduled.cancelled_at,
+        });
+    }
+
+    let (outcome, booted_at) = if boot_id == scheduled.boot_id {
+        if now < scheduled.expected_at + grace {
+            return None;
+        }
+        (RebootOutcome::NotPerformed, None)
+    } else if booted_at < scheduled.expected_at - Duration::minutes(1) {
+        (RebootOutcome::Early, Some(booted_at))
+    } else if booted_at > scheduled.expected_at + grace {
+        (RebootOutcome::Delayed, Some(booted_at))
+    } else {
+        (RebootOutcome::Completed, Some(booted_at))
+    };
+
+    Some(RebootEvent {
+        outcome,
+        scheduled_for: scheduled.expected_at,
+        booted_at,
+        cancelled_at: None,
+    })
+}
+
+/// Cancels a pending `shutdown -r` (logind's scheduled shutdown) and
+/// records the change of plan for the next report. Returns the agent's
+/// reboot, if it had scheduled one; a reboot scheduled by someone else is
+/// cancelled all the same.
+pub fn cancel(config: &AgentConfig) -> Result<Option<ScheduledReboot>> {
+    let output = crate::audit::output(std::process::Command::new("shutdown").arg("-c"))
+        .with_context(|| "Failed to run shutdown -c")?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "Failed to cancel reboot: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    info!("Scheduled reboot cancelled by operator");
+    RebootTracker::new(config).record_cancelled()
+}
+
+fn current_boot_id() -> Result<String> {
+    fs::read_to_string(BOOT_ID_PATH)
+        .map(|id| id.trim().to_string())
+        .with_context(|| format!("Failed to read {}", BOOT_ID_PATH))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scheduled() -> ScheduledReboot {
+        let scheduled_at = "2030-01-01T02:00:00Z".parse().unwrap();
+        ScheduledReboot {
+            scheduled_at,
+            expected_at: scheduled_at + Duration::minutes(5),
+            boot_id: "boot-a".to_string(),
+            cancelled_at: None,
+        }
+    }
+
+    #[test]
+    fn test_classify_same_boot() {
+        let scheduled = scheduled();
+        let booted_at = scheduled.scheduled_at - Duration::days(3);
+
+        let soon = scheduled.expected_at + Duration::minutes(2);
+        assert_eq!(classify(&scheduled, "boot-a", booted_at, soon), None);
+
+        let later = scheduled.expected_at + Duration::hours(1);
+        let event = classify(&scheduled, "boot-a", booted_at, later).unwrap();
+        assert_eq!(event.outcome, RebootOutcome::NotPerformed);
+        assert_eq!(event.booted_at, None);
+
+        let cancelled = ScheduledReboot {
+            cancelled_at: Some(scheduled.scheduled_at + Duration::minutes(1)),
+            ..scheduled
+        };
+        let event = classify(&cancelled, "boot-a", booted_at, soon).unwrap();
+        assert_eq!(event.outcome, RebootOutcome::Cancelled);
+        assert_eq!(event.cancelled_at, cancelled.cancelled_at);
+    }
+
+    #[test]
+    fn test_classify_new_boot() {
+        let scheduled = scheduled();
+        let now = scheduled.expected_at + Duration::days(1);
+        let outcome = |booted_at| {
+            classify(&scheduled, "boot-b", booted_at, now)
+                .unwrap()
+                .outcome
+        };
+
+        assert_eq!(
+            outcome(scheduled.expected_at + Duration::minutes(2)),
+            RebootOutcome::Completed
+        );
+        assert_eq!(
+            outcome(scheduled.scheduled_at + Duration::minutes(1)),
+            RebootOutcome::Early
+        );
+        assert_eq!(
+            outcome(scheduled.expected_at + Duration::hours(3)),
+            RebootOutcome::Delayed
+        );
+    }
+}
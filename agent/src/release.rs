@@ -0,0 +1,397 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tracing::{error, info, warn};
+
+use crate::audit::CommandAudit;
+use crate::config::AgentConfig;
+use crate::http_client::SecureHttpClient;
+use crate::privacy::Redactor;
+use crate::privileges::{Operation, Privileges};
+use crate::rollback::{RollbackOutcome, SnapshotManager, SnapshotRecord};
+use crate::updater::{terminate_process_group, KILL_GRACE_PERIOD};
+
+/// Output lines kept for the failure report
+const OUTPUT_TAIL: usize = 20;
+
+/// Stages of `do-release-upgrade`, in order. Progress only moves forward.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpgradePhase {
+    Started,
+    Snapshot,
+    Preparing,
+    ModifyingSources,
+    Fetching,
+    Installing,
+    CleaningUp,
+    Complete,
+    Failed,
+}
+
+/// POSTed to `/api/v1/release-upgrade` when the upgrade starts, at each
+/// phase change and when it ends.
+#[derive(Debug, Serialize)]
+struct ReleaseUpgradeEvent<'a> {
+    hostname: String,
+    agent_version: &'static str,
+    timestamp: DateTime<Utc>,
+    from_release: &'a str,
+    to_release: &'a str,
+    phase: UpgradePhase,
+    snapshot: Option<&'a SnapshotRecord>,
+    rollback: Option<&'a RollbackOutcome>,
+    error_message: Option<String>,
+}
+
+/// Runs `do-release-upgrade` non-interactively, gated by
+/// `[release_upgrade]`, and reports its progress to the backend.
+pub struct ReleaseUpgrader<'a> {
+    config: &'a AgentConfig,
+    http_client: SecureHttpClient,
+    hostname: String,
+}
+
+impl<'a> ReleaseUpgrader<'a> {
+    pub fn new(config: &'a AgentConfig) -> Result<Self> {
+        let http_client = SecureHttpClient::new(config)
+            .with_context(|| "Failed to initialize HTTP client")?
+            .for_subsystem("release_upgrade");
+        let hostname = crate::host::hostname();
+        let hostname = match Redactor::new(config) {
+            Some(redactor) => redactor.hash(&hostname),
+            None => hostname,
+        };
+        Ok(Self {
+            config,
+            http_client,
+            hostname,
+        })
+    }
+
+    /// Codename of the release `do-release-upgrade` would move to, or `None`
+    /// when this is the newest release its Prompt= setting allows.
+    pub async fn available(&self) -> Result<Option<String>> {
+        let output = crate::audit::output_async(
+            tokio::process::Command::new("do-release-upgrade")
+                .arg("--check-dist-upgrade-only")
+                .stdin(Stdio::null()),
+        )
+        .await
+        .with_context(|| {
+            "Failed to run do-release-upgrade (is ubuntu-release-upgrader-core installed?)"
+        })?;
+        Ok(parse_new_release(&format!(
+            "{}{}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        )))
+    }
+
+    /// Upgrades to the next release. Returns the codename upgraded to; the
+    /// host needs a reboot to finish.
+    pub async fn upgrade(&self) -> Result<String> {
+        let settings = &self.config.release_upgrade;
+        if !settings.enabled {
+            return Err(anyhow::anyhow!(
+                "Release upgrades are disabled, set release_upgrade.enabled = true"
+            ));
+        }
+        Privileges::current().require(Operation::SystemUpdates)?;
+        if Path::new("/var/run/reboot-required").exists() {
+            return Err(anyhow::anyhow!(
+                "A reboot is pending, reboot before upgrading the release"
+            ));
+        }
+        let Some(to_release) = self.available().await? else {
+            return Err(anyhow::anyhow!("No new release is available"));
+        };
+        let from_release = crate::host::os_version();
+        info!("Upgrading from {} to {}", from_release, to_release);
+        let event = |phase| (phase, from_release.as_str(), to_release.as_str());
+        self.send(event(UpgradePhase::Started), None, None, None)
+            .await;
+
+        let snapshot_manager = SnapshotManager::new(self.config);
+        self.send(event(UpgradePhase::Snapshot), None, None, None)
+            .await;
+        let snapshot = match snapshot_manager.create_snapshot("Before release upgrade") {
+            Ok(record) => Some(record),
+            Err(e) if settings.require_snapshot => {
+                let e = e.context("Refusing to upgrade the release without a snapshot");
+                self.send(
+                    event(UpgradePhase::Failed),
+                    None,
+                    None,
+                    Some(format!("{:#}", e)),
+                )
+                .await;
+                return Err(e);
+            }
+            Err(e) => {
+                warn!("Failed to create pre-upgrade snapshot: {}", e);
+                None
+            }
+        };
+
+        let result = self
+            .run_release_upgrade(&from_release, &to_release, snapshot.as_ref())
+            .await;
+        let Err(e) = result else {
+            info!("Release upgrade to {} complete", to_release);
+            self.send(event(UpgradePhase::Complete), snapshot.as_ref(), None, None)
+                .await;
+            if self.config.updates.auto_reboot {
+                crate::schedule_reboot(self.config).await?;
+            }
+            return Ok(to_release);
+        };
+
+        error!("Release upgrade failed: {:#}", e);
+        let rollback = match &snapshot {
+            Some(record) if self.config.updates.rollback_on_failure => {
+                let outcome = snapshot_manager.rollback(record);
+                if let Some(e) = &outcome.error_message {
+                    error!("Rollback to snapshot {} failed: {}", record.id, e);
+                }
+                Some(outcome)
+            }
+            _ => None,
+        };
+        self.send(
+            event(UpgradePhase::Failed),
+            snapshot.as_ref(),
+            rollback.as_ref(),
+            Some(format!("{:#}", e)),
+        )
+        .await;
+        Err(e)
+    }
+
+    async fn run_release_upgrade(
+        &self,
+        from_release: &str,
+        to_release: &str,
+        snapshot: Option<&SnapshotRecord>,
+    ) -> Result<()> {
+        let mut command = tokio::process::Command::new("do-release-upgrade");
+        // Own process group, as for apt, so a timeout also reaches dpkg and
+        // maintainer scripts
+        command
+            .args(["--frontend", "DistUpgradeViewNonInteractive"])
+            .env("DEBIAN_FRONTEND", "noninteractive")
+            .env(crate::updater::AGENT_RUN_ENV, "1")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .process_group(0)
+            .kill_on_drop(true);
+        let mut audit = CommandAudit::of(command.as_std());
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                audit.finish(None, Some(e.to_string()));
+                return Err(e).with_context(|| "Failed to start do-release-upgrade");
+            }
+        };
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let stderr = child.stderr.take().expect("stderr is piped");
+
+        let timeout = Duration::from_secs(self.config.release_upgrade.timeout_minutes * 60);
+        let mut tail = VecDeque::with_capacity(OUTPUT_TAIL);
+        let mut stderr_tail = VecDeque::with_capacity(OUTPUT_TAIL);
+        let progress = async {
+            let mut lines = BufReader::new(stdout).lines();
+            let mut errors = BufReader::new(stderr).lines();
+            let (mut stdout_open, mut stderr_open) = (true, true);
+            let mut phase = UpgradePhase::Snapshot;
+            while stdout_open || stderr_open {
+                tokio::select! {
+                    line = lines.next_line(), if stdout_open => {
+                        let Some(line) = line? else {
+                            stdout_open = false;
+                            continue;
+                        };
+                        info!(target: "do-release-upgrade", "{}", line);
+                        audit.output(line.as_bytes());
+                        audit.output(b"\n");
+                        if let Some(next) = phase_for_line(&line).filter(|next| *next > phase) {
+                            phase = next;
+                            self.send((phase, from_release, to_release), snapshot, None, None)
+                                .await;
+                        }
+                        push_tail(&mut tail, line);
+                    }
+                    line = errors.next_line(), if stderr_open => {
+                        let Some(line) = line? else {
+                            stderr_open = false;
+                            continue;
+                        };
+                        warn!(target: "do-release-upgrade", "{}", line);
+                        audit.output(line.as_bytes());
+                        audit.output(b"\n");
+                        push_tail(&mut stderr_tail, line);
+                    }
+                }
+            }
+            child.wait().await
+        };
+        let status = match tokio::time::timeout(timeout, progress).await {
+            Ok(Ok(status)) => status,
+            Ok(Err(e)) => {
+                terminate_process_group(&mut child, KILL_GRACE_PERIOD).await;
+                audit.finish(None, Some(e.to_string()));
+                return Err(e).with_context(|| "Failed to wait for do-release-upgrade");
+            }
+            Err(_) => {
+                let termination = terminate_process_group(&mut child, KILL_GRACE_PERIOD).await;
+                let error = format!(
+                    "do-release-upgrade timed out after {} minutes and was {}: {}",
+                    self.config.release_upgrade.timeout_minutes,
+                    termination,
+                    output_tail(&tail, &stderr_tail)
+                );
+                audit.finish(None, Some(error.clone()));
+                return Err(anyhow::anyhow!(error));
+            }
+        };
+
+        if !status.success() {
+            let error = format!(
+                "do-release-upgrade exited with {}: {}",
+                status,
+                output_tail(&tail, &stderr_tail)
+            );
+            audit.finish(Some(status), Some(error.clone()));
+            return Err(anyhow::anyhow!(error));
+        }
+        audit.finish(Some(status), None);
+        Ok(())
+    }
+
+    /// Progress is best effort: a backend that can't be reached must not
+    /// interrupt an upgrade halfway through.
+    async fn send(
+        &self,
+        (phase, from_release, to_release): (UpgradePhase, &str, &str),
+        snapshot: Option<&SnapshotRecord>,
+        rollback: Option<&RollbackOutcome>,
+        error_message: Option<String>,
+    ) {
+        let event = ReleaseUpgradeEvent {
+            hostname: self.hostname.clone(),
+            agent_version: env!("CARGO_PKG_VERSION"),
+            timestamp: Utc::now(),
+            from_release,
+            to_release,
+            phase,
+            snapshot,
+            rollback,
+            error_message,
+        };
+        match self.http_client.post("release-upgrade", &event).await {
+            Ok(response) if response.status().is_success() => {}
+            Ok(response) => warn!(
+                "Backend returned {} for release upgrade progress",
+                response.status()
+            ),
+            Err(e) => warn!("Failed to report release upgrade progress: {:#}", e),
+        }
+    }
+}
+
+/// "New release 'noble' available." from `do-release-upgrade -c`.
+fn parse_new_release(output: &str) -> Option<String> {
+    let re = Regex::new(r"New release '([^']+)' available").ok()?;
+    Some(re.captures(output)?.get(1)?.as_str().to_string())
+}
+
+fn push_tail(tail: &mut VecDeque<String>, line: String) {
+    if tail.len() == OUTPUT_TAIL {
+        tail.pop_front();
+    }
+    tail.push_back(line);
+}
+
+/// The last lines of stdout, then of stderr, for the error and audit entry.
+fn output_tail(stdout: &VecDeque<String>, stderr: &VecDeque<String>) -> String {
+    stdout
+        .iter()
+        .chain(stderr)
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Phase a line of the non-interactive frontend's output belongs to.
+fn phase_for_line(line: &str) -> Option<UpgradePhase> {
+    let line = line.trim();
+    if line.starts_with("Reading cache") || line.starts_with("Checking package manager") {
+        Some(UpgradePhase::Preparing)
+    } else if line.starts_with("Updating repository information") {
+        Some(UpgradePhase::ModifyingSources)
+    } else if line.starts_with("Fetching") || line.starts_with("Calculating the changes") {
+        Some(UpgradePhase::Fetching)
+    } else if line.starts_with("Upgrading")
+        || line.starts_with("Preparing to unpack")
+        || line.starts_with("Unpacking ")
+    {
+        Some(UpgradePhase::Installing)
+    } else if line.starts_with("Searching for obsolete software")
+        || line.starts_with("Removing obsolete")
+    {
+        Some(UpgradePhase::CleaningUp)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upgrade_output_parsing() {
+        assert_eq!(
+            parse_new_release("Checking for a new Ubuntu release\nNew release '24.04.1 LTS' available.\nRun 'do-release-upgrade' to upgrade to it.\n"),
+            Some("24.04.1 LTS".to_string())
+        );
+        assert_eq!(parse_new_release("No new release found.\n"), None);
+
+        let output = [
+            "Reading cache",
+            "Checking package manager",
+            "Updating repository information",
+            "Get:1 http://archive.ubuntu.com/ubuntu noble InRelease [256 kB]",
+            "Calculating the changes",
+            "Fetching",
+            "Preparing to unpack .../libc6_2.39-0ubuntu8_amd64.deb ...",
+            "Setting up libc6:amd64 (2.39-0ubuntu8) ...",
+            "Searching for obsolete software",
+        ];
+        let mut phase = UpgradePhase::Snapshot;
+        let mut phases = Vec::new();
+        for line in output {
+            if let Some(next) = phase_for_line(line).filter(|next| *next > phase) {
+                phase = next;
+                phases.push(phase);
+            }
+        }
+        assert_eq!(
+            phases,
+            [
+                UpgradePhase::Preparing,
+                UpgradePhase::ModifyingSources,
+                UpgradePhase::Fetching,
+                UpgradePhase::Installing,
+                UpgradePhase::CleaningUp
+            ]
+        );
+    }
+}
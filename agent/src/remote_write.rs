@@ -0,0 +1,232 @@
+use anyhow::{Context, Result};
+use prometheus::proto::{MetricFamily, MetricType};
+use prost::Message;
+use reqwest::Method;
+use std::collections::BTreeMap;
+use std::fs;
+use tracing::info;
+
+use crate::config::AgentConfig;
+use crate::http_client::SecureHttpClient;
+use crate::privacy::Redactor;
+
+/// The remote_write 1.0 `WriteRequest`, without the metadata field.
+#[derive(Clone, PartialEq, Message)]
+struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    timeseries: Vec<TimeSeries>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct TimeSeries {
+    /// Sorted by name, `__name__` included
+    #[prost(message, repeated, tag = "1")]
+    labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    samples: Vec<Sample>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Label {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Sample {
+    #[prost(double, tag = "1")]
+    value: f64,
+    #[prost(int64, tag = "2")]
+    timestamp: i64,
+}
+
+/// Pushes the gathered metrics to `remote_write.url` as one
+/// snappy-compressed `WriteRequest`, every series stamped with the time of
+/// the push.
+pub async fn push(
+    config: &AgentConfig,
+    http_client: &SecureHttpClient,
+    families: &[MetricFamily],
+) -> Result<()> {
+    let remote_write = &config.remote_write;
+    let hostname = crate::host::hostname();
+    let hostname = match Redactor::new(config) {
+        Some(redactor) => redactor.hash(&hostname),
+        None => hostname,
+    };
+    let mut labels = remote_write.labels.clone();
+    labels.insert("instance".to_string(), hostname);
+    labels.insert("job".to_string(), remote_write.job.clone());
+
+    let request = write_request(families, &labels, chrono::Utc::now().timestamp_millis());
+    let body = snap::raw::Encoder::new()
+        .compress_vec(&request.encode_to_vec())
+        .context("Failed to compress remote_write request")?;
+
+    let http_client = http_client.for_subsystem("remote_write");
+    let mut http_request = http_client
+        .external(Method::POST, &remote_write.url)
+        .header("Content-Encoding", "snappy")
+        .header("Content-Type", "application/x-protobuf")
+        .header("X-Prometheus-Remote-Write-Version", "0.1.0");
+    for (name, value) in &remote_write.headers {
+        http_request = http_request.header(name, value);
+    }
+    if let Some(path) = &remote_write.bearer_token_file {
+        let path = config.security.credential_path(path);
+        let token = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read token from {:?}", path))?;
+        http_request = http_request.bearer_auth(token.trim());
+    }
+
+    let response = http_client
+        .send_external(http_request.body(body))
+        .await
+        .with_context(|| format!("Failed to push metrics to {}", remote_write.url))?;
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        return Err(anyhow::anyhow!(
+            "remote_write endpoint returned {}: {}",
+            status,
+            body.trim()
+        ));
+    }
+    info!(
+        "Pushed {} series to remote_write endpoint",
+        request.timeseries.len()
+    );
+    Ok(())
+}
+
+/// Flattens metric families into series the way the text exposition does:
+/// histograms become `_bucket` series with `le` plus `_sum` and `_count`,
+/// summaries `quantile` series plus `_sum` and `_count`.
+fn write_request(
+    families: &[MetricFamily],
+    extra_labels: &BTreeMap<String, String>,
+    timestamp: i64,
+) -> WriteRequest {
+    let mut timeseries = Vec::new();
+    for family in families {
+        let name = family.name();
+        for metric in &family.metric {
+            let mut labels = extra_labels.clone();
+            for pair in &metric.label {
+                labels.insert(pair.name().to_string(), pair.value().to_string());
+            }
+            let mut add = |suffix: &str, extra: Option<(&str, String)>, value: f64| {
+                let mut labels = labels.clone();
+                labels.insert("__name__".to_string(), format!("{}{}", name, suffix));
+                if let Some((label, label_value)) = extra {
+                    labels.insert(label.to_string(), label_value);
+                }
+                timeseries.push(TimeSeries {
+                    labels: labels
+                        .into_iter()
+                        .map(|(name, value)| Label { name, value })
+                        .collect(),
+                    samples: vec![Sample { value, timestamp }],
+                });
+            };
+
+            match family.type_() {
+                MetricType::COUNTER => add("", None, metric.counter.value()),
+                MetricType::GAUGE => add("", None, metric.gauge.value()),
+                MetricType::UNTYPED => add("", None, metric.untyped.value()),
+                MetricType::HISTOGRAM => {
+                    let histogram = &metric.histogram;
+                    for bucket in &histogram.bucket {
+                        add(
+                            "_bucket",
+                            Some(("le", bucket.upper_bound().to_string())),
+                            bucket.cumulative_count() as f64,
+                        );
+                    }
+                    let count = histogram.sample_count() as f64;
+                    add("_bucket", Some(("le", "+Inf".to_string())), count);
+                    add("_sum", None, histogram.sample_sum());
+                    add("_count", None, count);
+                }
+                MetricType::SUMMARY => {
+                    let summary = &metric.summary;
+                    for quantile in &summary.quantile {
+                        add(
+                            "",
+                            Some(("quantile", quantile.quantile().to_string())),
+                            quantile.value(),
+                        );
+                    }
+                    add("_sum", None, summary.sample_sum());
+                    add("_count", None, summary.sample_count() as f64);
+                }
+            }
+        }
+    }
+    WriteRequest { timeseries }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::{Histogram, HistogramOpts, IntCounterVec, Opts, Registry};
+
+    #[test]
+    fn test_write_request_flattens_families() {
+        let registry = Registry::new();
+        let runs = IntCounterVec::new(Opts::new("runs_total", "Runs"), &["result"]).unwrap();
+        let duration =
+            Histogram::with_opts(HistogramOpts::new("run_seconds", "Run").buckets(vec![60.0]))
+                .unwrap();
+        registry.register(Box::new(runs.clone())).unwrap();
+        registry.register(Box::new(duration.clone())).unwrap();
+        runs.with_label_values(&["success"]).inc_by(3);
+        duration.observe(42.0);
+        duration.observe(90.0);
+
+        let extra = BTreeMap::from([("job".to_string(), "uau".to_string())]);
+        let request = write_request(&registry.gather(), &extra, 1_700_000_000_000);
+        let encoded = snap::raw::Encoder::new()
+            .compress_vec(&request.encode_to_vec())
+            .unwrap();
+        let decoded = WriteRequest::decode(
+            snap::raw::Decoder::new()
+                .decompress_vec(&encoded)
+                .unwrap()
+                .as_slice(),
+        )
+        .unwrap();
+        assert_eq!(decoded, request);
+
+        let series: Vec<(String, f64)> = decoded
+            .timeseries
+            .iter()
+            .map(|series| {
+                let labels: Vec<String> = series
+                    .labels
+                    .iter()
+                    .map(|label| format!("{}={}", label.name, label.value))
+                    .collect();
+                (labels.join(","), series.samples[0].value)
+            })
+            .collect();
+        assert_eq!(
+            series,
+            vec![
+                ("__name__=run_seconds_bucket,job=uau,le=60".to_string(), 1.0),
+                (
+                    "__name__=run_seconds_bucket,job=uau,le=+Inf".to_string(),
+                    2.0
+                ),
+                ("__name__=run_seconds_sum,job=uau".to_string(), 132.0),
+                ("__name__=run_seconds_count,job=uau".to_string(), 2.0),
+                (
+                    "__name__=runs_total,job=uau,result=success".to_string(),
+                    3.0
+                ),
+            ]
+        );
+    }
+}
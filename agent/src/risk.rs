@@ -0,0 +1,161 @@
+use chrono::Weekday;
+
+use crate::config::{RiskConfig, RiskLevel};
+use crate::updater::is_graphics_package;
+
+/// Packages whose breakage can leave a host unbootable or unreachable
+const HIGH_RISK_PACKAGES: &[&str] = &[
+    "linux-image-*",
+    "linux-modules-*",
+    "linux-headers-*",
+    "linux-generic*",
+    "linux-firmware",
+    "libc6*",
+    "libc-bin",
+    "systemd",
+    "systemd-*",
+    "libsystemd0",
+    "udev",
+    "libudev1",
+    "grub-*",
+    "grub2-*",
+    "shim-signed",
+    // Raspberry Pi and other board firmware and bootloaders
+    "linux-firmware-raspi*",
+    "rpi-eeprom",
+    "u-boot*",
+    "flash-kernel",
+    "initramfs-tools*",
+    "dbus",
+];
+
+/// Shared libraries and remote access, which many services depend on
+const MEDIUM_RISK_PACKAGES: &[&str] = &["lib*", "openssh-server", "openssl"];
+
+/// Scores pending updates by how much a bad version could break, using
+/// `[[risk.rules]]` first and the built-in lists after, and decides which
+/// levels may be applied today under `risk.*_risk_days`.
+pub struct RiskScorer<'a> {
+    config: &'a RiskConfig,
+}
+
+impl<'a> RiskScorer<'a> {
+    pub fn new(config: &'a RiskConfig) -> Self {
+        Self { config }
+    }
+
+    pub fn score(&self, source: &str, package: &str) -> RiskLevel {
+        if let Some(rule) = self
+            .config
+            .rules
+            .iter()
+            .find(|rule| matches_pattern(&rule.pattern, package))
+        {
+            return rule.level;
+        }
+        // Snaps and flatpaks bundle their dependencies
+        if source != "apt" {
+            return RiskLevel::Low;
+        }
+
+        if is_graphics_package(package)
+            || HIGH_RISK_PACKAGES
+                .iter()
+                .any(|pattern| matches_pattern(pattern, package))
+        {
+            RiskLevel::High
+        } else if MEDIUM_RISK_PACKAGES
+            .iter()
+            .any(|pattern| matches_pattern(pattern, package))
+        {
+            RiskLevel::Medium
+        } else {
+            RiskLevel::Low
+        }
+    }
+
+    /// Whether updates of `level` may be applied on `day`.
+    pub fn allowed_on(&self, level: RiskLevel, day: Weekday) -> bool {
+        let days = match level {
+            RiskLevel::High => &self.config.high_risk_days,
+            RiskLevel::Medium => &self.config.medium_risk_days,
+            RiskLevel::Low => return true,
+        };
+        days.is_empty()
+            || days.iter().any(|allowed| {
+                allowed
+                    .parse::<Weekday>()
+                    .is_ok_and(|allowed| allowed == day)
+            })
+    }
+}
+
+/// Glob match where `*` stands for any run of characters.
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+
+    let mut parts = parts.peekable();
+    if parts.peek().is_none() {
+        return rest.is_empty();
+    }
+    while let Some(part) = parts.next() {
+        if parts.peek().is_none() {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RiskRule;
+
+    #[test]
+    fn test_matches_pattern() {
+        assert!(matches_pattern(
+            "linux-image-*",
+            "linux-image-6.8.0-45-generic"
+        ));
+        assert!(matches_pattern("libc6*", "libc6"));
+        assert!(matches_pattern("*-dbg", "nginx-dbg"));
+        assert!(matches_pattern("postgresql-*-main", "postgresql-16-main"));
+        assert!(matches_pattern("udev", "udev"));
+        assert!(!matches_pattern("udev", "udevil"));
+        assert!(!matches_pattern("lib*", "firefox"));
+        assert!(!matches_pattern("postgresql-*-main", "postgresql-16"));
+    }
+
+    #[test]
+    fn test_score_and_schedule() {
+        let config = RiskConfig {
+            rules: vec![RiskRule {
+                pattern: "postgresql*".to_string(),
+                level: RiskLevel::High,
+            }],
+            high_risk_days: vec!["sat".to_string(), "sun".to_string()],
+            medium_risk_days: vec![],
+        };
+        let scorer = RiskScorer::new(&config);
+
+        assert_eq!(scorer.score("apt", "linux-image-generic"), RiskLevel::High);
+        assert_eq!(scorer.score("apt", "mesa-vulkan-drivers"), RiskLevel::High);
+        assert_eq!(scorer.score("apt", "postgresql-16"), RiskLevel::High);
+        assert_eq!(scorer.score("apt", "libxml2"), RiskLevel::Medium);
+        assert_eq!(scorer.score("apt", "htop"), RiskLevel::Low);
+        assert_eq!(scorer.score("snap", "libreoffice"), RiskLevel::Low);
+
+        assert!(scorer.allowed_on(RiskLevel::High, Weekday::Sat));
+        assert!(!scorer.allowed_on(RiskLevel::High, Weekday::Wed));
+        assert!(scorer.allowed_on(RiskLevel::Medium, Weekday::Wed));
+        assert!(scorer.allowed_on(RiskLevel::Low, Weekday::Wed));
+    }
+}
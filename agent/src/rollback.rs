@@ -0,0 +1,424 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tracing::{debug, info, warn};
+
+use crate::config::{AgentConfig, SnapshotConfig};
+use crate::privileges::{Operation, Privileges};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SnapshotBackend {
+    Timeshift,
+    Snapper,
+    Btrfs,
+    Lvm,
+}
+
+impl SnapshotBackend {
+    pub fn parse(name: &str) -> Result<Option<Self>> {
+        match name {
+            "auto" => Ok(None),
+            "timeshift" => Ok(Some(Self::Timeshift)),
+            "snapper" => Ok(Some(Self::Snapper)),
+            "btrfs" => Ok(Some(Self::Btrfs)),
+            "lvm" => Ok(Some(Self::Lvm)),
+            _ => Err(anyhow::anyhow!("Unknown snapshot backend: {}", name)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotRecord {
+    pub backend: SnapshotBackend,
+    pub id: String,
+    pub created_at: DateTime<Utc>,
+    pub description: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RollbackOutcome {
+    pub snapshot_id: String,
+    pub success: bool,
+    pub reboot_required: bool,
+    pub error_message: Option<String>,
+}
+
+pub struct SnapshotManager {
+    config: SnapshotConfig,
+    log_path: PathBuf,
+    /// Runs snapshot tool commands; replaced in tests
+    run: fn(&str, &[&str]) -> Result<String>,
+}
+
+impl SnapshotManager {
+    pub fn new(config: &AgentConfig) -> Self {
+        Self {
+            config: config.snapshot.clone(),
+            log_path: config.state.dir.join("snapshots.json"),
+            run,
+        }
+    }
+
+    pub fn detect_backend(&self) -> Result<SnapshotBackend> {
+        if let Some(backend) = SnapshotBackend::parse(&self.config.backend)? {
+            return Ok(backend);
+        }
+
+        if Path::new("/etc/snapper/configs/root").exists() && command_exists("snapper") {
+            return Ok(SnapshotBackend::Snapper);
+        }
+        if Path::new("/etc/timeshift/timeshift.json").exists() && command_exists("timeshift") {
+            return Ok(SnapshotBackend::Timeshift);
+        }
+
+        let fstype = run("findmnt", &["-no", "FSTYPE", "/"]).unwrap_or_default();
+        if fstype.trim() == "btrfs" && command_exists("btrfs") {
+            return Ok(SnapshotBackend::Btrfs);
+        }
+
+        if self.config.lvm_volume.is_some() && command_exists("lvcreate") {
+            return Ok(SnapshotBackend::Lvm);
+        }
+
+        Err(anyhow::anyhow!(
+            "No supported snapshot backend found (timeshift, snapper, btrfs, lvm)"
+        ))
+    }
+
+    pub fn create_snapshot(&self, description: &str) -> Result<SnapshotRecord> {
+        Privileges::current().require(Operation::Snapshots)?;
+        let backend = self.detect_backend()?;
+        let name = format!("ua-{}", Utc::now().format("%Y%m%d-%H%M%S"));
+        info!("Creating {:?} snapshot before updates", backend);
+
+        let id = match backend {
+            SnapshotBackend::Timeshift => {
+                let output = run(
+                    "timeshift",
+                    &[
+                        "--create",
+                        "--comments",
+                        description,
+                        "--tags",
+                        "O",
+                        "--scripted",
+                    ],
+                )?;
+                parse_timeshift_snapshot_name(&output).ok_or_else(|| {
+                    anyhow::anyhow!("Could not find snapshot name in timeshift output")
+                })?
+            }
+            SnapshotBackend::Snapper => run(
+                "snapper",
+                &[
+                    "-c",
+                    "root",
+                    "create",
+                    "--type",
+                    "single",
+                    "--cleanup-algorithm",
+                    "number",
+                    "--print-number",
+                    "--description",
+                    description,
+                ],
+            )?
+            .trim()
+            .to_string(),
+            SnapshotBackend::Btrfs => {
+                let target = self.config.btrfs_snapshot_dir.join(&name);
+                fs::create_dir_all(&self.config.btrfs_snapshot_dir).with_context(|| {
+                    format!(
+                        "Failed to create snapshot directory: {:?}",
+                        self.config.btrfs_snapshot_dir
+                    )
+                })?;
+                run(
+                    "btrfs",
+                    &[
+                        "subvolume",
+                        "snapshot",
+                        "-r",
+                        "/",
+                        &target.to_string_lossy(),
+                    ],
+                )?;
+                target.to_string_lossy().to_string()
+            }
+            SnapshotBackend::Lvm => {
+                let volume = self.lvm_volume()?;
+                run("lvcreate", &["--snapshot", "--name", &name, volume])?;
+                format!("{}/{}", volume_group(volume), name)
+            }
+        };
+
+        let record = SnapshotRecord {
+            backend,
+            id,
+            created_at: Utc::now(),
+            description: description.to_string(),
+        };
+        self.append_record(&record)?;
+
+        info!("Snapshot created: {}", record.id);
+        Ok(record)
+    }
+
+    /// Restores a snapshot. Every backend finishes the restore on the next boot.
+    pub fn rollback(&self, record: &SnapshotRecord) -> RollbackOutcome {
+        warn!(
+            "Rolling back to {:?} snapshot {}",
+            record.backend, record.id
+        );
+
+        let result = match record.backend {
+            SnapshotBackend::Timeshift => run(
+                "timeshift",
+                &["--restore", "--snapshot", &record.id, "--yes", "--scripted"],
+            ),
+            SnapshotBackend::Snapper => run("snapper", &["-c", "root", "rollback", &record.id]),
+            SnapshotBackend::Btrfs => {
+                let writable = format!("{}-rollback", record.id);
+                run("btrfs", &["subvolume", "snapshot", &record.id, &writable])
+                    .and_then(|_| run("btrfs", &["subvolume", "set-default", &writable]))
+            }
+            SnapshotBackend::Lvm => run("lvconvert", &["--merge", &record.id]),
+        };
+
+        match result {
+            Ok(_) => RollbackOutcome {
+                snapshot_id: record.id.clone(),
+                success: true,
+                reboot_required: true,
+                error_message: None,
+            },
+            Err(e) => RollbackOutcome {
+                snapshot_id: record.id.clone(),
+                success: false,
+                reboot_required: false,
+                error_message: Some(e.to_string()),
+            },
+        }
+    }
+
+    pub fn list(&self) -> Result<Vec<SnapshotRecord>> {
+        if !self.log_path.exists() {
+            return Ok(vec![]);
+        }
+
+        let content = fs::read_to_string(&self.log_path)
+            .with_context(|| format!("Failed to read snapshot log {:?}", self.log_path))?;
+        serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse snapshot log {:?}", self.log_path))
+    }
+
+    pub fn find(&self, id: Option<&str>) -> Result<SnapshotRecord> {
+        let records = self.list()?;
+        let record = match id {
+            Some(id) => records.into_iter().rev().find(|r| r.id == id),
+            None => records.into_iter().last(),
+        };
+        record.ok_or_else(|| anyhow::anyhow!("No matching snapshot recorded by the agent"))
+    }
+
+    fn append_record(&self, record: &SnapshotRecord) -> Result<()> {
+        let mut records = self.list()?;
+        records.push(record.clone());
+
+        // Only the most recent snapshots are worth rolling back to
+        let keep = self.config.keep.max(1);
+        let pruned: Vec<SnapshotRecord> = if records.len() > keep {
+            records.drain(..records.len() - keep).collect()
+        } else {
+            Vec::new()
+        };
+
+        if let Some(parent) = self.log_path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory: {:?}", parent))?;
+        }
+        fs::write(&self.log_path, serde_json::to_string_pretty(&records)?)
+            .with_context(|| format!("Failed to write snapshot log {:?}", self.log_path))?;
+
+        debug!("Recorded snapshot {} in {:?}", record.id, self.log_path);
+
+        for old in &pruned {
+            self.delete_snapshot(old);
+        }
+        Ok(())
+    }
+
+    /// Deletes a snapshot that fell out of `snapshot.keep`, so btrfs
+    /// subvolumes don't pile up and LVM snapshots don't fill the volume
+    /// group. Snapper prunes its own with the number cleanup algorithm. A
+    /// failure is logged, not returned; the run goes ahead regardless.
+    fn delete_snapshot(&self, record: &SnapshotRecord) {
+        let result = match record.backend {
+            SnapshotBackend::Timeshift => (self.run)(
+                "timeshift",
+                &["--delete", "--snapshot", &record.id, "--scripted"],
+            ),
+            SnapshotBackend::Snapper => return,
+            SnapshotBackend::Btrfs => (self.run)("btrfs", &["subvolume", "delete", &record.id]),
+            SnapshotBackend::Lvm => (self.run)("lvremove", &["--yes", &record.id]),
+        };
+        match result {
+            Ok(_) => info!("Deleted {:?} snapshot {}", record.backend, record.id),
+            Err(e) => warn!(
+                "Failed to delete {:?} snapshot {}: {:#}",
+                record.backend, record.id, e
+            ),
+        }
+    }
+
+    fn lvm_volume(&self) -> Result<&str> {
+        self.config
+            .lvm_volume
+            .as_deref()
+            .ok_or_else(|| anyhow::anyhow!("snapshot.lvm_volume must be set for the lvm backend"))
+    }
+}
+
+fn parse_timeshift_snapshot_name(output: &str) -> Option<String> {
+    // "Tagged snapshot '2024-01-01_02-00-01': ondemand"
+    output
+        .lines()
+        .find(|line| line.starts_with("Tagged snapshot"))
+        .and_then(|line| line.split('\'').nth(1))
+        .map(|name| name.to_string())
+}
+
+fn volume_group(volume: &str) -> &str {
+    volume.split('/').next().unwrap_or(volume)
+}
+
+pub(crate) fn command_exists(name: &str) -> bool {
+    std::env::var_os("PATH")
+        .map(|paths| std::env::split_paths(&paths).any(|dir| dir.join(name).is_file()))
+        .unwrap_or(false)
+}
+
+fn run(command: &str, args: &[&str]) -> Result<String> {
+    debug!("Running command: {} {}", command, args.join(" "));
+
+    let output = crate::audit::output(Command::new(command).args(args))
+        .with_context(|| format!("Failed to run {}", command))?;
+
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "{} failed: {}",
+            command,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_parse_timeshift_snapshot_name() {
+        let output = "Creating new snapshot...(RSYNC)\nSaving to device: /dev/sda1\nTagged snapshot '2024-01-01_02-00-01': ondemand\n";
+        assert_eq!(
+            parse_timeshift_snapshot_name(output).as_deref(),
+            Some("2024-01-01_02-00-01")
+        );
+        assert!(parse_timeshift_snapshot_name("nothing here").is_none());
+    }
+
+    #[test]
+    fn test_snapshot_log_keeps_recent_records() {
+        let temp_dir = tempdir().unwrap();
+        let mut config = AgentConfig::default();
+        config.state.dir = temp_dir.path().to_path_buf();
+        config.snapshot.keep = 2;
+        let manager = SnapshotManager::new(&config);
+
+        for id in ["1", "2", "3"] {
+            manager
+                .append_record(&SnapshotRecord {
+                    backend: SnapshotBackend::Snapper,
+                    id: id.to_string(),
+                    created_at: Utc::now(),
+                    description: "test".to_string(),
+                })
+                .unwrap();
+        }
+
+        let ids: Vec<String> = manager.list().unwrap().into_iter().map(|r| r.id).collect();
+        assert_eq!(ids, vec!["2", "3"]);
+        assert_eq!(manager.find(None).unwrap().id, "3");
+        assert_eq!(manager.find(Some("2")).unwrap().id, "2");
+        assert!(manager.find(Some("1")).is_err());
+    }
+
+    #[test]
+    fn test_pruned_snapshots_are_deleted() {
+        use std::cell::RefCell;
+        thread_local! {
+            static ISSUED: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+        }
+        fn record_command(command: &str, args: &[&str]) -> Result<String> {
+            ISSUED.with(|issued| {
+                issued
+                    .borrow_mut()
+                    .push(format!("{} {}", command, args.join(" ")))
+            });
+            if command == "lvremove" {
+                return Err(anyhow::anyhow!("lvremove failed: in use"));
+            }
+            Ok(String::new())
+        }
+
+        let temp_dir = tempdir().unwrap();
+        let mut config = AgentConfig::default();
+        config.state.dir = temp_dir.path().to_path_buf();
+        config.snapshot.keep = 1;
+        let mut manager = SnapshotManager::new(&config);
+        manager.run = record_command;
+
+        for (backend, id) in [
+            (SnapshotBackend::Btrfs, "/.snapshots/ua-1"),
+            (SnapshotBackend::Lvm, "vg0/ua-2"),
+            (SnapshotBackend::Snapper, "42"),
+            (SnapshotBackend::Snapper, "43"),
+        ] {
+            manager
+                .append_record(&SnapshotRecord {
+                    backend,
+                    id: id.to_string(),
+                    created_at: Utc::now(),
+                    description: "test".to_string(),
+                })
+                .unwrap();
+        }
+
+        let issued = ISSUED.with(|issued| issued.borrow().clone());
+        assert_eq!(
+            issued,
+            vec![
+                "btrfs subvolume delete /.snapshots/ua-1",
+                "lvremove --yes vg0/ua-2"
+            ]
+        );
+        assert_eq!(manager.find(None).unwrap().id, "43");
+    }
+
+    #[test]
+    fn test_backend_parsing() {
+        assert_eq!(SnapshotBackend::parse("auto").unwrap(), None);
+        assert_eq!(
+            SnapshotBackend::parse("lvm").unwrap(),
+            Some(SnapshotBackend::Lvm)
+        );
+        assert!(SnapshotBackend::parse("zfs").is_err());
+    }
+}
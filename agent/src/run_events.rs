@@ -0,0 +1,136 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use reqwest::StatusCode;
+use serde::Serialize;
+use tracing::{debug, info, warn};
+
+use crate::config::{AgentConfig, Transport};
+use crate::http_client::SecureHttpClient;
+#[cfg(feature = "mqtt")]
+use crate::mqtt::MqttTransport;
+#[cfg(feature = "nats")]
+use crate::nats::NatsTransport;
+use crate::privacy::{seal, Redactor};
+use crate::updater::PendingUpdate;
+
+/// Sent to `/api/v1/runs/started` once an update run is past its deferral
+/// checks and before it changes anything. The run's report carries the
+/// same `run_id`, so a start without a report marks a host that died or
+/// hung mid-update.
+#[derive(Debug, Clone, Serialize)]
+pub struct RunStarted {
+    pub run_id: String,
+    pub hostname: String,
+    pub agent_version: String,
+    pub timestamp: DateTime<Utc>,
+    pub dry_run: bool,
+    pub policy_version: Option<String>,
+    /// Updates pending in the apt cache and from snapd/flatpak when the run
+    /// started; the apt-get update the run does first may add to them
+    pub planned: Vec<PendingUpdate>,
+}
+
+impl RunStarted {
+    pub fn new(
+        config: &AgentConfig,
+        policy_version: Option<String>,
+        planned: Vec<PendingUpdate>,
+    ) -> Self {
+        Self {
+            run_id: uuid::Uuid::new_v4().to_string(),
+            hostname: crate::host::hostname(),
+            agent_version: env!("CARGO_PKG_VERSION").to_string(),
+            timestamp: Utc::now(),
+            dry_run: config.updates.dry_run,
+            policy_version,
+            planned,
+        }
+    }
+
+    /// The minimal reporting profile hashes the hostname, as in reports.
+    fn redacted(mut self, redactor: &Redactor) -> Self {
+        self.hostname = redactor.hash(&self.hostname);
+        self
+    }
+}
+
+/// Sends the start event once, without the retries reports get: the run
+/// doesn't wait on it, and the report that follows is what counts.
+pub async fn announce(
+    config: &AgentConfig,
+    client: &SecureHttpClient,
+    event: &RunStarted,
+) -> Result<()> {
+    let event = match Redactor::new(config) {
+        Some(redactor) => event.clone().redacted(&redactor),
+        None => event.clone(),
+    };
+
+    // Nothing reads them from a bucket
+    if config.backend.transport == Transport::S3 {
+        return Ok(());
+    }
+
+    #[cfg(feature = "nats")]
+    if config.backend.transport == Transport::Nats {
+        let nats = NatsTransport::connect(config).await?;
+        match seal(config, &event)? {
+            Some(sealed) => nats.publish("runs.started", &sealed).await,
+            None => nats.publish("runs.started", &event).await,
+        }
+        .with_context(|| "Failed to publish run start to NATS")?;
+        debug!("Run start {} published to NATS", event.run_id);
+        return Ok(());
+    }
+
+    #[cfg(feature = "mqtt")]
+    if config.backend.transport == Transport::Mqtt {
+        let mut mqtt = MqttTransport::connect(config).await?;
+        match seal(config, &event)? {
+            Some(sealed) => mqtt.publish("runs/started", &sealed, false).await,
+            None => mqtt.publish("runs/started", &event, false).await,
+        }
+        .with_context(|| "Failed to publish run start to MQTT")?;
+        mqtt.close().await;
+        debug!("Run start {} published to MQTT", event.run_id);
+        return Ok(());
+    }
+
+    let response = match seal(config, &event)? {
+        Some(sealed) => client.post("runs/started", &sealed).await,
+        None => client.post("runs/started", &event).await,
+    }
+    .with_context(|| "Failed to send run start to backend")?;
+
+    match response.status() {
+        status if status.is_success() => {
+            info!(
+                "Announced run {} with {} planned update(s)",
+                event.run_id,
+                event.planned.len()
+            );
+        }
+        // Backends from before start events
+        StatusCode::NOT_FOUND => debug!("Backend does not accept run start events"),
+        status => warn!("Backend returned {} for run start", status),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_minimal_profile_hashes_hostname() {
+        let mut config = AgentConfig::default();
+        config.reporting.profile = "minimal".to_string();
+        let event = RunStarted::new(&config, Some("v7".to_string()), Vec::new());
+        let hostname = event.hostname.clone();
+
+        let redacted = event.clone().redacted(&Redactor::new(&config).unwrap());
+        assert_ne!(redacted.hostname, hostname);
+        assert_eq!(redacted.run_id, event.run_id);
+        assert_eq!(redacted.policy_version.as_deref(), Some("v7"));
+    }
+}
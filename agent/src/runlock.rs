@@ -0,0 +1,117 @@
+use anyhow::{Context, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info};
+
+use crate::config::AgentConfig;
+
+/// Exit status of an invocation that found another run in progress
+/// (EX_TEMPFAIL), so timers and cron wrappers can tell it from a failure.
+pub const EXIT_ALREADY_RUNNING: i32 = 75;
+
+#[derive(Debug, thiserror::Error)]
+#[error("Another update run is in progress{}", holder(*pid))]
+pub struct AlreadyRunning {
+    pub pid: Option<u32>,
+}
+
+fn holder(pid: Option<u32>) -> String {
+    pid.map(|pid| format!(" (pid {})", pid)).unwrap_or_default()
+}
+
+/// Exclusive hold on `<state.dir>/run.lock` for the length of an update run,
+/// so the timer, cron, the daemon and a manual `run` never overlap.
+///
+/// The lock is an flock(2) on the file, which the kernel drops when the
+/// holder exits however it exits. The file also records the holder's pid
+/// for the "in progress" message; it is emptied on a clean release, so a
+/// pid left behind marks a run that was killed or lost power.
+pub struct RunLock {
+    file: File,
+}
+
+impl RunLock {
+    /// The lock lives under state.dir rather than /run, which is read-only
+    /// under the units' ProtectSystem=strict.
+    pub fn acquire(config: &AgentConfig) -> Result<Self> {
+        Self::acquire_at(&config.state.dir.join("run.lock"))
+    }
+
+    fn acquire_at(path: &Path) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {:?}", dir))?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("Failed to open run lock {:?}", path))?;
+
+        // SAFETY: the descriptor stays open for the call
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let e = std::io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::EWOULDBLOCK) {
+                return Err(AlreadyRunning {
+                    pid: recorded_pid(&mut file),
+                }
+                .into());
+            }
+            return Err(e).with_context(|| format!("Failed to lock {:?}", path));
+        }
+
+        if let Some(pid) = recorded_pid(&mut file) {
+            if pid != std::process::id() && !PathBuf::from(format!("/proc/{}", pid)).exists() {
+                info!(
+                    "Previous run (pid {}) ended without releasing its lock, taking it over",
+                    pid
+                );
+            }
+        }
+        file.set_len(0)
+            .and_then(|_| file.rewind())
+            .and_then(|_| writeln!(file, "{}", std::process::id()))
+            .with_context(|| format!("Failed to write run lock {:?}", path))?;
+        debug!("Holding run lock {:?}", path);
+        Ok(Self { file })
+    }
+}
+
+impl Drop for RunLock {
+    fn drop(&mut self) {
+        // Closing the file releases the flock
+        let _ = self.file.set_len(0);
+    }
+}
+
+fn recorded_pid(file: &mut File) -> Option<u32> {
+    let mut contents = String::new();
+    file.rewind().ok()?;
+    file.read_to_string(&mut contents).ok()?;
+    contents.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_second_run_is_refused() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("run.lock");
+        // Left behind by a run that was killed
+        std::fs::write(&path, "999999999\n").unwrap();
+
+        let lock = RunLock::acquire_at(&path).unwrap();
+        let err = RunLock::acquire_at(&path).err().unwrap();
+        let running = err.downcast_ref::<AlreadyRunning>().unwrap();
+        assert_eq!(running.pid, Some(std::process::id()));
+
+        drop(lock);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+        assert!(RunLock::acquire_at(&path).is_ok());
+    }
+}
@@ -0,0 +1,193 @@
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use crate::config::AgentConfig;
+use crate::privileges::{Capability, Privileges};
+
+/// Last-resort state directory; on tmpfs, so its contents don't survive a
+/// reboot
+const RUNTIME_STATE_DIR: &str = "/run/ubuntu-auto-update";
+
+/// A configured path that wasn't writable and what was used instead.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Relocation {
+    pub setting: &'static str,
+    pub from: PathBuf,
+    /// `None` when no fallback was writable either. `state.dir` then stays
+    /// as configured; `logging.file` is dropped in favour of the journal
+    pub to: Option<PathBuf>,
+}
+
+/// Restrictions the agent found itself running under, from a systemd
+/// unit's sandboxing (ProtectSystem, NoNewPrivileges, DynamicUser,
+/// CapabilityBoundingSet) or a container.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SandboxStatus {
+    pub no_new_privileges: bool,
+    pub cap_sys_admin: bool,
+    pub read_only_etc: bool,
+    pub relocations: Vec<Relocation>,
+}
+
+impl SandboxStatus {
+    /// One line per restriction that will keep some feature from working,
+    /// for the startup log and `doctor`.
+    pub fn warnings(&self, config: &AgentConfig) -> Vec<String> {
+        let mut warnings: Vec<String> = self
+            .relocations
+            .iter()
+            .map(|relocation| match &relocation.to {
+                Some(to) => format!(
+                    "{} {:?} is not writable, using {:?}",
+                    relocation.setting, relocation.from, to
+                ),
+                None => format!(
+                    "{} {:?} is not writable and neither is any fallback",
+                    relocation.setting, relocation.from
+                ),
+            })
+            .collect();
+        if self.read_only_etc {
+            warnings.push(
+                "/etc is read-only (ProtectSystem=strict/full?), hold/unhold cannot update \
+                 the config file and apt cannot write its configuration"
+                    .to_string(),
+            );
+        }
+        if config.snapshot.enabled && !self.cap_sys_admin {
+            warnings
+                .push("CAP_SYS_ADMIN is missing, so pre-update snapshots will fail".to_string());
+        }
+        if self.no_new_privileges && config.updates.update_sources.snap {
+            warnings.push(
+                "NoNewPrivileges is set; snap refresh and setuid maintainer scripts may fail"
+                    .to_string(),
+            );
+        }
+        warnings
+    }
+}
+
+/// Probes the sandbox and points `state.dir` and `logging.file` at writable
+/// locations when the configured ones aren't, so a hardened unit degrades
+/// instead of failing with a bare "Permission denied" on first write.
+pub fn adapt(config: &mut AgentConfig) -> SandboxStatus {
+    let no_new_privileges = std::fs::read_to_string("/proc/self/status")
+        .is_ok_and(|status| parse_no_new_privs(&status));
+    let mut status = SandboxStatus {
+        no_new_privileges,
+        cap_sys_admin: Privileges::current().has(Capability::SysAdmin),
+        read_only_etc: read_only(Path::new("/etc")),
+        relocations: Vec::new(),
+    };
+
+    if !writable(&config.state.dir) {
+        let to = state_dir_candidates().into_iter().find(|dir| writable(dir));
+        status.relocations.push(Relocation {
+            setting: "state.dir",
+            from: config.state.dir.clone(),
+            to: to.clone(),
+        });
+        if let Some(to) = to {
+            config.state.dir = to;
+        }
+    }
+
+    if let Some(file) = config.logging.file.clone() {
+        let dir = file.parent().unwrap_or(Path::new("."));
+        if !writable(dir) {
+            // systemd's LogsDirectory=, otherwise log to the journal only
+            let to = systemd_directory("LOGS_DIRECTORY")
+                .filter(|dir| writable(dir))
+                .map(|dir| dir.join(file.file_name().unwrap_or_default()));
+            status.relocations.push(Relocation {
+                setting: "logging.file",
+                from: file,
+                to: to.clone(),
+            });
+            config.logging.file = to;
+        }
+    }
+
+    status
+}
+
+/// `StateDirectory=` and `RuntimeDirectory=` from the unit, then the
+/// runtime fallback under /run.
+fn state_dir_candidates() -> Vec<PathBuf> {
+    let mut candidates: Vec<PathBuf> = ["STATE_DIRECTORY", "RUNTIME_DIRECTORY"]
+        .into_iter()
+        .filter_map(systemd_directory)
+        .collect();
+    candidates.push(PathBuf::from(RUNTIME_STATE_DIR));
+    candidates
+}
+
+/// First entry of a colon-separated directory variable systemd sets for
+/// `StateDirectory=`, `RuntimeDirectory=` and `LogsDirectory=`.
+fn systemd_directory(variable: &str) -> Option<PathBuf> {
+    let value = std::env::var(variable).ok()?;
+    value
+        .split(':')
+        .find(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+}
+
+/// The NoNewPrivs line of /proc/self/status.
+fn parse_no_new_privs(status: &str) -> bool {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("NoNewPrivs:"))
+        .is_some_and(|value| value.trim() == "1")
+}
+
+fn writable(dir: &Path) -> bool {
+    std::fs::create_dir_all(dir).is_ok() && access(dir, libc::W_OK)
+}
+
+fn read_only(path: &Path) -> bool {
+    let Ok(path) = CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    // SAFETY: statvfs only writes to the struct passed in
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    let result = unsafe { libc::statvfs(path.as_ptr(), &mut stat) };
+    result == 0 && stat.f_flag & libc::ST_RDONLY != 0
+}
+
+fn access(path: &Path, mode: libc::c_int) -> bool {
+    let Ok(path) = CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    // SAFETY: path is a valid NUL-terminated string
+    unsafe { libc::access(path.as_ptr(), mode) == 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_no_new_privs() {
+        let status = "Name:\tua-agent\nCapEff:\t000001ffffffffff\nNoNewPrivs:\t0\n";
+        assert!(!parse_no_new_privs(status));
+
+        // DynamicUser: NoNewPrivileges implied
+        let status = "Name:\tua-agent\nCapEff:\t0000000000000000\nNoNewPrivs:\t1\n";
+        assert!(parse_no_new_privs(status));
+    }
+
+    #[test]
+    fn test_adapt_keeps_writable_paths() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut config = AgentConfig::default();
+        config.state.dir = temp_dir.path().join("state");
+        config.logging.file = Some(temp_dir.path().join("log/agent.log"));
+
+        let status = adapt(&mut config);
+        assert!(status.relocations.is_empty());
+        assert_eq!(config.state.dir, temp_dir.path().join("state"));
+        assert!(config.state.dir.is_dir());
+    }
+}
@@ -0,0 +1,399 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use serde_json::{json, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tracing::{debug, info, warn};
+
+use crate::config::{AgentConfig, SbomFormat, Transport};
+use crate::distro::DistroInfo;
+use crate::http_client::SecureHttpClient;
+use crate::privacy::{seal, Redactor};
+use crate::updater::parse_snap_list;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstalledPackage {
+    /// "apt", "snap" or "flatpak", as in `list-updates`
+    pub source: &'static str,
+    pub name: String,
+    pub version: String,
+    pub architecture: Option<String>,
+}
+
+/// Lists installed deb packages, plus snaps and flatpaks when their updates
+/// are enabled.
+pub fn collect_installed(config: &AgentConfig) -> Result<Vec<InstalledPackage>> {
+    let output = crate::audit::output(Command::new("dpkg-query").args([
+        "-W",
+        "-f=${db:Status-Abbrev}\t${Package}\t${Version}\t${Architecture}\n",
+    ]))
+    .with_context(|| "Failed to run dpkg-query")?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "dpkg-query failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let mut packages = parse_dpkg_query(&String::from_utf8_lossy(&output.stdout));
+
+    if config.updates.update_sources.snap && Path::new("/usr/bin/snap").exists() {
+        let output = crate::audit::output(Command::new("snap").arg("list"))
+            .with_context(|| "Failed to run snap list")?;
+        let mut snaps: Vec<_> = parse_snap_list(&String::from_utf8_lossy(&output.stdout))
+            .into_iter()
+            .map(|(name, version)| InstalledPackage {
+                source: "snap",
+                name,
+                version,
+                architecture: None,
+            })
+            .collect();
+        snaps.sort_by(|a, b| a.name.cmp(&b.name));
+        packages.extend(snaps);
+    }
+
+    if config.updates.update_sources.flatpak && Path::new("/usr/bin/flatpak").exists() {
+        let output = crate::audit::output(
+            Command::new("flatpak").args(["list", "--columns=application,version,arch,branch"]),
+        )
+        .with_context(|| "Failed to run flatpak list")?;
+        packages.extend(parse_flatpak_list(&String::from_utf8_lossy(&output.stdout)));
+    }
+    Ok(packages)
+}
+
+/// Parses `dpkg-query -W` output, keeping fully installed ("ii") packages.
+fn parse_dpkg_query(output: &str) -> Vec<InstalledPackage> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            if fields.next()?.trim() != "ii" {
+                return None;
+            }
+            Some(InstalledPackage {
+                source: "apt",
+                name: fields.next()?.to_string(),
+                version: fields.next()?.to_string(),
+                architecture: fields.next().map(str::to_string),
+            })
+        })
+        .collect()
+}
+
+/// Parses tab-separated `flatpak list --columns=application,version,arch,branch`.
+/// Runtimes often have no version, so the branch stands in.
+fn parse_flatpak_list(output: &str) -> Vec<InstalledPackage> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t').map(str::trim);
+            let name = fields.next().filter(|name| name.contains('.'))?;
+            let version = fields.next().unwrap_or_default();
+            let architecture = fields.next().filter(|arch| !arch.is_empty());
+            let branch = fields.next().unwrap_or_default();
+            Some(InstalledPackage {
+                source: "flatpak",
+                name: name.to_string(),
+                version: if version.is_empty() { branch } else { version }.to_string(),
+                architecture: architecture.map(str::to_string),
+            })
+        })
+        .collect()
+}
+
+/// `pkg:deb` purl for a deb, so vulnerability scanners can match it against
+/// distro advisories. Snaps and flatpaks have no purl type.
+fn purl(package: &InstalledPackage, distro: &DistroInfo) -> Option<String> {
+    if package.source != "apt" {
+        return None;
+    }
+    let mut purl = format!(
+        "pkg:deb/{}/{}@{}",
+        distro.id,
+        purl_encode(&package.name),
+        purl_encode(&package.version)
+    );
+    let mut qualifiers = Vec::new();
+    if let Some(architecture) = &package.architecture {
+        qualifiers.push(format!("arch={}", architecture));
+    }
+    if let Some(codename) = distro.version_codename.as_deref().filter(|c| !c.is_empty()) {
+        qualifiers.push(format!("distro={}", codename));
+    }
+    if !qualifiers.is_empty() {
+        purl = format!("{}?{}", purl, qualifiers.join("&"));
+    }
+    Some(purl)
+}
+
+/// Builds a CycloneDX 1.5 JSON document.
+pub fn cyclonedx(packages: &[InstalledPackage], hostname: &str, distro: &DistroInfo) -> Value {
+    let codename = distro.version_codename.as_deref().unwrap_or_default();
+    let components: Vec<Value> = packages
+        .iter()
+        .map(|package| match purl(package, distro) {
+            Some(purl) => json!({
+                "type": "library",
+                "bom-ref": purl,
+                "name": package.name,
+                "version": package.version,
+                "purl": purl,
+            }),
+            None => json!({
+                "type": "application",
+                "bom-ref": format!("{}:{}@{}", package.source, package.name, package.version),
+                "name": package.name,
+                "version": package.version,
+                "properties": [{"name": "ubuntu-auto-update:source", "value": package.source}],
+            }),
+        })
+        .collect();
+
+    json!({
+        "bomFormat": "CycloneDX",
+        "specVersion": "1.5",
+        "serialNumber": format!("urn:uuid:{}", uuid::Uuid::new_v4()),
+        "version": 1,
+        "metadata": {
+            "timestamp": Utc::now().to_rfc3339(),
+            "tools": {
+                "components": [{
+                    "type": "application",
+                    "name": "ua-agent",
+                    "version": env!("CARGO_PKG_VERSION"),
+                }],
+            },
+            "component": {
+                "type": "operating-system",
+                "bom-ref": "host",
+                "name": hostname,
+                "properties": [
+                    {"name": "os:id", "value": distro.id},
+                    {"name": "os:codename", "value": codename},
+                ],
+            },
+        },
+        "components": components,
+    })
+}
+
+/// Builds an SPDX 2.3 JSON document: the host as an operating-system
+/// package that contains every installed one.
+pub fn spdx(packages: &[InstalledPackage], hostname: &str, distro: &DistroInfo) -> Value {
+    let mut spdx_packages = vec![json!({
+        "name": hostname,
+        "SPDXID": "SPDXRef-host",
+        "versionInfo": distro.version_codename.as_deref().unwrap_or_default(),
+        "downloadLocation": "NOASSERTION",
+        "filesAnalyzed": false,
+        "primaryPackagePurpose": "OPERATING-SYSTEM",
+        "comment": distro.pretty_name.as_deref().unwrap_or(&distro.id),
+    })];
+    let mut relationships = vec![json!({
+        "spdxElementId": "SPDXRef-DOCUMENT",
+        "relationshipType": "DESCRIBES",
+        "relatedSpdxElement": "SPDXRef-host",
+    })];
+
+    for (index, package) in packages.iter().enumerate() {
+        // SPDX ids only allow letters, digits, '.' and '-'
+        let id = format!("SPDXRef-Package-{}", index + 1);
+        let mut entry = json!({
+            "name": package.name,
+            "SPDXID": id,
+            "versionInfo": package.version,
+            "downloadLocation": "NOASSERTION",
+            "filesAnalyzed": false,
+            "primaryPackagePurpose": if package.source == "apt" { "LIBRARY" } else { "APPLICATION" },
+            "sourceInfo": format!("installed with {}", package.source),
+        });
+        if let Some(purl) = purl(package, distro) {
+            entry["externalRefs"] = json!([{
+                "referenceCategory": "PACKAGE-MANAGER",
+                "referenceType": "purl",
+                "referenceLocator": purl,
+            }]);
+        }
+        spdx_packages.push(entry);
+        relationships.push(json!({
+            "spdxElementId": "SPDXRef-host",
+            "relationshipType": "CONTAINS",
+            "relatedSpdxElement": id,
+        }));
+    }
+
+    json!({
+        "spdxVersion": "SPDX-2.3",
+        "dataLicense": "CC0-1.0",
+        "SPDXID": "SPDXRef-DOCUMENT",
+        "name": hostname,
+        "documentNamespace": format!("https://spdx.org/spdxdocs/ua-agent-{}", uuid::Uuid::new_v4()),
+        "creationInfo": {
+            "created": Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+            "creators": [format!("Tool: ua-agent-{}", env!("CARGO_PKG_VERSION"))],
+        },
+        "packages": spdx_packages,
+        "relationships": relationships,
+    })
+}
+
+/// Percent-encodes everything but unreserved characters, so epochs (`1:`)
+/// and `+` in Debian versions survive in a purl.
+fn purl_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'.' | b'-' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// Generates the host SBOM, with the hostname hashed under the minimal
+/// reporting profile.
+pub fn generate(config: &AgentConfig, format: SbomFormat) -> Result<Value> {
+    let packages = collect_installed(config)?;
+    let hostname = crate::host::hostname();
+    let hostname = match Redactor::new(config) {
+        Some(redactor) => redactor.hash(&hostname),
+        None => hostname,
+    };
+    debug!("SBOM lists {} packages", packages.len());
+    let distro = DistroInfo::detect();
+    Ok(match format {
+        SbomFormat::Cyclonedx => cyclonedx(&packages, &hostname, &distro),
+        SbomFormat::Spdx => spdx(&packages, &hostname, &distro),
+    })
+}
+
+/// POSTs an SBOM to `/api/v1/sbom`, sealed like reports when
+/// `reporting.encrypt_to` is set.
+pub async fn upload(
+    config: &AgentConfig,
+    http_client: &SecureHttpClient,
+    sbom: &Value,
+) -> Result<()> {
+    let sealed = seal(config, sbom)?;
+    if config.s3.enabled {
+        let uploaded = match &sealed {
+            Some(sealed) => crate::s3::put_json(config, http_client, "sbom", sealed).await,
+            None => crate::s3::put_json(config, http_client, "sbom", sbom).await,
+        };
+        match uploaded {
+            Ok(_) if config.backend.transport == Transport::S3 => return Ok(()),
+            Ok(_) => {}
+            Err(e) if config.backend.transport == Transport::S3 => return Err(e),
+            Err(e) => warn!("Failed to upload SBOM to S3: {:#}", e),
+        }
+    }
+
+    let response = match sealed {
+        Some(sealed) => http_client.post("sbom", &sealed).await,
+        None => http_client.post("sbom", sbom).await,
+    }
+    .with_context(|| "Failed to upload SBOM")?;
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        return Err(anyhow::anyhow!(
+            "Backend returned {} for SBOM: {}",
+            status,
+            body
+        ));
+    }
+    info!("SBOM uploaded to backend");
+    Ok(())
+}
+
+/// Uploads a fresh SBOM when `sbom.upload` is set and the last upload is
+/// older than `sbom.interval_hours`. Returns whether one was uploaded.
+pub async fn upload_if_due(config: &AgentConfig, http_client: &SecureHttpClient) -> Result<bool> {
+    if !config.sbom.upload {
+        return Ok(false);
+    }
+    let stamp = last_upload_path(config);
+    let interval = Duration::hours(config.sbom.interval_hours as i64);
+    if last_upload(&stamp).is_some_and(|last| Utc::now() - last < interval) {
+        return Ok(false);
+    }
+
+    upload(config, http_client, &generate(config, config.sbom.format)?).await?;
+    fs::write(&stamp, Utc::now().to_rfc3339())
+        .with_context(|| format!("Failed to write {:?}", stamp))?;
+    Ok(true)
+}
+
+fn last_upload_path(config: &AgentConfig) -> PathBuf {
+    config.state.dir.join("sbom.last-upload")
+}
+
+fn last_upload(path: &Path) -> Option<DateTime<Utc>> {
+    let content = fs::read_to_string(path).ok()?;
+    DateTime::parse_from_rfc3339(content.trim())
+        .ok()
+        .map(|time| time.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cyclonedx_from_dpkg_query() {
+        let output = "ii \tbash\t5.1-6ubuntu1.1\tamd64\n\
+                      rc \told-package\t1.0\tamd64\n\
+                      ii \tlibssl3\t3.0.2-0ubuntu1.15\tamd64\n\
+                      ii \ttzdata\t2024a-0ubuntu0.22.04\tall\n\
+                      ii \tvim\t2:8.2.3995-1ubuntu2.17\tamd64\n";
+        let packages = parse_dpkg_query(output);
+        assert_eq!(packages.len(), 4);
+
+        let distro = DistroInfo {
+            id: "ubuntu".to_string(),
+            version_codename: Some("jammy".to_string()),
+            ..DistroInfo::default()
+        };
+        let sbom = cyclonedx(&packages, "web01", &distro);
+        assert_eq!(sbom["bomFormat"], "CycloneDX");
+        assert_eq!(sbom["metadata"]["component"]["name"], "web01");
+        assert_eq!(
+            sbom["components"][3]["purl"],
+            "pkg:deb/ubuntu/vim@2%3A8.2.3995-1ubuntu2.17?arch=amd64&distro=jammy"
+        );
+    }
+
+    #[test]
+    fn test_spdx_with_flatpaks() {
+        let mut packages = parse_dpkg_query("ii \tbash\t5.1-6ubuntu1.1\tamd64\n");
+        packages.extend(parse_flatpak_list(
+            "org.mozilla.firefox\t128.0\tx86_64\tstable\n\
+             org.freedesktop.Platform\t\tx86_64\t23.08\n\
+             Application ID\tVersion\tArch\tBranch\n",
+        ));
+        assert_eq!(packages.len(), 3);
+        assert_eq!(packages[2].version, "23.08");
+
+        let distro = DistroInfo {
+            id: "ubuntu".to_string(),
+            ..DistroInfo::default()
+        };
+        let sbom = spdx(&packages, "web01", &distro);
+        assert_eq!(sbom["spdxVersion"], "SPDX-2.3");
+        assert_eq!(
+            sbom["packages"][0]["primaryPackagePurpose"],
+            "OPERATING-SYSTEM"
+        );
+        assert_eq!(
+            sbom["packages"][1]["externalRefs"][0]["referenceLocator"],
+            "pkg:deb/ubuntu/bash@5.1-6ubuntu1.1?arch=amd64"
+        );
+        assert_eq!(sbom["packages"][2]["name"], "org.mozilla.firefox");
+        assert!(sbom["packages"][2].get("externalRefs").is_none());
+        assert_eq!(sbom["relationships"].as_array().unwrap().len(), 4);
+    }
+}
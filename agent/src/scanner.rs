@@ -0,0 +1,298 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+use tracing::{debug, info};
+
+use crate::config::AgentConfig;
+
+/// Placeholder in `scanner.command` for the SBOM path
+const SBOM_PLACEHOLDER: &str = "{sbom}";
+
+/// Findings listed individually in the report; the rest are only counted
+const MAX_FINDINGS: usize = 50;
+
+const SEVERITIES: &[&str] = &["critical", "high", "medium", "low", "unknown"];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Finding {
+    pub id: String,
+    pub package: String,
+    /// critical, high, medium, low or unknown
+    pub severity: String,
+}
+
+/// Summarized output of the external scanner, attached to the run report.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScanSummary {
+    /// File name of the scanner binary, e.g. "trivy"
+    pub scanner: String,
+    pub scanned_at: DateTime<Utc>,
+    pub total: usize,
+    pub by_severity: BTreeMap<String, usize>,
+    /// Most severe first, at most `MAX_FINDINGS`
+    pub findings: Vec<Finding>,
+}
+
+/// Runs `scanner.command` after an update, e.g. `trivy sbom --format json
+/// {sbom}` or `osv-scanner --format json --sbom {sbom}`, and summarizes its
+/// JSON output. Returns `None` when no scanner is configured.
+pub async fn scan(config: &AgentConfig) -> Result<Option<ScanSummary>> {
+    let command = &config.scanner.command;
+    if command.is_empty() {
+        return Ok(None);
+    }
+
+    let sbom_path = config.state.dir.join("sbom.cdx.json");
+    if command.iter().any(|arg| arg.contains(SBOM_PLACEHOLDER)) {
+        let sbom = crate::sbom::generate(config, crate::config::SbomFormat::Cyclonedx)?;
+        std::fs::write(&sbom_path, serde_json::to_vec(&sbom)?)
+            .with_context(|| format!("Failed to write SBOM to {:?}", sbom_path))?;
+    }
+    let argv: Vec<String> = command
+        .iter()
+        .map(|arg| arg.replace(SBOM_PLACEHOLDER, &sbom_path.to_string_lossy()))
+        .collect();
+
+    info!("Running vulnerability scanner: {}", argv.join(" "));
+    let timeout = Duration::from_secs(config.scanner.timeout_seconds);
+    let output = tokio::time::timeout(
+        timeout,
+        crate::audit::output_async(
+            tokio::process::Command::new(&argv[0])
+                .args(&argv[1..])
+                .kill_on_drop(true),
+        ),
+    )
+    .await
+    .map_err(|_| anyhow::anyhow!("{} timed out after {:?}", argv[0], timeout))?
+    .with_context(|| format!("Failed to run {}", argv[0]))?;
+
+    // Scanners exit non-zero when they find something, so only unparsable
+    // output counts as a failure
+    let findings = parse_findings(&output.stdout).with_context(|| {
+        format!(
+            "{} exited with {}: {}",
+            argv[0],
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+    })?;
+    debug!("Scanner reported {} findings", findings.len());
+
+    let scanner = Path::new(&argv[0])
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| argv[0].clone());
+    Ok(Some(summarize(scanner, findings)))
+}
+
+fn summarize(scanner: String, mut findings: Vec<Finding>) -> ScanSummary {
+    let mut by_severity = BTreeMap::new();
+    for finding in &findings {
+        *by_severity.entry(finding.severity.clone()).or_insert(0) += 1;
+    }
+
+    let rank = |severity: &str| SEVERITIES.iter().position(|s| *s == severity);
+    findings.sort_by(|a, b| {
+        rank(&a.severity)
+            .cmp(&rank(&b.severity))
+            .then_with(|| a.id.cmp(&b.id))
+    });
+    let total = findings.len();
+    findings.truncate(MAX_FINDINGS);
+
+    ScanSummary {
+        scanner,
+        scanned_at: Utc::now(),
+        total,
+        by_severity,
+        findings,
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct TrivyReport {
+    #[serde(default)]
+    results: Vec<TrivyResult>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct TrivyResult {
+    /// `null` rather than missing when there are none
+    #[serde(default)]
+    vulnerabilities: Option<Vec<TrivyVulnerability>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct TrivyVulnerability {
+    #[serde(rename = "VulnerabilityID")]
+    vulnerability_id: String,
+    pkg_name: String,
+    #[serde(default)]
+    severity: String,
+}
+
+#[derive(Deserialize)]
+struct OsvReport {
+    #[serde(default)]
+    results: Vec<OsvResult>,
+}
+
+#[derive(Deserialize)]
+struct OsvResult {
+    #[serde(default)]
+    packages: Vec<OsvPackage>,
+}
+
+#[derive(Deserialize)]
+struct OsvPackage {
+    package: OsvPackageInfo,
+    #[serde(default)]
+    vulnerabilities: Vec<OsvVulnerability>,
+    /// Aliases of the same vulnerability, with their highest CVSS score
+    #[serde(default)]
+    groups: Vec<OsvGroup>,
+}
+
+#[derive(Deserialize)]
+struct OsvPackageInfo {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct OsvVulnerability {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct OsvGroup {
+    ids: Vec<String>,
+    #[serde(default)]
+    max_severity: String,
+}
+
+/// Reads trivy (`Results`) or osv-scanner (`results`) JSON.
+fn parse_findings(stdout: &[u8]) -> Result<Vec<Finding>> {
+    let json: serde_json::Value =
+        serde_json::from_slice(stdout).context("Scanner output is not JSON")?;
+
+    if json.get("Results").is_some() || json.get("SchemaVersion").is_some() {
+        let report: TrivyReport = serde_json::from_value(json)?;
+        return Ok(report
+            .results
+            .into_iter()
+            .flat_map(|result| result.vulnerabilities.unwrap_or_default())
+            .map(|vulnerability| Finding {
+                id: vulnerability.vulnerability_id,
+                package: vulnerability.pkg_name,
+                severity: normalize_severity(&vulnerability.severity),
+            })
+            .collect());
+    }
+
+    if json.get("results").is_some() {
+        let report: OsvReport = serde_json::from_value(json)?;
+        return Ok(report
+            .results
+            .into_iter()
+            .flat_map(|result| result.packages)
+            .flat_map(|package| {
+                let name = package.package.name;
+                if package.groups.is_empty() {
+                    package
+                        .vulnerabilities
+                        .into_iter()
+                        .map(|vulnerability| Finding {
+                            id: vulnerability.id,
+                            package: name.clone(),
+                            severity: "unknown".to_string(),
+                        })
+                        .collect::<Vec<_>>()
+                } else {
+                    package
+                        .groups
+                        .into_iter()
+                        .filter_map(|group| {
+                            Some(Finding {
+                                id: group.ids.first()?.clone(),
+                                package: name.clone(),
+                                severity: cvss_severity(&group.max_severity),
+                            })
+                        })
+                        .collect()
+                }
+            })
+            .collect());
+    }
+
+    Err(anyhow::anyhow!(
+        "Unrecognized scanner output, expected trivy or osv-scanner JSON"
+    ))
+}
+
+fn normalize_severity(severity: &str) -> String {
+    let severity = severity.to_lowercase();
+    if SEVERITIES.contains(&severity.as_str()) {
+        severity
+    } else {
+        "unknown".to_string()
+    }
+}
+
+/// Maps a CVSS base score to its qualitative rating.
+fn cvss_severity(score: &str) -> String {
+    let severity = match score.parse::<f64>() {
+        Ok(score) if score >= 9.0 => "critical",
+        Ok(score) if score >= 7.0 => "high",
+        Ok(score) if score >= 4.0 => "medium",
+        Ok(score) if score > 0.0 => "low",
+        _ => "unknown",
+    };
+    severity.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_trivy_and_osv_output() {
+        let trivy = br#"{
+            "SchemaVersion": 2,
+            "Results": [
+                {"Target": "sbom.cdx.json", "Vulnerabilities": [
+                    {"VulnerabilityID": "CVE-2024-2511", "PkgName": "libssl3", "Severity": "LOW"},
+                    {"VulnerabilityID": "CVE-2024-6387", "PkgName": "openssh-server", "Severity": "HIGH"}
+                ]},
+                {"Target": "snaps", "Vulnerabilities": null}
+            ]
+        }"#;
+        let summary = summarize("trivy".to_string(), parse_findings(trivy).unwrap());
+        assert_eq!(summary.total, 2);
+        assert_eq!(summary.findings[0].id, "CVE-2024-6387");
+        assert_eq!(summary.by_severity["low"], 1);
+
+        let osv = br#"{"results": [{"packages": [{
+            "package": {"name": "openssh", "ecosystem": "Ubuntu:22.04:LTS"},
+            "vulnerabilities": [{"id": "UBUNTU-CVE-2024-6387"}, {"id": "USN-6859-1"}],
+            "groups": [{"ids": ["UBUNTU-CVE-2024-6387", "USN-6859-1"], "max_severity": "8.1"}]
+        }]}]}"#;
+        let findings = parse_findings(osv).unwrap();
+        assert_eq!(
+            findings,
+            vec![Finding {
+                id: "UBUNTU-CVE-2024-6387".to_string(),
+                package: "openssh".to_string(),
+                severity: "high".to_string(),
+            }]
+        );
+
+        assert!(parse_findings(b"{\"matches\": []}").is_err());
+    }
+}
@@ -0,0 +1,364 @@
+use chrono::{DateTime, Duration, Local, NaiveDateTime, Utc};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::process::Command;
+use tracing::{debug, warn};
+
+use crate::config::AgentConfig;
+use crate::rollback::command_exists;
+use crate::window::Window;
+
+/// Timer that starts one-shot runs in timer mode.
+const AGENT_TIMER: &str = "ubuntu-auto-update-agent.timer";
+
+const TIMER_PROPERTIES: &str =
+    "LoadState,ActiveState,UnitFileState,Persistent,LastTriggerUSec,NextElapseUSecRealtime,TimersCalendar";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimerState {
+    /// Active and firing on schedule
+    Healthy,
+    /// Scheduled runs were missed while the host was powered off
+    MissedWhileDown,
+    /// Scheduled runs were missed while the host was up, so the timer
+    /// itself is likely broken
+    MissedWhileUp,
+    /// Installed but not running (disabled, stopped, or daemon mode)
+    Inactive,
+    /// The timer unit isn't installed
+    NotFound,
+}
+
+/// Scheduling health of the agent's systemd timer, reported so the backend
+/// can tell a host with a broken timer from one that was simply off.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TimerHealth {
+    pub unit: String,
+    pub state: TimerState,
+    pub enabled: bool,
+    /// Persistent=true, so a run missed while off is caught up after boot
+    pub persistent: bool,
+    pub last_trigger: Option<DateTime<Utc>>,
+    pub next_trigger: Option<DateTime<Utc>>,
+    pub boot_time: Option<DateTime<Utc>>,
+    /// Time between two activations of the timer's OnCalendar schedule
+    pub interval_seconds: Option<u64>,
+    /// Scheduled activations that passed without a run
+    pub missed_runs: u32,
+}
+
+impl TimerHealth {
+    /// Reads the timer's properties from systemd (`systemctl show`, which
+    /// queries them over D-Bus). `previous_run` is the last recorded run
+    /// before the current one; during a run the timer's last trigger is the
+    /// run itself, so gaps are measured from there instead. Returns `None`
+    /// when systemd isn't available.
+    pub fn detect(previous_run: Option<DateTime<Utc>>) -> Option<Self> {
+        if !command_exists("systemctl") {
+            return None;
+        }
+        let output = crate::audit::output(Command::new("systemctl").args([
+            "show",
+            "--timestamp=unix",
+            "--property",
+            TIMER_PROPERTIES,
+            AGENT_TIMER,
+        ]))
+        .ok()?;
+        if !output.status.success() {
+            debug!(
+                "systemctl show {} failed: {}",
+                AGENT_TIMER,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            return None;
+        }
+
+        let properties = parse_properties(&String::from_utf8_lossy(&output.stdout));
+        let interval = properties
+            .get("TimersCalendar")
+            .and_then(|calendars| on_calendar(calendars))
+            .and_then(calendar_interval);
+        Some(assess(
+            &properties,
+            interval,
+            crate::host::boot_time(),
+            previous_run,
+            Utc::now(),
+        ))
+    }
+}
+
+fn assess(
+    properties: &HashMap<String, String>,
+    interval: Option<chrono::Duration>,
+    boot_time: Option<DateTime<Utc>>,
+    previous_run: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+) -> TimerHealth {
+    let property = |name: &str| properties.get(name).map(String::as_str).unwrap_or("");
+    let last_trigger = parse_unix_timestamp(property("LastTriggerUSec"));
+    let next_trigger = parse_unix_timestamp(property("NextElapseUSecRealtime"));
+
+    // Runs scheduled between the previous run and the next one due, minus
+    // the one that is expected in that gap
+    let (gap_start, gap_end) = match previous_run {
+        Some(previous_run) => (Some(previous_run), Some(now)),
+        None => (last_trigger, next_trigger),
+    };
+    let missed_runs = match (gap_start, gap_end, interval) {
+        (Some(start), Some(end), Some(interval)) if interval.num_seconds() > 0 => {
+            let periods = (end - start).num_seconds() as f64 / interval.num_seconds() as f64;
+            (periods.round() as i64 - 1).max(0) as u32
+        }
+        _ => 0,
+    };
+
+    let state = if property("LoadState") != "loaded" {
+        TimerState::NotFound
+    } else if property("ActiveState") != "active" {
+        TimerState::Inactive
+    } else if missed_runs == 0 {
+        TimerState::Healthy
+    } else {
+        // A boot inside the gap, later than the first run that should have
+        // happened, means the host was off when it was due
+        let down = match (gap_start, boot_time, interval) {
+            (Some(start), Some(boot), Some(interval)) => boot > start + interval,
+            _ => false,
+        };
+        if down {
+            TimerState::MissedWhileDown
+        } else {
+            TimerState::MissedWhileUp
+        }
+    };
+
+    TimerHealth {
+        unit: AGENT_TIMER.to_string(),
+        state,
+        enabled: property("UnitFileState") == "enabled",
+        persistent: property("Persistent") == "yes",
+        last_trigger,
+        next_trigger,
+        boot_time,
+        interval_seconds: interval.map(|interval| interval.num_seconds() as u64),
+        missed_runs,
+    }
+}
+
+fn parse_properties(output: &str) -> HashMap<String, String> {
+    output
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.trim().to_string()))
+        .collect()
+}
+
+/// `@1760580000` as printed with `--timestamp=unix`; empty or "n/a" when
+/// the timer never fired.
+fn parse_unix_timestamp(value: &str) -> Option<DateTime<Utc>> {
+    let seconds = value.strip_prefix('@')?.parse().ok()?;
+    DateTime::from_timestamp(seconds, 0)
+}
+
+/// The first OnCalendar expression of a TimersCalendar property, which looks
+/// like `{ OnCalendar=*-*-* 02:00:00 ; next_elapse=@1760666400 }`.
+fn on_calendar(calendars: &str) -> Option<String> {
+    let (_, rest) = calendars.split_once("OnCalendar=")?;
+    let (spec, _) = rest.split_once(" ;")?;
+    Some(spec.trim().to_string())
+}
+
+/// Interval between the next two elapses of a calendar expression.
+fn calendar_interval(spec: String) -> Option<chrono::Duration> {
+    let output = crate::audit::output(Command::new("systemd-analyze").args([
+        "calendar",
+        "--iterations=2",
+        &spec,
+    ]))
+    .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let elapses = parse_calendar_elapses(&String::from_utf8_lossy(&output.stdout));
+    match elapses.as_slice() {
+        [first, second, ..] => Some(*second - *first),
+        _ => None,
+    }
+}
+
+/// The elapses `systemd-analyze calendar` prints in UTC: its "(in UTC)"
+/// lines, or the elapse lines themselves when local time is UTC.
+fn parse_calendar_elapses(output: &str) -> Vec<DateTime<Utc>> {
+    let mut elapses: Vec<DateTime<Utc>> = output
+        .lines()
+        .filter_map(|line| line.split_once(": "))
+        .filter_map(|(_, time)| {
+            NaiveDateTime::parse_from_str(time.trim(), "%a %Y-%m-%d %H:%M:%S UTC").ok()
+        })
+        .map(|time| time.and_utc())
+        .collect();
+    elapses.dedup();
+    elapses
+}
+
+/// Delay before a scheduled run: until the host's slot with
+/// `updates.slots`, otherwise random in `0..=updates.splay_seconds`.
+pub fn splay(config: &AgentConfig) -> std::time::Duration {
+    if let Some(delay) = slot_delay(config) {
+        return delay;
+    }
+    let max = config.updates.splay_seconds;
+    let seconds = if max == 0 {
+        0
+    } else {
+        rand::thread_rng().gen_range(0..=max)
+    };
+    std::time::Duration::from_secs(seconds)
+}
+
+/// Time until this host's slot, from the last applied policy's
+/// `slots` or the local ones. `None` without a slot window.
+fn slot_delay(config: &AgentConfig) -> Option<std::time::Duration> {
+    let slots = crate::policy::last_applied(config)
+        .and_then(|policy| policy.slots)
+        .unwrap_or_else(|| config.updates.slots.clone());
+    let spec = slots.window.as_deref()?;
+    let window: Window = match spec.parse() {
+        Ok(window) => window,
+        Err(e) => {
+            warn!("Ignoring slot window {:?}: {}", spec, e);
+            return None;
+        }
+    };
+    // Hosts that haven't enrolled yet still get a stable slot
+    let host_id = fs::read_to_string(&config.enrollment.host_id_file)
+        .map(|host_id| host_id.trim().to_string())
+        .unwrap_or_else(|_| crate::host::hostname());
+    let now = Local::now().naive_local();
+    let delay = until_slot(&window, &host_id, slots.slot_minutes, now);
+    debug!(
+        "Slot in {:?} for host {} starts in {}s",
+        spec,
+        host_id,
+        delay.num_seconds()
+    );
+    delay.to_std().ok()
+}
+
+/// Time from `now` to the host's slot in the window that is open or opens
+/// later today; zero once the slot has passed or with no such window, so a
+/// run is never held to the next day. Slots are `slot_minutes` apart, and
+/// the first 8 bytes of the host ID's SHA-256 pick one.
+fn until_slot(window: &Window, host_id: &str, slot_minutes: u32, now: NaiveDateTime) -> Duration {
+    let today = now.date();
+    let Some((start, end)) = [today.pred_opt(), Some(today)]
+        .into_iter()
+        .flatten()
+        .filter_map(|day| window.on(day))
+        .find(|(_, end)| now <= *end)
+    else {
+        return Duration::zero();
+    };
+
+    let slot_minutes = i64::from(slot_minutes.max(1));
+    let slots = ((end - start).num_minutes() / slot_minutes).max(1) as u64;
+    let digest = Sha256::digest(host_id.as_bytes());
+    let hash = u64::from_be_bytes(digest[..8].try_into().expect("SHA-256 has 32 bytes"));
+    let slot = start + Duration::minutes((hash % slots) as i64 * slot_minutes);
+    (slot - now).max(Duration::zero())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_splay_stays_within_bounds() {
+        let mut config = AgentConfig::default();
+        assert!(splay(&config).is_zero());
+        config.updates.splay_seconds = 30;
+        assert!((0..20).all(|_| splay(&config).as_secs() <= 30));
+    }
+
+    #[test]
+    fn test_slots_spread_hosts_over_the_window() {
+        let window: Window = "01:00-05:00".parse().unwrap();
+        let at = |time: &str| {
+            NaiveDateTime::parse_from_str(&format!("2026-10-17 {}", time), "%Y-%m-%d %H:%M")
+                .unwrap()
+        };
+
+        let delay = until_slot(&window, "host-1", 5, at("00:30"));
+        assert_eq!(delay, until_slot(&window, "host-1", 5, at("00:30")));
+        assert!(delay >= Duration::minutes(30) && delay < Duration::minutes(270));
+        assert_eq!(delay.num_minutes() % 5, 0);
+        let slot = at("00:30") + delay;
+        assert_eq!(
+            until_slot(&window, "host-1", 5, slot - Duration::minutes(10)),
+            Duration::minutes(10)
+        );
+        assert_eq!(until_slot(&window, "host-1", 5, slot), Duration::zero());
+        assert_eq!(
+            until_slot(&window, "host-1", 5, at("06:00")),
+            Duration::zero()
+        );
+
+        let starts: std::collections::HashSet<_> = (0..100)
+            .map(|host| {
+                at("01:00") + until_slot(&window, &format!("host-{}", host), 5, at("01:00"))
+            })
+            .collect();
+        assert!(starts.len() > 30);
+        assert!(starts.iter().all(|start| *start < at("05:00")));
+    }
+
+    #[test]
+    fn test_assess_missed_runs() {
+        let elapses = parse_calendar_elapses(
+            "Original form: *-*-* 02:00:00\n\
+             Next elapse: Sat 2026-10-17 04:00:00 CEST\n\
+             (in UTC): Sat 2026-10-17 02:00:00 UTC\n\
+             Iter. #2: Sun 2026-10-18 04:00:00 CEST\n\
+             (in UTC): Sun 2026-10-18 02:00:00 UTC\n",
+        );
+        let interval = elapses[1] - elapses[0];
+        assert_eq!(interval, chrono::Duration::days(1));
+
+        let at = |rfc3339: &str| DateTime::parse_from_rfc3339(rfc3339).unwrap().to_utc();
+        let properties = parse_properties(
+            "LoadState=loaded\nActiveState=active\nUnitFileState=enabled\nPersistent=yes\n\
+             LastTriggerUSec=@1760580000\nNextElapseUSecRealtime=@1760666400\n",
+        );
+
+        let on_time = assess(&properties, Some(interval), None, None, Utc::now());
+        assert_eq!(on_time.state, TimerState::Healthy);
+        assert_eq!(on_time.missed_runs, 0);
+        assert!(on_time.persistent);
+
+        // Last run three days ago, rebooted this morning
+        let after_downtime = assess(
+            &properties,
+            Some(interval),
+            Some(at("2026-10-16T01:50:00Z")),
+            Some(at("2026-10-13T02:10:00Z")),
+            at("2026-10-16T02:05:00Z"),
+        );
+        assert_eq!(after_downtime.missed_runs, 2);
+        assert_eq!(after_downtime.state, TimerState::MissedWhileDown);
+
+        let stuck = assess(
+            &properties,
+            Some(interval),
+            Some(at("2026-09-01T00:00:00Z")),
+            Some(at("2026-10-13T02:10:00Z")),
+            at("2026-10-16T02:05:00Z"),
+        );
+        assert_eq!(stuck.state, TimerState::MissedWhileUp);
+    }
+}
@@ -0,0 +1,168 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::process::Command;
+use tracing::{debug, info, warn};
+
+use crate::config::AgentConfig;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ServiceAction {
+    Stop,
+    Start,
+    Restart,
+}
+
+/// One stop, start or restart of a service, included in the run report.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServiceTransition {
+    pub unit: String,
+    pub action: ServiceAction,
+    pub success: bool,
+    pub error_message: Option<String>,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Stops `updates.stop_services` before packages are upgraded, so e.g. a
+/// kiosk browser or a database isn't replaced underneath itself, and starts
+/// them again afterwards when `updates.start_after` is set. Units that
+/// weren't running are left alone, and only units the agent stopped are
+/// started again.
+pub struct ServiceQuiesce {
+    units: Vec<String>,
+    start_after: bool,
+}
+
+impl ServiceQuiesce {
+    /// Returns `None` when no services are configured or in dry-run mode.
+    pub fn new(config: &AgentConfig) -> Option<Self> {
+        if config.updates.stop_services.is_empty() {
+            return None;
+        }
+        if config.updates.dry_run {
+            info!(
+                "Dry run, not stopping {}",
+                config.updates.stop_services.join(", ")
+            );
+            return None;
+        }
+        Some(Self {
+            units: config.updates.stop_services.clone(),
+            start_after: config.updates.start_after,
+        })
+    }
+
+    /// Stops the configured units that are currently active. A unit that
+    /// fails to stop is recorded but doesn't prevent the update.
+    pub fn stop(&self) -> Vec<ServiceTransition> {
+        self.units
+            .iter()
+            .filter(|unit| {
+                let active = is_active(unit);
+                if !active {
+                    debug!("{} is not active, not stopping it", unit);
+                }
+                active
+            })
+            .map(|unit| transition(unit, ServiceAction::Stop))
+            .collect()
+    }
+
+    /// Starts the units `stop` stopped, in reverse order, if configured.
+    pub fn start(&self, stopped: &[ServiceTransition]) -> Vec<ServiceTransition> {
+        let units = units_to_start(stopped);
+        if !self.start_after {
+            if !units.is_empty() {
+                info!("Leaving {} stopped (start_after = false)", units.join(", "));
+            }
+            return Vec::new();
+        }
+        units
+            .into_iter()
+            .rev()
+            .map(|unit| transition(unit, ServiceAction::Start))
+            .collect()
+    }
+}
+
+/// Restarts a unit still running replaced binaries or libraries.
+pub fn restart(unit: &str) -> ServiceTransition {
+    transition(unit, ServiceAction::Restart)
+}
+
+fn units_to_start(stopped: &[ServiceTransition]) -> Vec<&str> {
+    stopped
+        .iter()
+        .filter(|t| t.action == ServiceAction::Stop && t.success)
+        .map(|t| t.unit.as_str())
+        .collect()
+}
+
+fn is_active(unit: &str) -> bool {
+    crate::audit::status(Command::new("systemctl").args(["is-active", "--quiet", unit]))
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+fn transition(unit: &str, action: ServiceAction) -> ServiceTransition {
+    let verb = match action {
+        ServiceAction::Stop => "stop",
+        ServiceAction::Start => "start",
+        ServiceAction::Restart => "restart",
+    };
+    info!("Running systemctl {} {}", verb, unit);
+
+    let result = systemctl(verb, unit);
+    if let Err(e) = &result {
+        warn!("Failed to {} {}: {:#}", verb, unit, e);
+    }
+    ServiceTransition {
+        unit: unit.to_string(),
+        action,
+        success: result.is_ok(),
+        error_message: result.err().map(|e| format!("{:#}", e)),
+        timestamp: Utc::now(),
+    }
+}
+
+fn systemctl(verb: &str, unit: &str) -> Result<()> {
+    let output = crate::audit::output(Command::new("systemctl").args([verb, unit]))
+        .with_context(|| format!("Failed to run systemctl {}", verb))?;
+
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "systemctl {} {} failed: {}",
+            verb,
+            unit,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_successfully_stopped_units_are_started() {
+        let stopped = |unit: &str, success| ServiceTransition {
+            unit: unit.to_string(),
+            action: ServiceAction::Stop,
+            success,
+            error_message: None,
+            timestamp: Utc::now(),
+        };
+        let transitions = vec![
+            stopped("kiosk.service", true),
+            stopped("postgresql.service", false),
+            stopped("redis-server.service", true),
+        ];
+
+        assert_eq!(
+            units_to_start(&transitions),
+            vec!["kiosk.service", "redis-server.service"]
+        );
+    }
+}
@@ -0,0 +1,158 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use reqwest::Method;
+use serde::Serialize;
+use std::fs;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::config::{AgentConfig, ReportSink, Transport};
+use crate::http_client::SecureHttpClient;
+
+/// Attempts per sink; only transport errors and 5xx are retried
+const MAX_ATTEMPTS: u32 = 3;
+
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Sends a copy of a report to every `reporting.sinks` entry and the `[s3]`
+/// bucket at once. A sink that can't be reached is logged and skipped; it
+/// never fails the backend delivery.
+pub async fn deliver<T: Serialize>(
+    config: &AgentConfig,
+    http_client: &SecureHttpClient,
+    hostname: &str,
+    report: &T,
+) {
+    // With the s3 transport the bucket is the primary destination
+    let bucket = async {
+        if config.s3.enabled && config.backend.transport != Transport::S3 {
+            if let Err(e) = crate::s3::put_json(config, http_client, "reports", report).await {
+                warn!("Failed to archive report to S3: {:#}", e);
+            }
+        }
+    };
+    tokio::join!(
+        deliver_to_sinks(config, http_client, hostname, report),
+        bucket
+    );
+}
+
+async fn deliver_to_sinks<T: Serialize>(
+    config: &AgentConfig,
+    http_client: &SecureHttpClient,
+    hostname: &str,
+    report: &T,
+) {
+    if config.reporting.sinks.is_empty() {
+        return;
+    }
+    let client = http_client.for_subsystem("sinks");
+    let result = async {
+        let body = serde_json::to_vec(report).context("Failed to serialize report")?;
+        let host_id = fs::read_to_string(&config.enrollment.host_id_file)
+            .map(|id| id.trim().to_string())
+            .unwrap_or_default();
+        let now = Utc::now();
+
+        let deliveries = config.reporting.sinks.iter().map(|sink| {
+            let url = expand_url(&sink.url, hostname, &host_id, now);
+            let client = &client;
+            let body = &body;
+            async move {
+                match send(config, client, sink, &url, body).await {
+                    Ok(()) => info!("Report archived to sink {}", sink.name),
+                    Err(e) => warn!("Failed to archive report to sink {}: {:#}", sink.name, e),
+                }
+            }
+        });
+        futures::future::join_all(deliveries).await;
+        Ok::<_, anyhow::Error>(())
+    };
+    if let Err(e) = result.await {
+        warn!("Failed to archive report: {:#}", e);
+    }
+}
+
+async fn send(
+    config: &AgentConfig,
+    client: &SecureHttpClient,
+    sink: &ReportSink,
+    url: &str,
+    body: &[u8],
+) -> Result<()> {
+    let token = match &sink.token_file {
+        Some(path) => {
+            let path = config.security.credential_path(path);
+            let token = fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read token from {:?}", path))?;
+            Some(token.trim().to_string())
+        }
+        None => None,
+    };
+
+    let mut attempt = 1;
+    loop {
+        let request = match sink.method.as_str() {
+            "post" => client.external(Method::POST, url),
+            _ => client.external(Method::PUT, url),
+        }
+        .header("Content-Type", "application/json")
+        .body(body.to_vec());
+        let request = match &token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        };
+
+        let retry = match client.send_external(request).await {
+            Ok(response) if response.status().is_success() => return Ok(()),
+            Ok(response) if response.status().is_server_error() => {
+                anyhow::anyhow!("{} returned {}", sink.name, response.status())
+            }
+            Ok(response) => {
+                return Err(anyhow::anyhow!(
+                    "{} returned {}",
+                    sink.name,
+                    response.status()
+                ))
+            }
+            Err(e) => e.context(format!("Failed to reach {}", sink.name)),
+        };
+        if attempt == MAX_ATTEMPTS {
+            return Err(retry);
+        }
+        warn!(
+            "Report sink attempt {}/{} failed: {:#}",
+            attempt, MAX_ATTEMPTS, retry
+        );
+        attempt += 1;
+        tokio::time::sleep(RETRY_DELAY).await;
+    }
+}
+
+/// Fills in the placeholders of a sink URL. The hostname is already hashed
+/// under the minimal reporting profile.
+fn expand_url(template: &str, hostname: &str, host_id: &str, now: DateTime<Utc>) -> String {
+    template
+        .replace("{hostname}", hostname)
+        .replace("{host_id}", host_id)
+        .replace("{timestamp}", &now.format("%Y%m%dT%H%M%SZ").to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_url() {
+        let now = "2030-01-02T03:04:05Z".parse().unwrap();
+        assert_eq!(
+            expand_url(
+                "https://archive.example.com/reports/{host_id}/{hostname}-{timestamp}.json",
+                "kiosk-7",
+                "3f2a",
+                now
+            ),
+            "https://archive.example.com/reports/3f2a/kiosk-7-20300102T030405Z.json"
+        );
+    }
+}
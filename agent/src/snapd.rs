@@ -0,0 +1,252 @@
+use anyhow::{Context, Result};
+use reqwest::{Client, RequestBuilder};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+use crate::updater::CommandTimedOut;
+
+const SOCKET: &str = "/run/snapd.socket";
+
+/// For the requests themselves; changes are waited on separately
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(120);
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Every response is wrapped in this: `sync` ones carry `result`, `async`
+/// ones the id of the change they started, `error` ones a message in
+/// `result`.
+#[derive(Debug, Deserialize)]
+struct Envelope {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(rename = "status-code")]
+    status_code: u16,
+    #[serde(default)]
+    result: Value,
+    change: Option<String>,
+}
+
+/// An installed snap, from `GET /v2/snaps`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Snap {
+    pub name: String,
+    pub version: String,
+    pub revision: String,
+    /// `None` for snaps installed from a file
+    #[serde(rename = "tracking-channel")]
+    pub tracking_channel: Option<String>,
+    /// When a `snap refresh --hold` ends; set while the snap is held
+    pub hold: Option<String>,
+}
+
+/// A refresh the store offers, from `GET /v2/find?select=refresh`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RefreshCandidate {
+    pub name: String,
+    pub version: String,
+    #[serde(rename = "download-size", default)]
+    pub download_size: u64,
+    pub publisher: Option<Publisher>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Publisher {
+    pub username: String,
+}
+
+/// A change started by an async request, from `GET /v2/changes/<id>`.
+#[derive(Debug, Deserialize)]
+struct Change {
+    summary: String,
+    ready: bool,
+    err: Option<String>,
+    #[serde(default)]
+    tasks: Vec<Task>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Task {
+    summary: String,
+    status: String,
+    #[serde(default)]
+    progress: Progress,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Progress {
+    done: u64,
+    total: u64,
+}
+
+impl Change {
+    /// The running task and how far along it is, in 10% steps so a
+    /// download isn't logged on every poll.
+    fn progress(&self) -> Option<String> {
+        let task = self.tasks.iter().find(|task| task.status == "Doing")?;
+        let Progress { done, total } = task.progress;
+        if total > 1 {
+            Some(format!("{} ({}%)", task.summary, done * 10 / total * 10))
+        } else {
+            Some(task.summary.clone())
+        }
+    }
+}
+
+/// Talks to snapd's REST API on its unix socket, which gives structured
+/// results where `snap` only prints tables, and tracks refreshes as changes
+/// whose tasks report their own progress.
+pub struct SnapdClient {
+    client: Client,
+}
+
+impl SnapdClient {
+    /// `None` when snapd isn't running on this host.
+    pub fn connect() -> Result<Option<Self>> {
+        if !Path::new(SOCKET).exists() {
+            debug!("No snapd socket at {}", SOCKET);
+            return Ok(None);
+        }
+        let client = Client::builder()
+            .unix_socket(SOCKET)
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .context("Failed to create snapd client")?;
+        Ok(Some(Self { client }))
+    }
+
+    pub async fn snaps(&self) -> Result<Vec<Snap>> {
+        self.get("/v2/snaps").await
+    }
+
+    /// Refreshes the store has for installed snaps, held ones included.
+    pub async fn refresh_candidates(&self) -> Result<Vec<RefreshCandidate>> {
+        self.get("/v2/find?select=refresh").await
+    }
+
+    /// Refreshes every snap that isn't held and waits for snapd to finish.
+    pub async fn refresh_all(&self, timeout: Duration) -> Result<()> {
+        let body = json!({"action": "refresh"});
+        self.run("/v2/snaps", &body, timeout).await
+    }
+
+    /// Moves `name` to `channel`, refreshing it from there.
+    pub async fn switch_channel(&self, name: &str, channel: &str, timeout: Duration) -> Result<()> {
+        let body = json!({"action": "refresh", "channel": channel});
+        self.run(&format!("/v2/snaps/{}", name), &body, timeout)
+            .await
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        let envelope = self.send(self.client.get(url(path))).await?;
+        serde_json::from_value(envelope.result)
+            .with_context(|| format!("Unexpected snapd response to {}", path))
+    }
+
+    /// Starts a change and polls it until it's done, logging each task as it
+    /// runs. A change still running at `timeout` is aborted, and reported
+    /// like a package manager command that timed out.
+    async fn run(&self, path: &str, body: &Value, timeout: Duration) -> Result<()> {
+        let envelope = self.send(self.client.post(url(path)).json(body)).await?;
+        // Nothing to do comes back sync
+        let Some(id) = envelope.change else {
+            return Ok(());
+        };
+
+        let started = Instant::now();
+        let mut logged = None;
+        loop {
+            let change: Change = self.get(&format!("/v2/changes/{}", id)).await?;
+            if change.ready {
+                return match change.err {
+                    Some(err) => Err(anyhow::anyhow!("{}: {}", change.summary, err)),
+                    None => {
+                        info!("{}", change.summary);
+                        Ok(())
+                    }
+                };
+            }
+            let progress = change.progress();
+            if progress.is_some() && progress != logged {
+                info!("{}", progress.as_deref().unwrap_or_default());
+                logged = progress;
+            }
+            if started.elapsed() >= timeout {
+                let abort = self.client.post(url(&format!("/v2/changes/{}", id)));
+                let termination = match self.send(abort.json(&json!({"action": "abort"}))).await {
+                    Ok(_) => "change aborted".to_string(),
+                    Err(e) => format!("failed to abort the change: {:#}", e),
+                };
+                return Err(CommandTimedOut {
+                    command: format!("snapd change {} ({})", id, change.summary),
+                    timeout,
+                    termination,
+                }
+                .into());
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    async fn send(&self, request: RequestBuilder) -> Result<Envelope> {
+        let response = request.send().await.context("Failed to reach snapd")?;
+        let envelope: Envelope = response
+            .json()
+            .await
+            .context("Failed to parse snapd response")?;
+        if envelope.kind == "error" {
+            let message = envelope
+                .result
+                .get("message")
+                .and_then(Value::as_str)
+                .unwrap_or("no message");
+            return Err(anyhow::anyhow!(
+                "snapd returned {}: {}",
+                envelope.status_code,
+                message
+            ));
+        }
+        Ok(envelope)
+    }
+}
+
+/// The host part is ignored on a unix socket
+fn url(path: &str) -> String {
+    format!("http://localhost{}", path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_responses() {
+        let envelope: Envelope = serde_json::from_str(
+            r#"{"type":"sync","status-code":200,"status":"OK","result":[
+                {"name":"core22","version":"20241001","revision":"1663",
+                 "tracking-channel":"latest/stable","hold":"2315-06-19T13:00:37Z",
+                 "publisher":{"id":"canonical","username":"canonical","validation":"verified"}},
+                {"name":"hello","version":"2.10","revision":"x1"}]}"#,
+        )
+        .unwrap();
+        let snaps: Vec<Snap> = serde_json::from_value(envelope.result).unwrap();
+        assert_eq!(snaps[0].tracking_channel.as_deref(), Some("latest/stable"));
+        assert!(snaps[0].hold.is_some());
+        assert!(snaps[1].hold.is_none() && snaps[1].tracking_channel.is_none());
+
+        let change: Change = serde_json::from_str(
+            r#"{"id":"42","kind":"refresh-snap","summary":"Refresh snaps \"firefox\"",
+                "status":"Doing","ready":false,"tasks":[
+                {"summary":"Ensure prerequisites for \"firefox\" are available","status":"Done","progress":{"label":"","done":1,"total":1}},
+                {"summary":"Download snap \"firefox\" (5134) from channel \"latest/stable\"","status":"Doing","progress":{"label":"firefox","done":141000000,"total":283000000}}]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            change.progress().as_deref(),
+            Some("Download snap \"firefox\" (5134) from channel \"latest/stable\" (40%)")
+        );
+    }
+}
@@ -0,0 +1,62 @@
+use anyhow::{Context, Result};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::trace::{SdkTracer, SdkTracerProvider};
+use opentelemetry_sdk::Resource;
+use std::sync::OnceLock;
+use tracing::Subscriber;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::config::TelemetryConfig;
+
+/// Kept so `shutdown` can flush spans still queued in the batch exporter.
+static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+/// Builds the layer that exports tracing spans (update runs, package
+/// manager commands, backend requests) over OTLP/HTTP, or `None` when
+/// `telemetry.enabled` is off. Spans carry the hostname, so fleets can
+/// line slow updates up with backend latency in their tracing stack.
+pub fn otlp_layer<S>(config: &TelemetryConfig) -> Result<Option<OpenTelemetryLayer<S, SdkTracer>>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    if !config.enabled {
+        return Ok(None);
+    }
+
+    let mut exporter = SpanExporter::builder().with_http();
+    if let Some(endpoint) = &config.endpoint {
+        exporter = exporter.with_endpoint(endpoint);
+    }
+    let exporter = exporter
+        .build()
+        .context("Failed to create OTLP span exporter")?;
+
+    let hostname = crate::host::hostname();
+    let resource = Resource::builder()
+        .with_service_name(config.service_name.clone())
+        .with_attributes([
+            KeyValue::new("host.name", hostname),
+            KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+        ])
+        .build();
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource)
+        .build();
+    let tracer = provider.tracer("ua-agent");
+    let _ = PROVIDER.set(provider);
+
+    Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer)))
+}
+
+/// Flushes and stops the exporter before the process exits.
+pub fn shutdown() {
+    if let Some(provider) = PROVIDER.get() {
+        if let Err(e) = provider.shutdown() {
+            eprintln!("Failed to flush OpenTelemetry spans: {}", e);
+        }
+    }
+}
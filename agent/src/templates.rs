@@ -0,0 +1,11 @@
+// Builds without the `templates` feature leave out minijinja; they render
+// the built-in notification text directly, and overrides are rejected.
+#[cfg(not(feature = "templates"))]
+mod builtin;
+#[cfg(feature = "templates")]
+mod jinja;
+
+#[cfg(not(feature = "templates"))]
+pub use builtin::Templates;
+#[cfg(feature = "templates")]
+pub use jinja::Templates;
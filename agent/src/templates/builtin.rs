@@ -0,0 +1,139 @@
+use anyhow::Result;
+use fluent_bundle::FluentArgs;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::config::NotificationTemplates;
+
+/// Stands in for the minijinja templates in builds without the `templates`
+/// feature, writing out what the built-in templates say.
+pub struct Templates;
+
+impl Templates {
+    pub fn new(_config: &NotificationTemplates) -> Self {
+        Self
+    }
+
+    /// Renders a built-in template; any other name renders empty.
+    pub fn render<S: Serialize>(&self, name: &str, ctx: &S) -> String {
+        let ctx = serde_json::to_value(ctx).unwrap_or_default();
+        let set = |field: &str| ctx.get(field).is_some_and(truthy);
+        let t = |key: &str, args: &[(&str, &str)]| translate(key, &ctx, args);
+
+        let mut text = String::new();
+        match name {
+            "title" if set("success") => text = t("notify-title-ok", &[("hostname", "hostname")]),
+            "title" => text = t("notify-title-failed", &[("hostname", "hostname")]),
+            "message" => {
+                text = t(
+                    "notify-summary",
+                    &[
+                        ("updated", "packages_updated"),
+                        ("available", "packages_available"),
+                    ],
+                );
+                if set("reboot_required") {
+                    text = format!("{}, {}", text, t("notify-reboot-required", &[]));
+                }
+                if set("error") {
+                    text = format!("{}\n{}", text, t("notify-error", &[("error", "error")]));
+                }
+            }
+            "motd" => {
+                if set("skipped_reason") {
+                    text = t(
+                        "motd-last-run-skipped",
+                        &[("time", "time"), ("reason", "skipped_reason")],
+                    );
+                    text.push('\n');
+                } else if set("success") {
+                    text = t(
+                        "motd-last-run-ok",
+                        &[("time", "time"), ("updated", "packages_updated")],
+                    );
+                    text.push('\n');
+                    if set("pending") {
+                        text.push_str(&t("motd-pending", &[("count", "pending")]));
+                        text.push('\n');
+                    }
+                } else {
+                    text = t(
+                        "motd-last-run-failed",
+                        &[("time", "time"), ("error", "error")],
+                    );
+                    text.push('\n');
+                }
+                if set("reboot_required") {
+                    text.push_str(&t("motd-reboot-required", &[]));
+                    text.push('\n');
+                }
+            }
+            "wall" => text = t("notify-wall-reboot", &[]),
+            _ => {}
+        }
+        text
+    }
+
+    /// There are no templates besides the built-ins to render.
+    pub fn try_render<S: Serialize>(&self, name: &str, _ctx: &S) -> Result<String> {
+        Err(anyhow::anyhow!(
+            "No notification template {}: this agent was built without the templates feature",
+            name
+        ))
+    }
+}
+
+/// `t(key, name=field, ...)` as the built-in templates call it.
+fn translate(key: &str, ctx: &Value, args: &[(&str, &str)]) -> String {
+    let mut fluent_args = FluentArgs::new();
+    for (name, field) in args {
+        match &ctx[*field] {
+            Value::Number(number) if number.is_i64() => {
+                fluent_args.set(*name, number.as_i64().unwrap_or_default())
+            }
+            Value::String(value) => fluent_args.set(*name, value.clone()),
+            value => fluent_args.set(*name, value.to_string()),
+        }
+    }
+    crate::i18n::message(key, Some(&fluent_args))
+}
+
+/// Whether a `{% if %}` on the value would pass.
+fn truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(value) => *value,
+        Value::Number(number) => number.as_f64() != Some(0.0),
+        Value::String(value) => !value.is_empty(),
+        Value::Array(values) => !values.is_empty(),
+        Value::Object(fields) => !fields.is_empty(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_builtin_text() {
+        let templates = Templates::new(&NotificationTemplates::default());
+        let notice = json!({
+            "hostname": "web-1",
+            "success": false,
+            "packages_updated": 0,
+            "packages_available": 12,
+            "reboot_required": true,
+            "error": "dpkg was interrupted",
+        });
+        assert_eq!(
+            templates.render("title", &notice),
+            "Update run failed on web-1"
+        );
+        assert_eq!(
+            templates.render("message", &notice),
+            "0 package(s) updated, 12 still available, reboot required\nError: dpkg was interrupted"
+        );
+        assert!(templates.try_render("slack-body", &notice).is_err());
+    }
+}
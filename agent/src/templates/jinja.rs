@@ -0,0 +1,176 @@
+use anyhow::{Context, Result};
+use fluent_bundle::FluentArgs;
+use minijinja::value::{Kwargs, Value};
+use minijinja::{context, Environment, Error};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use tracing::{debug, warn};
+
+use crate::config::NotificationTemplates;
+
+/// Extension of template files in `notifications.templates.dir`
+const TEMPLATE_EXTENSION: &str = "j2";
+
+/// What each notification says unless overridden, in the configured
+/// language. Also registered as `builtin/<name>`, the fallback when an
+/// override fails to render.
+const BUILTIN: &[(&str, &str)] = &[
+    (
+        "title",
+        "{% if success %}{{ t('notify-title-ok', hostname=hostname) }}\
+         {% else %}{{ t('notify-title-failed', hostname=hostname) }}{% endif %}",
+    ),
+    (
+        "message",
+        "{{ t('notify-summary', updated=packages_updated, available=packages_available) }}\
+         {% if reboot_required %}, {{ t('notify-reboot-required') }}{% endif %}\
+         {% if error %}{{ '\\n' ~ t('notify-error', error=error) }}{% endif %}",
+    ),
+    (
+        "motd",
+        "{% if skipped_reason %}{{ t('motd-last-run-skipped', time=time, reason=skipped_reason) }}\n\
+         {% elif success %}{{ t('motd-last-run-ok', time=time, updated=packages_updated) }}\n\
+         {% if pending %}{{ t('motd-pending', count=pending) }}\n{% endif %}\
+         {% else %}{{ t('motd-last-run-failed', time=time, error=error) }}\n{% endif %}\
+         {% if reboot_required %}{{ t('motd-reboot-required') }}\n{% endif %}",
+    ),
+    ("wall", "{{ t('notify-wall-reboot') }}"),
+];
+
+/// Notification text, rendered with minijinja from the built-in templates
+/// or the deployment's overrides.
+///
+/// Templates see the notification's fields, `vars` from the configuration,
+/// and `t(key, ...)` for the agent's own translated messages.
+pub struct Templates {
+    env: Environment<'static>,
+    vars: BTreeMap<String, String>,
+}
+
+impl Templates {
+    /// Loads the built-ins, then `<dir>/<name>.j2` files, then inline
+    /// templates. An override that doesn't parse is logged and skipped.
+    pub fn new(config: &NotificationTemplates) -> Self {
+        let mut env = Environment::new();
+        env.add_function("t", translate);
+        for (name, source) in BUILTIN {
+            env.add_template(name, source)
+                .expect("built-in templates are valid");
+            env.add_template_owned(format!("builtin/{}", name), *source)
+                .expect("built-in templates are valid");
+        }
+
+        let mut overrides = Vec::new();
+        if let Some(dir) = &config.dir {
+            match read_dir(dir) {
+                Ok(files) => overrides.extend(files),
+                Err(e) => warn!("Failed to read notification templates: {:#}", e),
+            }
+        }
+        overrides.extend(config.inline.clone());
+        for (name, source) in overrides {
+            match env.add_template_owned(name.clone(), source) {
+                Ok(()) => debug!("Loaded notification template {}", name),
+                Err(e) => warn!("Ignoring notification template {}: {:#}", name, e),
+            }
+        }
+
+        Self {
+            env,
+            vars: config.vars.clone(),
+        }
+    }
+
+    /// Renders a built-in template or its override, falling back to the
+    /// built-in when the override fails.
+    pub fn render<S: Serialize>(&self, name: &str, ctx: &S) -> String {
+        self.try_render(name, ctx).unwrap_or_else(|e| {
+            warn!("Failed to render notification template {}: {:#}", name, e);
+            self.try_render(&format!("builtin/{}", name), ctx)
+                .unwrap_or_default()
+        })
+    }
+
+    /// Renders a template with no built-in, such as a webhook body.
+    pub fn try_render<S: Serialize>(&self, name: &str, ctx: &S) -> Result<String> {
+        let template = self
+            .env
+            .get_template(name)
+            .with_context(|| format!("No notification template {}", name))?;
+        template
+            .render(context! { vars => &self.vars, ..Value::from_serialize(ctx) })
+            .with_context(|| format!("Failed to render template {}", name))
+    }
+}
+
+/// `t(key, name=value, ...)`: a message from the agent's translations.
+fn translate(key: &str, kwargs: Kwargs) -> Result<String, Error> {
+    let mut args = FluentArgs::new();
+    for name in kwargs.args() {
+        let value: Value = kwargs.get(name)?;
+        match value.as_i64() {
+            Some(number) => args.set(name.to_string(), number),
+            None => args.set(name.to_string(), value.to_string()),
+        }
+    }
+    Ok(crate::i18n::message(key, Some(&args)))
+}
+
+fn read_dir(dir: &std::path::Path) -> Result<Vec<(String, String)>> {
+    let mut templates = Vec::new();
+    for entry in fs::read_dir(dir).with_context(|| format!("Failed to read {:?}", dir))? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some(TEMPLATE_EXTENSION) {
+            continue;
+        }
+        let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+        let source = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read template {:?}", path))?;
+        templates.push((name.to_string(), source));
+    }
+    Ok(templates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_overrides_and_fallback() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        fs::write(
+            temp_dir.path().join("title.j2"),
+            "[{{ vars.customer }}] {{ hostname }}",
+        )
+        .unwrap();
+        let mut config = NotificationTemplates {
+            dir: Some(temp_dir.path().to_path_buf()),
+            ..NotificationTemplates::default()
+        };
+        config
+            .vars
+            .insert("customer".to_string(), "Acme".to_string());
+        config
+            .inline
+            .insert("message".to_string(), "{{ hostname | nope }}".to_string());
+        let templates = Templates::new(&config);
+
+        let notice = json!({
+            "hostname": "web-1",
+            "success": true,
+            "packages_updated": 3,
+            "packages_available": 3,
+            "reboot_required": false,
+        });
+        assert_eq!(templates.render("title", &notice), "[Acme] web-1");
+        assert_eq!(
+            templates.render("message", &notice),
+            "3 package(s) updated, 3 still available"
+        );
+        assert!(templates.try_render("slack-body", &notice).is_err());
+    }
+}
@@ -0,0 +1,180 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+use tracing::{debug, info, warn};
+
+use crate::rollback::command_exists;
+
+/// systemd timers that drive APT::Periodic (list refresh and unattended-upgrade)
+const APT_TIMERS: &[&str] = &["apt-daily.timer", "apt-daily-upgrade.timer"];
+
+/// Sorts after 20auto-upgrades / 50unattended-upgrades so its values win
+const OWNERSHIP_CONF: &str = "/etc/apt/apt.conf.d/90ubuntu-auto-update-periodic";
+
+const OWNERSHIP_CONF_CONTENT: &str = "\
+// Written by ubuntu-auto-update-agent (unattended_upgrades.policy = \"take_ownership\").
+// The agent runs apt itself; remove this file to hand updates back to
+// unattended-upgrades.
+APT::Periodic::Update-Package-Lists \"0\";
+APT::Periodic::Download-Upgradeable-Packages \"0\";
+APT::Periodic::Unattended-Upgrade \"0\";
+";
+
+/// What to do when unattended-upgrades would also drive apt on this host.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CoexistencePolicy {
+    /// Warn and report the conflict, but keep updating
+    Report,
+    /// Turn off APT::Periodic and the apt timers so only the agent drives apt
+    TakeOwnership,
+    /// Leave apt to unattended-upgrades; the agent only reports
+    Passive,
+}
+
+impl CoexistencePolicy {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "report" => Ok(Self::Report),
+            "take_ownership" => Ok(Self::TakeOwnership),
+            "passive" => Ok(Self::Passive),
+            other => Err(anyhow::anyhow!(
+                "Unknown unattended_upgrades.policy: {}",
+                other
+            )),
+        }
+    }
+}
+
+/// unattended-upgrades state as reported to the backend.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct UnattendedUpgradesStatus {
+    pub installed: bool,
+    /// APT::Periodic::Unattended-Upgrade is non-zero
+    pub enabled: bool,
+    /// Enabled apt timers that will run apt behind the agent's back
+    pub active_timers: Vec<String>,
+}
+
+impl UnattendedUpgradesStatus {
+    pub fn detect() -> Self {
+        let installed = command_exists("unattended-upgrade");
+
+        let enabled = installed
+            && apt_config_value("APT::Periodic::Unattended-Upgrade")
+                .is_some_and(|value| periodic_enabled(&value));
+
+        let active_timers = APT_TIMERS
+            .iter()
+            .filter(|timer| timer_enabled(timer))
+            .map(|timer| timer.to_string())
+            .collect();
+
+        Self {
+            installed,
+            enabled,
+            active_timers,
+        }
+    }
+
+    /// Whether something other than the agent will run apt upgrades.
+    pub fn conflicts(&self) -> bool {
+        self.enabled && !self.active_timers.is_empty()
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "unattended-upgrades is enabled (timers: {})",
+            self.active_timers.join(", ")
+        )
+    }
+}
+
+/// Disables APT::Periodic and the apt timers so unattended-upgrades no
+/// longer competes with the agent for the dpkg lock.
+pub fn take_ownership() -> Result<()> {
+    let path = PathBuf::from(OWNERSHIP_CONF);
+    fs::write(&path, OWNERSHIP_CONF_CONTENT)
+        .with_context(|| format!("Failed to write {:?}", path))?;
+    info!("Disabled APT::Periodic in {:?}", path);
+
+    for timer in APT_TIMERS {
+        let output =
+            crate::audit::output(Command::new("systemctl").args(["disable", "--now", timer]))
+                .with_context(|| format!("Failed to run systemctl disable {}", timer))?;
+        if output.status.success() {
+            info!("Disabled {}", timer);
+        } else {
+            warn!(
+                "Failed to disable {}: {}",
+                timer,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+    }
+    Ok(())
+}
+
+fn apt_config_value(key: &str) -> Option<String> {
+    let output = crate::audit::output(Command::new("apt-config").args(["shell", "VALUE", key]))
+        .map_err(|e| debug!("Failed to run apt-config: {}", e))
+        .ok()?;
+    parse_apt_config_shell(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Parses `apt-config shell VALUE <key>` output such as `VALUE='1'`.
+fn parse_apt_config_shell(output: &str) -> Option<String> {
+    output
+        .lines()
+        .find_map(|line| line.strip_prefix("VALUE="))
+        .map(|value| value.trim().trim_matches('\'').to_string())
+}
+
+/// APT::Periodic values are day intervals ("1") or, since apt 1.5,
+/// durations ("1d", "12h"); "0" and empty mean disabled.
+fn periodic_enabled(value: &str) -> bool {
+    let digits: String = value.chars().take_while(|c| c.is_ascii_digit()).collect();
+    !value.is_empty() && digits.parse::<u64>().map_or(true, |n| n > 0)
+}
+
+fn timer_enabled(timer: &str) -> bool {
+    crate::audit::status(Command::new("systemctl").args(["is-enabled", "--quiet", timer]))
+        .is_ok_and(|status| status.success())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_apt_config_shell() {
+        assert_eq!(parse_apt_config_shell("VALUE='1'\n").as_deref(), Some("1"));
+        assert_eq!(parse_apt_config_shell("").as_deref(), None);
+
+        assert!(periodic_enabled("1"));
+        assert!(periodic_enabled("1d"));
+        assert!(!periodic_enabled("0"));
+        assert!(!periodic_enabled(""));
+    }
+
+    #[test]
+    fn test_conflicts_requires_enabled_and_timer() {
+        let mut status = UnattendedUpgradesStatus {
+            installed: true,
+            enabled: true,
+            active_timers: vec![],
+        };
+        assert!(!status.conflicts());
+
+        status.active_timers = vec!["apt-daily-upgrade.timer".to_string()];
+        assert!(status.conflicts());
+        assert_eq!(
+            status.describe(),
+            "unattended-upgrades is enabled (timers: apt-daily-upgrade.timer)"
+        );
+
+        assert!(CoexistencePolicy::parse("passive").is_ok());
+        assert!(CoexistencePolicy::parse("ignore").is_err());
+    }
+}
@@ -0,0 +1,384 @@
+use anyhow::{Context, Result};
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+
+use crate::config::{AgentConfig, UpdateMode};
+
+/// Shipped timers; their schedule doesn't depend on the configuration.
+const TIMERS: &[(&str, &str)] = &[
+    (
+        "ubuntu-auto-update-agent.timer",
+        include_str!("../systemd/ubuntu-auto-update-agent.timer"),
+    ),
+    (
+        "ubuntu-auto-update-agent-beacon.timer",
+        include_str!("../systemd/ubuntu-auto-update-agent-beacon.timer"),
+    ),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Role {
+    /// One-shot update run started by the timer
+    Run,
+    /// Long-running agent, the alternative to the timer
+    Daemon,
+    /// State refresh started by the apt hook
+    Refresh,
+    /// Post-update health beacon
+    Beacon,
+}
+
+impl Role {
+    const ALL: [Role; 4] = [Role::Run, Role::Daemon, Role::Refresh, Role::Beacon];
+
+    fn unit_name(self) -> &'static str {
+        match self {
+            Role::Run => "ubuntu-auto-update-agent.service",
+            Role::Daemon => "ubuntu-auto-update-agentd.service",
+            Role::Refresh => "ubuntu-auto-update-agent-refresh.service",
+            Role::Beacon => "ubuntu-auto-update-agent-beacon.service",
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Role::Run => "Ubuntu Auto-Update Agent",
+            Role::Daemon => "Ubuntu Auto-Update Agent (daemon mode)",
+            Role::Refresh => "Ubuntu Auto-Update Agent state refresh (apt hook)",
+            Role::Beacon => "Ubuntu Auto-Update Agent post-update health beacon",
+        }
+    }
+
+    fn subcommand(self) -> &'static str {
+        match self {
+            Role::Run => "run",
+            Role::Daemon => "daemon",
+            Role::Refresh => "refresh",
+            Role::Beacon => "beacon",
+        }
+    }
+
+    /// (TimeoutStartSec, MemoryMax, TasksMax)
+    fn limits(self) -> (u32, &'static str, u32) {
+        match self {
+            Role::Run => (3600, "512M", 100),
+            Role::Daemon => (30, "512M", 100),
+            Role::Refresh => (300, "512M", 100),
+            Role::Beacon => (120, "128M", 20),
+        }
+    }
+
+    /// Runs apt, snap or flatpak on the host's behalf
+    fn uses_package_managers(self) -> bool {
+        self != Role::Beacon
+    }
+
+    fn schedules_runs(self) -> bool {
+        matches!(self, Role::Run | Role::Daemon)
+    }
+}
+
+/// A unit file for `generate-units`.
+#[derive(Debug)]
+pub struct UnitFile {
+    pub name: &'static str,
+    pub contents: String,
+}
+
+/// The agent's service units with sandboxing derived from `config`, plus
+/// the shipped timers. Writable paths, snapshot device access, setuid
+/// installs and reboot syscalls follow what the configuration enables, so
+/// regenerating after a config change keeps the units in step.
+pub fn generate(config: &AgentConfig, binary: &Path) -> Vec<UnitFile> {
+    let mut units: Vec<UnitFile> = Role::ALL
+        .iter()
+        .map(|role| UnitFile {
+            name: role.unit_name(),
+            contents: service(config, binary, *role),
+        })
+        .collect();
+    units.extend(TIMERS.iter().map(|(name, contents)| UnitFile {
+        name,
+        contents: contents.to_string(),
+    }));
+    units
+}
+
+/// Writes every unit into `dir`, e.g. /etc/systemd/system.
+pub fn write_all(units: &[UnitFile], dir: &Path) -> Result<()> {
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create unit directory: {:?}", dir))?;
+    for unit in units {
+        let path = dir.join(unit.name);
+        std::fs::write(&path, &unit.contents)
+            .with_context(|| format!("Failed to write unit file: {:?}", path))?;
+    }
+    Ok(())
+}
+
+fn service(config: &AgentConfig, binary: &Path, role: Role) -> String {
+    let updates = &config.updates;
+    // dpkg writes anywhere under /usr, /etc and /boot and installs setuid
+    // binaries, which ProtectSystem=strict and RestrictSUIDSGID would break
+    let installs = role.schedules_runs() && updates.mode == UpdateMode::Manage && !updates.dry_run;
+    let snapshots = installs && config.snapshot.enabled;
+    let (timeout, memory, tasks) = role.limits();
+
+    let mut unit = String::new();
+    let _ = writeln!(
+        unit,
+        "# Generated by `ua-agent generate-units` from the agent configuration"
+    );
+    let _ = writeln!(unit, "[Unit]");
+    let _ = writeln!(unit, "Description={}", role.description());
+    let _ = writeln!(
+        unit,
+        "Documentation=https://github.com/patel5d2/ubuntu-auto-update"
+    );
+    let _ = writeln!(unit, "Wants=network-online.target");
+    let _ = writeln!(unit, "After=network-online.target");
+    if role == Role::Daemon {
+        let _ = writeln!(unit, "Conflicts=ubuntu-auto-update-agent.timer");
+    }
+    let _ = writeln!(unit, "ConditionPathExists={}", binary.display());
+    if role == Role::Beacon {
+        let _ = writeln!(
+            unit,
+            "ConditionPathExists={}",
+            config.state.dir.join("beacon.json").display()
+        );
+    }
+
+    let _ = writeln!(unit, "\n[Service]");
+    let _ = writeln!(
+        unit,
+        "Type={}",
+        if role == Role::Daemon {
+            "simple"
+        } else {
+            "oneshot"
+        }
+    );
+    let _ = writeln!(unit, "User=root\nGroup=root");
+    let _ = writeln!(unit, "ExecStart={} {}", binary.display(), role.subcommand());
+    if role == Role::Daemon {
+        let _ = writeln!(unit, "ExecReload=/bin/kill -HUP $MAINPID");
+    }
+    let _ = writeln!(unit, "StandardOutput=journal\nStandardError=journal");
+    let _ = writeln!(unit, "SyslogIdentifier=ubuntu-auto-update-agent");
+
+    let _ = writeln!(unit, "\n# Security settings");
+    // snap refresh and setuid maintainer scripts need to gain privileges
+    let new_privileges = installs && updates.update_sources.snap;
+    let _ = writeln!(unit, "NoNewPrivileges={}", yes_no(!new_privileges));
+    let _ = writeln!(
+        unit,
+        "ProtectSystem={}",
+        if installs { "no" } else { "strict" }
+    );
+    let _ = writeln!(unit, "ProtectHome=yes\nPrivateTmp=yes");
+    // LVM and btrfs snapshots need the block devices
+    let _ = writeln!(unit, "PrivateDevices={}", yes_no(!snapshots));
+    for setting in [
+        "ProtectHostname=yes",
+        "ProtectKernelTunables=yes",
+        "ProtectKernelModules=yes",
+        "ProtectControlGroups=yes",
+        "RestrictNamespaces=yes",
+        "LockPersonality=yes",
+        "MemoryDenyWriteExecute=no",
+        "RestrictRealtime=yes",
+    ] {
+        let _ = writeln!(unit, "{}", setting);
+    }
+    let _ = writeln!(unit, "RestrictSUIDSGID={}", yes_no(!installs));
+    let _ = writeln!(unit, "RemoveIPC=yes");
+    if !snapshots {
+        let _ = writeln!(unit, "CapabilityBoundingSet=~CAP_SYS_ADMIN");
+    }
+    // Reboots are requested from logind; the agent itself never reboots
+    // unless updates.auto_reboot allows it
+    if !(role.schedules_runs() && updates.auto_reboot) {
+        let _ = writeln!(unit, "SystemCallFilter=~@reboot");
+    }
+    // The agent only makes outgoing connections, apart from the local API
+    if let Some(listen) = local_api_address(config, role) {
+        let _ = writeln!(unit, "SocketBindAllow=tcp:{}", listen.port());
+    }
+    let _ = writeln!(unit, "SocketBindDeny=any");
+    let _ = writeln!(unit, "PrivateNetwork=no");
+
+    let _ = writeln!(unit, "\n# Writable paths");
+    for line in writable_paths(config, role, installs) {
+        let _ = writeln!(unit, "{}", line);
+    }
+    if config.security.credentials_from_systemd {
+        let security = &config.security;
+        for file in std::iter::once(&security.api_key_file).chain(&security.hmac_secret_file) {
+            if let Some(name) = file.file_name() {
+                let _ = writeln!(
+                    unit,
+                    "LoadCredential={}:{}",
+                    name.to_string_lossy(),
+                    security.api_key_store_path().with_file_name(name).display()
+                );
+            }
+        }
+    }
+
+    let _ = writeln!(unit, "\n# Resource limits");
+    let _ = writeln!(unit, "TimeoutStartSec={}", timeout);
+    let _ = writeln!(
+        unit,
+        "TimeoutStopSec={}",
+        if role == Role::Daemon { 120 } else { 30 }
+    );
+    let _ = writeln!(unit, "MemoryMax={}\nTasksMax={}", memory, tasks);
+
+    let _ = writeln!(unit, "\n# Environment");
+    if role.uses_package_managers() {
+        let _ = writeln!(unit, "Environment=\"DEBIAN_FRONTEND=noninteractive\"");
+    }
+    let _ = writeln!(
+        unit,
+        "Environment=\"PATH=/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin\""
+    );
+    let _ = writeln!(unit, "AppArmorProfile=ubuntu-auto-update-agent");
+
+    if role.schedules_runs() {
+        let _ = writeln!(
+            unit,
+            "\nRestart=on-failure\nRestartSec=60s\n# Another run was in progress\nRestartPreventExitStatus={}",
+            crate::runlock::EXIT_ALREADY_RUNNING
+        );
+        let _ = writeln!(unit, "\n[Install]\nWantedBy=multi-user.target");
+    }
+    unit
+}
+
+/// StateDirectory=/LogsDirectory= for paths under /var/lib and /var/log,
+/// ReadWritePaths= for everything else the role writes. Nothing is needed
+/// beyond the state and log directories with ProtectSystem=no.
+fn writable_paths(config: &AgentConfig, role: Role, installs: bool) -> Vec<String> {
+    let mut lines = vec![managed_directory(
+        "StateDirectory",
+        "/var/lib",
+        &config.state.dir,
+    )];
+    if let Some(dir) = config.logging.file.as_deref().and_then(Path::parent) {
+        lines.push(managed_directory("LogsDirectory", "/var/log", dir));
+    }
+    if role == Role::Daemon && config.local_api.enabled {
+        if let Some(dir) = config.local_api.socket_path.parent() {
+            lines.push(managed_directory("RuntimeDirectory", "/run", dir));
+        }
+    }
+    if installs {
+        return lines;
+    }
+
+    let mut paths: Vec<String> = Vec::new();
+    if role.uses_package_managers() {
+        let security = &config.security;
+        let enrollment = &config.enrollment;
+        let mut files: Vec<&PathBuf> = vec![&enrollment.token_file, &enrollment.host_id_file];
+        if !security.credentials_from_systemd {
+            files.push(&security.api_key_file);
+        }
+        for file in files {
+            if let Some(dir) = file.parent() {
+                paths.push(dir.display().to_string());
+            }
+        }
+        if config.motd.enabled {
+            if let Some(dir) = config.motd.path.parent() {
+                paths.push(format!("-{}", dir.display()));
+            }
+        }
+        if config.metrics.enabled {
+            if let Some(dir) = &config.metrics.textfile_path {
+                paths.push(dir.display().to_string());
+            }
+        }
+        if role.schedules_runs() && config.unattended_upgrades.policy == "take_ownership" {
+            paths.push("/etc/apt/apt.conf.d".to_string());
+        }
+        if config.updates.update_sources.apt {
+            paths.extend(["/var/cache/apt", "/var/lib/apt", "/var/lib/dpkg"].map(String::from));
+            if let Some(dir) = &config.updates.apt_cache_dir {
+                paths.push(dir.display().to_string());
+            }
+        }
+        if config.updates.update_sources.flatpak {
+            paths.push("/var/lib/flatpak".to_string());
+        }
+    }
+    paths.sort();
+    paths.dedup();
+    lines.extend(
+        paths
+            .into_iter()
+            .map(|path| format!("ReadWritePaths={}", path)),
+    );
+    lines
+}
+
+/// The loopback address the daemon's local API listens on, if any.
+fn local_api_address(config: &AgentConfig, role: Role) -> Option<std::net::SocketAddr> {
+    let local_api = &config.local_api;
+    if role != Role::Daemon || !local_api.enabled {
+        return None;
+    }
+    local_api.listen.as_deref()?.parse().ok()
+}
+
+fn managed_directory(setting: &str, base: &str, dir: &Path) -> String {
+    match dir.strip_prefix(base) {
+        Ok(relative) if !relative.as_os_str().is_empty() => {
+            format!("{}={}", setting, relative.display())
+        }
+        _ => format!("ReadWritePaths={}", dir.display()),
+    }
+}
+
+fn yes_no(value: bool) -> &'static str {
+    if value {
+        "yes"
+    } else {
+        "no"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_units_follow_config() {
+        let binary = Path::new("/usr/local/bin/ua-agent");
+        let mut config = AgentConfig::default();
+        let units = generate(&config, binary);
+        assert_eq!(units.len(), 6);
+        let run = &units[0].contents;
+        assert!(run.contains("ExecStart=/usr/local/bin/ua-agent run\n"));
+        assert!(run.contains("ProtectSystem=no\n"));
+        assert!(run.contains("RestrictSUIDSGID=no\n"));
+        assert!(run.contains("StateDirectory=ubuntu-auto-update\n"));
+        assert!(run.contains("SystemCallFilter=~@reboot\n"));
+        assert!(run.contains("CapabilityBoundingSet=~CAP_SYS_ADMIN\n"));
+        assert!(run.contains("RestartPreventExitStatus=75\n"));
+
+        config.updates.mode = UpdateMode::Observe;
+        config.updates.auto_reboot = true;
+        config.state.dir = PathBuf::from("/srv/ua-state");
+        let run = service(&config, binary, Role::Run);
+        assert!(run.contains("ProtectSystem=strict\n"));
+        assert!(run.contains("ReadWritePaths=/srv/ua-state\n"));
+        assert!(run.contains("ReadWritePaths=/var/lib/dpkg\n"));
+        assert!(!run.contains("@reboot"));
+
+        let beacon = service(&config, binary, Role::Beacon);
+        assert!(beacon.contains("SystemCallFilter=~@reboot\n"));
+        assert!(!beacon.contains("/var/lib/dpkg"));
+    }
+}
@@ -1,14 +1,128 @@
 use anyhow::{Context, Result};
-use chrono::{Local, NaiveTime};
+use chrono::{Datelike, Local, NaiveDateTime, NaiveTime};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
 use std::process::{Command, Output, Stdio};
 use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
 use tokio::time::timeout;
 use tracing::{debug, error, info, warn};
 
-use crate::config::AgentConfig;
+use crate::accounting::{self, ResourceUsage, RunAccounting};
+use crate::audit::CommandAudit;
+use crate::config::{AgentConfig, PackageBackend, ResourceLimits, RiskLevel};
+use crate::debdelta::{self, DeltaSavings, DEFAULT_ARCHIVES};
+use crate::diskspace::{self, mounted_filesystems, SpaceCheck};
+use crate::distro::{DistroInfo, PocketMap};
+#[cfg(feature = "dbus")]
+use crate::packagekit::{PackageInfo, PackageKitClient};
+use crate::privileges::{Operation, Privileges};
+use crate::risk::RiskScorer;
+use crate::services::ServiceTransition;
+use crate::snapd::{RefreshCandidate, Snap, SnapdClient};
+use crate::watchdog::Activity;
+use crate::window::Window;
+
+/// Set on package manager children so the apt hook can tell the agent's own
+/// runs apart from externally initiated ones.
+pub const AGENT_RUN_ENV: &str = "UA_AGENT_RUN";
+
+/// Packages whose postinst asked for a reboot, one per line
+const REBOOT_REQUIRED_PKGS: &str = "/var/run/reboot-required.pkgs";
+
+/// How long snapd gets for a refresh before the change is aborted
+const SNAP_REFRESH_TIMEOUT: Duration = Duration::from_secs(900);
+
+/// How long a timed out command gets to exit after SIGTERM before SIGKILL.
+pub(crate) const KILL_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// Packages an upgrade is never allowed to remove, even when listed in
+/// `updates.allowed_removals`, in addition to `updates.protected_packages`.
+const PROTECTED_PACKAGES: &[&str] = &[
+    "ubuntu-minimal",
+    "ubuntu-standard",
+    "ubuntu-server",
+    "ubuntu-desktop",
+    "openssh-server",
+    "sudo",
+];
+
+/// A package manager command that exceeded its timeout and was terminated.
+#[derive(Debug, thiserror::Error)]
+#[error("{command} timed out after {timeout:?}, {termination}")]
+pub struct CommandTimedOut {
+    pub command: String,
+    pub timeout: Duration,
+    pub termination: String,
+}
+
+/// A package manager command that stopped making progress and was
+/// terminated before its timeout.
+#[derive(Debug, thiserror::Error)]
+#[error("{command} stalled, no output or dpkg progress for {idle:?}, {termination}")]
+pub struct CommandStalled {
+    pub command: String,
+    pub idle: Duration,
+    pub termination: String,
+    /// The process group's state and the dpkg lock holders when it was
+    /// found stalled
+    pub diagnostics: Vec<String>,
+}
+
+/// Why a package manager command was terminated.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandFailure {
+    /// Still working when its timeout ran out
+    Slow,
+    /// No output, dpkg progress or download for `updates.watchdog.stall_minutes`
+    Stalled,
+}
+
+impl CommandFailure {
+    pub fn of(error: &anyhow::Error) -> Option<Self> {
+        if error.is::<CommandStalled>() {
+            Some(Self::Stalled)
+        } else if error.is::<CommandTimedOut>() {
+            Some(Self::Slow)
+        } else {
+            None
+        }
+    }
+}
+
+/// A pre-flight path would drop below its floor during the upgrade.
+#[derive(Debug, thiserror::Error)]
+#[error("Not enough disk space for the upgrade: {}", short_filesystems(checks))]
+pub struct InsufficientDiskSpace {
+    pub checks: Vec<SpaceCheck>,
+}
+
+fn short_filesystems(checks: &[SpaceCheck]) -> String {
+    checks
+        .iter()
+        .filter(|check| !check.sufficient)
+        .map(|check| {
+            format!(
+                "{} has {} MiB free, needs {} MiB",
+                check.path.display(),
+                check.free_bytes / 1024 / 1024,
+                check.required_bytes / 1024 / 1024
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// The simulated upgrade would remove packages that aren't allowed to go.
+#[derive(Debug, thiserror::Error)]
+#[error("Refusing {command}, it would remove {}", packages.join(", "))]
+pub struct RemovalsRefused {
+    pub command: String,
+    pub packages: Vec<String>,
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UpdateResults {
@@ -20,67 +134,228 @@ pub struct UpdateResults {
     pub reboot_required: bool,
     pub error_message: Option<String>,
     pub apt_output: String,
-    pub snap_output: Option<String>,
-    pub flatpak_output: Option<String>,
+    /// Installed snaps after the snap phase
+    #[serde(default)]
+    pub snaps: Vec<SnapStatus>,
+    /// What the flatpak phase updated, installed or removed
+    #[serde(default)]
+    pub flatpaks: Vec<FlatpakChange>,
+    /// Graphics stack packages held back by `graphics.caution`
+    pub graphics_deferred: Vec<String>,
+    /// Packages held back because their risk level isn't scheduled today
+    pub risk_deferred: Vec<String>,
+    /// Set when `updates.debdelta` rebuilt packages from deltas
+    #[serde(default)]
+    pub debdelta: Option<DeltaSavings>,
+    /// Packages the upgrade would have removed, when it was refused for
+    /// that
+    #[serde(default)]
+    pub removals_refused: Vec<String>,
+    /// Pre-flight free space checks; an insufficient one aborted the run
+    #[serde(default)]
+    pub disk_space: Vec<SpaceCheck>,
+    /// What a dry run would have updated and downloaded. The counters above
+    /// stay at zero since nothing was installed.
+    #[serde(default)]
+    pub dry_run: Option<DryRunEstimate>,
+    /// Services restarted because they still used replaced libraries
+    #[serde(default)]
+    pub services_restarted: Vec<ServiceTransition>,
+    /// Packages that asked for the pending reboot, e.g. linux-image-* or
+    /// libssl3; empty when no reboot is required
+    #[serde(default)]
+    pub reboot_required_packages: Vec<String>,
+    /// Set with `updates.resource_limits.accounting` where systemd runs
+    #[serde(default)]
+    pub resource_usage: Option<ResourceUsage>,
+    /// Set when a package command had to be terminated
+    #[serde(default)]
+    pub command_failure: Option<CommandFailure>,
+}
+
+/// Package counts and download sizes a dry run found pending, per source.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DryRunEstimate {
+    pub apt_packages: u64,
+    pub apt_download_bytes: u64,
+    pub snap_packages: u64,
+    pub snap_download_bytes: u64,
+    pub flatpak_refs: u64,
+    pub flatpak_download_bytes: u64,
+}
+
+impl UpdateResults {
+    /// The apt phase was refused by a pre-flight check, so nothing was
+    /// changed and there is nothing to roll back.
+    pub fn refused_before_changes(&self) -> bool {
+        !self.removals_refused.is_empty() || self.disk_space.iter().any(|check| !check.sufficient)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FlatpakKind {
+    App,
+    Runtime,
+}
+
+/// One operation of a `flatpak update` transaction.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FlatpakChange {
+    pub id: String,
+    pub kind: FlatpakKind,
+    pub branch: String,
+    pub remote: String,
+    /// "update", "install" (a new runtime dependency) or "uninstall"
+    pub operation: String,
+    /// Completed without error; false for dry runs
+    pub applied: bool,
+}
+
+/// A snap as `snap list` shows it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SnapStatus {
+    pub name: String,
+    pub version: String,
+    pub revision: String,
+    /// Channel the snap follows, e.g. "latest/stable"; `None` for snaps
+    /// installed from a file
+    pub tracking: Option<String>,
+    pub held: bool,
+    /// The revision changed during this run
+    #[serde(default)]
+    pub refreshed: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingUpdate {
+    pub source: String,
+    pub package: String,
+    pub current_version: Option<String>,
+    pub candidate_version: String,
+    pub origin: String,
+    pub security: bool,
+    #[serde(default)]
+    pub risk: RiskLevel,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HeldPackage {
+    pub source: String,
+    pub package: String,
 }
 
 pub struct UpdateManager {
     config: AgentConfig,
     dry_run: bool,
+    pockets: PocketMap,
+    kill_grace: Duration,
+    /// Idle time after which the watchdog terminates a package command
+    stall_after: Duration,
+    /// apt's download cap (KB/s) while on a metered connection
+    download_limit: Option<u32>,
+    /// Slice the package commands run in while the run is accounted
+    accounting_slice: Option<&'static str>,
+    /// Multiplier for package command timeouts on slow hardware
+    timeout_factor: u32,
 }
 
 impl UpdateManager {
-    pub fn new(config: AgentConfig) -> Result<Self> {
+    pub fn new(mut config: AgentConfig) -> Result<Self> {
+        let timeout_factor = crate::platform::adjust_for_platform(&mut config);
+        let distro = DistroInfo::detect();
+        debug!(
+            "Detected distribution {} (base codename {:?})",
+            distro.id,
+            distro.base_codename()
+        );
+
         Ok(Self {
             dry_run: config.updates.dry_run,
+            pockets: PocketMap::new(&distro, &config.updates.security_pockets),
+            kill_grace: KILL_GRACE_PERIOD,
+            stall_after: Duration::from_secs(config.updates.watchdog.stall_minutes * 60)
+                * timeout_factor,
+            download_limit: None,
+            accounting_slice: None,
+            timeout_factor,
             config,
         })
     }
 
     pub fn is_in_maintenance_window(&self) -> bool {
-        let (start, end) = match (
-            &self.config.updates.maintenance_window_start,
-            &self.config.updates.maintenance_window_end,
-        ) {
-            (Some(start_str), Some(end_str)) => {
-                let start_time = match NaiveTime::parse_from_str(start_str, "%H:%M") {
-                    Ok(time) => time,
-                    Err(e) => {
-                        warn!(
-                            "Failed to parse maintenance window start: {} - {}",
-                            start_str, e
-                        );
-                        return true; // Default to allowing updates
-                    }
-                };
-
-                let end_time = match NaiveTime::parse_from_str(end_str, "%H:%M") {
-                    Ok(time) => time,
-                    Err(e) => {
-                        warn!(
-                            "Failed to parse maintenance window end: {} - {}",
-                            end_str, e
-                        );
-                        return true; // Default to allowing updates
-                    }
-                };
+        in_maintenance_window(
+            &crate::window::maintenance_windows(&self.config.updates),
+            Local::now().naive_local(),
+        )
+    }
 
-                (start_time, end_time)
+    /// Which of the upgradable `packages` to hold back for this run:
+    /// graphics stack updates under `graphics.caution`, and updates whose
+    /// risk level isn't scheduled for today under `[risk]`. Packages the
+    /// admin already held are left out, since they are released again
+    /// after the upgrade.
+    async fn updates_to_defer(&self, packages: Vec<String>) -> (Vec<String>, Vec<String>) {
+        let graphics = &self.config.graphics;
+        let defer_graphics = graphics.caution && !graphics.allow_updates && {
+            let in_graphics_window =
+                in_window("graphics", &graphics.window_start, &graphics.window_end) == Some(true);
+            if in_graphics_window {
+                info!("In the graphics update window, installing graphics stack updates");
             }
-            _ => return true, // No maintenance window configured
+            !in_graphics_window
         };
 
-        let now = Local::now().time();
-
-        // Handle maintenance windows that cross midnight
-        if start <= end {
-            now >= start && now <= end
-        } else {
-            now >= start || now <= end
+        let scorer = RiskScorer::new(&self.config.risk);
+        let today = Local::now().weekday();
+        let mut graphics_deferred = Vec::new();
+        let mut risk_deferred = Vec::new();
+        for package in packages {
+            if defer_graphics && is_graphics_package(&package) {
+                graphics_deferred.push(package);
+            } else if !scorer.allowed_on(scorer.score("apt", &package), today) {
+                risk_deferred.push(package);
+            }
         }
+        if graphics_deferred.is_empty() && risk_deferred.is_empty() {
+            return (graphics_deferred, risk_deferred);
+        }
+
+        let held = self.list_held_packages().await.unwrap_or_else(|e| {
+            warn!("Failed to list held packages: {}", e);
+            Vec::new()
+        });
+        let not_held = |package: &String| {
+            !held
+                .iter()
+                .any(|h| h.source == "apt" && &h.package == package)
+        };
+        (
+            graphics_deferred.into_iter().filter(not_held).collect(),
+            risk_deferred.into_iter().filter(not_held).collect(),
+        )
     }
 
     pub async fn run_updates(&mut self) -> Result<UpdateResults> {
+        let accounting = self
+            .config
+            .updates
+            .resource_limits
+            .accounting
+            .then(RunAccounting::start)
+            .flatten();
+        self.accounting_slice = accounting.as_ref().map(|_| accounting::SLICE);
+        let results = self.apply_updates().await;
+        self.accounting_slice = None;
+
+        let resource_usage = accounting.and_then(RunAccounting::finish);
+        results.map(|results| UpdateResults {
+            resource_usage,
+            ..results
+        })
+    }
+
+    async fn apply_updates(&mut self) -> Result<UpdateResults> {
         info!("Starting system update process (dry_run: {})", self.dry_run);
         let start_time = std::time::Instant::now();
 
@@ -93,28 +368,64 @@ impl UpdateManager {
             reboot_required: false,
             error_message: None,
             apt_output: String::new(),
-            snap_output: None,
-            flatpak_output: None,
+            snaps: Vec::new(),
+            flatpaks: Vec::new(),
+            graphics_deferred: Vec::new(),
+            risk_deferred: Vec::new(),
+            debdelta: None,
+            removals_refused: Vec::new(),
+            disk_space: Vec::new(),
+            dry_run: None,
+            services_restarted: Vec::new(),
+            reboot_required_packages: Vec::new(),
+            resource_usage: None,
+            command_failure: None,
         };
+        let mut estimate = DryRunEstimate::default();
 
-        // Check if we're root (required for most operations)
-        if !self.is_running_as_root() && !self.dry_run {
-            return Err(anyhow::anyhow!(
-                "Must run as root to perform system updates"
-            ));
+        if !self.dry_run {
+            Privileges::current().require(Operation::SystemUpdates)?;
+        }
+        let updates = &self.config.updates;
+        if updates.respect_metered && !self.dry_run && crate::metered::connection_metered() {
+            self.download_limit = updates.metered_rate_limit_kbps;
+            if let Some(kbps) = self.download_limit {
+                info!(
+                    "Connection is metered, limiting apt downloads to {} KB/s",
+                    kbps
+                );
+            }
         }
 
         // Run apt updates
         if self.config.updates.update_sources.apt {
-            match self.run_apt_updates().await {
+            crate::panics::set_phase("apt");
+            let apt_results = match self.config.updates.package_backend {
+                PackageBackend::Apt | PackageBackend::Libapt => self.run_apt_updates().await,
+                PackageBackend::Packagekit => self.run_packagekit_updates().await,
+            };
+            match apt_results {
                 Ok(apt_results) => {
                     results.apt_output = apt_results.output;
                     results.packages_updated += apt_results.packages_updated;
                     results.packages_available += apt_results.packages_available;
                     results.bytes_downloaded += apt_results.bytes_downloaded;
+                    results.graphics_deferred = apt_results.graphics_deferred;
+                    results.risk_deferred = apt_results.risk_deferred;
+                    results.debdelta = apt_results.debdelta;
+                    results.disk_space = apt_results.disk_space;
+                    estimate.apt_packages = apt_results.would_update.0;
+                    estimate.apt_download_bytes = apt_results.would_update.1;
                 }
                 Err(e) => {
                     error!("APT updates failed: {}", e);
+                    if let Some(refused) = e.downcast_ref::<RemovalsRefused>() {
+                        results.removals_refused = refused.packages.clone();
+                    }
+                    if let Some(insufficient) = e.downcast_ref::<InsufficientDiskSpace>() {
+                        results.disk_space = insufficient.checks.clone();
+                    }
+                    results.command_failure = CommandFailure::of(&e);
                     results.error_message = Some(format!("APT: {}", e));
                     results.duration_seconds = start_time.elapsed().as_secs_f64();
                     return Ok(results);
@@ -123,33 +434,94 @@ impl UpdateManager {
         }
 
         // Run snap updates
-        if self.config.updates.update_sources.snap {
+        if self.download_limit.is_some() {
+            info!("Skipping snap and flatpak updates, they can't be rate limited on a metered connection");
+        } else if self.config.updates.update_sources.snap {
+            crate::panics::set_phase("snap");
             match self.run_snap_updates().await {
-                Ok(snap_output) => {
-                    results.snap_output = Some(snap_output);
+                Ok(snaps) => {
+                    results.snaps = snaps;
                 }
                 Err(e) => {
                     warn!("Snap updates failed: {}", e);
-                    // Don't fail the entire update for snap failures
+                    // Don't fail the entire update for snap failures, but
+                    // keep a record when a hung snapd had to be killed
+                    if let Some(failure) = CommandFailure::of(&e) {
+                        results.command_failure = Some(failure);
+                        results.error_message = Some(format!("Snap: {}", e));
+                    }
                 }
             }
         }
 
         // Run flatpak updates
-        if self.config.updates.update_sources.flatpak {
+        if self.config.updates.update_sources.flatpak && self.download_limit.is_none() {
+            crate::panics::set_phase("flatpak");
             match self.run_flatpak_updates().await {
-                Ok(flatpak_output) => {
-                    results.flatpak_output = Some(flatpak_output);
+                Ok(flatpaks) => {
+                    results.flatpaks = flatpaks;
                 }
                 Err(e) => {
                     warn!("Flatpak updates failed: {}", e);
                     // Don't fail the entire update for flatpak failures
+                    if let Some(failure) = CommandFailure::of(&e) {
+                        results.command_failure = Some(failure);
+                        results.error_message = Some(format!("Flatpak: {}", e));
+                    }
                 }
             }
         }
 
+        if self.dry_run {
+            if self.config.updates.update_sources.snap {
+                match self.snap_download_estimate().await {
+                    Ok((packages, bytes)) => {
+                        estimate.snap_packages = packages;
+                        estimate.snap_download_bytes = bytes;
+                    }
+                    Err(e) => warn!("Failed to estimate snap refreshes: {}", e),
+                }
+            }
+            if self.config.updates.update_sources.flatpak {
+                match self.flatpak_download_estimate().await {
+                    Ok((refs, bytes)) => {
+                        estimate.flatpak_refs = refs;
+                        estimate.flatpak_download_bytes = bytes;
+                    }
+                    Err(e) => warn!("Failed to estimate flatpak updates: {}", e),
+                }
+            }
+            info!(
+                "Dry run would update {} apt packages ({} bytes), {} snaps ({} bytes) and {} flatpaks ({} bytes)",
+                estimate.apt_packages,
+                estimate.apt_download_bytes,
+                estimate.snap_packages,
+                estimate.snap_download_bytes,
+                estimate.flatpak_refs,
+                estimate.flatpak_download_bytes
+            );
+            results.dry_run = Some(estimate);
+        }
+
+        // Restarting what still runs on replaced libraries can make the
+        // reboot unnecessary
+        let mut reboot_avoided = false;
+        if !self.dry_run && self.config.needrestart.enabled && results.packages_updated > 0 {
+            crate::panics::set_phase("needrestart");
+            if let Some(restarts) = crate::needrestart::restart_outdated(&self.config.needrestart) {
+                reboot_avoided = !restarts.reboot_needed;
+                results.services_restarted = restarts.transitions;
+            }
+        }
+
         // Check if reboot is required
-        results.reboot_required = self.check_reboot_required()?;
+        results.reboot_required = self.check_reboot_required()? && !reboot_avoided;
+        if reboot_avoided && Path::new("/var/run/reboot-required").exists() {
+            info!("Outdated services were restarted, no reboot needed");
+        }
+        if results.reboot_required {
+            results.reboot_required_packages = reboot_required_packages();
+        }
 
         results.success = true;
         results.duration_seconds = start_time.elapsed().as_secs_f64();
@@ -165,14 +537,193 @@ impl UpdateManager {
         Ok(results)
     }
 
+    /// Lists pending updates without changing anything. `refresh_cache`
+    /// runs `apt-get update` first, which needs the apt lock.
+    pub async fn list_pending_updates(&self, refresh_cache: bool) -> Result<Vec<PendingUpdate>> {
+        let mut pending = Vec::new();
+        let sources = &self.config.updates.update_sources;
+
+        if sources.apt && refresh_cache {
+            if let Err(e) = Privileges::current().require(Operation::SystemUpdates) {
+                warn!("{:#}, listing from existing apt cache", e);
+            } else {
+                let update_output = self
+                    .run_command_with_timeout("apt-get", &["update"], Duration::from_secs(300))
+                    .await?;
+                if !update_output.status.success() {
+                    warn!(
+                        "apt-get update failed, listing from existing cache: {}",
+                        String::from_utf8_lossy(&update_output.stderr)
+                    );
+                }
+            }
+        }
+
+        if sources.apt && self.uses_libapt() {
+            pending.extend(self.libapt_upgradable().await?);
+        } else if sources.apt {
+            let list_output = self
+                .run_command_with_timeout("apt", &["list", "--upgradable"], Duration::from_secs(60))
+                .await?;
+            pending.extend(parse_apt_upgradable(
+                &String::from_utf8_lossy(&list_output.stdout),
+                &self.pockets,
+            ));
+        }
+
+        if sources.snap {
+            if let Some(snapd) = SnapdClient::connect()? {
+                let installed = snapd.snaps().await?;
+                pending.extend(snap_pending_updates(
+                    snapd.refresh_candidates().await?,
+                    &installed,
+                ));
+            }
+        }
+
+        if sources.flatpak && Path::new("/usr/bin/flatpak").exists() {
+            let output = self
+                .run_command_with_timeout(
+                    "flatpak",
+                    &[
+                        "remote-ls",
+                        "--updates",
+                        "--columns=application,version,branch,origin",
+                    ],
+                    Duration::from_secs(120),
+                )
+                .await?;
+            pending.extend(
+                parse_flatpak_updates(&String::from_utf8_lossy(&output.stdout))
+                    .into_iter()
+                    .filter(|update| self.flatpak_remote_enabled(&update.origin)),
+            );
+        }
+
+        let scorer = RiskScorer::new(&self.config.risk);
+        for update in &mut pending {
+            update.risk = scorer.score(&update.source, &update.package);
+        }
+        Ok(pending)
+    }
+
+    /// Holds (or releases) a package so upgrades skip it. Snap holds are
+    /// persisted by snapd itself.
+    pub async fn set_package_held(&self, package: &str, snap: bool, held: bool) -> Result<()> {
+        let output = if snap {
+            let flag = if held { "--hold" } else { "--unhold" };
+            self.run_command_with_timeout(
+                "snap",
+                &["refresh", flag, package],
+                Duration::from_secs(60),
+            )
+            .await?
+        } else {
+            let action = if held { "hold" } else { "unhold" };
+            self.run_command_with_timeout("apt-mark", &[action, package], Duration::from_secs(60))
+                .await?
+        };
+
+        if !output.status.success() {
+            return Err(anyhow::anyhow!(
+                "Failed to {} {}: {}",
+                if held { "hold" } else { "unhold" },
+                package,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+
+        info!("{} {}", if held { "Held" } else { "Released" }, package);
+        Ok(())
+    }
+
+    pub async fn list_held_packages(&self) -> Result<Vec<HeldPackage>> {
+        let mut held = Vec::new();
+
+        let output = self
+            .run_command_with_timeout("apt-mark", &["showhold"], Duration::from_secs(60))
+            .await?;
+        held.extend(
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(|package| HeldPackage {
+                    source: "apt".to_string(),
+                    package: package.to_string(),
+                }),
+        );
+
+        if let Some(snapd) = SnapdClient::connect()? {
+            held.extend(
+                snapd
+                    .snaps()
+                    .await?
+                    .into_iter()
+                    .filter(|snap| snap.hold.is_some())
+                    .map(|snap| HeldPackage {
+                        source: "snap".to_string(),
+                        package: snap.name,
+                    }),
+            );
+        }
+
+        Ok(held)
+    }
+
+    /// `-o` value pointing apt at `updates.apt_cache_dir`. The directory
+    /// itself must exist, so an unmounted network share falls back to apt's
+    /// default cache instead of filling the root filesystem.
+    fn apt_cache_option(&self) -> Option<String> {
+        let dir = self.config.updates.apt_cache_dir.as_ref()?;
+        if !dir.is_dir() {
+            warn!(
+                "apt cache directory {:?} is missing, using apt's default cache",
+                dir
+            );
+            return None;
+        }
+        if let Err(e) = std::fs::create_dir_all(dir.join("partial")) {
+            warn!(
+                "Failed to prepare apt cache directory {:?}, using apt's default cache: {}",
+                dir, e
+            );
+            return None;
+        }
+        Some(format!("Dir::Cache::Archives={}/", dir.display()))
+    }
+
+    /// `-o` options capping apt's download rate while metered.
+    fn download_limit_options(&self) -> Vec<String> {
+        let Some(kbps) = self.download_limit else {
+            return Vec::new();
+        };
+        ["http", "https"]
+            .into_iter()
+            .flat_map(|scheme| {
+                [
+                    "-o".to_string(),
+                    format!("Acquire::{}::Dl-Limit={}", scheme, kbps),
+                ]
+            })
+            .collect()
+    }
+
+    #[tracing::instrument(name = "apt_updates", skip_all)]
     async fn run_apt_updates(&self) -> Result<AptResults> {
         info!("Running APT updates");
 
         // First, update package lists
+        let limit_options = self.download_limit_options();
+        let update_args: Vec<&str> = limit_options
+            .iter()
+            .map(String::as_str)
+            .chain(["update"])
+            .collect();
         let update_output = self
             .run_command_with_timeout(
                 "apt-get",
-                &["update"],
+                &update_args,
                 Duration::from_secs(300), // 5 minutes
             )
             .await?;
@@ -185,14 +736,23 @@ impl UpdateManager {
         }
 
         // Get list of available updates
-        let list_output = self
-            .run_command_with_timeout("apt", &["list", "--upgradable"], Duration::from_secs(60))
-            .await?;
-
-        let packages_available = if list_output.status.success() {
-            self.parse_apt_upgradable_count(&String::from_utf8_lossy(&list_output.stdout))?
+        let (packages_available, upgradable) = if self.uses_libapt() {
+            let upgradable = self.libapt_upgradable().await?;
+            (upgradable.len() as u64, upgradable)
         } else {
-            0
+            let list_output = self
+                .run_command_with_timeout("apt", &["list", "--upgradable"], Duration::from_secs(60))
+                .await?;
+            let list = String::from_utf8_lossy(&list_output.stdout);
+            let packages_available = if list_output.status.success() {
+                self.parse_apt_upgradable_count(&list)?
+            } else {
+                0
+            };
+            (
+                packages_available,
+                parse_apt_upgradable(&list, &self.pockets),
+            )
         };
 
         let mut apt_output = format!(
@@ -200,15 +760,63 @@ impl UpdateManager {
             String::from_utf8_lossy(&update_output.stdout)
         );
 
+        let upgradable = upgradable
+            .into_iter()
+            .map(|update| update.package)
+            .collect();
+        let (graphics_deferred, risk_deferred) = self.updates_to_defer(upgradable).await;
+        log_deferred(&graphics_deferred, &risk_deferred);
+
+        let upgrade_command = self.config.updates.upgrade_mode.apt_command();
+        let mut delta_savings = None;
+        let mut disk_space = Vec::new();
+        let mut would_update = (0, 0);
         let (packages_updated, bytes_downloaded) = if self.dry_run {
             // Dry run - just show what would be updated
             let dry_run_output = self
                 .run_command_with_timeout(
                     "apt-get",
-                    &["--dry-run", "upgrade"],
+                    &["--dry-run", upgrade_command],
                     Duration::from_secs(300),
                 )
                 .await?;
+            let refused = self.refused_removals(&String::from_utf8_lossy(&dry_run_output.stdout));
+            if !refused.is_empty() {
+                warn!(
+                    "apt-get {} would remove packages and will be refused: {}",
+                    upgrade_command,
+                    refused.join(", ")
+                );
+            }
+
+            match self
+                .check_disk_space(&[], Path::new(DEFAULT_ARCHIVES))
+                .await
+            {
+                Ok(checks) => disk_space = checks,
+                Err(e) => warn!("Upgrade would be refused: {:#}", e),
+            }
+
+            // The simulation doesn't print sizes, --print-uris and the
+            // apt cache do
+            let would_download = if self.uses_libapt() {
+                self.libapt_download_size().await
+            } else {
+                self.apt_print_uris(&[])
+                    .await
+                    .and_then(|summary| self.parse_apt_bytes_downloaded(&summary))
+            };
+            let would_download = match would_download {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    warn!("Failed to estimate the apt download size: {:#}", e);
+                    0
+                }
+            };
+            would_update = (
+                self.parse_apt_packages_updated(&String::from_utf8_lossy(&dry_run_output.stdout))?,
+                would_download,
+            );
 
             apt_output.push_str(&format!(
                 "\n=== Dry Run Upgrade Output ===\n{}",
@@ -217,20 +825,80 @@ impl UpdateManager {
 
             (0, 0) // No actual updates in dry run
         } else {
-            // Apply excluded packages filter
-            let mut upgrade_args = vec!["upgrade", "-y"];
-            for excluded in &self.config.updates.excluded_packages {
-                upgrade_args.extend_from_slice(&["--hold", excluded]);
+            // Hold excluded packages so apt-get upgrade skips them
+            if !self.config.updates.excluded_packages.is_empty() {
+                let mut hold_args = vec!["hold"];
+                hold_args.extend(
+                    self.config
+                        .updates
+                        .excluded_packages
+                        .iter()
+                        .map(String::as_str),
+                );
+                let hold_output = self
+                    .run_command_with_timeout("apt-mark", &hold_args, Duration::from_secs(60))
+                    .await?;
+                if !hold_output.status.success() {
+                    warn!(
+                        "Failed to hold excluded packages: {}",
+                        String::from_utf8_lossy(&hold_output.stderr)
+                    );
+                }
+            }
+            // Graphics and risk holds only last for this run
+            let deferred: Vec<&str> = graphics_deferred
+                .iter()
+                .chain(&risk_deferred)
+                .map(String::as_str)
+                .collect();
+            if !deferred.is_empty() {
+                let hold_args: Vec<&str> = [&["hold"][..], &deferred].concat();
+                self.run_command_with_timeout("apt-mark", &hold_args, Duration::from_secs(60))
+                    .await?;
+            }
+            let cache_option = self.apt_cache_option();
+            let mut apt_options = Vec::new();
+            if let Some(option) = &cache_option {
+                apt_options.extend(["-o", option.as_str()]);
+            }
+            apt_options.extend(limit_options.iter().map(String::as_str));
+            let upgrade_args = [&apt_options[..], &[upgrade_command, "-y"]].concat();
+
+            let archives = match (&cache_option, &self.config.updates.apt_cache_dir) {
+                (Some(_), Some(dir)) => dir.as_path(),
+                _ => Path::new(DEFAULT_ARCHIVES),
+            };
+            let checked = match self.check_removals(&apt_options).await {
+                Ok(()) => self.check_disk_space(&apt_options, archives).await,
+                Err(e) => Err(e),
+            };
+            if checked.is_ok() && self.config.updates.debdelta {
+                delta_savings = self.run_debdelta(archives).await;
             }
 
             // Run the actual upgrade
-            let upgrade_output = self
-                .run_command_with_timeout(
-                    "apt-get",
-                    &upgrade_args,
-                    Duration::from_secs(1800), // 30 minutes
-                )
-                .await?;
+            let upgrade_output = match checked {
+                Ok(checks) => {
+                    disk_space = checks;
+                    self.run_command_with_timeout(
+                        "apt-get",
+                        &upgrade_args,
+                        Duration::from_secs(1800), // 30 minutes
+                    )
+                    .await
+                }
+                Err(e) => Err(e),
+            };
+            if !deferred.is_empty() {
+                let unhold_args: Vec<&str> = [&["unhold"][..], &deferred].concat();
+                let unhold = self
+                    .run_command_with_timeout("apt-mark", &unhold_args, Duration::from_secs(60))
+                    .await;
+                if !unhold.as_ref().is_ok_and(|output| output.status.success()) {
+                    warn!("Failed to release deferred holds: {}", deferred.join(", "));
+                }
+            }
+            let upgrade_output = upgrade_output?;
 
             apt_output.push_str(&format!(
                 "\n=== Upgrade Output ===\n{}",
@@ -239,15 +907,21 @@ impl UpdateManager {
 
             if !upgrade_output.status.success() {
                 return Err(anyhow::anyhow!(
-                    "apt-get upgrade failed: {}",
+                    "apt-get {} failed: {}",
+                    upgrade_command,
                     String::from_utf8_lossy(&upgrade_output.stderr)
                 ));
             }
 
             let packages_updated =
                 self.parse_apt_packages_updated(&String::from_utf8_lossy(&upgrade_output.stdout))?;
-            let bytes_downloaded =
-                self.parse_apt_bytes_downloaded(&String::from_utf8_lossy(&upgrade_output.stdout))?;
+            // apt only counts what it still had to fetch after debdelta
+            let bytes_downloaded = self
+                .parse_apt_bytes_downloaded(&String::from_utf8_lossy(&upgrade_output.stdout))?
+                + delta_savings
+                    .as_ref()
+                    .and_then(|savings| savings.delta_bytes)
+                    .unwrap_or(0);
 
             // Clean up
             let _ = self
@@ -258,9 +932,13 @@ impl UpdateManager {
                 )
                 .await;
 
-            let _ = self
-                .run_command_with_timeout("apt-get", &["autoclean"], Duration::from_secs(60))
-                .await;
+            // autoclean drops .debs this host can no longer download, which
+            // in a shared cache may be ones other hosts still need
+            if cache_option.is_none() {
+                let _ = self
+                    .run_command_with_timeout("apt-get", &["autoclean"], Duration::from_secs(60))
+                    .await;
+            }
 
             (packages_updated, bytes_downloaded)
         };
@@ -270,111 +948,432 @@ impl UpdateManager {
             packages_updated,
             packages_available,
             bytes_downloaded,
+            graphics_deferred,
+            risk_deferred,
+            debdelta: delta_savings,
+            disk_space,
+            would_update,
         })
     }
 
-    async fn run_snap_updates(&self) -> Result<String> {
-        info!("Running snap updates");
-
-        if !Path::new("/usr/bin/snap").exists() {
-            return Ok("Snap not installed".to_string());
+    /// Rebuilds upgradable packages from deltas into the archive cache with
+    /// `debdelta-upgrade`, so the apt upgrade finds them there instead of
+    /// downloading them in full. A failure only costs the savings.
+    async fn run_debdelta(&self, archives: &Path) -> Option<DeltaSavings> {
+        if !crate::rollback::command_exists("debdelta-upgrade") {
+            warn!("updates.debdelta is set but debdelta is not installed");
+            return None;
         }
 
-        let output = if self.dry_run {
-            self.run_command_with_timeout("snap", &["refresh", "--list"], Duration::from_secs(60))
-                .await?
-        } else {
-            self.run_command_with_timeout(
-                "snap",
-                &["refresh"],
-                Duration::from_secs(900), // 15 minutes
+        let before = debdelta::cached_debs(archives);
+        let dir = archives.to_string_lossy();
+        let mut args = Vec::new();
+        if archives != Path::new(DEFAULT_ARCHIVES) {
+            args.extend(["--dir", dir.as_ref()]);
+        }
+        match self
+            .run_command_with_timeout(
+                "debdelta-upgrade",
+                &args,
+                Duration::from_secs(1800), // 30 minutes
             )
-            .await?
+            .await
+        {
+            Ok(output) if output.status.success() => {
+                let statistics = format!(
+                    "{}\n{}",
+                    String::from_utf8_lossy(&output.stdout),
+                    String::from_utf8_lossy(&output.stderr)
+                );
+                let savings =
+                    debdelta::savings(&before, &debdelta::cached_debs(archives), &statistics);
+                info!(
+                    "debdelta rebuilt {} packages ({} bytes), saving {:?} bytes",
+                    savings.debs, savings.deb_bytes, savings.bytes_saved
+                );
+                Some(savings)
+            }
+            Ok(output) => {
+                warn!(
+                    "debdelta-upgrade failed, downloading full packages: {}",
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+                None
+            }
+            Err(e) => {
+                warn!(
+                    "debdelta-upgrade failed, downloading full packages: {:#}",
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    /// Applies `updates.snap.packages` channel pins and holds, refreshes
+    /// everything not held, and returns each snap's state afterwards.
+    #[tracing::instrument(name = "snap_updates", skip_all)]
+    async fn run_snap_updates(&self) -> Result<Vec<SnapStatus>> {
+        info!("Running snap updates");
+
+        let Some(snapd) = SnapdClient::connect()? else {
+            debug!("snapd not running");
+            return Ok(Vec::new());
         };
 
-        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+        let before: Vec<SnapStatus> = snapd.snaps().await?.iter().map(snap_status).collect();
+        if self.dry_run {
+            return Ok(before);
+        }
+
+        for (name, pin) in &self.config.updates.snap.packages {
+            let Some(current) = before.iter().find(|snap| &snap.name == name) else {
+                warn!("Snap {} in updates.snap.packages is not installed", name);
+                continue;
+            };
+            if let Some(channel) = pin
+                .channel
+                .as_deref()
+                .filter(|channel| current.tracking.as_deref() != Some(*channel))
+            {
+                info!("Switching snap {} to channel {}", name, channel);
+                if let Err(e) = snapd
+                    .switch_channel(name, channel, SNAP_REFRESH_TIMEOUT * self.timeout_factor)
+                    .await
+                {
+                    if e.is::<CommandTimedOut>() {
+                        return Err(e);
+                    }
+                    warn!("Failed to switch snap {} to {}: {:#}", name, channel, e);
+                }
+            }
+            if pin.hold && !current.held {
+                if let Err(e) = self.set_package_held(name, true, true).await {
+                    warn!("{:#}", e);
+                }
+            }
+        }
+
+        if let Err(e) = snapd
+            .refresh_all(SNAP_REFRESH_TIMEOUT * self.timeout_factor)
+            .await
+        {
+            if e.is::<CommandTimedOut>() {
+                return Err(e);
+            }
+            warn!("snap refresh failed: {:#}", e);
+        }
+
+        let mut after: Vec<SnapStatus> = snapd.snaps().await?.iter().map(snap_status).collect();
+        for snap in &mut after {
+            snap.refreshed = before
+                .iter()
+                .find(|old| old.name == snap.name)
+                .is_some_and(|old| old.revision != snap.revision);
+        }
+        Ok(after)
     }
 
-    async fn run_flatpak_updates(&self) -> Result<String> {
+    /// Adds missing `updates.flatpak.remotes`, updates refs from remotes
+    /// with updates enabled, and returns the transaction's operations.
+    #[tracing::instrument(name = "flatpak_updates", skip_all)]
+    async fn run_flatpak_updates(&self) -> Result<Vec<FlatpakChange>> {
         info!("Running flatpak updates");
 
         if !Path::new("/usr/bin/flatpak").exists() {
-            return Ok("Flatpak not installed".to_string());
+            debug!("Flatpak not installed");
+            return Ok(Vec::new());
         }
 
-        let output = if self.dry_run {
-            self.run_command_with_timeout(
+        if self.dry_run {
+            let output = self
+                .run_command_with_timeout(
+                    "flatpak",
+                    &["remote-ls", "--updates", "--columns=ref,origin"],
+                    Duration::from_secs(120),
+                )
+                .await?;
+            return Ok(parse_flatpak_refs(&String::from_utf8_lossy(&output.stdout))
+                .into_iter()
+                .filter(|(_, origin)| self.flatpak_remote_enabled(origin))
+                .filter_map(|(flatpak_ref, origin)| {
+                    flatpak_change(&flatpak_ref, &origin, "update", false)
+                })
+                .collect());
+        }
+
+        self.ensure_flatpak_remotes().await;
+
+        let installed = self
+            .run_command_with_timeout(
                 "flatpak",
-                &["update", "--show-details"],
+                &["list", "--columns=ref,origin"],
                 Duration::from_secs(60),
             )
-            .await?
-        } else {
-            self.run_command_with_timeout(
+            .await?;
+        let installed = parse_flatpak_refs(&String::from_utf8_lossy(&installed.stdout));
+
+        let mut args = vec!["update", "-y", "--noninteractive"];
+        if installed
+            .iter()
+            .any(|(_, origin)| !self.flatpak_remote_enabled(origin))
+        {
+            let refs: Vec<&str> = installed
+                .iter()
+                .filter(|(_, origin)| self.flatpak_remote_enabled(origin))
+                .map(|(flatpak_ref, _)| flatpak_ref.as_str())
+                .collect();
+            if refs.is_empty() {
+                info!("Updates are disabled for every flatpak remote in use");
+                return Ok(Vec::new());
+            }
+            args.extend(refs);
+        }
+
+        let output = self
+            .run_command_with_timeout(
                 "flatpak",
-                &["update", "-y"],
+                &args,
                 Duration::from_secs(900), // 15 minutes
             )
-            .await?
+            .await?;
+        let transcript = format!(
+            "{}\n{}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        );
+        Ok(parse_flatpak_transaction(&transcript, &installed))
+    }
+
+    /// Snaps the store has refreshes for, minus those pinned with `hold`,
+    /// and their total download size.
+    async fn snap_download_estimate(&self) -> Result<(u64, u64)> {
+        let Some(snapd) = SnapdClient::connect()? else {
+            return Ok((0, 0));
         };
+        let pending: Vec<u64> = snapd
+            .refresh_candidates()
+            .await?
+            .into_iter()
+            .filter(|candidate| {
+                !self
+                    .config
+                    .updates
+                    .snap
+                    .packages
+                    .get(&candidate.name)
+                    .is_some_and(|pin| pin.hold)
+            })
+            .map(|candidate| candidate.download_size)
+            .collect();
+        Ok((pending.len() as u64, pending.iter().sum()))
+    }
 
-        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    /// Flatpak refs with updates on enabled remotes and their total
+    /// download size.
+    async fn flatpak_download_estimate(&self) -> Result<(u64, u64)> {
+        if !Path::new("/usr/bin/flatpak").exists() {
+            return Ok((0, 0));
+        }
+        let output = self
+            .run_command_with_timeout(
+                "flatpak",
+                &[
+                    "remote-ls",
+                    "--updates",
+                    "--columns=ref,origin,download-size",
+                ],
+                Duration::from_secs(120),
+            )
+            .await?;
+        let pending: Vec<u64> = parse_flatpak_refs(&String::from_utf8_lossy(&output.stdout))
+            .into_iter()
+            .filter_map(|(_, columns)| {
+                let (origin, size) = columns.split_once('\t').unwrap_or((&columns, ""));
+                self.flatpak_remote_enabled(origin.trim())
+                    .then(|| parse_human_size(size).unwrap_or(0))
+            })
+            .collect();
+        Ok((pending.len() as u64, pending.iter().sum()))
+    }
+
+    fn flatpak_remote_enabled(&self, name: &str) -> bool {
+        self.config
+            .updates
+            .flatpak
+            .remotes
+            .iter()
+            .find(|remote| remote.name == name)
+            .is_none_or(|remote| remote.update)
     }
 
+    async fn ensure_flatpak_remotes(&self) {
+        for remote in &self.config.updates.flatpak.remotes {
+            let Some(url) = &remote.url else {
+                continue;
+            };
+            let result = self
+                .run_command_with_timeout(
+                    "flatpak",
+                    &["remote-add", "--if-not-exists", &remote.name, url],
+                    Duration::from_secs(120),
+                )
+                .await;
+            match result {
+                Ok(output) if output.status.success() => {}
+                Ok(output) => warn!(
+                    "Failed to add flatpak remote {}: {}",
+                    remote.name,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ),
+                Err(e) => warn!("Failed to add flatpak remote {}: {:#}", remote.name, e),
+            }
+        }
+    }
+
+    #[tracing::instrument(
+        name = "package_command",
+        skip(self, timeout_duration),
+        fields(exit_code = tracing::field::Empty)
+    )]
     async fn run_command_with_timeout(
         &self,
         command: &str,
         args: &[&str],
         timeout_duration: Duration,
     ) -> Result<Output> {
-        debug!("Running command: {} {}", command, args.join(" "));
-
-        let child = Command::new(command)
-            .args(args)
+        let argv = apply_resource_limits(
+            &self.config.updates.resource_limits,
+            self.accounting_slice,
+            command,
+            args,
+        );
+        debug!("Running command: {}", argv.join(" "));
+        let timeout_duration = timeout_duration * self.timeout_factor;
+        let mut audit = CommandAudit::start(&argv);
+
+        // Own process group so a timeout can also reach dpkg, maintainer
+        // scripts and other grandchildren
+        let child = tokio::process::Command::new(&argv[0])
+            .args(&argv[1..])
+            .env(AGENT_RUN_ENV, "1")
             .stdin(Stdio::null())
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
-            .spawn()
-            .with_context(|| format!("Failed to spawn command: {}", command))?;
+            .process_group(0)
+            .kill_on_drop(true)
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(e) => {
+                audit.finish(None, Some(e.to_string()));
+                return Err(e).with_context(|| format!("Failed to spawn command: {}", command));
+            }
+        };
 
-        let output = timeout(timeout_duration, async {
-            tokio::task::spawn_blocking(move || child.wait_with_output())
-                .await
-                .unwrap()
-        })
-        .await
-        .with_context(|| {
-            format!(
-                "Command timed out after {:?}: {}",
-                timeout_duration, command
-            )
-        })?
-        .with_context(|| format!("Command failed: {}", command))?;
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let stderr = child.stderr.take().expect("stderr is piped");
+        let activity = Activity::new(
+            self.config
+                .updates
+                .apt_cache_dir
+                .as_deref()
+                .unwrap_or(Path::new(DEFAULT_ARCHIVES)),
+        );
+
+        let run = timeout(timeout_duration, async {
+            let (stdout, stderr) = tokio::try_join!(
+                stream_lines(command, "stdout", stdout, &activity),
+                stream_lines(command, "stderr", stderr, &activity)
+            )?;
+            let status = child.wait().await?;
+            Ok::<_, std::io::Error>(Output {
+                status,
+                stdout,
+                stderr,
+            })
+        });
+        let result = tokio::select! {
+            result = run => result.map_err(|_| None),
+            idle = activity.stalled(self.stall_after) => Err(Some(idle)),
+        };
 
+        let output = match result {
+            Ok(Ok(output)) => output,
+            Err(Some(idle)) => {
+                audit.finish(None, Some(format!("stalled for {:?}", idle)));
+                warn!(
+                    "Command made no progress for {:?}, terminating: {}",
+                    idle, command
+                );
+                let diagnostics = child
+                    .id()
+                    .map(crate::watchdog::diagnostics)
+                    .unwrap_or_default();
+                for line in &diagnostics {
+                    warn!("Stalled {}: {}", command, line);
+                }
+                let termination = terminate_process_group(&mut child, self.kill_grace).await;
+                return Err(CommandStalled {
+                    command: command.to_string(),
+                    idle,
+                    termination,
+                    diagnostics,
+                }
+                .into());
+            }
+            Ok(Err(e)) => {
+                audit.finish(None, Some(e.to_string()));
+                return Err(e).with_context(|| format!("Command failed: {}", command));
+            }
+            Err(None) => {
+                audit.finish(
+                    None,
+                    Some(format!("timed out after {:?}", timeout_duration)),
+                );
+                warn!(
+                    "Command timed out after {:?}, terminating: {}",
+                    timeout_duration, command
+                );
+                let termination = terminate_process_group(&mut child, self.kill_grace).await;
+                return Err(CommandTimedOut {
+                    command: command.to_string(),
+                    timeout: timeout_duration,
+                    termination,
+                }
+                .into());
+            }
+        };
+
+        audit.output(&output.stdout);
+        audit.output(&output.stderr);
+        audit.finish(Some(output.status), None);
         debug!(
             "Command completed with exit code: {:?}",
             output.status.code()
         );
+        if let Some(code) = output.status.code() {
+            tracing::Span::current().record("exit_code", code);
+        }
         Ok(output)
     }
 
-    fn check_reboot_required(&self) -> Result<bool> {
+    pub fn check_reboot_required(&self) -> Result<bool> {
         // Check /var/run/reboot-required file
         if Path::new("/var/run/reboot-required").exists() {
             return Ok(true);
         }
 
         // Check if kernel has been updated
-        let output = Command::new("uname")
-            .arg("-r")
-            .output()
+        let output = crate::audit::output(Command::new("uname").arg("-r"))
             .with_context(|| "Failed to get kernel version")?;
 
         if output.status.success() {
             let running_kernel = String::from_utf8_lossy(&output.stdout).trim().to_string();
 
             // Check if there's a newer kernel installed
-            let dpkg_output = Command::new("dpkg").args(["-l", "linux-image-*"]).output();
+            let dpkg_output =
+                crate::audit::output(Command::new("dpkg").args(["-l", "linux-image-*"]));
 
             if let Ok(dpkg_output) = dpkg_output {
                 if dpkg_output.status.success() {
@@ -390,6 +1389,35 @@ impl UpdateManager {
         Ok(false)
     }
 
+    fn uses_libapt(&self) -> bool {
+        self.config.updates.package_backend == PackageBackend::Libapt
+    }
+
+    /// Upgradable packages read from the apt cache through libapt-pkg
+    /// rather than parsed from `apt list --upgradable`.
+    async fn libapt_upgradable(&self) -> Result<Vec<PendingUpdate>> {
+        #[cfg(feature = "libapt")]
+        {
+            let pockets = self.pockets.clone();
+            tokio::task::spawn_blocking(move || crate::libapt::upgradable(&pockets)).await?
+        }
+        #[cfg(not(feature = "libapt"))]
+        Err(anyhow::anyhow!(
+            "This agent was built without the libapt feature"
+        ))
+    }
+
+    async fn libapt_download_size(&self) -> Result<u64> {
+        #[cfg(feature = "libapt")]
+        {
+            tokio::task::spawn_blocking(crate::libapt::download_size).await?
+        }
+        #[cfg(not(feature = "libapt"))]
+        Err(anyhow::anyhow!(
+            "This agent was built without the libapt feature"
+        ))
+    }
+
     fn parse_apt_upgradable_count(&self, output: &str) -> Result<u64> {
         let lines: Vec<&str> = output.lines().collect();
         // First line is usually "Listing..." so count actual package lines
@@ -402,6 +1430,224 @@ impl UpdateManager {
         Ok(count as u64)
     }
 
+    /// The apt phase through PackageKit, with `updates.package_backend =
+    /// "packagekit"`. packagekitd serializes its transactions with those of
+    /// desktop update tools, so the run waits its turn instead of failing on
+    /// the dpkg lock. UpdatePackages may install new dependencies whatever
+    /// `upgrade_mode` says; removals are checked as for a full-upgrade.
+    #[cfg(feature = "dbus")]
+    #[tracing::instrument(name = "packagekit_updates", skip_all)]
+    async fn run_packagekit_updates(&self) -> Result<AptResults> {
+        info!("Running updates through PackageKit");
+        let client = PackageKitClient::connect().await?;
+        client
+            .refresh_cache(Duration::from_secs(300) * self.timeout_factor)
+            .await?;
+        let available = client
+            .get_updates(Duration::from_secs(60) * self.timeout_factor)
+            .await?;
+
+        let (graphics_deferred, risk_deferred) = self
+            .updates_to_defer(available.iter().map(|p| p.name().to_string()).collect())
+            .await;
+        log_deferred(&graphics_deferred, &risk_deferred);
+        let excluded = &self.config.updates.excluded_packages;
+        let package_ids: Vec<String> = available
+            .iter()
+            .filter(|package| {
+                let name = package.name().to_string();
+                !excluded.contains(&name)
+                    && !graphics_deferred.contains(&name)
+                    && !risk_deferred.contains(&name)
+            })
+            .map(|package| package.id.clone())
+            .collect();
+
+        let mut results = AptResults {
+            output: format!(
+                "=== PackageKit Updates ===\n{}\n",
+                available
+                    .iter()
+                    .map(|package| package.id.as_str())
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            ),
+            packages_available: available.len() as u64,
+            graphics_deferred,
+            risk_deferred,
+            ..AptResults::default()
+        };
+        if package_ids.is_empty() {
+            return Ok(results);
+        }
+
+        let simulated = client
+            .update_packages(
+                &package_ids,
+                true,
+                Duration::from_secs(300) * self.timeout_factor,
+            )
+            .await?;
+        let refused = self.refused_of(
+            simulated
+                .packages
+                .iter()
+                .filter(|package| package.info == PackageInfo::Removing)
+                .map(|package| package.name().to_string())
+                .collect(),
+        );
+        if self.dry_run {
+            if !refused.is_empty() {
+                warn!(
+                    "PackageKit would remove packages and will be refused: {}",
+                    refused.join(", ")
+                );
+            }
+            results.would_update = (package_ids.len() as u64, 0);
+            return Ok(results);
+        }
+        if !refused.is_empty() {
+            return Err(RemovalsRefused {
+                command: "PackageKit UpdatePackages".to_string(),
+                packages: refused,
+            }
+            .into());
+        }
+
+        let log = client
+            .update_packages(
+                &package_ids,
+                false,
+                Duration::from_secs(1800) * self.timeout_factor,
+            )
+            .await?;
+        let updated: Vec<&str> = log
+            .packages
+            .iter()
+            .filter(|package| package.info == PackageInfo::Updating)
+            .map(|package| package.id.as_str())
+            .collect();
+        results.packages_updated = updated.len() as u64;
+        results
+            .output
+            .push_str(&format!("\n=== Updated ===\n{}\n", updated.join("\n")));
+        Ok(results)
+    }
+
+    #[cfg(not(feature = "dbus"))]
+    async fn run_packagekit_updates(&self) -> Result<AptResults> {
+        Err(anyhow::anyhow!(
+            "This agent was built without the dbus feature"
+        ))
+    }
+
+    /// Simulates the upgrade and refuses it with [`RemovalsRefused`] when
+    /// apt would remove a package that `updates.allowed_removals` doesn't
+    /// cover, or a protected one.
+    async fn check_removals(&self, apt_options: &[&str]) -> Result<()> {
+        let command = self.config.updates.upgrade_mode.apt_command();
+        let simulate_args = [apt_options, &["--simulate", command]].concat();
+        let simulated = self
+            .run_command_with_timeout("apt-get", &simulate_args, Duration::from_secs(300))
+            .await?;
+        if !simulated.status.success() {
+            return Err(anyhow::anyhow!(
+                "apt-get --simulate {} failed: {}",
+                command,
+                String::from_utf8_lossy(&simulated.stderr)
+            ));
+        }
+
+        let output = String::from_utf8_lossy(&simulated.stdout);
+        let removals = parse_apt_removals(&output);
+        if !removals.is_empty() {
+            info!("apt-get {} will remove: {}", command, removals.join(", "));
+        }
+        let packages = self.refused_removals(&output);
+        if !packages.is_empty() {
+            return Err(RemovalsRefused {
+                command: format!("apt-get {}", command),
+                packages,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Compares free space on `disk_space.preflight_paths` with what apt
+    /// reports the upgrade will download into `archives` and install under
+    /// /usr, and fails with [`InsufficientDiskSpace`] before dpkg can run
+    /// out halfway. `--print-uris` prints apt's summary and stops.
+    async fn check_disk_space(
+        &self,
+        apt_options: &[&str],
+        archives: &Path,
+    ) -> Result<Vec<SpaceCheck>> {
+        if !self.config.disk_space.preflight {
+            return Ok(Vec::new());
+        }
+        let summary = self.apt_print_uris(apt_options).await?;
+        let download_bytes = self.parse_apt_bytes_downloaded(&summary)?;
+        let install_bytes = parse_apt_size(
+            r"After this operation, ([0-9.,]+)\s*([kMG]?B) of additional disk space will be used",
+            &summary,
+        )?;
+        debug!(
+            "apt needs {} bytes for downloads and {} bytes once installed",
+            download_bytes, install_bytes
+        );
+        let checks = diskspace::preflight(
+            &self.config.disk_space,
+            &mounted_filesystems(),
+            &[
+                (Path::new("/usr"), install_bytes),
+                (archives, download_bytes),
+            ],
+        );
+        if checks.iter().any(|check| !check.sufficient) {
+            return Err(InsufficientDiskSpace { checks }.into());
+        }
+        Ok(checks)
+    }
+
+    /// `apt-get --print-uris` for the upgrade, whose summary has the
+    /// download and install sizes a plain simulation leaves out.
+    async fn apt_print_uris(&self, apt_options: &[&str]) -> Result<String> {
+        let command = self.config.updates.upgrade_mode.apt_command();
+        let args = [apt_options, &["--print-uris", "-y", command]].concat();
+        let output = self
+            .run_command_with_timeout("apt-get", &args, Duration::from_secs(300))
+            .await?;
+        if !output.status.success() {
+            return Err(anyhow::anyhow!(
+                "apt-get --print-uris {} failed: {}",
+                command,
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    fn refused_removals(&self, simulation: &str) -> Vec<String> {
+        self.refused_of(parse_apt_removals(simulation))
+    }
+
+    /// The `removals` that aren't allowed.
+    fn refused_of(&self, removals: Vec<String>) -> Vec<String> {
+        let updates = &self.config.updates;
+        removals
+            .into_iter()
+            .filter(|package| {
+                PROTECTED_PACKAGES.contains(&package.as_str())
+                    || updates.protected_packages.contains(package)
+                    || !updates
+                        .allowed_removals
+                        .iter()
+                        .any(|pattern| removal_allowed(pattern, package))
+            })
+            .collect()
+    }
+
     fn parse_apt_packages_updated(&self, output: &str) -> Result<u64> {
         // Look for patterns like "X upgraded, Y newly installed"
         let re = Regex::new(r"(\d+)\s+upgraded")?;
@@ -417,39 +1663,522 @@ impl UpdateManager {
 
     fn parse_apt_bytes_downloaded(&self, output: &str) -> Result<u64> {
         // Look for patterns like "Need to get 42.1 MB of archives"
-        let re = Regex::new(r"Need to get ([0-9.,]+)\s*([kMG]?B)")?;
+        parse_apt_size(r"Need to get ([0-9.,]+)\s*([kMG]?B)", output)
+    }
+}
 
-        if let Some(captures) = re.captures(output) {
-            if let (Some(size_str), Some(unit_str)) = (captures.get(1), captures.get(2)) {
-                let size: f64 = size_str.as_str().replace(",", "").parse()?;
-                let multiplier = match unit_str.as_str() {
-                    "kB" => 1_000,
-                    "MB" => 1_000_000,
-                    "GB" => 1_000_000_000,
-                    _ => 1,
-                };
+/// Sends SIGTERM to the child's process group, escalating to SIGKILL if it
+/// is still running after `grace`. Returns how the command was terminated.
+pub(crate) async fn terminate_process_group(
+    child: &mut tokio::process::Child,
+    grace: Duration,
+) -> String {
+    let Some(pid) = child.id() else {
+        return "exited before it could be terminated".to_string();
+    };
+
+    signal_process_group(pid, libc::SIGTERM);
+    if let Ok(status) = timeout(grace, child.wait()).await {
+        debug!("Process group {} exited after SIGTERM: {:?}", pid, status);
+        return "terminated with SIGTERM".to_string();
+    }
 
-                return Ok((size * multiplier as f64) as u64);
-            }
+    warn!(
+        "Process group {} ignored SIGTERM for {:?}, sending SIGKILL",
+        pid, grace
+    );
+    signal_process_group(pid, libc::SIGKILL);
+    if let Err(e) = child.wait().await {
+        warn!("Failed to reap killed process {}: {}", pid, e);
+    }
+    format!("killed with SIGKILL after a {:?} grace period", grace)
+}
+
+fn signal_process_group(pid: u32, signal: libc::c_int) {
+    // SAFETY: kill(2) has no memory safety requirements; a negative pid
+    // addresses the process group created with process_group(0)
+    let rc = unsafe { libc::kill(-(pid as libc::pid_t), signal) };
+    if rc != 0 {
+        debug!(
+            "kill(-{}, {}) failed: {}",
+            pid,
+            signal,
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+/// Reads a child's output line by line, logging each line as it arrives so
+/// long apt runs show progress, and returns everything that was read.
+async fn stream_lines<R: AsyncRead + Unpin>(
+    command: &str,
+    stream: &str,
+    reader: R,
+    activity: &Activity,
+) -> std::io::Result<Vec<u8>> {
+    let mut reader = BufReader::new(reader);
+    let mut collected = Vec::new();
+    let mut line = Vec::new();
+    let mut progress = 0u64;
+
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line).await? == 0 {
+            break;
+        }
+        collected.extend_from_slice(&line);
+        activity.touch();
+
+        let text = String::from_utf8_lossy(&line);
+        let text = text.trim_end();
+        debug!("[{} {}] {}", command, stream, text);
+        if let Some(package) = progress_package(text) {
+            progress += 1;
+            info!(
+                "{}: configured {} ({} packages so far)",
+                command, package, progress
+            );
         }
+    }
+    Ok(collected)
+}
 
-        Ok(0)
+/// Whether `now` falls in any of the windows; always when there are none.
+fn in_maintenance_window(windows: &[Window], now: NaiveDateTime) -> bool {
+    windows.is_empty() || windows.iter().any(|window| window.contains(now))
+}
+
+/// Whether the local time is inside the `start`-`end` window; `None` when
+/// no window is configured. Unparseable times count as inside, so a typo
+/// doesn't stop updates.
+fn in_window(name: &str, start: &Option<String>, end: &Option<String>) -> Option<bool> {
+    let (Some(start_str), Some(end_str)) = (start, end) else {
+        return None;
+    };
+    let start = match NaiveTime::parse_from_str(start_str, "%H:%M") {
+        Ok(time) => time,
+        Err(e) => {
+            warn!(
+                "Failed to parse {} window start: {} - {}",
+                name, start_str, e
+            );
+            return Some(true); // Default to allowing updates
+        }
+    };
+    let end = match NaiveTime::parse_from_str(end_str, "%H:%M") {
+        Ok(time) => time,
+        Err(e) => {
+            warn!("Failed to parse {} window end: {} - {}", name, end_str, e);
+            return Some(true); // Default to allowing updates
+        }
+    };
+
+    let now = Local::now().time();
+
+    // Handle windows that cross midnight
+    Some(if start <= end {
+        now >= start && now <= end
+    } else {
+        now >= start || now <= end
+    })
+}
+
+/// Package name stems of the graphics stack: mesa, DRM, nvidia, X.org and
+/// the common wayland compositors.
+const GRAPHICS_PACKAGES: &[&str] = &[
+    "mesa",
+    "libgl1-mesa-dri",
+    "libglx-mesa",
+    "libegl-mesa",
+    "libgbm",
+    "libdrm",
+    "nvidia",
+    "libnvidia",
+    "xserver-xorg",
+    "xwayland",
+    "libwayland",
+    "weston",
+    "mutter",
+    "libmutter",
+    "gnome-shell",
+    "kwin",
+    "sway",
+    "cage",
+];
+
+/// Matches a stem exactly or followed by "-" or a soname digit, so
+/// "libdrm2" and "nvidia-driver-550" match but "cagefs" doesn't.
+pub(crate) fn is_graphics_package(package: &str) -> bool {
+    GRAPHICS_PACKAGES.iter().any(|stem| {
+        package.strip_prefix(stem).is_some_and(|rest| {
+            rest.is_empty()
+                || rest.starts_with('-')
+                || rest.starts_with(|c: char| c.is_ascii_digit())
+        })
+    })
+}
+
+/// Packages apt configured in an upgrade, from its "Setting up" lines; new
+/// dependencies are included.
+pub fn configured_packages(apt_output: &str) -> Vec<String> {
+    let mut packages: Vec<String> = Vec::new();
+    for package in apt_output.lines().filter_map(progress_package) {
+        if !packages.iter().any(|listed| listed == package) {
+            packages.push(package.to_string());
+        }
     }
+    packages
+}
+
+/// Recognizes apt's per-package "Setting up" lines used as progress markers.
+fn progress_package(line: &str) -> Option<&str> {
+    line.strip_prefix("Setting up ")
+        .and_then(|rest| rest.split_whitespace().next())
+}
 
-    fn is_running_as_root(&self) -> bool {
-        // Check if running as root
-        std::process::id() == 0
-            || std::env::var("USER").unwrap_or_default() == "root"
-            || std::env::var("EUID").unwrap_or_default() == "0"
+/// Prefixes a command with systemd-run/ionice/nice wrappers according to the
+/// configured limits. Returns the full argv.
+fn apply_resource_limits(
+    limits: &ResourceLimits,
+    slice: Option<&str>,
+    command: &str,
+    args: &[&str],
+) -> Vec<String> {
+    let mut argv = Vec::new();
+
+    let properties: Vec<String> = [
+        limits.cpu_quota.as_ref().map(|v| format!("CPUQuota={}", v)),
+        limits
+            .memory_max
+            .as_ref()
+            .map(|v| format!("MemoryMax={}", v)),
+        limits.io_weight.map(|v| format!("IOWeight={}", v)),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+
+    if !properties.is_empty() || slice.is_some() {
+        argv.extend(["systemd-run", "--scope", "--quiet", "--collect"].map(String::from));
+        if let Some(slice) = slice {
+            argv.push(format!("--slice={}", slice));
+        }
+        for property in properties {
+            argv.push("-p".to_string());
+            argv.push(property);
+        }
+        argv.push("--".to_string());
     }
+
+    if let Some(class) = &limits.ionice_class {
+        let class_number = match class.as_str() {
+            "realtime" => "1",
+            "best-effort" => "2",
+            _ => "3",
+        };
+        argv.extend([
+            "ionice".to_string(),
+            "-c".to_string(),
+            class_number.to_string(),
+        ]);
+        // The idle class has no priority levels
+        if let Some(level) = limits.ionice_level.filter(|_| class_number != "3") {
+            argv.extend(["-n".to_string(), level.to_string()]);
+        }
+    }
+
+    if let Some(nice) = limits.nice {
+        argv.extend(["nice".to_string(), "-n".to_string(), nice.to_string()]);
+    }
+
+    argv.push(command.to_string());
+    argv.extend(args.iter().map(|arg| arg.to_string()));
+    argv
 }
 
-#[derive(Debug)]
+/// A size apt prints in its summary, with `pattern` capturing the number and
+/// its unit; 0 when the line is missing.
+fn parse_apt_size(pattern: &str, output: &str) -> Result<u64> {
+    let re = Regex::new(pattern)?;
+
+    if let Some(captures) = re.captures(output) {
+        if let (Some(size_str), Some(unit_str)) = (captures.get(1), captures.get(2)) {
+            let size: f64 = size_str.as_str().replace(",", "").parse()?;
+            let multiplier = match unit_str.as_str() {
+                "kB" => 1_000,
+                "MB" => 1_000_000,
+                "GB" => 1_000_000_000,
+                _ => 1,
+            };
+
+            return Ok((size * multiplier as f64) as u64);
+        }
+    }
+
+    Ok(0)
+}
+
+/// An `updates.allowed_removals` entry: a package name, or a prefix ending
+/// in `*` such as `linux-image-*`.
+fn removal_allowed(pattern: &str, package: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => package.starts_with(prefix),
+        None => pattern == package,
+    }
+}
+
+/// The packages listed in /var/run/reboot-required.pkgs, once each.
+pub fn reboot_required_packages() -> Vec<String> {
+    std::fs::read_to_string(REBOOT_REQUIRED_PKGS)
+        .map(|contents| parse_reboot_required_pkgs(&contents))
+        .unwrap_or_default()
+}
+
+/// Postinst scripts append without checking, so a package upgraded twice
+/// before the reboot is listed twice.
+fn parse_reboot_required_pkgs(contents: &str) -> Vec<String> {
+    let mut packages: Vec<String> = Vec::new();
+    for package in contents.lines().map(str::trim) {
+        if !package.is_empty() && !packages.iter().any(|listed| listed == package) {
+            packages.push(package.to_string());
+        }
+    }
+    packages
+}
+
+/// Packages an `apt-get --simulate` run would remove, from lines such as
+/// `Remv ubuntu-server [1.539.2]`.
+fn parse_apt_removals(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter_map(|line| line.strip_prefix("Remv "))
+        .filter_map(|rest| rest.split_whitespace().next())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Parses `apt list --upgradable` lines such as
+/// `firefox/jammy-updates,jammy-security 108.0.1 amd64 [upgradable from: 108.0]`.
+fn parse_apt_upgradable(output: &str, pockets: &PocketMap) -> Vec<PendingUpdate> {
+    output
+        .lines()
+        .filter(|line| line.contains('/') && line.contains("upgradable"))
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let (package, origin) = fields.next()?.split_once('/')?;
+            let candidate = fields.next()?;
+            let current = line
+                .split("upgradable from: ")
+                .nth(1)
+                .map(|rest| rest.trim_end_matches(']').to_string());
+
+            Some(PendingUpdate {
+                source: "apt".to_string(),
+                package: package.to_string(),
+                current_version: current,
+                candidate_version: candidate.to_string(),
+                origin: origin.to_string(),
+                security: pockets.is_security(origin),
+                risk: RiskLevel::default(),
+            })
+        })
+        .collect()
+}
+
+/// Maps snap name to installed version from `snap list`.
+pub(crate) fn parse_snap_list(output: &str) -> HashMap<String, String> {
+    output
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            Some((fields.next()?.to_string(), fields.next()?.to_string()))
+        })
+        .collect()
+}
+
+fn snap_status(snap: &Snap) -> SnapStatus {
+    SnapStatus {
+        name: snap.name.clone(),
+        version: snap.version.clone(),
+        revision: snap.revision.clone(),
+        tracking: snap.tracking_channel.clone(),
+        held: snap.hold.is_some(),
+        refreshed: false,
+    }
+}
+
+fn snap_pending_updates(
+    candidates: Vec<RefreshCandidate>,
+    installed: &[Snap],
+) -> Vec<PendingUpdate> {
+    candidates
+        .into_iter()
+        .map(|candidate| PendingUpdate {
+            source: "snap".to_string(),
+            current_version: installed
+                .iter()
+                .find(|snap| snap.name == candidate.name)
+                .map(|snap| snap.version.clone()),
+            package: candidate.name,
+            candidate_version: candidate.version,
+            origin: candidate
+                .publisher
+                .map(|publisher| publisher.username)
+                .unwrap_or_else(|| "snapcraft".to_string()),
+            security: false,
+            risk: RiskLevel::default(),
+        })
+        .collect()
+}
+
+/// Sizes as flatpak prints them: "283MB", "75.6 MB", "1.2 GB", "512
+/// bytes". Units are decimal like its own.
+fn parse_human_size(size: &str) -> Option<u64> {
+    let size: String = size.chars().filter(|c| !c.is_whitespace()).collect();
+    let split = size.find(|c: char| !c.is_ascii_digit() && c != '.' && c != ',')?;
+    let (number, unit) = size.split_at(split);
+    let number: f64 = number.replace(',', "").parse().ok()?;
+    let multiplier = match unit {
+        "B" | "bytes" => 1,
+        "kB" | "KB" => 1_000,
+        "MB" => 1_000_000,
+        "GB" => 1_000_000_000,
+        _ => return None,
+    };
+    Some((number * multiplier as f64) as u64)
+}
+
+/// Parses tab-separated `flatpak list --columns=ref,origin` (or
+/// `remote-ls`) into (ref, origin) pairs.
+fn parse_flatpak_refs(output: &str) -> Vec<(String, String)> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (flatpak_ref, origin) = line.split_once('\t')?;
+            flatpak_ref
+                .contains('/')
+                .then(|| (flatpak_ref.trim().to_string(), origin.trim().to_string()))
+        })
+        .collect()
+}
+
+/// Splits a ref like "app/org.mozilla.firefox/x86_64/stable".
+fn flatpak_change(
+    flatpak_ref: &str,
+    remote: &str,
+    operation: &str,
+    applied: bool,
+) -> Option<FlatpakChange> {
+    let mut parts = flatpak_ref.split('/');
+    let kind = match parts.next()? {
+        "app" => FlatpakKind::App,
+        "runtime" => FlatpakKind::Runtime,
+        _ => return None,
+    };
+    let id = parts.next()?.to_string();
+    let branch = parts.nth(1)?.to_string();
+    Some(FlatpakChange {
+        id,
+        kind,
+        branch,
+        remote: remote.to_string(),
+        operation: operation.to_string(),
+        applied,
+    })
+}
+
+/// Parses `flatpak update --noninteractive`, which prints one
+/// "Updating <ref>" (or Installing/Uninstalling) line per operation and
+/// "Failed to <op> <ref>: ..." for the ones that failed.
+fn parse_flatpak_transaction(output: &str, installed: &[(String, String)]) -> Vec<FlatpakChange> {
+    let failed: HashSet<&str> = output
+        .lines()
+        .filter_map(|line| line.split_once("Failed to ").map(|(_, rest)| rest))
+        .filter_map(|rest| rest.split_whitespace().nth(1))
+        .map(|flatpak_ref| flatpak_ref.trim_end_matches(':'))
+        .collect();
+
+    output
+        .lines()
+        .filter_map(|line| {
+            let (operation, flatpak_ref) = line.trim().split_once(' ')?;
+            let operation = match operation {
+                "Updating" => "update",
+                "Installing" => "install",
+                "Uninstalling" => "uninstall",
+                _ => return None,
+            };
+            let flatpak_ref = flatpak_ref.trim();
+            let remote = installed
+                .iter()
+                .find(|(installed_ref, _)| installed_ref == flatpak_ref)
+                .map(|(_, origin)| origin.as_str())
+                .unwrap_or_default();
+            flatpak_change(
+                flatpak_ref,
+                remote,
+                operation,
+                !failed.contains(flatpak_ref),
+            )
+        })
+        .collect()
+}
+
+/// Parses tab-separated `flatpak remote-ls --updates --columns=application,version,branch,origin`.
+fn parse_flatpak_updates(output: &str) -> Vec<PendingUpdate> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() < 4 || fields[0].is_empty() {
+                return None;
+            }
+            let version = if fields[1].is_empty() {
+                fields[2]
+            } else {
+                fields[1]
+            };
+            Some(PendingUpdate {
+                source: "flatpak".to_string(),
+                package: fields[0].to_string(),
+                current_version: None,
+                candidate_version: version.to_string(),
+                origin: fields[3].to_string(),
+                security: false,
+                risk: RiskLevel::default(),
+            })
+        })
+        .collect()
+}
+
+fn log_deferred(graphics_deferred: &[String], risk_deferred: &[String]) {
+    if !graphics_deferred.is_empty() {
+        warn!(
+            "Holding back graphics stack updates outside the graphics window: {}",
+            graphics_deferred.join(", ")
+        );
+    }
+    if !risk_deferred.is_empty() {
+        info!(
+            "Holding back updates not scheduled for today by risk level: {}",
+            risk_deferred.join(", ")
+        );
+    }
+}
+
+#[derive(Debug, Default)]
 struct AptResults {
     output: String,
     packages_updated: u64,
     packages_available: u64,
     bytes_downloaded: u64,
+    graphics_deferred: Vec<String>,
+    risk_deferred: Vec<String>,
+    debdelta: Option<DeltaSavings>,
+    disk_space: Vec<SpaceCheck>,
+    /// Packages and bytes a dry run would have upgraded and downloaded
+    would_update: (u64, u64),
 }
 
 #[cfg(test)]
@@ -470,6 +2199,65 @@ thunderbird/jammy-updates,jammy-security 1:102.6.0+build1-0ubuntu0.22.04.1 amd64
         assert_eq!(count, 2);
     }
 
+    #[test]
+    fn test_dry_run_sizes() {
+        assert_eq!(parse_human_size("283MB"), Some(283_000_000));
+        assert_eq!(parse_human_size("75.6\u{a0}MB"), Some(75_600_000));
+        assert_eq!(parse_human_size("1.2 GB"), Some(1_200_000_000));
+        assert_eq!(parse_human_size("512 bytes"), Some(512));
+        assert_eq!(parse_human_size("unknown"), None);
+    }
+
+    #[test]
+    fn test_parse_reboot_required_pkgs() {
+        let contents = "linux-image-6.8.0-48-generic\nlibssl3t64\n\nlinux-image-6.8.0-48-generic\n";
+        assert_eq!(
+            parse_reboot_required_pkgs(contents),
+            vec!["linux-image-6.8.0-48-generic", "libssl3t64"]
+        );
+    }
+
+    #[test]
+    fn test_refused_removals() {
+        let mut config = AgentConfig::default();
+        config.updates.protected_packages = vec!["libfoo1".to_string()];
+        config.updates.allowed_removals = vec!["libfoo1".to_string(), "vim-*".to_string()];
+        let manager = UpdateManager::new(config).unwrap();
+
+        let output = r#"The following packages will be REMOVED:
+  libfoo1 ubuntu-server vim-tiny
+Remv libfoo1 [1.0-1]
+Remv ubuntu-server [1.539.2]
+Remv vim-tiny [2:9.1.0016-1ubuntu7]
+Inst linux-image-6.8.0-48-generic (6.8.0-48.48 Ubuntu:24.04/noble-updates [amd64])
+"#;
+        assert_eq!(parse_apt_removals(output).len(), 3);
+        assert_eq!(
+            manager.refused_removals(output),
+            ["libfoo1", "ubuntu-server"]
+        );
+    }
+
+    #[test]
+    fn test_is_graphics_package() {
+        for package in [
+            "mesa-vulkan-drivers",
+            "libgl1-mesa-dri",
+            "libdrm2",
+            "libdrm-amdgpu1",
+            "nvidia-driver-550",
+            "libnvidia-gl-550",
+            "xserver-xorg-core",
+            "xwayland",
+            "cage",
+        ] {
+            assert!(is_graphics_package(package), "{}", package);
+        }
+        for package in ["firefox", "openssl", "cagefs", "libdrmaa1", "swaybg"] {
+            assert!(!is_graphics_package(package), "{}", package);
+        }
+    }
+
     #[test]
     fn test_parse_apt_packages_updated() {
         let manager = UpdateManager::new(AgentConfig::default()).unwrap();
@@ -502,16 +2290,260 @@ After this operation, 512 kB of additional disk space will be used.
         assert_eq!(bytes, 42_100_000);
     }
 
+    #[test]
+    fn test_parse_apt_upgradable() {
+        let output = r#"Listing...
+firefox/jammy-updates,jammy-security 108.0.1+build1-0ubuntu0.22.04.1 amd64 [upgradable from: 108.0+build2-0ubuntu0.22.04.1]
+htop/jammy-updates 3.0.5-7build3 amd64 [upgradable from: 3.0.5-7build2]
+"#;
+
+        let updates = parse_apt_upgradable(output, &PocketMap::default());
+        assert_eq!(updates.len(), 2);
+        assert_eq!(updates[0].package, "firefox");
+        assert_eq!(
+            updates[0].candidate_version,
+            "108.0.1+build1-0ubuntu0.22.04.1"
+        );
+        assert_eq!(
+            updates[0].current_version.as_deref(),
+            Some("108.0+build2-0ubuntu0.22.04.1")
+        );
+        assert!(updates[0].security);
+        assert_eq!(updates[1].origin, "jammy-updates");
+        assert!(!updates[1].security);
+    }
+
+    #[test]
+    fn test_parse_snap_and_flatpak_updates() {
+        let installed: Vec<Snap> = serde_json::from_str(
+            r#"[{"name":"core20","version":"20230503","revision":"1891","tracking-channel":"latest/stable","hold":"2315-06-19T13:00:37Z"}]"#,
+        )
+        .unwrap();
+        let candidates = serde_json::from_str(
+            r#"[{"name":"core20","version":"20230622","revision":"1974","download-size":66000000,"publisher":{"username":"canonical"}}]"#,
+        )
+        .unwrap();
+        let snaps = snap_pending_updates(candidates, &installed);
+        assert_eq!(snaps.len(), 1);
+        assert_eq!(snaps[0].current_version.as_deref(), Some("20230503"));
+        assert_eq!(snaps[0].candidate_version, "20230622");
+        assert_eq!(snaps[0].origin, "canonical");
+        let status = snap_status(&installed[0]);
+        assert!(status.held);
+        assert_eq!(status.tracking.as_deref(), Some("latest/stable"));
+
+        let flatpaks = parse_flatpak_updates(
+            "org.mozilla.firefox\t121.0\tstable\tflathub\norg.gnome.Platform\t\t45\tflathub\n",
+        );
+        assert_eq!(flatpaks.len(), 2);
+        assert_eq!(flatpaks[1].candidate_version, "45");
+        assert_eq!(flatpaks[1].origin, "flathub");
+    }
+
+    #[test]
+    fn test_parse_flatpak_transaction() {
+        let installed = parse_flatpak_refs(
+            "app/org.mozilla.firefox/x86_64/stable\tflathub\n\
+             runtime/org.gnome.Platform/x86_64/45\tflathub\n",
+        );
+        let output = "Looking for updates…\n\
+                      Updating app/org.mozilla.firefox/x86_64/stable\n\
+                      Installing runtime/org.gnome.Platform/x86_64/46\n\
+                      Updating runtime/org.gnome.Platform/x86_64/45\n\
+                      Warning: Failed to update runtime/org.gnome.Platform/x86_64/45: No space left\n";
+        let changes = parse_flatpak_transaction(output, &installed);
+
+        assert_eq!(changes.len(), 3);
+        assert_eq!(changes[0].id, "org.mozilla.firefox");
+        assert_eq!(changes[0].kind, FlatpakKind::App);
+        assert_eq!(changes[0].remote, "flathub");
+        assert!(changes[0].applied);
+        assert_eq!(changes[1].operation, "install");
+        assert_eq!(changes[1].branch, "46");
+        assert_eq!(changes[2].kind, FlatpakKind::Runtime);
+        assert!(!changes[2].applied);
+    }
+
+    #[tokio::test]
+    async fn test_run_command_streams_output() {
+        let manager = UpdateManager::new(AgentConfig::default()).unwrap();
+
+        let output = manager
+            .run_command_with_timeout(
+                "sh",
+                &["-c", "echo 'Setting up curl (8.5.0) ...'; echo oops >&2"],
+                Duration::from_secs(10),
+            )
+            .await
+            .unwrap();
+
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"Setting up curl (8.5.0) ...\n");
+        assert_eq!(output.stderr, b"oops\n");
+
+        // The apt hook relies on this to ignore the agent's own runs
+        let output = manager
+            .run_command_with_timeout("sh", &["-c", "echo $UA_AGENT_RUN"], Duration::from_secs(10))
+            .await
+            .unwrap();
+        assert_eq!(output.stdout, b"1\n");
+        assert_eq!(
+            progress_package("Setting up curl (8.5.0) ..."),
+            Some("curl")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_command_timeout_kills_child() {
+        let manager = UpdateManager::new(AgentConfig::default()).unwrap();
+
+        let started = std::time::Instant::now();
+        let err = manager
+            .run_command_with_timeout("sleep", &["30"], Duration::from_millis(200))
+            .await
+            .unwrap_err();
+
+        let timed_out = err.downcast_ref::<CommandTimedOut>().unwrap();
+        assert_eq!(timed_out.termination, "terminated with SIGTERM");
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn test_run_command_timeout_escalates_to_sigkill() {
+        let mut manager = UpdateManager::new(AgentConfig::default()).unwrap();
+        manager.kill_grace = Duration::from_millis(200);
+
+        let started = std::time::Instant::now();
+        let err = manager
+            .run_command_with_timeout(
+                "sh",
+                &["-c", "trap '' TERM; sleep 30 & wait"],
+                Duration::from_millis(200),
+            )
+            .await
+            .unwrap_err();
+
+        let timed_out = err.downcast_ref::<CommandTimedOut>().unwrap();
+        assert!(timed_out.termination.starts_with("killed with SIGKILL"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn test_run_command_stall_is_not_a_timeout() {
+        let mut manager = UpdateManager::new(AgentConfig::default()).unwrap();
+        manager.stall_after = Duration::from_millis(400);
+
+        let err = manager
+            .run_command_with_timeout(
+                "sh",
+                &["-c", "echo starting; sleep 30"],
+                Duration::from_secs(60),
+            )
+            .await
+            .unwrap_err();
+
+        let stalled = err.downcast_ref::<CommandStalled>().unwrap();
+        assert!(stalled.idle >= Duration::from_millis(400));
+        assert!(stalled
+            .diagnostics
+            .iter()
+            .any(|line| line.contains("sleep")));
+        assert_eq!(CommandFailure::of(&err), Some(CommandFailure::Stalled));
+    }
+
+    #[test]
+    fn test_apply_resource_limits() {
+        let unlimited =
+            apply_resource_limits(&ResourceLimits::default(), None, "apt-get", &["update"]);
+        assert_eq!(unlimited, vec!["apt-get", "update"]);
+
+        let limits = ResourceLimits {
+            nice: Some(10),
+            ionice_class: Some("best-effort".to_string()),
+            ionice_level: Some(7),
+            cpu_quota: Some("50%".to_string()),
+            memory_max: None,
+            io_weight: Some(10),
+            accounting: false,
+        };
+        let argv = apply_resource_limits(&limits, None, "apt-get", &["upgrade", "-y"]);
+        assert_eq!(
+            argv.join(" "),
+            "systemd-run --scope --quiet --collect -p CPUQuota=50% -p IOWeight=10 -- \
+             ionice -c 2 -n 7 nice -n 10 apt-get upgrade -y"
+        );
+
+        let idle = ResourceLimits {
+            ionice_class: Some("idle".to_string()),
+            ionice_level: Some(4),
+            ..ResourceLimits::default()
+        };
+        assert_eq!(
+            apply_resource_limits(&idle, None, "snap", &["refresh"]).join(" "),
+            "ionice -c 3 snap refresh"
+        );
+        assert_eq!(
+            apply_resource_limits(&idle, Some("ua_agent_run.slice"), "snap", &["refresh"]).join(" "),
+            "systemd-run --scope --quiet --collect --slice=ua_agent_run.slice -- ionice -c 3 snap refresh"
+        );
+    }
+
+    #[test]
+    fn test_apt_cache_option() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut config = AgentConfig::default();
+        config.updates.apt_cache_dir = Some(temp_dir.path().join("debs"));
+
+        // Not mounted: keep apt's default rather than writing below it
+        let manager = UpdateManager::new(config.clone()).unwrap();
+        assert_eq!(manager.apt_cache_option(), None);
+
+        std::fs::create_dir(temp_dir.path().join("debs")).unwrap();
+        let option = manager.apt_cache_option().unwrap();
+        assert_eq!(
+            option,
+            format!(
+                "Dir::Cache::Archives={}/",
+                temp_dir.path().join("debs").display()
+            )
+        );
+        assert!(temp_dir.path().join("debs/partial").is_dir());
+    }
+
     #[test]
     fn test_maintenance_window_check() {
+        let at = |time: &str| NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M").unwrap();
         let mut config = AgentConfig::default();
+        assert!(in_maintenance_window(
+            &crate::window::maintenance_windows(&config.updates),
+            at("2026-10-14 12:00")
+        ));
+
         config.updates.maintenance_window_start = Some("02:00".to_string());
         config.updates.maintenance_window_end = Some("04:00".to_string());
-
-        let manager = UpdateManager::new(config).unwrap();
-
-        // This test would need to be run at different times or mock the time
-        // For now, just ensure it doesn't panic
-        let _in_window = manager.is_in_maintenance_window();
+        let windows = crate::window::maintenance_windows(&config.updates);
+        assert!(in_maintenance_window(&windows, at("2026-10-14 03:00")));
+        assert!(!in_maintenance_window(&windows, at("2026-10-14 12:00")));
+
+        // The list replaces the pair; 2026-10-17 is a Saturday
+        config.updates.maintenance_windows = vec![
+            "Sat 02:00-05:00".to_string(),
+            "Sun 02:00-05:00".to_string(),
+            "Wed 22:00-01:00".to_string(),
+        ];
+        let windows = crate::window::maintenance_windows(&config.updates);
+        assert_eq!(windows.len(), 3);
+        assert!(in_maintenance_window(&windows, at("2026-10-17 04:00")));
+        assert!(in_maintenance_window(&windows, at("2026-10-18 02:00")));
+        assert!(in_maintenance_window(&windows, at("2026-10-15 00:30")));
+        assert!(!in_maintenance_window(&windows, at("2026-10-14 03:00")));
+        assert!(!in_maintenance_window(&windows, at("2026-10-19 03:00")));
+
+        assert!(config.validate().is_err());
+        config.updates.maintenance_window_start = None;
+        config.updates.maintenance_window_end = None;
+        assert!(config.validate().is_ok());
+        config.updates.maintenance_windows = vec!["Sat 02:00-5".to_string()];
+        assert!(config.validate().is_err());
     }
 }
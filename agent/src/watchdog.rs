@@ -0,0 +1,175 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::debug;
+
+use crate::doctor::DPKG_LOCKS;
+
+/// dpkg rewrites these as it unpacks and configures packages, even while
+/// its frontend prints nothing
+const DPKG_STATUS: &str = "/var/lib/dpkg/status";
+const DPKG_UPDATES: &str = "/var/lib/dpkg/updates";
+
+/// Longest gap between checks for progress
+const MAX_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// When a running package command last showed signs of life: a line of
+/// output, a change to dpkg's status database, or a download growing.
+pub struct Activity {
+    last: Mutex<Instant>,
+    /// apt's `partial` download directory
+    partial: PathBuf,
+}
+
+impl Activity {
+    pub fn new(archives: &Path) -> Self {
+        Self {
+            last: Mutex::new(Instant::now()),
+            partial: archives.join("partial"),
+        }
+    }
+
+    pub fn touch(&self) {
+        *self.last.lock().unwrap() = Instant::now();
+    }
+
+    fn idle(&self) -> Duration {
+        self.last.lock().unwrap().elapsed()
+    }
+
+    /// dpkg status mtimes and bytes downloaded so far; any change counts
+    /// as progress.
+    fn fingerprint(&self) -> (Option<SystemTime>, Option<SystemTime>, u64) {
+        let modified = |path: &str| fs::metadata(path).and_then(|m| m.modified()).ok();
+        let downloaded = fs::read_dir(&self.partial)
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok()?.metadata().ok())
+                    .map(|metadata| metadata.len())
+                    .sum()
+            })
+            .unwrap_or(0);
+        (modified(DPKG_STATUS), modified(DPKG_UPDATES), downloaded)
+    }
+
+    /// Resolves once the command has shown no sign of life for
+    /// `stall_after`, with how long it has been idle. Never resolves for a
+    /// zero `stall_after`.
+    pub async fn stalled(&self, stall_after: Duration) -> Duration {
+        if stall_after.is_zero() {
+            return std::future::pending().await;
+        }
+        let interval = (stall_after / 4).min(MAX_CHECK_INTERVAL);
+        let mut fingerprint = self.fingerprint();
+        loop {
+            tokio::time::sleep(interval).await;
+            let current = self.fingerprint();
+            if current != fingerprint {
+                fingerprint = current;
+                self.touch();
+            }
+            let idle = self.idle();
+            if idle >= stall_after {
+                return idle;
+            }
+        }
+    }
+}
+
+/// What the stalled command's process group is doing and who holds the
+/// dpkg locks, one line each, for the log and the error.
+pub fn diagnostics(pgid: u32) -> Vec<String> {
+    let mut lines = Vec::new();
+    let pids: Vec<u32> = fs::read_dir("/proc")
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    for &pid in &pids {
+        let Some(stat) = ProcStat::read(pid) else {
+            continue;
+        };
+        if stat.pgrp == pgid {
+            let wchan = fs::read_to_string(format!("/proc/{}/wchan", pid)).unwrap_or_default();
+            let cmdline = fs::read(format!("/proc/{}/cmdline", pid))
+                .map(|cmdline| String::from_utf8_lossy(&cmdline).replace('\0', " "))
+                .unwrap_or_default();
+            lines.push(format!(
+                "pid {} ({}) state {} waiting in {}: {}",
+                pid,
+                stat.comm,
+                stat.state,
+                if wchan.is_empty() || wchan == "0" {
+                    "-"
+                } else {
+                    &wchan
+                },
+                cmdline.trim()
+            ));
+        }
+        let Ok(fds) = fs::read_dir(format!("/proc/{}/fd", pid)) else {
+            continue;
+        };
+        for fd in fds.filter_map(|fd| fd.ok()) {
+            let Ok(target) = fs::read_link(fd.path()) else {
+                continue;
+            };
+            if let Some(lock) = DPKG_LOCKS.iter().find(|lock| target == Path::new(lock)) {
+                lines.push(format!("{} open in pid {} ({})", lock, pid, stat.comm));
+            }
+        }
+    }
+    debug!("Collected {} lines of stall diagnostics", lines.len());
+    lines
+}
+
+/// The fields of `/proc/<pid>/stat` the diagnostics need.
+#[derive(Debug, PartialEq)]
+struct ProcStat {
+    comm: String,
+    state: char,
+    pgrp: u32,
+}
+
+impl ProcStat {
+    fn read(pid: u32) -> Option<Self> {
+        Self::parse(&fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?)
+    }
+
+    /// "pid (comm) state ppid pgrp ...", where comm may hold spaces and
+    /// parentheses of its own.
+    fn parse(stat: &str) -> Option<Self> {
+        let (head, rest) = stat.rsplit_once(')')?;
+        let (_, comm) = head.split_once('(')?;
+        let mut fields = rest.split_whitespace();
+        let state = fields.next()?.chars().next()?;
+        let _ppid = fields.next()?;
+        Some(Self {
+            comm: comm.to_string(),
+            state,
+            pgrp: fields.next()?.parse().ok()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_proc_stat() {
+        assert_eq!(
+            ProcStat::parse("4242 (dpkg (frontend)) D 4200 4190 4190 0 -1 4194560 1800"),
+            Some(ProcStat {
+                comm: "dpkg (frontend)".to_string(),
+                state: 'D',
+                pgrp: 4190,
+            })
+        );
+        assert_eq!(ProcStat::parse("garbage"), None);
+    }
+}
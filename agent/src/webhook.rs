@@ -0,0 +1,294 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use reqwest::Method;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::fs;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+use crate::config::{AgentConfig, Webhook};
+use crate::http_client::SecureHttpClient;
+use crate::privacy::Redactor;
+use crate::templates::Templates;
+use crate::updater::UpdateResults;
+
+/// Notifications are best effort and must not hold up a run
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What a webhook hears about a finished run. The `generic` format posts
+/// it as is, and templates see its fields.
+#[derive(Debug, Serialize)]
+pub struct RunNotice {
+    /// "run_succeeded" or "run_failed"
+    pub event: &'static str,
+    pub hostname: String,
+    pub run_id: String,
+    pub timestamp: DateTime<Utc>,
+    pub success: bool,
+    pub packages_updated: u64,
+    pub packages_available: u64,
+    pub reboot_required: bool,
+    pub error: Option<String>,
+    /// Packages the run upgraded or installed
+    pub packages: Vec<String>,
+}
+
+impl RunNotice {
+    /// `results` is `None` for runs that failed before producing any.
+    /// Under the minimal reporting profile the hostname and error are
+    /// hashed, as in reports.
+    pub fn new(
+        config: &AgentConfig,
+        run_id: &str,
+        results: Option<&UpdateResults>,
+        error: Option<String>,
+    ) -> Self {
+        let hostname = crate::host::hostname();
+        let (hostname, error) = match Redactor::new(config) {
+            Some(redactor) => (
+                redactor.hash(&hostname),
+                redactor.hash_message(error.as_deref()),
+            ),
+            None => (hostname, error),
+        };
+        let success = error.is_none();
+        Self {
+            event: if success {
+                "run_succeeded"
+            } else {
+                "run_failed"
+            },
+            hostname,
+            run_id: run_id.to_string(),
+            timestamp: Utc::now(),
+            success,
+            packages_updated: results.map_or(0, |results| results.packages_updated),
+            packages_available: results.map_or(0, |results| results.packages_available),
+            reboot_required: results.is_some_and(|results| results.reboot_required),
+            error,
+            packages: results.map_or_else(Vec::new, |results| {
+                crate::updater::configured_packages(&results.apt_output)
+            }),
+        }
+    }
+
+    fn title(&self, templates: &Templates) -> String {
+        templates.render("title", self)
+    }
+
+    fn message(&self, templates: &Templates) -> String {
+        templates.render("message", self)
+    }
+}
+
+/// Request body for a webhook's `format`.
+#[derive(Debug, PartialEq)]
+enum Payload {
+    Json(Value),
+    /// ntfy takes the message as the body and the rest as headers
+    Ntfy {
+        title: String,
+        message: String,
+        priority: &'static str,
+        tags: &'static str,
+    },
+    /// A webhook `template` that didn't render to JSON
+    Text(String),
+}
+
+fn payload(webhook: &Webhook, notice: &RunNotice, templates: &Templates) -> Result<Payload> {
+    if let Some(name) = &webhook.template {
+        let body = templates.try_render(name, notice)?;
+        return Ok(match serde_json::from_str(&body) {
+            Ok(json) => Payload::Json(json),
+            Err(_) => Payload::Text(body),
+        });
+    }
+    let title = notice.title(templates);
+    let message = notice.message(templates);
+    Ok(match webhook.format.as_str() {
+        "slack" => Payload::Json(json!({
+            "text": format!("*{}*\n{}", title, message),
+        })),
+        // A legacy connector card, which Teams workflows accept too
+        "teams" => Payload::Json(json!({
+            "@type": "MessageCard",
+            "@context": "https://schema.org/extensions",
+            "summary": title,
+            "themeColor": if notice.success { "2EB67D" } else { "E01E5A" },
+            "title": title,
+            "text": message.replace('\n', "<br>"),
+        })),
+        "ntfy" => Payload::Ntfy {
+            title,
+            message,
+            priority: if notice.success { "default" } else { "high" },
+            tags: if notice.success {
+                "white_check_mark"
+            } else {
+                "rotating_light"
+            },
+        },
+        _ => Payload::Json(serde_json::to_value(notice)?),
+    })
+}
+
+/// Posts the run's outcome to every `notifications.webhooks` entry that
+/// wants it, at the same time. A webhook that fails is logged and skipped.
+pub async fn notify(config: &AgentConfig, http_client: &SecureHttpClient, notice: &RunNotice) {
+    let webhooks: Vec<&Webhook> = config
+        .notifications
+        .webhooks
+        .iter()
+        .filter(|webhook| !notice.success || !webhook.failures_only)
+        .collect();
+    if webhooks.is_empty() {
+        return;
+    }
+    let client = http_client.for_subsystem("webhook");
+
+    let templates = Templates::new(&config.notifications.templates);
+
+    let deliveries = webhooks.into_iter().map(|webhook| {
+        let client = &client;
+        let templates = &templates;
+        async move {
+            // The URL itself is often the secret, as with Slack
+            let host = reqwest::Url::parse(&webhook.url)
+                .ok()
+                .and_then(|url| url.host_str().map(str::to_string))
+                .unwrap_or_default();
+            match send(config, client, templates, webhook, notice).await {
+                Ok(()) => debug!("Notified {} webhook on {}", webhook.format, host),
+                Err(e) => warn!(
+                    "Failed to notify {} webhook on {}: {:#}",
+                    webhook.format, host, e
+                ),
+            }
+        }
+    });
+    futures::future::join_all(deliveries).await;
+}
+
+async fn send(
+    config: &AgentConfig,
+    client: &SecureHttpClient,
+    templates: &Templates,
+    webhook: &Webhook,
+    notice: &RunNotice,
+) -> Result<()> {
+    let mut request = match payload(webhook, notice, templates)? {
+        Payload::Json(body) => client.external(Method::POST, &webhook.url).json(&body),
+        Payload::Ntfy {
+            title,
+            message,
+            priority,
+            tags,
+        } => client
+            .external(Method::POST, &webhook.url)
+            .header("Title", title)
+            .header("Priority", priority)
+            .header("Tags", tags)
+            .body(message),
+        Payload::Text(body) => client
+            .external(Method::POST, &webhook.url)
+            .header(reqwest::header::CONTENT_TYPE, "text/plain; charset=utf-8")
+            .body(body),
+    };
+    if let Some(path) = &webhook.token_file {
+        let path = config.security.credential_path(path);
+        let token = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read token from {:?}", path))?;
+        request = request.bearer_auth(token.trim());
+    }
+
+    let response = client
+        .send_external(request.timeout(TIMEOUT))
+        .await
+        .context("Failed to reach webhook")?;
+    if !response.status().is_success() {
+        return Err(anyhow::anyhow!("Webhook returned {}", response.status()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::NotificationTemplates;
+
+    fn webhook(format: &str) -> Webhook {
+        Webhook {
+            format: format.to_string(),
+            ..Webhook::default()
+        }
+    }
+
+    #[test]
+    fn test_payload_formats() {
+        let notice = RunNotice {
+            event: "run_failed",
+            hostname: "web-1".to_string(),
+            run_id: "0b6b0c5e".to_string(),
+            timestamp: Utc::now(),
+            success: false,
+            packages_updated: 0,
+            packages_available: 12,
+            reboot_required: true,
+            error: Some("dpkg was interrupted".to_string()),
+            packages: vec!["openssl".to_string()],
+        };
+        let mut config = NotificationTemplates::default();
+        config.inline.insert(
+            "pager".to_string(),
+            r#"{"host": "{{ hostname }}", "packages": {{ packages | tojson }}}"#.to_string(),
+        );
+        let templates = Templates::new(&config);
+
+        let Payload::Json(slack) = payload(&webhook("slack"), &notice, &templates).unwrap() else {
+            panic!("slack posts JSON");
+        };
+        assert_eq!(
+            slack["text"],
+            "*Update run failed on web-1*\n0 package(s) updated, 12 still available, reboot required\nError: dpkg was interrupted"
+        );
+
+        let Payload::Json(teams) = payload(&webhook("teams"), &notice, &templates).unwrap() else {
+            panic!("teams posts JSON");
+        };
+        assert_eq!(teams["themeColor"], "E01E5A");
+        assert!(teams["text"].as_str().unwrap().contains("<br>Error:"));
+
+        match payload(&webhook("ntfy"), &notice, &templates).unwrap() {
+            Payload::Ntfy {
+                title, priority, ..
+            } => {
+                assert_eq!(title, "Update run failed on web-1");
+                assert_eq!(priority, "high");
+            }
+            other => panic!("unexpected ntfy payload {:?}", other),
+        }
+
+        let Payload::Json(generic) = payload(&webhook("generic"), &notice, &templates).unwrap()
+        else {
+            panic!("generic posts JSON");
+        };
+        assert_eq!(generic["event"], "run_failed");
+        assert_eq!(generic["packages_available"], 12);
+
+        let pager = Webhook {
+            template: Some("pager".to_string()),
+            ..webhook("generic")
+        };
+        let rendered = payload(&pager, &notice, &templates);
+        if cfg!(feature = "templates") {
+            assert_eq!(
+                rendered.unwrap(),
+                Payload::Json(json!({ "host": "web-1", "packages": ["openssl"] }))
+            );
+        } else {
+            assert!(rendered.is_err());
+        }
+    }
+}
@@ -0,0 +1,227 @@
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, NaiveTime, Weekday};
+use std::str::FromStr;
+use tracing::warn;
+
+use crate::config::UpdateConfig;
+
+/// A recurring local-time window: "Sat,Sun 02:00-05:00", "Mon-Fri
+/// 22:00-01:00", or "02:00-04:00" for every day. A window that crosses
+/// midnight belongs to the day it starts on, so "Sat 23:00-01:00" runs into
+/// Sunday morning. Start and end must differ; "02:00-02:00" is rejected
+/// rather than read as either a whole day or a single minute.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Window {
+    /// Empty for every day
+    days: Vec<Weekday>,
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl Window {
+    /// The legacy `*_start`/`*_end` pair: the same window every day. Equal
+    /// times are accepted, as they always were, and only match that moment.
+    pub fn daily(start: &str, end: &str) -> Result<Self, String> {
+        Ok(Self {
+            days: Vec::new(),
+            start: parse_time(start)?,
+            end: parse_time(end)?,
+        })
+    }
+
+    fn new(days: Vec<Weekday>, start: NaiveTime, end: NaiveTime) -> Result<Self, String> {
+        if start == end {
+            return Err(format!(
+                "window {} starts and ends at the same time",
+                start.format("%H:%M")
+            ));
+        }
+        Ok(Self { days, start, end })
+    }
+
+    pub fn contains(&self, now: NaiveDateTime) -> bool {
+        let time = now.time();
+        if self.start <= self.end {
+            self.starts_on(now.weekday()) && time >= self.start && time <= self.end
+        } else {
+            (self.starts_on(now.weekday()) && time >= self.start)
+                || (self.starts_on(now.weekday().pred()) && time <= self.end)
+        }
+    }
+
+    /// Start and end of the window opening on `day`, if it opens that day.
+    pub fn on(&self, day: NaiveDate) -> Option<(NaiveDateTime, NaiveDateTime)> {
+        if !self.starts_on(day.weekday()) {
+            return None;
+        }
+        let end_day = if self.end < self.start {
+            day + Duration::days(1)
+        } else {
+            day
+        };
+        Some((day.and_time(self.start), end_day.and_time(self.end)))
+    }
+
+    fn starts_on(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+}
+
+impl FromStr for Window {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, String> {
+        let spec = spec.trim();
+        let (days, range) = match spec.rsplit_once(char::is_whitespace) {
+            Some((days, range)) => (parse_days(days.trim())?, range),
+            None => (Vec::new(), spec),
+        };
+        let (start, end) = range
+            .split_once('-')
+            .ok_or_else(|| format!("expected HH:MM-HH:MM, got {:?}", range))?;
+        Self::new(days, parse_time(start)?, parse_time(end)?)
+    }
+}
+
+/// "Sat,Sun", "Mon-Fri" or a mix such as "Mon-Wed,Sat". A range may wrap
+/// around the week, as in "Fri-Mon".
+fn parse_days(spec: &str) -> Result<Vec<Weekday>, String> {
+    let day = |name: &str| {
+        name.trim()
+            .parse::<Weekday>()
+            .map_err(|_| format!("invalid day {:?}", name.trim()))
+    };
+    let mut days = Vec::new();
+    for part in spec.split(',') {
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let (mut current, last) = (day(first)?, day(last)?);
+                days.push(current);
+                while current != last {
+                    current = current.succ();
+                    days.push(current);
+                }
+            }
+            None => days.push(day(part)?),
+        }
+    }
+    Ok(days)
+}
+
+fn parse_time(time: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(time.trim(), "%H:%M")
+        .map_err(|_| format!("invalid time {:?}, expected HH:MM", time.trim()))
+}
+
+/// The configured maintenance windows: `maintenance_windows`, or the
+/// `maintenance_window_start`/`_end` pair as one daily window. Empty when
+/// updates may run at any time. Invalid entries, which validation already
+/// rejects, are logged and skipped.
+pub fn maintenance_windows(updates: &UpdateConfig) -> Vec<Window> {
+    if !updates.maintenance_windows.is_empty() {
+        return updates
+            .maintenance_windows
+            .iter()
+            .filter_map(|spec| match spec.parse() {
+                Ok(window) => Some(window),
+                Err(e) => {
+                    warn!("Ignoring maintenance window {:?}: {}", spec, e);
+                    None
+                }
+            })
+            .collect();
+    }
+    match (
+        &updates.maintenance_window_start,
+        &updates.maintenance_window_end,
+    ) {
+        (Some(start), Some(end)) => match Window::daily(start, end) {
+            Ok(window) => vec![window],
+            Err(e) => {
+                warn!("Ignoring maintenance window {}-{}: {}", start, end, e);
+                Vec::new()
+            }
+        },
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(date: &str, time: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(&format!("{} {}", date, time), "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    #[test]
+    fn test_parse_windows() {
+        let window: Window = "Sat,Sun 02:00-05:00".parse().unwrap();
+        assert_eq!(window.days, vec![Weekday::Sat, Weekday::Sun]);
+        assert_eq!(window.start, NaiveTime::from_hms_opt(2, 0, 0).unwrap());
+
+        let window: Window = "Fri-Mon,Wed 22:00-01:00".parse().unwrap();
+        assert_eq!(
+            window.days,
+            vec![
+                Weekday::Fri,
+                Weekday::Sat,
+                Weekday::Sun,
+                Weekday::Mon,
+                Weekday::Wed
+            ]
+        );
+        assert!("02:00-04:00".parse::<Window>().unwrap().days.is_empty());
+        assert_eq!(
+            " Monday  01:30-02:00 ".parse::<Window>().unwrap().days,
+            vec![Weekday::Mon]
+        );
+
+        assert!("Sat 02:00".parse::<Window>().is_err());
+        assert!("Sat 25:00-26:00".parse::<Window>().is_err());
+        assert!("Caturday 02:00-05:00".parse::<Window>().is_err());
+        assert!("Sat-Sun-Mon 02:00-05:00".parse::<Window>().is_err());
+        assert!("Sat 02:00-02:00".parse::<Window>().is_err());
+
+        // The legacy pair keeps its old meaning
+        let legacy = Window::daily("03:00", "03:00").unwrap();
+        assert!(legacy.contains(at("2026-10-14", "03:00")));
+        assert!(!legacy.contains(at("2026-10-14", "03:01")));
+        let day = NaiveDate::from_ymd_opt(2026, 10, 14).unwrap();
+        assert_eq!(
+            legacy.on(day),
+            Some((at("2026-10-14", "03:00"), at("2026-10-14", "03:00")))
+        );
+    }
+
+    #[test]
+    fn test_window_contains() {
+        // 2026-10-17 is a Saturday
+        let weekend: Window = "Sat,Sun 02:00-05:00".parse().unwrap();
+        assert!(weekend.contains(at("2026-10-17", "02:00")));
+        assert!(weekend.contains(at("2026-10-18", "05:00")));
+        assert!(!weekend.contains(at("2026-10-17", "05:01")));
+        assert!(!weekend.contains(at("2026-10-19", "03:00")));
+
+        // Crossing midnight belongs to the starting day
+        let friday_night: Window = "Fri 23:00-01:00".parse().unwrap();
+        assert!(friday_night.contains(at("2026-10-16", "23:30")));
+        assert!(friday_night.contains(at("2026-10-17", "00:30")));
+        assert!(!friday_night.contains(at("2026-10-16", "00:30")));
+        assert!(!friday_night.contains(at("2026-10-17", "23:30")));
+
+        let daily = Window::daily("02:00", "04:00").unwrap();
+        assert!(daily.contains(at("2026-10-14", "03:00")));
+        assert!(!daily.contains(at("2026-10-14", "04:30")));
+
+        let (start, end) = friday_night
+            .on(NaiveDate::from_ymd_opt(2026, 10, 16).unwrap())
+            .unwrap();
+        assert_eq!(
+            (start, end),
+            (at("2026-10-16", "23:00"), at("2026-10-17", "01:00"))
+        );
+        assert!(friday_night
+            .on(NaiveDate::from_ymd_opt(2026, 10, 17).unwrap())
+            .is_none());
+    }
+}